@@ -0,0 +1,24 @@
+#![no_main]
+
+use azure_service_bus::servicebus::atom::{parse_duration, parse_feed, Entry};
+use azure_service_bus::servicebus::description::{
+    EntityDescription, QueueDescription, RuleDescription, SubscriptionDescription, TopicDescription,
+};
+use libfuzzer_sys::fuzz_target;
+
+fn describe(entry: &Entry) {
+    let _ = QueueDescription::from_entry(entry);
+    let _ = TopicDescription::from_entry(entry);
+    let _ = SubscriptionDescription::from_entry(entry);
+    let _ = RuleDescription::from_entry(entry);
+}
+
+fuzz_target!(|data: &str| {
+    if let Ok(entry) = Entry::parse(data) {
+        describe(&entry);
+    }
+    if let Ok(entries) = parse_feed(data) {
+        entries.iter().for_each(describe);
+    }
+    let _ = parse_duration(data);
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+use azure_service_bus::servicebus::brokeredmessage::{BrokerProperties, BrokeredMessage};
+use hyper::header::HeaderValue;
+use hyper::Response;
+use libfuzzer_sys::fuzz_target;
+
+// The first line is used as the BrokerProperties header, the rest as the body.
+fuzz_target!(|data: &[u8]| {
+    let split = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+    let (header, body) = (&data[..split], data.get(split + 1..).unwrap_or_default());
+    let body = String::from_utf8_lossy(body);
+
+    let mut response = Response::new(body.to_string());
+    if let Ok(value) = HeaderValue::from_bytes(header) {
+        response.headers_mut().insert("BrokerProperties", value);
+    }
+    let message = BrokeredMessage::with_response(response);
+    if let Ok(inner) = message.get_body() {
+        assert!(body.contains(&inner));
+    }
+    serde_json::from_str::<BrokerProperties>(&message.props_as_json()).unwrap();
+
+    let wrapped = BrokeredMessage::with_body(&body);
+    assert_eq!(body, wrapped.get_body().unwrap());
+});
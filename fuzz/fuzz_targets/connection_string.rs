@@ -0,0 +1,11 @@
+#![no_main]
+
+use azure_service_bus::core::connection_string::ConnectionString;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(conn) = data.parse::<ConnectionString>() {
+        let reparsed: ConnectionString = conn.to_string().parse().unwrap();
+        assert_eq!(conn, reparsed);
+    }
+});
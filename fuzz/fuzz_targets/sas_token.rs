@@ -0,0 +1,12 @@
+#![no_main]
+
+use azure_service_bus::core::sas::{self, SasToken};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(token) = data.parse::<SasToken>() {
+        let _ = token.grants("https://test.servicebus.windows.net/orders");
+        let _ = token.resource();
+    }
+    let _ = sas::verify(data, "dGVzdA==", 0);
+});
@@ -0,0 +1,454 @@
+//! Sends, receives and inspects Service Bus messages, and manages the entities
+//! of a namespace, from the command line.
+//!
+//! ```text
+//! sbctl send    --queue orders [--property priority=2] [--label order] [--lines] [FILE...]
+//! sbctl receive --queue orders [--count 10] [--timeout 5] [--delete]
+//! sbctl peek    --topic events --subscription audit --dead-letter [--count 10]
+//! sbctl complete --queue orders --lock-token TOKEN --sequence 17
+//! sbctl purge   --queue orders --dead-letter
+//! sbctl record  --queue orders --dead-letter --keep orders.jsonl
+//! sbctl replay  --queue orders-test --speed 60 orders.jsonl
+//!
+//! sbctl list    subscriptions [--topic events] [--output json]
+//! sbctl create  --queue orders --set max_delivery_count=5 --set lock_duration=PT1M
+//! sbctl update  --topic events --subscription audit --description audit.json
+//! sbctl create  --topic events --subscription audit --rule high --sql "priority > 1"
+//! sbctl delete  --topic events --subscription audit --rule high
+//! ```
+//!
+//! Messages are printed one JSON object per line with their body, broker
+//! properties and custom properties. Recordings are JSON Lines files in the
+//! format of `azure_service_bus::servicebus::archive`. Entities are listed as
+//! a table of message counts, or as JSON with `--output json`. The namespace
+//! comes from `--connection-string`, `SERVICEBUS_CONNECTION_STRING` or a
+//! profile, see `azure_service_bus::cli`.
+
+use azure_service_bus::cli::admin::{self, Listing, Settings};
+use azure_service_bus::cli::archive;
+use azure_service_bus::cli::messages::{self, MessageTemplate, ReceiveMode, Target};
+use azure_service_bus::cli::{self, HttpExecutor};
+use azure_service_bus::core::clock::SystemClock;
+use azure_service_bus::servicebus::archive::{read_lines, BodyEncoding, Pacing};
+use azure_service_bus::servicebus::brokeredmessage::BrokeredMessage;
+use azure_service_bus::{NamespaceClient, QueueClient};
+use eyre::{eyre, Report, WrapErr};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: sbctl <command> [options]
+
+Commands:
+  send        Send stdin, each line of stdin or each file as a message
+  receive     Receive messages and print them, locked unless --delete is given
+  peek        Print messages of a dead letter queue and leave them there
+  complete    Complete a message received earlier
+  abandon     Abandon a message received earlier
+  purge       Delete every message
+  record      Write messages to a JSON Lines file, or stdout, taking them off the entity
+  replay      Send the messages of recordings, or of stdin, to a queue or topic
+  list        List queues, topics, subscriptions or rules: sbctl list <kind>
+  get         Print the description of an entity as JSON
+  create      Create a queue, topic, subscription or rule
+  update      Change settings of an entity, leaving the others as they are
+  delete      Delete an entity
+
+Connection:
+  --connection-string <conn>   Connection string [env: SERVICEBUS_CONNECTION_STRING]
+  --profile <name>             Profile from the profile file [env: SBCTL_PROFILE]
+  --profiles <file>            Profile file [env: SBCTL_PROFILES]
+                               [default: ~/.config/sbctl/profiles.toml]
+
+Entity:
+  --queue <name>               Queue to use
+  --topic <name>               Topic to send to, or to receive from with --subscription
+  --subscription <name>        Subscription of the topic
+  --rule <name>                Rule of the subscription
+  --dead-letter                Use the dead letter queue of the entity
+
+Send:
+  --lines                      Send each line of the input as its own message
+  --raw                        Send bodies as they are instead of as a DataContract string
+  --property <name=value>      Custom property, JSON values keep their type (repeatable)
+  --label <label>              Label of the messages
+  --message-id <id>            MessageId of the messages
+  --correlation-id <id>        CorrelationId of the messages
+  --session-id <id>            SessionId of the messages
+  --ttl <seconds>              Time to live of the messages
+
+Receive and peek:
+  --count <n>                  Most messages to print [default: 1]
+  --timeout <seconds>          How long to wait for the first message [default: 5]
+  --delete                     Delete messages as they are received
+  --force                      Peek outside a dead letter queue, which counts as a delivery
+                               and dead letters messages that are on their last one
+
+Record and replay:
+  --count <n>                  Most messages to record [default: all]
+  --timeout <seconds>          Stop once no message arrives for this long [default: 5]
+  --keep                       Leave messages on the entity by peeking them, see --force
+  --base64                     Record bodies as base64 instead of text
+  --rate <n>                   Most messages to replay a second
+  --speed <factor>             Keep the original gaps between messages, divided by this
+
+Complete and abandon:
+  --lock-token <token>         LockToken the message was received with
+  --sequence <number>          SequenceNumber of the message
+  --message-id <id>            MessageId of the message, when there is no sequence number
+
+List, get, create and update:
+  --output <table|json>        How to print entities [default: table for list, json for get]
+  --set <field=value>          Setting of the description, e.g. lock_duration=PT1M (repeatable)
+  --description <file>         JSON file with settings, applied before --set
+  --sql <expression>           SQL filter of a rule
+  --action <expression>        SQL action of a rule
+
+  -h, --help                   Print this message";
+
+const COMMANDS: &[&str] = &[
+    "send", "receive", "peek", "complete", "abandon", "purge", "record", "replay", "list", "get",
+    "create", "update", "delete",
+];
+
+#[derive(Default)]
+struct Options {
+    command: String,
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles: Option<PathBuf>,
+    queue: Option<String>,
+    topic: Option<String>,
+    subscription: Option<String>,
+    rule: Option<String>,
+    dead_letter: bool,
+    // Files to send, record to or replay, or what to list.
+    args: Vec<String>,
+    lines: bool,
+    template: MessageTemplate,
+    count: Option<usize>,
+    timeout: Duration,
+    delete: bool,
+    keep: bool,
+    force: bool,
+    encoding: Option<BodyEncoding>,
+    pacing: Pacing,
+    lock_token: Option<String>,
+    sequence: Option<usize>,
+    json: Option<bool>,
+    settings: Settings,
+    description: Option<PathBuf>,
+    sql: Option<String>,
+    action: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options {
+        command: match args.next() {
+            Some(command) if COMMANDS.contains(&command.as_str()) => command,
+            Some(command) if command != "-h" && command != "--help" => {
+                return Err(eyre!("Unknown command {}.\n\n{}", command, USAGE))
+            }
+            _ => return Ok(None),
+        },
+        timeout: Duration::from_secs(5),
+        ..Default::default()
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--connection-string" => options.connection_string = Some(value()?),
+            "--profile" => options.profile = Some(value()?),
+            "--profiles" => options.profiles = Some(PathBuf::from(value()?)),
+            "--queue" => options.queue = Some(value()?),
+            "--topic" => options.topic = Some(value()?),
+            "--subscription" => options.subscription = Some(value()?),
+            "--rule" => options.rule = Some(value()?),
+            "--dead-letter" => options.dead_letter = true,
+            "--lines" => options.lines = true,
+            "--raw" => options.template.raw = true,
+            "--property" => {
+                let (name, value) = messages::parse_property(&value()?)?;
+                options.template.properties.insert(name, value);
+            }
+            "--label" => options.template.props.Label = Some(value()?),
+            "--message-id" => options.template.props.MessageId = Some(value()?),
+            "--correlation-id" => options.template.props.CorrelationId = Some(value()?),
+            "--session-id" => options.template.props.SessionId = Some(value()?),
+            "--ttl" => options.template.props.TimeToLive = Some(value()?.parse()?),
+            "--count" => options.count = Some(value()?.parse()?),
+            "--timeout" => options.timeout = Duration::from_secs(value()?.parse()?),
+            "--delete" => options.delete = true,
+            "--keep" => options.keep = true,
+            "--force" => options.force = true,
+            "--base64" => options.encoding = Some(BodyEncoding::Base64),
+            "--rate" => options.pacing.rate = Some(value()?.parse()?),
+            "--speed" => options.pacing.speed = Some(value()?.parse()?),
+            "--lock-token" => options.lock_token = Some(value()?),
+            "--sequence" => options.sequence = Some(value()?.parse()?),
+            "--output" => {
+                options.json = match value()?.as_str() {
+                    "json" => Some(true),
+                    "table" => Some(false),
+                    other => return Err(eyre!("Unknown output {}.\n\n{}", other, USAGE)),
+                }
+            }
+            "--set" => {
+                let (name, value) = messages::parse_property(&value()?)?;
+                options.settings.insert(name, value);
+            }
+            "--description" => options.description = Some(PathBuf::from(value()?)),
+            "--sql" => options.sql = Some(value()?),
+            "--action" => options.action = Some(value()?),
+            "-h" | "--help" => return Ok(None),
+            other if !other.starts_with('-') => options.args.push(other.to_string()),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+// The bodies to send: each file, each line of stdin, or all of stdin.
+fn bodies(options: &Options) -> Result<Vec<String>, Report> {
+    let mut bodies = Vec::new();
+    if options.args.is_empty() {
+        if options.lines {
+            for line in std::io::stdin().lock().lines() {
+                bodies.push(line?);
+            }
+        } else {
+            let mut body = String::new();
+            std::io::stdin().read_to_string(&mut body)?;
+            bodies.push(body);
+        }
+    }
+    for file in options.args.iter().map(PathBuf::from) {
+        let contents = std::fs::read_to_string(&file)
+            .wrap_err_with(|| format!("Could not read {}", file.display()))?;
+        if options.lines {
+            bodies.extend(contents.lines().map(str::to_string));
+        } else {
+            bodies.push(contents);
+        }
+    }
+    Ok(bodies)
+}
+
+fn print(messages: &[BrokeredMessage]) {
+    for message in messages {
+        println!("{}", messages::to_json(message));
+    }
+}
+
+fn run(options: Options) -> Result<(), Report> {
+    let conn = cli::connection_string(
+        options.connection_string.clone(),
+        options.profile.clone(),
+        options.profiles.clone(),
+    )?;
+    let exec = HttpExecutor::new();
+
+    if ["list", "get", "create", "update", "delete"].contains(&options.command.as_str()) {
+        return manage(&options, &NamespaceClient::with_conn(&conn)?, &exec);
+    }
+    if options.command == "send" || options.command == "replay" {
+        let entity = match (&options.queue, &options.topic) {
+            (Some(name), None) | (None, Some(name)) => name,
+            _ => return Err(eyre!("Name either a queue or a topic to send to.")),
+        };
+        let queue = QueueClient::with_conn_and_queue(&conn, entity)?;
+        let sent = if options.command == "replay" {
+            replay(&options, &exec, &queue)?
+        } else {
+            let bodies = bodies(&options)?;
+            messages::send(
+                &exec,
+                &queue,
+                bodies.iter().map(|body| options.template.message(body)),
+            )?
+        };
+        eprintln!("Sent {} message(s) to {}", sent, entity);
+        return Ok(());
+    }
+
+    let mut target = Target::open(
+        &conn,
+        options.queue.as_deref(),
+        options.topic.as_deref(),
+        options.subscription.as_deref(),
+    )?;
+    if options.dead_letter {
+        target = target.dead_letter_queue();
+    }
+    let locked = || {
+        let lock_token = options
+            .lock_token
+            .as_deref()
+            .ok_or_else(|| eyre!("--lock-token is required."))?;
+        messages::locked_message(
+            lock_token,
+            options.sequence,
+            options.template.props.MessageId.as_deref(),
+        )
+    };
+    match options.command.as_str() {
+        "receive" => {
+            let mode = if options.delete {
+                ReceiveMode::ReceiveAndDelete
+            } else {
+                ReceiveMode::PeekLock
+            };
+            print(&messages::receive(
+                &exec,
+                &target,
+                mode,
+                options.count.unwrap_or(1),
+                options.timeout,
+            )?);
+        }
+        "peek" => print(&messages::peek(
+            &exec,
+            &target,
+            options.count.unwrap_or(1),
+            options.timeout,
+            options.force,
+        )?),
+        "complete" => messages::complete(&exec, &target, locked()?)?,
+        "abandon" => messages::abandon(&exec, &target, locked()?)?,
+        "purge" => eprintln!("Deleted {} message(s)", messages::purge(&exec, &target)?),
+        "record" => eprintln!("Recorded {} message(s)", record(&options, &exec, &target)?),
+        _ => unreachable!("commands are checked by parse_args"),
+    }
+    Ok(())
+}
+
+// Records to the file named on the command line, or to stdout.
+fn record(options: &Options, exec: &HttpExecutor, target: &Target) -> Result<usize, Report> {
+    let mut out: Box<dyn Write> = match options.args.as_slice() {
+        [] => Box::new(std::io::stdout().lock()),
+        [file] => Box::new(
+            std::fs::File::create(file).wrap_err_with(|| format!("Could not create {}", file))?,
+        ),
+        _ => return Err(eyre!("Record to one file at a time.")),
+    };
+    archive::record(
+        exec,
+        target,
+        options.count.unwrap_or(usize::MAX),
+        options.timeout,
+        options.keep,
+        options.force,
+        options.encoding.unwrap_or(BodyEncoding::Text),
+        &SystemClock,
+        &mut out,
+    )
+}
+
+// Replays each recording named on the command line in turn, or stdin.
+fn replay(options: &Options, exec: &HttpExecutor, queue: &QueueClient) -> Result<usize, Report> {
+    if options.args.is_empty() {
+        return archive::replay(
+            exec,
+            queue,
+            read_lines(std::io::stdin().lock()),
+            options.pacing,
+        );
+    }
+    let mut sent = 0;
+    for file in &options.args {
+        let input =
+            std::fs::File::open(file).wrap_err_with(|| format!("Could not read {}", file))?;
+        let recorded = read_lines(BufReader::new(input))
+            .map(|line| line.wrap_err_with(|| format!("In {}", file)));
+        sent += archive::replay(exec, queue, recorded, options.pacing)?;
+    }
+    Ok(sent)
+}
+
+// The settings from --description, then --sql and --action, then --set.
+fn settings(options: &Options) -> Result<Settings, Report> {
+    let mut settings = match &options.description {
+        Some(path) => admin::read_settings(path)?,
+        None => Settings::new(),
+    };
+    settings.extend(admin::rule_settings(
+        options.sql.as_deref(),
+        options.action.as_deref(),
+    )?);
+    settings.extend(options.settings.clone());
+    Ok(settings)
+}
+
+fn print_json(value: &serde_json::Value) -> Result<(), Report> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn manage(options: &Options, client: &NamespaceClient, exec: &HttpExecutor) -> Result<(), Report> {
+    if options.command == "list" {
+        let what = options.args.first().ok_or_else(|| {
+            eyre!(
+                "List what? queues, topics, subscriptions or rules.\n\n{}",
+                USAGE
+            )
+        })?;
+        let listing = Listing::parse(
+            what,
+            options.topic.as_deref(),
+            options.subscription.as_deref(),
+        )?;
+        let entities = admin::list(exec, client, &listing)?;
+        if options.json == Some(true) {
+            let values = entities
+                .iter()
+                .map(admin::to_json)
+                .collect::<Result<Vec<_>, _>>()?;
+            print_json(&serde_json::Value::from(values))?;
+        } else {
+            print!("{}", admin::table(&entities));
+        }
+        return Ok(());
+    }
+
+    let entity = admin::named(
+        options.queue.as_deref(),
+        options.topic.as_deref(),
+        options.subscription.as_deref(),
+        options.rule.as_deref(),
+    )?;
+    let entity = match options.command.as_str() {
+        "get" => admin::get(exec, client, &entity)?,
+        "create" => admin::create(exec, client, &entity, &settings(options)?)?,
+        "update" => admin::update(exec, client, &entity, &settings(options)?)?,
+        _ => {
+            admin::delete(exec, client, &entity)?;
+            eprintln!("Deleted {} {}", entity.kind(), entity.path());
+            return Ok(());
+        }
+    };
+    if options.json == Some(false) {
+        print!("{}", admin::table(std::slice::from_ref(&entity)));
+    } else {
+        print_json(&admin::to_json(&entity)?)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    run(options)
+}
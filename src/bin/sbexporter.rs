@@ -0,0 +1,118 @@
+//! Serves the message counts of queues, topics and subscriptions as
+//! Prometheus metrics.
+//!
+//! ```text
+//! sbexporter --queue orders --topic events --subscription billing/invoices --port 9464
+//! sbexporter --interval 60
+//! ```
+//!
+//! Without any `--queue`, `--topic` or `--subscription` every entity in the
+//! namespace is reported. A topic is reported along with all of its
+//! subscriptions. See `azure_service_bus::cli::exporter` for the metrics.
+
+use azure_service_bus::cli::exporter::{Exporter, Watch, DEFAULT_INTERVAL};
+use azure_service_bus::cli::{self, HttpExecutor};
+use azure_service_bus::NamespaceClient;
+use eyre::{eyre, Report};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 9464;
+
+const USAGE: &str = "\
+Usage: sbexporter [options]
+
+Connection:
+  --connection-string <conn>   Connection string [env: SERVICEBUS_CONNECTION_STRING]
+  --profile <name>             Profile from the profile file [env: SBCTL_PROFILE]
+  --profiles <file>            Profile file [env: SBCTL_PROFILES]
+
+Entities (repeatable; without any, everything is reported):
+  --queue <name>               Queue to report on
+  --topic <name>               Topic to report on, with its subscriptions
+  --subscription <topic/name>  Subscription to report on
+
+Exporter:
+  --port <port>                Port to serve /metrics on [default: 9464]
+  --bind <address>             Address to listen on [default: 127.0.0.1]
+  --interval <seconds>         How often to read the counts [default: 30]
+
+  -h, --help                   Print this message";
+
+struct Options {
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles: Option<PathBuf>,
+    watches: Vec<Watch>,
+    addr: SocketAddr,
+    interval: Duration,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options {
+        connection_string: None,
+        profile: None,
+        profiles: None,
+        watches: Vec::new(),
+        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+        interval: DEFAULT_INTERVAL,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--connection-string" => options.connection_string = Some(value()?),
+            "--profile" => options.profile = Some(value()?),
+            "--profiles" => options.profiles = Some(PathBuf::from(value()?)),
+            "--queue" => options.watches.push(Watch::Queue(value()?)),
+            "--topic" => options.watches.push(Watch::Topic(value()?)),
+            "--subscription" => {
+                let path = value()?;
+                let (topic, name) = path
+                    .split_once('/')
+                    .ok_or_else(|| eyre!("Subscriptions are given as topic/name, not {}.", path))?;
+                options
+                    .watches
+                    .push(Watch::Subscription(topic.to_string(), name.to_string()));
+            }
+            "--port" => options.addr.set_port(value()?.parse()?),
+            "--bind" => options.addr.set_ip(value()?.parse()?),
+            "--interval" => options.interval = Duration::from_secs(value()?.parse()?),
+            "-h" | "--help" => return Ok(None),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+fn run(options: Options) -> Result<(), Report> {
+    let conn =
+        cli::connection_string(options.connection_string, options.profile, options.profiles)?;
+    let exporter = Exporter::new(NamespaceClient::with_conn(&conn)?, options.watches)
+        .interval(options.interval);
+
+    let listener = TcpListener::bind(options.addr)?;
+    eprintln!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    exporter.serve(listener, HttpExecutor::new())
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    run(options)
+}
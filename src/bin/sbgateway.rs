@@ -0,0 +1,169 @@
+//! Accepts webhook calls over http and forwards their bodies to a queue or
+//! topic.
+//!
+//! ```text
+//! sbgateway --topic webhooks --port 8080 --key-name webhook --header X-GitHub-Event=event
+//! sbgateway --key-name webhook --sign https://hooks.example.com/github --valid-for 365
+//! ```
+//!
+//! Calls are authenticated with shared access signatures signed with the
+//! gateway's key, given with `--key` or `SBGATEWAY_KEY`. `--sign` prints a
+//! token for a url, both as an `Authorization` header and as a url with the
+//! token in its query string, for tools that only take a url. See
+//! `azure_service_bus::cli::gateway` for what is accepted and answered.
+
+use azure_service_bus::cli::gateway::{Gateway, HeaderMapping, DEFAULT_MAX_BODY};
+use azure_service_bus::cli::{self, HttpExecutor};
+use azure_service_bus::core::sas::SasToken;
+use azure_service_bus::QueueClient;
+use eyre::{eyre, Report};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const KEY_ENV: &str = "SBGATEWAY_KEY";
+const DEFAULT_KEY_NAME: &str = "webhook";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_VALID_FOR_DAYS: u64 = 365;
+
+const USAGE: &str = "\
+Usage: sbgateway [options]
+
+Connection:
+  --connection-string <conn>   Connection string [env: SERVICEBUS_CONNECTION_STRING]
+  --profile <name>             Profile from the profile file [env: SBCTL_PROFILE]
+  --profiles <file>            Profile file [env: SBCTL_PROFILES]
+  --queue <name>               Queue to forward to
+  --topic <name>               Topic to forward to
+
+Gateway:
+  --port <port>                Port to listen on [default: 8080]
+  --bind <address>             Address to listen on [default: 127.0.0.1]
+  --key-name <name>            Name callers sign their tokens with [default: webhook]
+  --key <key>                  Key callers sign their tokens with [env: SBGATEWAY_KEY]
+  --header <name[=property]>   Copy a request header into a custom property (repeatable)
+  --max-body <bytes>           Largest body accepted [default: 262144]
+
+Tokens:
+  --sign <url>                 Print a token for calls to this url and exit
+  --valid-for <days>           How long the token is accepted [default: 365]
+
+  -h, --help                   Print this message";
+
+struct Options {
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles: Option<PathBuf>,
+    queue: Option<String>,
+    topic: Option<String>,
+    addr: SocketAddr,
+    key_name: String,
+    key: Option<String>,
+    headers: Vec<HeaderMapping>,
+    max_body: usize,
+    sign: Option<String>,
+    valid_for: Duration,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options {
+        connection_string: None,
+        profile: None,
+        profiles: None,
+        queue: None,
+        topic: None,
+        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+        key_name: DEFAULT_KEY_NAME.to_string(),
+        key: std::env::var(KEY_ENV).ok(),
+        headers: Vec::new(),
+        max_body: DEFAULT_MAX_BODY,
+        sign: None,
+        valid_for: Duration::from_secs(DEFAULT_VALID_FOR_DAYS * 24 * 60 * 60),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--connection-string" => options.connection_string = Some(value()?),
+            "--profile" => options.profile = Some(value()?),
+            "--profiles" => options.profiles = Some(PathBuf::from(value()?)),
+            "--queue" => options.queue = Some(value()?),
+            "--topic" => options.topic = Some(value()?),
+            "--port" => options.addr.set_port(value()?.parse()?),
+            "--bind" => options.addr.set_ip(value()?.parse()?),
+            "--key-name" => options.key_name = value()?,
+            "--key" => options.key = Some(value()?),
+            "--header" => options.headers.push(value()?.parse()?),
+            "--max-body" => options.max_body = value()?.parse()?,
+            "--sign" => options.sign = Some(value()?),
+            "--valid-for" => {
+                let days: u64 = value()?.parse()?;
+                options.valid_for = Duration::from_secs(days * 24 * 60 * 60);
+            }
+            "-h" | "--help" => return Ok(None),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+fn run(options: Options) -> Result<(), Report> {
+    let key = options
+        .key
+        .as_deref()
+        .ok_or_else(|| eyre!("No key. Pass --key or set {}.", KEY_ENV))?;
+    if let Some(url) = &options.sign {
+        let token = SasToken::builder(url)
+            .valid_for(options.valid_for)
+            .sign(&options.key_name, key);
+        let header = token.to_string();
+        let query = header.trim_start_matches("SharedAccessSignature ");
+        println!("Authorization: {}", header);
+        println!("{}?{}", url, query);
+        return Ok(());
+    }
+
+    let conn = cli::connection_string(
+        options.connection_string.clone(),
+        options.profile.clone(),
+        options.profiles.clone(),
+    )?;
+    let entity = match (&options.queue, &options.topic) {
+        (Some(name), None) | (None, Some(name)) => name,
+        _ => return Err(eyre!("Name either a queue or a topic to forward to.")),
+    };
+    let mut gateway = Gateway::new(
+        QueueClient::with_conn_and_queue(&conn, entity)?,
+        &options.key_name,
+        key,
+    )
+    .max_body(options.max_body);
+    for mapping in options.headers {
+        gateway = gateway.header(mapping);
+    }
+
+    let listener = TcpListener::bind(options.addr)?;
+    eprintln!(
+        "Forwarding webhooks on http://{} to {}",
+        listener.local_addr()?,
+        entity
+    );
+    gateway.serve(listener, HttpExecutor::new())
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    run(options)
+}
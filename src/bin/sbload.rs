@@ -0,0 +1,156 @@
+//! Sends and receives at a target rate to see what a queue or topic can take.
+//!
+//! ```text
+//! sbload --queue load --rate 200 --size 4096 --senders 4 --receivers 4 --duration 60
+//! sbload --topic events --subscription audit --settle delete --output json
+//! ```
+//!
+//! Prints the messages sent and received a second and percentiles of how long
+//! sends took and how long messages waited between being enqueued and
+//! received. Run it against an empty entity: whatever was already there is
+//! received and counted too. The local emulator works as well as a namespace:
+//! start `servicebus-emulator` and pass its connection string.
+
+use azure_service_bus::cli::load::{self, LoadOptions};
+use azure_service_bus::cli::messages::{ReceiveMode, Target};
+use azure_service_bus::cli::{self, HttpExecutor};
+use azure_service_bus::core::clock::SystemClock;
+use azure_service_bus::QueueClient;
+use eyre::{eyre, Report};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: sbload [options]
+
+Connection:
+  --connection-string <conn>   Connection string [env: SERVICEBUS_CONNECTION_STRING]
+  --profile <name>             Profile from the profile file [env: SBCTL_PROFILE]
+  --profiles <file>            Profile file [env: SBCTL_PROFILES]
+
+Entity:
+  --queue <name>               Queue to send to and receive from
+  --topic <name>               Topic to send to
+  --subscription <name>        Subscription of the topic to receive from; without one
+                               messages are only sent
+
+Load:
+  --rate <n>                   Messages a second across all senders [default: as fast as possible]
+  --size <bytes>               Size of each message body [default: 1024]
+  --senders <n>                Threads sending [default: 1]
+  --receivers <n>              Threads receiving, 0 to only send [default: 1]
+  --duration <seconds>         How long to send for [default: 10]
+  --drain <seconds>            How long to wait for the last messages once sending stops [default: 5]
+  --settle <complete|delete>   Receive under a lock and complete, or receive and delete
+                               [default: complete]
+  --output <text|json>         How to print the results [default: text]
+
+  -h, --help                   Print this message";
+
+#[derive(Default)]
+struct Options {
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles: Option<PathBuf>,
+    queue: Option<String>,
+    topic: Option<String>,
+    subscription: Option<String>,
+    load: LoadOptions,
+    json: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--connection-string" => options.connection_string = Some(value()?),
+            "--profile" => options.profile = Some(value()?),
+            "--profiles" => options.profiles = Some(PathBuf::from(value()?)),
+            "--queue" => options.queue = Some(value()?),
+            "--topic" => options.topic = Some(value()?),
+            "--subscription" => options.subscription = Some(value()?),
+            "--rate" => options.load.rate = Some(value()?.parse()?),
+            "--size" => options.load.size = value()?.parse()?,
+            "--senders" => options.load.senders = value()?.parse()?,
+            "--receivers" => options.load.receivers = value()?.parse()?,
+            "--duration" => options.load.duration = Duration::from_secs(value()?.parse()?),
+            "--drain" => options.load.drain = Duration::from_secs(value()?.parse()?),
+            "--settle" => {
+                options.load.mode = match value()?.as_str() {
+                    "complete" => ReceiveMode::PeekLock,
+                    "delete" => ReceiveMode::ReceiveAndDelete,
+                    other => return Err(eyre!("Unknown settlement {}.\n\n{}", other, USAGE)),
+                }
+            }
+            "--output" => {
+                options.json = match value()?.as_str() {
+                    "json" => true,
+                    "text" => false,
+                    other => return Err(eyre!("Unknown output {}.\n\n{}", other, USAGE)),
+                }
+            }
+            "-h" | "--help" => return Ok(None),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+fn run(options: Options) -> Result<(), Report> {
+    let conn = cli::connection_string(
+        options.connection_string.clone(),
+        options.profile.clone(),
+        options.profiles.clone(),
+    )?;
+    let entity = match (&options.queue, &options.topic) {
+        (Some(name), None) | (None, Some(name)) => name,
+        _ => return Err(eyre!("Name either a queue or a topic.\n\n{}", USAGE)),
+    };
+    let sender = QueueClient::with_conn_and_queue(&conn, entity)?;
+    let receiver = if options.load.receivers > 0
+        && (options.queue.is_some() || options.subscription.is_some())
+    {
+        Some(Target::open(
+            &conn,
+            options.queue.as_deref(),
+            options.topic.as_deref(),
+            options.subscription.as_deref(),
+        )?)
+    } else {
+        None
+    };
+    let sender = Some(&sender).filter(|_| options.load.senders > 0);
+
+    let report = load::run(
+        &HttpExecutor::new(),
+        sender,
+        receiver.as_ref(),
+        &options.load,
+        &SystemClock,
+    )?;
+    if options.json {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    run(options)
+}
@@ -0,0 +1,493 @@
+//! A terminal browser for the queues and subscriptions of a namespace and
+//! their dead letter queues.
+//!
+//! ```text
+//! sbtui [--connection-string CONN | --profile NAME] [--count 50] [--refresh 5]
+//! ```
+//!
+//! The left pane lists every queue and subscription with its active and dead
+//! lettered message counts, refreshed every few seconds. Enter loads the dead
+//! letter queue of the selected entity into the right pane, where messages
+//! can be read, resubmitted or deleted.
+//!
+//! Only dead letter queues are browsed. The REST api can only show a message
+//! by receiving it under a peek lock, which counts as a delivery, and on a
+//! live entity that would dead letter messages that are on their last one.
+//! The locks are renewed while the messages are shown and given back when
+//! other messages are loaded or the browser quits.
+//!
+//! Messages from a queue are resubmitted to the queue, and messages from a
+//! subscription to the entity the subscription forwards to. `F` sends a
+//! subscription's message to its topic instead, which copies it to every
+//! subscription whose rules match.
+
+use azure_service_bus::cli::admin::{self, Listing};
+use azure_service_bus::cli::browse::{self, BodyView};
+use azure_service_bus::cli::messages::{self, ReceiveMode, Target};
+use azure_service_bus::cli::{self, HttpExecutor};
+use azure_service_bus::servicebus::brokeredmessage::BrokeredMessage;
+use azure_service_bus::servicebus::description::Entity;
+use azure_service_bus::NamespaceClient;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use eyre::{eyre, Report};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: sbtui [options]
+
+Options:
+  --connection-string <conn>   Connection string [env: SERVICEBUS_CONNECTION_STRING]
+  --profile <name>             Profile from the profile file [env: SBCTL_PROFILE]
+  --profiles <file>            Profile file [env: SBCTL_PROFILES]
+  --count <n>                  Most messages to load at once [default: 50]
+  --refresh <seconds>          How often the counts are refreshed [default: 5]
+  -h, --help                   Print this message
+
+Keys:
+  Tab             Switch between the entity and message panes
+  Up/Down, j/k    Select
+  Enter           Load the dead lettered messages of the selected entity
+  v               Show the body decoded, as JSON or as raw bytes
+  PgUp/PgDn       Scroll the message
+  r               Resubmit the selected message to its queue or forwarding target
+  F               Resubmit the selected subscription message to its topic
+  d, Delete       Delete the selected message
+  R, F5           Refresh the counts and reload the messages
+  q, Esc          Quit";
+
+struct Options {
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles: Option<PathBuf>,
+    count: usize,
+    refresh: Duration,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options {
+        connection_string: None,
+        profile: None,
+        profiles: None,
+        count: 50,
+        refresh: Duration::from_secs(5),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--connection-string" => options.connection_string = Some(value()?),
+            "--profile" => options.profile = Some(value()?),
+            "--profiles" => options.profiles = Some(PathBuf::from(value()?)),
+            "--count" => options.count = value()?.parse()?,
+            "--refresh" => options.refresh = Duration::from_secs(value()?.parse()?),
+            "-h" | "--help" => return Ok(None),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+// What Service Bus locks messages for when the entity does not say.
+const DEFAULT_LOCK_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Settle {
+    Resubmit { to_topic: bool },
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Entities,
+    Messages,
+}
+
+struct App {
+    conn: String,
+    client: NamespaceClient,
+    exec: HttpExecutor,
+    count: usize,
+    refresh: Duration,
+    last_refresh: Instant,
+
+    entities: Vec<Entity>,
+    entity_state: TableState,
+    // The dead letter queue the messages are locked on, where the entity it
+    // belongs to forwards, and how often the locks need renewing.
+    loaded: Option<Target>,
+    loaded_title: String,
+    forward_to: Option<String>,
+    renew_every: Duration,
+    last_renewal: Instant,
+    messages: Vec<BrokeredMessage>,
+    message_state: TableState,
+
+    focus: Focus,
+    view: BodyView,
+    scroll: u16,
+    status: String,
+}
+
+fn target_for(conn: &str, entity: &Entity) -> Result<Target, Report> {
+    match entity {
+        Entity::Queue(q) => Target::open(conn, Some(&q.name), None, None),
+        Entity::Subscription(s) => Target::open(conn, None, Some(&s.topic), Some(&s.name)),
+        other => Err(eyre!("{} {} has no messages.", other.kind(), other.path())),
+    }
+}
+
+fn counts(entity: &Entity) -> (String, String) {
+    let details = match entity {
+        Entity::Queue(q) => q.count_details,
+        Entity::Subscription(s) => s.count_details,
+        _ => None,
+    };
+    match details {
+        Some(d) => (
+            d.active_message_count.to_string(),
+            d.dead_letter_message_count.to_string(),
+        ),
+        None => ("-".to_string(), "-".to_string()),
+    }
+}
+
+impl App {
+    fn refresh_entities(&mut self) -> Result<(), Report> {
+        let selected = self
+            .entity_state
+            .selected()
+            .and_then(|i| self.entities.get(i))
+            .map(Entity::path);
+        let mut entities = admin::list(&self.exec, &self.client, &Listing::Queues)?;
+        entities.extend(admin::list(
+            &self.exec,
+            &self.client,
+            &Listing::Subscriptions(None),
+        )?);
+        self.entities = entities;
+        self.last_refresh = Instant::now();
+        let index = selected
+            .and_then(|path| self.entities.iter().position(|e| e.path() == path))
+            .or(if self.entities.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        self.entity_state.select(index);
+        Ok(())
+    }
+
+    // Gives the locks on the loaded messages back.
+    fn release(&mut self) {
+        if let Some(target) = &self.loaded {
+            for message in self.messages.drain(..) {
+                let _ = messages::abandon(&self.exec, target, message);
+            }
+        }
+        self.message_state.select(None);
+    }
+
+    fn load_messages(&mut self) -> Result<(), Report> {
+        self.release();
+        let entity = match self
+            .entity_state
+            .selected()
+            .and_then(|i| self.entities.get(i))
+        {
+            Some(entity) => entity,
+            None => return Ok(()),
+        };
+        let target = target_for(&self.conn, entity)?.dead_letter_queue();
+        let (lock_duration, forward_to) = match entity {
+            Entity::Queue(q) => (q.lock_duration, None),
+            Entity::Subscription(s) => (s.lock_duration, s.forward_to.clone()),
+            _ => (None, None),
+        };
+        self.loaded_title = format!("{}/$DeadLetterQueue", entity.path());
+        self.forward_to = forward_to;
+        self.renew_every = lock_duration.unwrap_or(DEFAULT_LOCK_DURATION) / 2;
+        self.last_renewal = Instant::now();
+        self.messages = messages::receive(
+            &self.exec,
+            &target,
+            ReceiveMode::PeekLock,
+            self.count,
+            Duration::from_secs(0),
+        )?;
+        self.loaded = Some(target);
+        self.message_state.select(if self.messages.is_empty() {
+            None
+        } else {
+            Some(0)
+        });
+        self.scroll = 0;
+        self.status = format!("Loaded {} message(s)", self.messages.len());
+        Ok(())
+    }
+
+    fn selected_message(&self) -> Option<(usize, &BrokeredMessage)> {
+        let i = self.message_state.selected()?;
+        Some((i, self.messages.get(i)?))
+    }
+
+    // Keeps the loaded messages locked while they are shown.
+    fn renew_locks(&mut self) -> Result<(), Report> {
+        self.last_renewal = Instant::now();
+        if let Some(target) = &self.loaded {
+            for message in &self.messages {
+                messages::renew(&self.exec, target, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn settle(&mut self, settle: Settle) -> Result<(), Report> {
+        let (target, (i, message)) = match (&self.loaded, self.selected_message()) {
+            (Some(target), Some(selected)) => (target, selected),
+            _ => return Ok(()),
+        };
+        if let Settle::Resubmit { to_topic } = settle {
+            let to = browse::resubmit_destination(target, self.forward_to.as_deref(), to_topic)?;
+            browse::resubmit(&self.exec, target, &to, message)?;
+            self.status = format!("Resubmitted message {} to {}", i + 1, to.queue());
+        } else {
+            messages::complete(&self.exec, target, message.clone())?;
+            self.status = format!("Deleted message {}", i + 1);
+        }
+        self.messages.remove(i);
+        let next = if self.messages.is_empty() {
+            None
+        } else {
+            Some(i.min(self.messages.len() - 1))
+        };
+        self.message_state.select(next);
+        self.scroll = 0;
+        Ok(())
+    }
+
+    fn handle(&mut self, key: KeyCode) -> Result<bool, Report> {
+        let state = match self.focus {
+            Focus::Entities => &mut self.entity_state,
+            Focus::Messages => &mut self.message_state,
+        };
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Entities => Focus::Messages,
+                    Focus::Messages => Focus::Entities,
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                state.select_next();
+                self.scroll = 0;
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                state.select_previous();
+                self.scroll = 0;
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter if self.focus == Focus::Entities => {
+                self.load_messages()?;
+                self.focus = Focus::Messages;
+            }
+            KeyCode::Char('v') => self.view = self.view.next(),
+            KeyCode::Char('r') => self.settle(Settle::Resubmit { to_topic: false })?,
+            KeyCode::Char('F') => self.settle(Settle::Resubmit { to_topic: true })?,
+            KeyCode::Char('d') | KeyCode::Delete => self.settle(Settle::Delete)?,
+            KeyCode::Char('R') | KeyCode::F(5) => {
+                self.refresh_entities()?;
+                if self.loaded.is_some() {
+                    self.load_messages()?;
+                }
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, detail, footer] = Layout::vertical([
+            Constraint::Percentage(45),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(top);
+        let current = self.focus;
+        let border = move |focus: Focus| {
+            if current == focus {
+                Style::new().bold()
+            } else {
+                Style::new().dim()
+            }
+        };
+
+        let rows = self.entities.iter().map(|entity| {
+            let (active, dead) = counts(entity);
+            Row::new(vec![entity.path(), active, dead])
+        });
+        let entities = Table::new(
+            rows,
+            [
+                Constraint::Min(10),
+                Constraint::Length(8),
+                Constraint::Length(8),
+            ],
+        )
+        .header(Row::new(vec!["ENTITY", "ACTIVE", "DLQ"]).bold())
+        .block(
+            Block::bordered()
+                .title(" Entities ")
+                .border_style(border(Focus::Entities)),
+        )
+        .row_highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(entities, left, &mut self.entity_state);
+
+        let rows = self.messages.iter().map(|m| {
+            let props = &m.props;
+            let reason = m
+                .properties
+                .get("deadletterreason")
+                .map(|r| r.as_str().map_or_else(|| r.to_string(), str::to_string))
+                .unwrap_or_default();
+            Row::new(vec![
+                props
+                    .SequenceNumber
+                    .map(|n| n.to_string())
+                    .unwrap_or_default(),
+                props.EnqueuedTimeUtc.clone().unwrap_or_default(),
+                props
+                    .Label
+                    .clone()
+                    .or_else(|| props.MessageId.clone())
+                    .unwrap_or_default(),
+                reason,
+            ])
+        });
+        let title = if self.loaded.is_some() {
+            format!(" {} ", self.loaded_title)
+        } else {
+            " Messages ".to_string()
+        };
+        let messages = Table::new(
+            rows,
+            [
+                Constraint::Length(8),
+                Constraint::Length(29),
+                Constraint::Min(10),
+                Constraint::Min(10),
+            ],
+        )
+        .header(Row::new(vec!["SEQ", "ENQUEUED", "LABEL", "REASON"]).bold())
+        .block(
+            Block::bordered()
+                .title(title)
+                .border_style(border(Focus::Messages)),
+        )
+        .row_highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(messages, right, &mut self.message_state);
+
+        let text = match self.selected_message() {
+            Some((_, message)) => format!(
+                "{}\n{}\n\n{}",
+                serde_json::to_string(&*message.props).unwrap_or_default(),
+                serde_json::to_string(&message.properties).unwrap_or_default(),
+                browse::render_body(message, self.view)
+            ),
+            None => String::new(),
+        };
+        let body = Paragraph::new(text)
+            .wrap(Wrap { trim: false })
+            .scroll((self.scroll, 0))
+            .block(Block::bordered().title(format!(" Message ({}) ", self.view.name())));
+        frame.render_widget(body, detail);
+
+        let help =
+            "Tab pane  Enter load  v view  r resubmit  F to topic  d delete  R refresh  q quit";
+        frame.render_widget(
+            Paragraph::new(format!("{}  |  {}", self.status, help)).dim(),
+            footer,
+        );
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Report> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(Duration::from_millis(250))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        match self.handle(key.code) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(()),
+                            Err(e) => self.status = format!("{:#}", e),
+                        }
+                    }
+                }
+            }
+            if self.loaded.is_some() && self.last_renewal.elapsed() >= self.renew_every {
+                if let Err(e) = self.renew_locks() {
+                    self.status = format!("Could not renew the locks: {:#}", e);
+                }
+            }
+            if self.last_refresh.elapsed() >= self.refresh {
+                if let Err(e) = self.refresh_entities() {
+                    self.status = format!("{:#}", e);
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let conn =
+        cli::connection_string(options.connection_string, options.profile, options.profiles)?;
+    let mut app = App {
+        client: NamespaceClient::with_conn(&conn)?,
+        conn,
+        exec: HttpExecutor::new(),
+        count: options.count,
+        refresh: options.refresh,
+        last_refresh: Instant::now(),
+        entities: Vec::new(),
+        entity_state: TableState::default(),
+        loaded: None,
+        loaded_title: String::new(),
+        forward_to: None,
+        renew_every: DEFAULT_LOCK_DURATION / 2,
+        last_renewal: Instant::now(),
+        messages: Vec::new(),
+        message_state: TableState::default(),
+        focus: Focus::Entities,
+        view: BodyView::Decoded,
+        scroll: 0,
+        status: String::new(),
+    };
+    app.refresh_entities()?;
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    app.release();
+    result
+}
@@ -0,0 +1,108 @@
+//! Runs the Service Bus emulator on localhost for local development.
+//!
+//! ```text
+//! servicebus-emulator [--port 5300] [--bind 127.0.0.1] [--data .servicebus-emulator]
+//!                     [--config topology.yaml] [--key-name NAME] [--key KEY] [--in-memory]
+//! ```
+//!
+//! Point an application at it with the connection string it prints, which
+//! has `UseDevelopmentEmulator=true` set so the clients use plain http.
+
+use azure_service_bus::emulator::{Emulator, DEFAULT_KEY_NAME};
+use azure_service_bus::servicebus::topology::Topology;
+use eyre::{eyre, Report};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+const DEFAULT_PORT: u16 = 5300;
+const DEFAULT_DATA_DIR: &str = ".servicebus-emulator";
+// The same well known key other Service Bus emulators use, so connection
+// strings can be checked in to development configs.
+const DEFAULT_KEY: &str = "SAS_KEY_VALUE";
+
+const USAGE: &str = "\
+Usage: servicebus-emulator [options]
+
+Options:
+  --port <port>        Port to listen on [default: 5300]
+  --bind <address>     Address to listen on [default: 127.0.0.1]
+  --data <dir>         Where queues and messages are saved [default: .servicebus-emulator]
+  --in-memory          Don't save anything
+  --config <file>      Topology file (json, or yaml/toml when enabled) with entities to create
+  --key-name <name>    Shared access key name [default: RootManageSharedAccessKey]
+  --key <key>          Shared access key [default: SAS_KEY_VALUE]
+  -h, --help           Print this message";
+
+struct Options {
+    addr: SocketAddr,
+    data_dir: Option<PathBuf>,
+    config: Option<PathBuf>,
+    key_name: String,
+    key: String,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, Report> {
+    let mut options = Options {
+        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+        data_dir: Some(PathBuf::from(DEFAULT_DATA_DIR)),
+        config: None,
+        key_name: DEFAULT_KEY_NAME.to_string(),
+        key: DEFAULT_KEY.to_string(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value.\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--port" => options.addr.set_port(value()?.parse()?),
+            "--bind" => options.addr.set_ip(value()?.parse()?),
+            "--data" => options.data_dir = Some(PathBuf::from(value()?)),
+            "--in-memory" => options.data_dir = None,
+            "--config" => options.config = Some(PathBuf::from(value()?)),
+            "--key-name" => options.key_name = value()?,
+            "--key" => options.key = value()?,
+            "-h" | "--help" => return Ok(None),
+            other => return Err(eyre!("Unknown argument {}.\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+fn main() -> Result<(), Report> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut builder = Emulator::builder()
+        .bind(options.addr)
+        .key(&options.key_name, &options.key);
+    if let Some(config) = &options.config {
+        builder = builder.topology(Topology::from_path(config)?);
+    }
+    if let Some(dir) = &options.data_dir {
+        builder = builder.persist_to(dir);
+    }
+    let emulator = builder.start()?;
+
+    println!("Service Bus emulator listening on {}", emulator.endpoint());
+    match emulator.data_dir() {
+        Some(dir) => println!("Saving to {}", dir.display()),
+        None => println!("Keeping everything in memory"),
+    }
+    println!("Connection string: {}", emulator.connection_string());
+
+    // Changes are saved within a fraction of a second, so killing the process
+    // loses at most the last few and there is nothing to clean up.
+    loop {
+        std::thread::park();
+    }
+}
@@ -0,0 +1,538 @@
+//! Listing, reading, creating, updating and deleting the entities of a
+//! namespace, as the `sbctl` management subcommands do it.
+//!
+//! Settings are given as JSON: the fields of the description types in
+//! `servicebus::description`, written the way they serialize, e.g.
+//! `{"lock_duration": "PT1M", "max_delivery_count": 5}`. They are applied on
+//! top of the entity's current description, so an update only changes the
+//! fields that are named. Names always come from the command line.
+
+use super::execute;
+use crate::core::exec::Executor;
+use crate::servicebus::atom::Entry;
+use crate::servicebus::description::{
+    CountDetails, Entity, EntityDescription, QueueDescription, RuleAction, RuleDescription,
+    RuleFilter, SubscriptionDescription, TopicDescription,
+};
+use crate::NamespaceClient;
+use eyre::{eyre, Report, WrapErr};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Settings to apply to a description, keyed by field name.
+pub type Settings = Map<String, Value>;
+
+/// What `sbctl list` lists.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listing {
+    Queues,
+    Topics,
+    /// The subscriptions of one topic, or of every topic.
+    Subscriptions(Option<String>),
+    /// The rules of a subscription.
+    Rules(String, String),
+}
+
+impl Listing {
+    /// Reads `queues`, `topics`, `subscriptions` or `rules`. Subscriptions
+    /// can be narrowed down to a topic, rules need both names.
+    pub fn parse(
+        what: &str,
+        topic: Option<&str>,
+        subscription: Option<&str>,
+    ) -> Result<Listing, Report> {
+        match (what, topic, subscription) {
+            ("queues", _, _) => Ok(Listing::Queues),
+            ("topics", _, _) => Ok(Listing::Topics),
+            ("subscriptions", topic, _) => Ok(Listing::Subscriptions(topic.map(str::to_string))),
+            ("rules", Some(topic), Some(subscription)) => {
+                Ok(Listing::Rules(topic.to_string(), subscription.to_string()))
+            }
+            ("rules", _, _) => Err(eyre!("Listing rules needs a topic and a subscription.")),
+            (other, _, _) => Err(eyre!(
+                "Can't list {}, only queues, topics, subscriptions or rules.",
+                other
+            )),
+        }
+    }
+}
+
+/// The entity named by the command line flags, with nothing but its name set:
+/// a queue, a topic, a subscription of a topic or a rule of a subscription.
+pub fn named(
+    queue: Option<&str>,
+    topic: Option<&str>,
+    subscription: Option<&str>,
+    rule: Option<&str>,
+) -> Result<Entity, Report> {
+    match (queue, topic, subscription, rule) {
+        (Some(queue), None, None, None) => Ok(Entity::Queue(QueueDescription::new(queue))),
+        (None, Some(topic), None, None) => Ok(Entity::Topic(TopicDescription::new(topic))),
+        (None, Some(topic), Some(sub), None) => Ok(Entity::Subscription(
+            SubscriptionDescription::new(topic, sub),
+        )),
+        (None, Some(topic), Some(sub), Some(rule)) => Ok(Entity::Rule(RuleDescription::new(
+            topic,
+            sub,
+            rule,
+            RuleFilter::True,
+        ))),
+        _ => Err(eyre!(
+            "Name a queue, a topic, a subscription of a topic or a rule of a subscription."
+        )),
+    }
+}
+
+/// Reads settings from a JSON file holding one description.
+pub fn read_settings(path: impl AsRef<Path>) -> Result<Settings, Report> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+    match serde_json::from_str(&contents)
+        .wrap_err_with(|| format!("Could not parse {}", path.display()))?
+    {
+        Value::Object(settings) => Ok(settings),
+        _ => Err(eyre!("{} does not hold a JSON object.", path.display())),
+    }
+}
+
+/// Settings for a rule's SQL filter and action.
+pub fn rule_settings(sql: Option<&str>, action: Option<&str>) -> Result<Settings, Report> {
+    let mut settings = Settings::new();
+    if let Some(expression) = sql {
+        let filter = RuleFilter::Sql {
+            expression: expression.to_string(),
+        };
+        settings.insert("filter".to_string(), serde_json::to_value(filter)?);
+    }
+    if let Some(expression) = action {
+        let action = RuleAction::Sql {
+            expression: expression.to_string(),
+        };
+        settings.insert("action".to_string(), serde_json::to_value(action)?);
+    }
+    Ok(settings)
+}
+
+// Applies settings to one description. A field the description doesn't have,
+// or a read only one, is an error rather than silently ignored.
+fn merge<D: Serialize + DeserializeOwned>(desc: &D, settings: &Settings) -> Result<D, Report> {
+    let mut value = serde_json::to_value(desc)?;
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| eyre!("Descriptions serialize to JSON objects."))?;
+    for (name, setting) in settings {
+        if name != "name" {
+            fields.insert(name.clone(), setting.clone());
+        }
+    }
+    let merged: D = serde_json::from_value(value)?;
+    let written = serde_json::to_value(&merged)?;
+    for (name, setting) in settings {
+        if !setting.is_null() && written.get(name).is_none() {
+            return Err(eyre!("{} is not a setting that can be changed.", name));
+        }
+    }
+    Ok(merged)
+}
+
+/// Applies settings on top of a description. The names are kept.
+pub fn apply_settings(entity: &Entity, settings: &Settings) -> Result<Entity, Report> {
+    Ok(match entity {
+        Entity::Queue(d) => Entity::Queue(merge(d, settings)?),
+        Entity::Topic(d) => Entity::Topic(merge(d, settings)?),
+        Entity::Subscription(d) => Entity::Subscription(SubscriptionDescription {
+            topic: d.topic.clone(),
+            ..merge(d, settings)?
+        }),
+        Entity::Rule(d) => Entity::Rule(RuleDescription {
+            topic: d.topic.clone(),
+            subscription: d.subscription.clone(),
+            ..merge(d, settings)?
+        }),
+    })
+}
+
+fn fetch<D: EntityDescription, E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    desc: &D,
+) -> Result<D, Report> {
+    let response = execute(exec, client.get(desc)?)?;
+    D::from_entry(&Entry::parse(response.body())?)
+}
+
+/// The current description of an entity, runtime information included.
+pub fn get<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    entity: &Entity,
+) -> Result<Entity, Report> {
+    Ok(match entity {
+        Entity::Queue(d) => Entity::Queue(fetch(exec, client, d)?),
+        Entity::Topic(d) => Entity::Topic(fetch(exec, client, d)?),
+        Entity::Subscription(d) => Entity::Subscription(SubscriptionDescription {
+            topic: d.topic.clone(),
+            ..fetch(exec, client, d)?
+        }),
+        Entity::Rule(d) => Entity::Rule(RuleDescription {
+            topic: d.topic.clone(),
+            subscription: d.subscription.clone(),
+            ..fetch(exec, client, d)?
+        }),
+    })
+}
+
+/// Creates an entity with the settings given, leaving the rest for Service
+/// Bus to default.
+pub fn create<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    entity: &Entity,
+    settings: &Settings,
+) -> Result<Entity, Report> {
+    let entity = apply_settings(entity, settings)?;
+    let request = match &entity {
+        Entity::Queue(d) => client.create(d)?,
+        Entity::Topic(d) => client.create(d)?,
+        Entity::Subscription(d) => client.create(d)?,
+        Entity::Rule(d) => client.create(d)?,
+    };
+    execute(exec, request)
+        .wrap_err_with(|| format!("Could not create {} {}", entity.kind(), entity.path()))?;
+    Ok(entity)
+}
+
+/// Changes the settings given and leaves the others as they are.
+pub fn update<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    entity: &Entity,
+    settings: &Settings,
+) -> Result<Entity, Report> {
+    let entity = apply_settings(&get(exec, client, entity)?, settings)?;
+    let request = match &entity {
+        Entity::Queue(d) => client.update(d)?,
+        Entity::Topic(d) => client.update(d)?,
+        Entity::Subscription(d) => client.update(d)?,
+        Entity::Rule(d) => client.update(d)?,
+    };
+    execute(exec, request)
+        .wrap_err_with(|| format!("Could not update {} {}", entity.kind(), entity.path()))?;
+    Ok(entity)
+}
+
+pub fn delete<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    entity: &Entity,
+) -> Result<(), Report> {
+    let request = match entity {
+        Entity::Queue(d) => client.delete(d)?,
+        Entity::Topic(d) => client.delete(d)?,
+        Entity::Subscription(d) => client.delete(d)?,
+        Entity::Rule(d) => client.delete(d)?,
+    };
+    execute(exec, request)
+        .wrap_err_with(|| format!("Could not delete {} {}", entity.kind(), entity.path()))?;
+    Ok(())
+}
+
+pub fn list<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    listing: &Listing,
+) -> Result<Vec<Entity>, Report> {
+    match listing {
+        Listing::Queues => client.queues(exec).map(|q| q.map(Entity::Queue)).collect(),
+        Listing::Topics => client.topics(exec).map(|t| t.map(Entity::Topic)).collect(),
+        Listing::Subscriptions(Some(topic)) => client
+            .subscriptions(topic, exec)
+            .map(|s| s.map(Entity::Subscription))
+            .collect(),
+        Listing::Subscriptions(None) => client
+            .entities(exec)
+            .filter(|e| !matches!(e, Ok(Entity::Queue(_)) | Ok(Entity::Topic(_))))
+            .collect(),
+        Listing::Rules(topic, subscription) => client
+            .rules(topic, subscription, exec)
+            .map(|r| r.map(Entity::Rule))
+            .collect(),
+    }
+}
+
+/// An entity as `sbctl` prints it as JSON: its description, with the names of
+/// the topic and subscription it belongs to.
+pub fn to_json(entity: &Entity) -> Result<Value, Report> {
+    let mut value = match entity {
+        Entity::Queue(d) => serde_json::to_value(d)?,
+        Entity::Topic(d) => serde_json::to_value(d)?,
+        Entity::Subscription(d) => serde_json::to_value(d)?,
+        Entity::Rule(d) => serde_json::to_value(d)?,
+    };
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("kind".to_string(), Value::from(entity.kind()));
+        match entity {
+            Entity::Subscription(d) => {
+                fields.insert("topic".to_string(), Value::from(d.topic.as_str()));
+            }
+            Entity::Rule(d) => {
+                fields.insert("topic".to_string(), Value::from(d.topic.as_str()));
+                fields.insert(
+                    "subscription".to_string(),
+                    Value::from(d.subscription.as_str()),
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(value)
+}
+
+fn filter_text(filter: &RuleFilter) -> String {
+    match filter {
+        RuleFilter::Sql { expression } => expression.clone(),
+        RuleFilter::Correlation(c) => format!("correlation {}", serde_json::json!(c)),
+        RuleFilter::True => "true".to_string(),
+        RuleFilter::False => "false".to_string(),
+    }
+}
+
+fn count(n: Option<u64>) -> String {
+    n.map_or_else(|| "-".to_string(), |n| n.to_string())
+}
+
+/// Lays entities out in columns: message counts for queues, topics and
+/// subscriptions, filters and actions for rules.
+pub fn table(entities: &[Entity]) -> String {
+    let mut rows = Vec::new();
+    if entities.iter().all(|e| matches!(e, Entity::Rule(_))) {
+        rows.push(
+            vec!["RULE", "FILTER", "ACTION"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+        for entity in entities {
+            if let Entity::Rule(rule) = entity {
+                rows.push(vec![
+                    rule.name.clone(),
+                    filter_text(&rule.filter),
+                    match &rule.action {
+                        Some(RuleAction::Sql { expression }) => expression.clone(),
+                        None => "-".to_string(),
+                    },
+                ]);
+            }
+        }
+    } else {
+        rows.push(
+            vec![
+                "PATH",
+                "ACTIVE",
+                "DEAD-LETTER",
+                "SCHEDULED",
+                "TRANSFER",
+                "SIZE",
+                "STATUS",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        );
+        for entity in entities {
+            let (details, size, status): (Option<CountDetails>, _, _) = match entity {
+                Entity::Queue(d) => (d.count_details, d.size_in_bytes, &d.status),
+                Entity::Topic(d) => (d.count_details, d.size_in_bytes, &d.status),
+                Entity::Subscription(d) => (d.count_details, None, &d.status),
+                Entity::Rule(_) => continue,
+            };
+            rows.push(vec![
+                entity.path(),
+                count(details.map(|c| c.active_message_count)),
+                count(details.map(|c| c.dead_letter_message_count)),
+                count(details.map(|c| c.scheduled_message_count)),
+                count(details.map(|c| c.transfer_message_count)),
+                count(size),
+                status.clone().unwrap_or_else(|| "-".to_string()),
+            ]);
+        }
+    }
+
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::servicebus::brokeredmessage::BrokeredMessage;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn settings(value: Value) -> Settings {
+        match value {
+            Value::Object(settings) => settings,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn settings_are_checked() -> Result<(), Report> {
+        let queue = named(Some("orders"), None, None, None)?;
+        let merged = apply_settings(
+            &queue,
+            &settings(json!({"name": "other", "lock_duration": "PT45S", "max_delivery_count": 3})),
+        )?;
+        match merged {
+            Entity::Queue(q) => {
+                assert_eq!("orders", q.name);
+                assert_eq!(Some(Duration::from_secs(45)), q.lock_duration);
+                assert_eq!(Some(3), q.max_delivery_count);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(apply_settings(&queue, &settings(json!({"lock_duraton": "PT45S"}))).is_err());
+        assert!(apply_settings(&queue, &settings(json!({"message_count": 3}))).is_err());
+        assert!(apply_settings(&queue, &settings(json!({"lock_duration": 45}))).is_err());
+
+        let rule = named(None, Some("events"), Some("audit"), Some("high"))?;
+        match apply_settings(&rule, &rule_settings(Some("priority > 1"), None)?)? {
+            Entity::Rule(r) => {
+                assert_eq!(
+                    ("events", "audit"),
+                    (r.topic.as_str(), r.subscription.as_str())
+                );
+                assert_eq!(
+                    RuleFilter::Sql {
+                        expression: "priority > 1".to_string()
+                    },
+                    r.filter
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(named(Some("orders"), Some("events"), None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn manage_entities() -> Result<(), Report> {
+        let emulator = Emulator::start()?;
+        let exec = emulator.executor();
+        let client = NamespaceClient::with_conn(&emulator.connection_string())?;
+
+        let orders = named(Some("orders"), None, None, None)?;
+        create(
+            &exec,
+            &client,
+            &orders,
+            &settings(json!({"max_delivery_count": 3})),
+        )?;
+        create(
+            &exec,
+            &client,
+            &named(None, Some("events"), None, None)?,
+            &Settings::new(),
+        )?;
+        let audit = named(None, Some("events"), Some("audit"), None)?;
+        create(&exec, &client, &audit, &Settings::new())?;
+        let rule = named(None, Some("events"), Some("audit"), Some("high"))?;
+        create(
+            &exec,
+            &client,
+            &rule,
+            &rule_settings(Some("priority > 1"), Some("SET seen = TRUE"))?,
+        )?;
+        assert!(create(&exec, &client, &orders, &Settings::new()).is_err());
+
+        emulator.send("orders", BrokeredMessage::with_body("order 1"))?;
+        let updated = update(
+            &exec,
+            &client,
+            &orders,
+            &settings(json!({"lock_duration": "PT45S"})),
+        )?;
+        let current = get(&exec, &client, &orders)?;
+        match (&updated, &current) {
+            (Entity::Queue(updated), Entity::Queue(current)) => {
+                assert_eq!(Some(3), current.max_delivery_count);
+                assert_eq!(Some(Duration::from_secs(45)), current.lock_duration);
+                assert_eq!(updated.max_delivery_count, current.max_delivery_count);
+                assert_eq!(1, current.count_details.unwrap().active_message_count);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let queues = list(&exec, &client, &Listing::Queues)?;
+        let printed = table(&queues);
+        assert_eq!(
+            "PATH    ACTIVE  DEAD-LETTER  SCHEDULED  TRANSFER  SIZE",
+            printed
+                .lines()
+                .next()
+                .unwrap()
+                .split("  STATUS")
+                .next()
+                .unwrap()
+        );
+        assert!(printed
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("orders  1       0"));
+
+        let subs = list(
+            &exec,
+            &client,
+            &Listing::parse("subscriptions", None, None)?,
+        )?;
+        assert_eq!(
+            vec!["events/Subscriptions/audit"],
+            subs.iter().map(Entity::path).collect::<Vec<_>>()
+        );
+        assert_eq!(json!("events"), to_json(&subs[0])?["topic"]);
+
+        let rules = list(
+            &exec,
+            &client,
+            &Listing::parse("rules", Some("events"), Some("audit"))?,
+        )?;
+        let printed = table(&rules);
+        assert!(printed.contains("high"));
+        assert!(printed.contains("priority > 1"));
+        assert!(printed.contains("SET seen = TRUE"));
+
+        delete(&exec, &client, &rule)?;
+        assert!(list(
+            &exec,
+            &client,
+            &Listing::Rules("events".into(), "audit".into())
+        )?
+        .iter()
+        .all(|r| r.path() != rule.path()));
+        delete(&exec, &client, &orders)?;
+        assert!(get(&exec, &client, &orders).is_err());
+        assert!(list(&exec, &client, &Listing::Queues)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Recording messages to a JSON Lines file and replaying them, as `sbctl
+//! record` and `sbctl replay` do it. The file format is in
+//! `servicebus::archive`.
+
+use super::messages::{self, ReceiveMode, Target};
+use crate::core::clock::Clock;
+use crate::core::exec::Executor;
+use crate::servicebus::archive::{self, ArchivedMessage, BodyEncoding, Pacer, Pacing};
+use crate::QueueClient;
+use eyre::{eyre, Report};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Records up to `max` messages, stopping once none arrives for `idle`.
+/// Each message is completed once it is written, unless `keep` is set: then
+/// they are peeked, which counts as a delivery and so is refused outside a
+/// dead letter queue unless `force` is set, see `messages::peek`. Returns how
+/// many were recorded.
+#[allow(clippy::too_many_arguments)]
+pub fn record<E: Executor + ?Sized, W: Write>(
+    exec: &E,
+    target: &Target,
+    max: usize,
+    idle: Duration,
+    keep: bool,
+    force: bool,
+    encoding: BodyEncoding,
+    clock: &dyn Clock,
+    out: &mut W,
+) -> Result<usize, Report> {
+    if keep {
+        let peeked = messages::peek(exec, target, max, idle, force)?;
+        for message in &peeked {
+            archive::write_line(
+                out,
+                &ArchivedMessage::record(message, encoding, clock.now()),
+            )?;
+        }
+        out.flush()?;
+        return Ok(peeked.len());
+    }
+    let mut recorded = 0;
+    while recorded < max {
+        let message = match messages::receive(exec, target, ReceiveMode::PeekLock, 1, idle)?.pop() {
+            Some(message) => message,
+            None => break,
+        };
+        archive::write_line(
+            out,
+            &ArchivedMessage::record(&message, encoding, clock.now()),
+        )?;
+        // Only take the message off the entity once it is safely written.
+        out.flush()?;
+        messages::complete(exec, target, message)?;
+        recorded += 1;
+    }
+    Ok(recorded)
+}
+
+/// Sends recorded messages to a queue or topic, paced by `pacing`. They are
+/// sent as new messages: the broker gives them new sequence numbers and
+/// enqueued times. Returns how many were sent.
+pub fn replay<E, I>(
+    exec: &E,
+    queue: &QueueClient,
+    recorded: I,
+    pacing: Pacing,
+) -> Result<usize, Report>
+where
+    E: Executor + ?Sized,
+    I: IntoIterator<Item = Result<ArchivedMessage, Report>>,
+{
+    let start = Instant::now();
+    let mut pacer = Pacer::new(pacing);
+    let mut sent = 0;
+    for archived in recorded {
+        let archived = archived?;
+        let due = start
+            .checked_add(pacer.offset(archived.sent_at()))
+            .ok_or_else(|| eyre!("The next message is due further off than can be waited for."))?;
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        messages::send(exec, queue, Some(archived.message()?.copy_for_resend()))?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::SystemClock;
+    use crate::emulator::Emulator;
+    use crate::servicebus::brokeredmessage::BrokeredMessage;
+    use crate::servicebus::description::{Entity, QueueDescription};
+
+    #[test]
+    fn record_then_replay_into_another_queue() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .entity(Entity::Queue(QueueDescription::new("replayed")))
+            .start()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let orders = Target::open(&conn, Some("orders"), None, None)?;
+        let sent = (1..=3).map(|i| {
+            BrokeredMessage::with_body(&format!("order {}", i)).with_property("priority", i)
+        });
+        messages::send(&exec, &orders.sender(), sent)?;
+
+        let wait = Duration::from_millis(200);
+        let mut kept = Vec::new();
+        let n = record(
+            &exec,
+            &orders,
+            2,
+            wait,
+            true,
+            true,
+            BodyEncoding::Text,
+            &SystemClock,
+            &mut kept,
+        )?;
+        assert_eq!(2, n);
+        let mut file = Vec::new();
+        let n = record(
+            &exec,
+            &orders,
+            10,
+            wait,
+            false,
+            false,
+            BodyEncoding::Base64,
+            &SystemClock,
+            &mut file,
+        )?;
+        assert_eq!(3, n);
+        assert_eq!(0, messages::purge(&exec, &orders)?);
+        assert_eq!(
+            3,
+            String::from_utf8(file.clone())?
+                .matches("bodyBase64")
+                .count()
+        );
+
+        let replayed = QueueClient::with_conn_and_queue(&conn, "replayed")?;
+        let pacing = Pacing {
+            rate: Some(100.0),
+            speed: None,
+        };
+        assert_eq!(
+            3,
+            replay(&exec, &replayed, archive::read_lines(&file[..]), pacing)?
+        );
+
+        let target = Target::open(&conn, Some("replayed"), None, None)?;
+        let received = messages::receive(&exec, &target, ReceiveMode::ReceiveAndDelete, 10, wait)?;
+        let bodies = received
+            .iter()
+            .map(|m| m.get_body())
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(vec!["order 1", "order 2", "order 3"], bodies);
+        assert_eq!(Some(&3.into()), received[2].properties.get("priority"));
+        assert_eq!(Some(1), received[0].props.DeliveryCount);
+        Ok(())
+    }
+}
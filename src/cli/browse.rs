@@ -0,0 +1,217 @@
+//! What the `sbtui` browser does with the messages it shows: decoding bodies
+//! for display, and resubmitting or deleting dead lettered messages.
+
+use super::messages::{self, Target};
+use crate::core::exec::Executor;
+use crate::servicebus::brokeredmessage::BrokeredMessage;
+use crate::servicebus::topology::forward_target;
+use crate::QueueClient;
+use eyre::{eyre, Report};
+use std::fmt::Write;
+
+/// How a message body is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyView {
+    /// The text inside a DataContract `<string>`, or the body as it is.
+    Decoded,
+    /// The decoded body pretty printed, if it is JSON.
+    Json,
+    /// A hex dump of the bytes.
+    Raw,
+}
+
+impl BodyView {
+    /// The view after this one, for cycling through them with one key.
+    pub fn next(self) -> BodyView {
+        match self {
+            BodyView::Decoded => BodyView::Json,
+            BodyView::Json => BodyView::Raw,
+            BodyView::Raw => BodyView::Decoded,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BodyView::Decoded => "decoded",
+            BodyView::Json => "json",
+            BodyView::Raw => "raw",
+        }
+    }
+}
+
+pub fn render_body(message: &BrokeredMessage, view: BodyView) -> String {
+    let decoded = message
+        .get_body()
+        .unwrap_or_else(|_| message.get_body_raw().to_string());
+    match view {
+        BodyView::Decoded => decoded,
+        BodyView::Json => match serde_json::from_str::<serde_json::Value>(&decoded) {
+            Ok(json) => serde_json::to_string_pretty(&json).unwrap_or(decoded),
+            Err(e) => format!("The body is not JSON: {}", e),
+        },
+        BodyView::Raw => hex_dump(message.get_body_raw().as_bytes()),
+    }
+}
+
+// Sixteen bytes a line: the offset, the bytes in hex and the printable ones.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(b) => {
+                    let _ = write!(out, " {:02x}", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+/// Where a dead lettered message from `from` is resubmitted to. Messages
+/// from a queue's dead letter queue go back to the queue. A subscription's go
+/// to the entity it forwards to, `forward_to`; sending them back to the topic
+/// copies them to every subscription whose rules match, so that only happens
+/// when asked for with `to_topic`.
+pub fn resubmit_destination(
+    from: &Target,
+    forward_to: Option<&str>,
+    to_topic: bool,
+) -> Result<QueueClient, Report> {
+    match (from, forward_to) {
+        (Target::Queue(_), _) => Ok(from.sender()),
+        (Target::Subscription(_), _) if to_topic => Ok(from.sender()),
+        (Target::Subscription(s), Some(forward_to)) => Ok(QueueClient::with_token_provider(
+            s.endpoint().clone(),
+            &forward_target(forward_to),
+            s.token_provider().clone(),
+        )),
+        (Target::Subscription(s), None) => Err(eyre!(
+            "The subscription does not forward anywhere. Resubmitting to topic {} \
+             would copy the message to every subscription that matches it.",
+            s.topic().trim_end_matches("/$DeadLetterQueue")
+        )),
+    }
+}
+
+/// Sends a copy of a message locked on `from` to `to`, then completes the
+/// original.
+pub fn resubmit<E: Executor + ?Sized>(
+    exec: &E,
+    from: &Target,
+    to: &QueueClient,
+    message: &BrokeredMessage,
+) -> Result<(), Report> {
+    messages::send(exec, to, Some(message.copy_for_resend()))?;
+    messages::complete(exec, from, message.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::messages::ReceiveMode;
+    use crate::emulator::Emulator;
+    use crate::servicebus::description::{
+        Entity, QueueDescription, SubscriptionDescription, TopicDescription,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn bodies_render_in_every_view() {
+        let message = BrokeredMessage::with_body("{\"id\":1}");
+        assert_eq!("{\"id\":1}", render_body(&message, BodyView::Decoded));
+        assert_eq!("{\n  \"id\": 1\n}", render_body(&message, BodyView::Json));
+        assert_eq!(
+            "00000000  3c 73 74 72 69 6e 67 3e 7b 22 69 64 22 3a 31 7d  |<string>{\"id\":1}|\n\
+             00000010  3c 2f 73 74 72 69 6e 67 3e                       |</string>|\n",
+            render_body(&message, BodyView::Raw)
+        );
+
+        let raw = BrokeredMessage::with_body_and_props("plain\ttext", Default::default());
+        assert_eq!("plain\ttext", render_body(&raw, BodyView::Decoded));
+        assert!(render_body(&raw, BodyView::Json).starts_with("The body is not JSON"));
+        assert!(render_body(&raw, BodyView::Raw).ends_with("|plain.text|\n"));
+        assert_eq!(BodyView::Decoded, BodyView::Decoded.next().next().next());
+    }
+
+    #[test]
+    fn resubmitted_messages_leave_the_dead_letter_queue() -> Result<(), Report> {
+        let mut orders = QueueDescription::new("orders");
+        orders.max_delivery_count = Some(1);
+        let emulator = Emulator::builder().entity(Entity::Queue(orders)).start()?;
+        let exec = emulator.executor();
+        let queue = Target::open(&emulator.connection_string(), Some("orders"), None, None)?;
+        let poison = BrokeredMessage::with_body("order 1").with_property("priority", 2);
+        messages::send(&exec, &queue.sender(), Some(poison))?;
+
+        // One delivery is all the queue allows, so abandoning dead letters it.
+        let wait = Duration::from_secs(1);
+        let received = messages::receive(&exec, &queue, ReceiveMode::PeekLock, 1, wait)?;
+        messages::abandon(&exec, &queue, received[0].clone())?;
+
+        let dlq = queue.dead_letter_queue();
+        let dead = messages::receive(&exec, &dlq, ReceiveMode::PeekLock, 1, wait)?;
+        assert!(dead[0].properties.contains_key("deadletterreason"));
+        resubmit(
+            &exec,
+            &dlq,
+            &resubmit_destination(&dlq, None, false)?,
+            &dead[0],
+        )?;
+
+        assert!(messages::receive(&exec, &dlq, ReceiveMode::PeekLock, 1, wait)?.is_empty());
+        let again = messages::receive(&exec, &queue, ReceiveMode::ReceiveAndDelete, 1, wait)?;
+        assert_eq!("order 1", again[0].get_body()?);
+        assert_eq!(Some(&2.into()), again[0].properties.get("priority"));
+        assert!(!again[0].properties.contains_key("deadletterreason"));
+        assert_eq!(Some(1), again[0].props.DeliveryCount);
+        Ok(())
+    }
+
+    #[test]
+    fn subscriptions_resubmit_without_copying_to_the_others() -> Result<(), Report> {
+        let mut audit = SubscriptionDescription::new("events", "audit");
+        audit.max_delivery_count = Some(1);
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("retries")))
+            .entity(Entity::Topic(TopicDescription::new("events")))
+            .entity(Entity::Subscription(audit))
+            .entity(Entity::Subscription(SubscriptionDescription::new(
+                "events", "billing",
+            )))
+            .start()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let audit = Target::open(&conn, None, Some("events"), Some("audit"))?;
+        let billing = Target::open(&conn, None, Some("events"), Some("billing"))?;
+        let retries = Target::open(&conn, Some("retries"), None, None)?;
+        emulator.send("events", BrokeredMessage::with_body("event 1"))?;
+        assert_eq!(1, messages::purge(&exec, &billing)?);
+
+        let wait = Duration::from_secs(1);
+        let received = messages::receive(&exec, &audit, ReceiveMode::PeekLock, 1, wait)?;
+        messages::abandon(&exec, &audit, received[0].clone())?;
+        let dlq = audit.dead_letter_queue();
+        let dead = messages::receive(&exec, &dlq, ReceiveMode::PeekLock, 1, wait)?;
+        messages::renew(&exec, &dlq, &dead[0])?;
+
+        assert!(resubmit_destination(&dlq, None, false).is_err());
+        let to = resubmit_destination(&dlq, Some("https://ns.example/retries"), false)?;
+        resubmit(&exec, &dlq, &to, &dead[0])?;
+        assert_eq!(1, messages::purge(&exec, &retries)?);
+        assert_eq!(0, messages::purge(&exec, &billing)?);
+        assert_eq!("events", resubmit_destination(&dlq, None, true)?.queue());
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+//! Message counts of queues, topics and subscriptions in the Prometheus text
+//! format, as the `sbexporter` binary serves them on `/metrics`.
+//!
+//! An `Exporter` polls the runtime information of the entities it watches
+//! every so often and renders the last counts it got, so scrapes never wait
+//! on Service Bus. Every entity gets these gauges, labelled with the
+//! `namespace`, its `kind` and its `entity` path, plus `topic` and
+//! `subscription` for subscriptions:
+//!
+//! ```text
+//! servicebus_active_messages
+//! servicebus_dead_letter_messages
+//! servicebus_scheduled_messages
+//! servicebus_transfer_messages
+//! servicebus_transfer_dead_letter_messages
+//! servicebus_size_bytes                       (queues and topics)
+//! ```
+//!
+//! Each watched queue, topic and subscription is polled on its own.
+//! `servicebus_entity_up` is 0 for one whose last poll failed, for instance
+//! because it was deleted, in which case its counts are the ones from the poll
+//! before. The others are unaffected. `servicebus_up` is 0 when nothing could
+//! be read at all, and `servicebus_last_poll_timestamp_seconds` says when the
+//! counts were last read.
+//!
+//! Only available with the `exporter` feature.
+
+use super::admin::{self, Listing};
+use crate::core::clock::{unix_time, Clock, SystemClock};
+use crate::core::exec::Executor;
+use crate::core::lock;
+use crate::servicebus::description::{
+    CountDetails, Entity, QueueDescription, SubscriptionDescription, TopicDescription,
+};
+use crate::NamespaceClient;
+use eyre::{eyre, Report};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// The content type of the Prometheus text format.
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// How often entities are polled unless told otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Which entities an exporter reports on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    /// Every queue, topic and subscription in the namespace.
+    Everything,
+    Queue(String),
+    /// A topic and all of its subscriptions.
+    Topic(String),
+    Subscription(String, String),
+}
+
+impl Watch {
+    /// The entity watched, or `None` for everything.
+    fn entity(&self) -> Option<Entity> {
+        match self {
+            Watch::Everything => None,
+            Watch::Queue(name) => Some(Entity::Queue(QueueDescription::new(name))),
+            Watch::Topic(name) => Some(Entity::Topic(TopicDescription::new(name))),
+            Watch::Subscription(topic, name) => Some(Entity::Subscription(
+                SubscriptionDescription::new(topic, name),
+            )),
+        }
+    }
+}
+
+/// Reads the runtime information of the watched entities, one result per
+/// watch, so an entity that can't be read doesn't hide the others.
+pub fn poll<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    watches: &[Watch],
+) -> Vec<Result<Vec<Entity>, Report>> {
+    watches
+        .iter()
+        .map(|watch| poll_one(exec, client, watch))
+        .collect()
+}
+
+fn poll_one<E: Executor + ?Sized>(
+    exec: &E,
+    client: &NamespaceClient,
+    watch: &Watch,
+) -> Result<Vec<Entity>, Report> {
+    let entity = match watch.entity() {
+        Some(entity) => entity,
+        None => return client.entities(exec).collect(),
+    };
+    let mut entities = vec![admin::get(exec, client, &entity)?];
+    if let Watch::Topic(name) = watch {
+        let subscriptions = Listing::Subscriptions(Some(name.clone()));
+        entities.extend(admin::list(exec, client, &subscriptions)?);
+    }
+    Ok(entities)
+}
+
+type Count = fn(&CountDetails) -> u64;
+
+const GAUGES: &[(&str, &str, Count)] = &[
+    (
+        "servicebus_active_messages",
+        "Messages that can be received.",
+        |c| c.active_message_count,
+    ),
+    (
+        "servicebus_dead_letter_messages",
+        "Messages in the dead letter queue.",
+        |c| c.dead_letter_message_count,
+    ),
+    (
+        "servicebus_scheduled_messages",
+        "Messages waiting for their scheduled enqueue time.",
+        |c| c.scheduled_message_count,
+    ),
+    (
+        "servicebus_transfer_messages",
+        "Messages waiting to be forwarded to another entity.",
+        |c| c.transfer_message_count,
+    ),
+    (
+        "servicebus_transfer_dead_letter_messages",
+        "Messages that could not be forwarded to another entity.",
+        |c| c.transfer_dead_letter_message_count,
+    ),
+];
+
+// Label values escape backslashes, quotes and new lines.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(namespace: &str, entity: &Entity) -> String {
+    let mut labels = format!(
+        "namespace=\"{}\",kind=\"{}\",entity=\"{}\"",
+        label(namespace),
+        entity.kind(),
+        label(&entity.path())
+    );
+    if let Entity::Subscription(d) = entity {
+        let _ = write!(
+            labels,
+            ",topic=\"{}\",subscription=\"{}\"",
+            label(&d.topic),
+            label(&d.name)
+        );
+    }
+    labels
+}
+
+fn family(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+}
+
+/// Renders entity counts in the Prometheus text format. Rules and entities
+/// without runtime information are left out. `watches` says whether the last
+/// poll of each watch worked.
+pub fn render(
+    namespace: &str,
+    entities: &[Entity],
+    watches: &[(Watch, bool)],
+    polled_at: Option<SystemTime>,
+) -> String {
+    let mut out = String::new();
+    let namespace_label = format!("namespace=\"{}\"", label(namespace));
+    let up = watches.iter().any(|(_, up)| *up);
+    family(
+        &mut out,
+        "servicebus_up",
+        "Whether the last poll of Service Bus worked.",
+    );
+    let _ = writeln!(out, "servicebus_up{{{}}} {}", namespace_label, up as u8);
+    let watched: Vec<(Entity, bool)> = watches
+        .iter()
+        .filter_map(|(watch, up)| Some((watch.entity()?, *up)))
+        .collect();
+    if !watched.is_empty() {
+        family(
+            &mut out,
+            "servicebus_entity_up",
+            "Whether the last poll of a watched entity worked.",
+        );
+        for (entity, up) in &watched {
+            let _ = writeln!(
+                out,
+                "servicebus_entity_up{{{}}} {}",
+                labels(namespace, entity),
+                *up as u8
+            );
+        }
+    }
+    if let Some(at) = polled_at {
+        family(
+            &mut out,
+            "servicebus_last_poll_timestamp_seconds",
+            "When the counts were read, in seconds since the unix epoch.",
+        );
+        let _ = writeln!(
+            out,
+            "servicebus_last_poll_timestamp_seconds{{{}}} {}",
+            namespace_label,
+            unix_time(at)
+        );
+    }
+
+    let counted: Vec<(String, Option<CountDetails>, Option<u64>)> = entities
+        .iter()
+        .filter_map(|entity| {
+            let (details, size) = match entity {
+                Entity::Queue(d) => (d.count_details, d.size_in_bytes),
+                Entity::Topic(d) => (d.count_details, d.size_in_bytes),
+                Entity::Subscription(d) => (d.count_details, None),
+                Entity::Rule(_) => return None,
+            };
+            Some((labels(namespace, entity), details, size))
+        })
+        .collect();
+    for (name, help, count) in GAUGES {
+        family(&mut out, name, help);
+        for (labels, details, _) in &counted {
+            if let Some(details) = details {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, count(details));
+            }
+        }
+    }
+    family(
+        &mut out,
+        "servicebus_size_bytes",
+        "Bytes the messages of a queue or topic take up.",
+    );
+    for (labels, _, size) in &counted {
+        if let Some(size) = size {
+            let _ = writeln!(out, "servicebus_size_bytes{{{}}} {}", labels, size);
+        }
+    }
+    out
+}
+
+// What the last poll of each watch found, in the order of the watches.
+struct Snapshot {
+    watches: Vec<Watched>,
+    polled_at: Option<SystemTime>,
+}
+
+struct Watched {
+    watch: Watch,
+    entities: Vec<Entity>,
+    up: bool,
+}
+
+/// Polls the watched entities of a namespace and serves their counts.
+pub struct Exporter {
+    client: NamespaceClient,
+    namespace: String,
+    watches: Vec<Watch>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    snapshot: Mutex<Snapshot>,
+}
+
+impl Exporter {
+    /// Watches `watches`, or everything if there are none.
+    pub fn new(client: NamespaceClient, watches: Vec<Watch>) -> Exporter {
+        let namespace = client.endpoint().host().unwrap_or_default().to_string();
+        let watches = if watches.is_empty() {
+            vec![Watch::Everything]
+        } else {
+            watches
+        };
+        let snapshot = Snapshot {
+            watches: watches
+                .iter()
+                .map(|watch| Watched {
+                    watch: watch.clone(),
+                    entities: Vec::new(),
+                    up: false,
+                })
+                .collect(),
+            polled_at: None,
+        };
+        Exporter {
+            client,
+            namespace,
+            watches,
+            interval: DEFAULT_INTERVAL,
+            clock: Arc::new(SystemClock),
+            snapshot: Mutex::new(snapshot),
+        }
+    }
+
+    /// How long `serve` waits between polls.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Where the time of each poll comes from.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn snapshot(&self) -> MutexGuard<'_, Snapshot> {
+        lock(&self.snapshot)
+    }
+
+    /// Reads the counts once. A watch that fails keeps the counts from its
+    /// last poll and its `servicebus_entity_up` goes to 0; the error says
+    /// which failed and why.
+    pub fn poll<E: Executor + ?Sized>(&self, exec: &E) -> Result<(), Report> {
+        let polled = poll(exec, &self.client, &self.watches);
+        let now = self.clock.now();
+        let mut snapshot = self.snapshot();
+        let mut failures = Vec::new();
+        for (watched, result) in snapshot.watches.iter_mut().zip(polled) {
+            watched.up = result.is_ok();
+            match result {
+                Ok(entities) => watched.entities = entities,
+                Err(e) => failures.push(match watched.watch.entity() {
+                    Some(entity) => format!("{} {}: {:#}", entity.kind(), entity.path(), e),
+                    None => format!("{:#}", e),
+                }),
+            }
+        }
+        if snapshot.watches.iter().any(|w| w.up) {
+            snapshot.polled_at = Some(now);
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("{}", failures.join("; ")))
+        }
+    }
+
+    /// The counts of the last poll in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let snapshot = self.snapshot();
+        let entities: Vec<Entity> = snapshot
+            .watches
+            .iter()
+            .flat_map(|w| w.entities.iter().cloned())
+            .collect();
+        let watches: Vec<(Watch, bool)> = snapshot
+            .watches
+            .iter()
+            .map(|w| (w.watch.clone(), w.up))
+            .collect();
+        render(&self.namespace, &entities, &watches, snapshot.polled_at)
+    }
+
+    /// Polls on a thread of its own and serves `GET /metrics` on `listener`
+    /// until the process ends. Failed polls are logged to stderr.
+    pub fn serve<E>(self, listener: TcpListener, exec: E) -> Result<(), Report>
+    where
+        E: Executor + Send + 'static,
+    {
+        let exporter = Arc::new(self);
+        let polling = exporter.clone();
+        std::thread::Builder::new()
+            .name("sbexporter-poll".to_string())
+            .spawn(move || loop {
+                if let Err(e) = polling.poll(&exec) {
+                    eprintln!("Could not poll Service Bus: {:#}", e);
+                }
+                std::thread::sleep(polling.interval);
+            })?;
+
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _context = runtime.enter();
+        let server = Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+            let exporter = exporter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = respond(&exporter, &req);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        }));
+        runtime.block_on(server)?;
+        Ok(())
+    }
+}
+
+fn respond(exporter: &Exporter, request: &Request<Body>) -> Response<Body> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, exporter.metrics()),
+        (&Method::GET, "/") => (StatusCode::OK, "Metrics are at /metrics\n".to_string()),
+        _ => (StatusCode::NOT_FOUND, "Not found\n".to_string()),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+    use crate::emulator::Emulator;
+    use crate::servicebus::brokeredmessage::BrokeredMessage;
+
+    #[test]
+    fn counts_are_rendered_in_the_text_format() {
+        let mut queue = QueueDescription::new("orders");
+        queue.size_in_bytes = Some(2048);
+        queue.count_details = Some(CountDetails {
+            active_message_count: 3,
+            dead_letter_message_count: 1,
+            ..Default::default()
+        });
+        let mut subscription = SubscriptionDescription::new("events", "a\"udit");
+        subscription.count_details = Some(CountDetails {
+            scheduled_message_count: 4,
+            ..Default::default()
+        });
+        let entities = [
+            Entity::Queue(queue),
+            Entity::Subscription(subscription),
+            Entity::Topic(TopicDescription::new("events")),
+        ];
+        let polled_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let watches = [
+            (Watch::Queue("orders".to_string()), true),
+            (Watch::Queue("gone".to_string()), false),
+        ];
+        let text = render("ns.test", &entities, &watches, Some(polled_at));
+
+        assert!(text.starts_with(
+            "# HELP servicebus_up Whether the last poll of Service Bus worked.\n\
+             # TYPE servicebus_up gauge\n\
+             servicebus_up{namespace=\"ns.test\"} 1\n"
+        ));
+        assert!(text.contains(
+            "servicebus_last_poll_timestamp_seconds{namespace=\"ns.test\"} 1700000000\n"
+        ));
+        let orders = "namespace=\"ns.test\",kind=\"queue\",entity=\"orders\"";
+        assert!(text.contains(&format!("servicebus_active_messages{{{}}} 3\n", orders)));
+        assert!(text.contains(&format!(
+            "servicebus_dead_letter_messages{{{}}} 1\n",
+            orders
+        )));
+        assert!(text.contains(&format!("servicebus_size_bytes{{{}}} 2048\n", orders)));
+        assert!(text.contains(&format!("servicebus_entity_up{{{}}} 1\n", orders)));
+        assert!(text.contains(
+            "servicebus_entity_up{namespace=\"ns.test\",kind=\"queue\",entity=\"gone\"} 0\n"
+        ));
+        assert!(text.contains(
+            "servicebus_scheduled_messages{namespace=\"ns.test\",kind=\"subscription\",\
+             entity=\"events/Subscriptions/a\\\"udit\",topic=\"events\",subscription=\"a\\\"udit\"} 4\n"
+        ));
+        // The topic has no runtime information to report.
+        assert!(!text.contains("kind=\"topic\""));
+        assert_eq!(
+            1,
+            text.matches("# TYPE servicebus_size_bytes gauge").count()
+        );
+    }
+
+    #[test]
+    fn polls_the_watched_entities() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .entity(Entity::Queue(QueueDescription::new("other")))
+            .entity(Entity::Topic(TopicDescription::new("events")))
+            .entity(Entity::Subscription(SubscriptionDescription::new(
+                "events", "audit",
+            )))
+            .entity(Entity::Subscription(SubscriptionDescription::new(
+                "events", "billing",
+            )))
+            .start()?;
+        let exec = emulator.executor();
+        emulator.send("orders", BrokeredMessage::with_body("1"))?;
+        emulator.send("orders", BrokeredMessage::with_body("2"))?;
+        emulator.send("events", BrokeredMessage::with_body("3"))?;
+
+        let client = NamespaceClient::with_conn(&emulator.connection_string())?;
+        let watches = vec![
+            Watch::Queue("orders".to_string()),
+            Watch::Subscription("events".to_string(), "audit".to_string()),
+        ];
+        let found = poll(&exec, &client, &watches)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        let paths: Vec<String> = found.iter().map(Entity::path).collect();
+        assert_eq!(vec!["orders", "events/Subscriptions/audit"], paths);
+        let topic = poll(&exec, &client, &[Watch::Topic("events".to_string())]).remove(0)?;
+        assert_eq!(3, topic.len());
+
+        let clock = Arc::new(ManualClock::at(1_700_000_000));
+        let exporter = Exporter::new(client.clone(), watches).clock(clock.clone());
+        let up = |n| format!("servicebus_up{{namespace=\"127.0.0.1\"}} {}\n", n);
+        assert!(exporter.metrics().contains(&up(0)));
+        exporter.poll(&exec)?;
+        let text = exporter.metrics();
+        assert!(text.contains(&up(1)));
+        assert!(text.contains(
+            "servicebus_active_messages{namespace=\"127.0.0.1\",kind=\"queue\",entity=\"orders\"} 2\n"
+        ));
+        assert!(text.contains("subscription=\"audit\"} 1\n"));
+        assert!(!text.contains("\"other\"") && !text.contains("\"billing\""));
+
+        // An entity that can't be read keeps the counts it had, and doesn't
+        // stop the others from being read.
+        admin::delete(
+            &exec,
+            &client,
+            &Entity::Queue(QueueDescription::new("orders")),
+        )?;
+        emulator.send("events", BrokeredMessage::with_body("4"))?;
+        clock.advance(Duration::from_secs(30));
+        let error = exporter.poll(&exec).unwrap_err().to_string();
+        assert!(error.starts_with("queue orders: "), "{}", error);
+        let text = exporter.metrics();
+        assert!(text.contains(&up(1)));
+        assert!(text.contains("} 1700000030\n"));
+        assert!(text.contains(
+            "servicebus_entity_up{namespace=\"127.0.0.1\",kind=\"queue\",entity=\"orders\"} 0\n"
+        ));
+        assert!(text.contains("servicebus_active_messages{namespace=\"127.0.0.1\",kind=\"queue\",entity=\"orders\"} 2\n"));
+        assert!(text.contains("subscription=\"audit\"} 2\n"));
+
+        // Nothing read at all is Service Bus being down.
+        admin::delete(
+            &exec,
+            &client,
+            &Entity::Topic(TopicDescription::new("events")),
+        )?;
+        assert!(exporter.poll(&exec).is_err());
+        assert!(exporter.metrics().contains(&up(0)));
+        Ok(())
+    }
+}
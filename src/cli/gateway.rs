@@ -0,0 +1,414 @@
+//! An http endpoint that turns webhook calls into messages, as the
+//! `sbgateway` binary serves it.
+//!
+//! Callers prove who they are with a shared access signature signed with the
+//! gateway's own key, not the namespace's, scoped to the url they post to.
+//! Tools that can only be given a url can put the token in the query string
+//! instead of the `Authorization` header:
+//!
+//! ```text
+//! https://hooks.example.com/github?sig=...&se=...&skn=webhook&sr=hooks.example.com%2Fgithub
+//! ```
+//!
+//! The body is sent as it is, without a DataContract wrapper, and selected
+//! headers become custom properties. The caller gets `202 Accepted` once
+//! Service Bus has the message, and `502 Bad Gateway` if it couldn't be sent,
+//! so webhook retries cover Service Bus being unavailable.
+//!
+//! Only available with the `gateway` feature.
+
+use super::execute;
+use crate::core::clock::{Clock, SystemClock};
+use crate::core::error::SasError;
+use crate::core::exec::Executor;
+use crate::core::sas;
+use crate::servicebus::brokeredmessage::BrokeredMessage;
+use crate::QueueClient;
+use eyre::{eyre, Report};
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderName, HeaderValue, ALLOW, AUTHORIZATION, CONTENT_LENGTH, HOST, WWW_AUTHENTICATE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// The largest message body a standard tier namespace accepts.
+pub const DEFAULT_MAX_BODY: usize = 256 * 1024;
+
+/// A request header that is copied into a custom property.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderMapping {
+    pub header: HeaderName,
+    pub property: String,
+}
+
+impl FromStr for HeaderMapping {
+    type Err = Report;
+
+    /// Reads `X-GitHub-Event`, which keeps the header's lower case name, or
+    /// `X-GitHub-Event=event` to name the property.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (header, property) = s.split_once('=').unwrap_or((s, s));
+        let header = HeaderName::from_str(header.trim())
+            .map_err(|_| eyre!("{} is not an http header name.", header))?;
+        let property = property.trim().to_ascii_lowercase();
+        if property.is_empty() {
+            return Err(eyre!("The property for {} has no name.", header));
+        }
+        Ok(HeaderMapping { header, property })
+    }
+}
+
+/// Checks webhook calls and forwards their bodies to a queue or topic.
+pub struct Gateway {
+    queue: QueueClient,
+    key_name: String,
+    key: Zeroizing<String>,
+    headers: Vec<HeaderMapping>,
+    max_body: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl Gateway {
+    /// Forwards to `queue`, which may also be a topic, the calls signed with
+    /// the shared access rule `key_name` and its `key`.
+    pub fn new(queue: QueueClient, key_name: &str, key: &str) -> Gateway {
+        Gateway {
+            queue,
+            key_name: key_name.to_string(),
+            key: Zeroizing::new(key.to_string()),
+            headers: Vec::new(),
+            max_body: DEFAULT_MAX_BODY,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn header(mut self, mapping: HeaderMapping) -> Self {
+        self.headers.push(mapping);
+        self
+    }
+
+    /// Bodies larger than this are turned away with `413 Payload Too Large`.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Where token expiry is checked against.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Answers one webhook call. Calls that are turned away get a 4xx
+    /// response; an error means the message couldn't be sent, which the
+    /// caller should answer with `502 Bad Gateway`.
+    pub fn handle<E: Executor + ?Sized>(
+        &self,
+        exec: &E,
+        request: Request<Vec<u8>>,
+    ) -> Result<Response<String>, Report> {
+        if request.method() != Method::POST {
+            let mut response = reply(StatusCode::METHOD_NOT_ALLOWED, "Only POST is accepted.");
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("POST"));
+            return Ok(response);
+        }
+        if let Some(response) = self.rejection(&request) {
+            return Ok(response);
+        }
+        if request.body().len() > self.max_body {
+            return Ok(too_large(self.max_body));
+        }
+        let (parts, body) = request.into_parts();
+        let body = match String::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => {
+                return Ok(reply(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "The body must be UTF-8 text.",
+                ))
+            }
+        };
+
+        let mut message = BrokeredMessage::with_body_and_props(&body, Default::default());
+        for mapping in &self.headers {
+            let value = parts
+                .headers
+                .get(&mapping.header)
+                .and_then(|v| v.to_str().ok());
+            if let Some(value) = value {
+                message = message.with_property(&mapping.property, value);
+            }
+        }
+        execute(exec, self.queue.send(message)?)?;
+        Ok(reply(StatusCode::ACCEPTED, "Accepted."))
+    }
+
+    // Why the call is turned away, if it is. The token comes from the
+    // Authorization header or the query string, and has to be scoped to the
+    // url that was called.
+    fn rejection(&self, request: &Request<Vec<u8>>) -> Option<Response<String>> {
+        let unauthorized = |message: &str| {
+            let mut response = reply(StatusCode::UNAUTHORIZED, message);
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("SharedAccessSignature"),
+            );
+            Some(response)
+        };
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| request.uri().query().filter(|q| q.contains("sig=")));
+        let token = match token {
+            Some(token) => token,
+            None => return unauthorized("The request has no shared access signature."),
+        };
+        let host = request.headers().get(HOST).and_then(|h| h.to_str().ok());
+        let host = match host {
+            Some(host) => host,
+            None => {
+                return Some(reply(
+                    StatusCode::BAD_REQUEST,
+                    "The request has no Host header.",
+                ))
+            }
+        };
+        let resource = format!("{}{}", host, request.uri().path());
+        let token = match sas::verify_for(token, &self.key, self.clock.unix_now(), &resource) {
+            Ok(token) => token,
+            Err(SasError::ResourceNotCovered(_)) => {
+                return Some(reply(
+                    StatusCode::FORBIDDEN,
+                    "The shared access signature is for another url.",
+                ))
+            }
+            Err(e) => return unauthorized(&e.to_string()),
+        };
+        if token.key_name() != self.key_name {
+            return unauthorized("The shared access signature was not signed with this key.");
+        }
+        None
+    }
+
+    /// Serves webhook calls on `listener` until the process ends, sending
+    /// with `exec`. Failed sends are logged to stderr.
+    pub fn serve<E>(self, listener: TcpListener, exec: E) -> Result<(), Report>
+    where
+        E: Executor + Send + Sync + 'static,
+    {
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _context = runtime.enter();
+        let state = Arc::new((self, exec));
+        let server = Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| respond(state.clone(), req))) }
+        }));
+        runtime.block_on(server)?;
+        Ok(())
+    }
+}
+
+fn reply(status: StatusCode, message: &str) -> Response<String> {
+    let mut response = Response::new(format!("{}\n", message));
+    *response.status_mut() = status;
+    response
+}
+
+fn too_large(max_body: usize) -> Response<String> {
+    reply(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        &format!("The body is larger than {} bytes.", max_body),
+    )
+}
+
+// Reads the body, giving up as soon as it is too large, then sends on a
+// blocking thread since executors block.
+async fn respond<E>(
+    state: Arc<(Gateway, E)>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+    E: Executor + Send + Sync + 'static,
+{
+    let (parts, mut body) = request.into_parts();
+    let max_body = state.0.max_body;
+    let declared = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok()?.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > max_body) {
+        return Ok(too_large(max_body).map(Body::from));
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if bytes.len() + chunk.len() <= max_body => bytes.extend_from_slice(&chunk),
+            Ok(_) => return Ok(too_large(max_body).map(Body::from)),
+            Err(_) => {
+                return Ok(
+                    reply(StatusCode::BAD_REQUEST, "The body could not be read.").map(Body::from),
+                )
+            }
+        }
+    }
+    let request = Request::from_parts(parts, bytes);
+    let handled = tokio::task::spawn_blocking(move || {
+        let (gateway, exec) = &*state;
+        gateway.handle(exec, request)
+    })
+    .await;
+    let response = match handled {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            eprintln!("Could not forward a message: {:#}", e);
+            reply(
+                StatusCode::BAD_GATEWAY,
+                "The message could not be forwarded.",
+            )
+        }
+        Err(_) => reply(StatusCode::INTERNAL_SERVER_ERROR, "The gateway failed."),
+    };
+    Ok(response.map(Body::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::messages::{self, ReceiveMode, Target};
+    use crate::core::sas::SasToken;
+    use crate::emulator::Emulator;
+    use crate::servicebus::description::{Entity, QueueDescription};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const KEY: &str = "d2ViaG9vayBrZXk=";
+
+    fn post(uri: &str, token: Option<&SasToken>, body: &[u8]) -> Request<Vec<u8>> {
+        let mut request = Request::post(uri)
+            .header(HOST, "hooks.test")
+            .header("X-Event", "push")
+            .header("X-Delivery", "d-1");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, token.to_string());
+        }
+        request.body(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn header_mappings_name_their_property() -> Result<(), Report> {
+        let mapping: HeaderMapping = "X-GitHub-Event".parse()?;
+        assert_eq!("x-github-event", mapping.property);
+        let mapping: HeaderMapping = "X-GitHub-Event=Event".parse()?;
+        assert_eq!(
+            ("x-github-event", "event"),
+            (mapping.header.as_str(), mapping.property.as_str())
+        );
+        assert!("bad header=x".parse::<HeaderMapping>().is_err());
+        assert!("X-Event=".parse::<HeaderMapping>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn calls_become_messages() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("hooks")))
+            .start()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let gateway = Gateway::new(
+            QueueClient::with_conn_and_queue(&conn, "hooks")?,
+            "webhook",
+            KEY,
+        )
+        .header("X-Event=event".parse()?)
+        .max_body(16);
+        let token = SasToken::builder("https://hooks.test/github").sign("webhook", KEY);
+        let status = |request| gateway.handle(&exec, request).map(|r| r.status());
+
+        assert_eq!(
+            StatusCode::ACCEPTED,
+            status(post("/github", Some(&token), b"{\"a\":1}"))?
+        );
+        let in_query = token
+            .to_string()
+            .replace("SharedAccessSignature ", "/github/push?");
+        assert_eq!(
+            StatusCode::ACCEPTED,
+            status(post(&in_query, None, b"{\"a\":2}"))?
+        );
+
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(post("/github", None, b"{}"))?
+        );
+        let other_key = SasToken::builder("hooks.test/github").sign("webhook", "b3RoZXI=");
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(post("/github", Some(&other_key), b"{}"))?
+        );
+        let other_rule = SasToken::builder("hooks.test/github").sign("admin", KEY);
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(post("/github", Some(&other_rule), b"{}"))?
+        );
+        let expired = SasToken::builder("hooks.test/github")
+            .expires_at(1)
+            .sign("webhook", KEY);
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(post("/github", Some(&expired), b"{}"))?
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(post("/gitlab", Some(&token), b"{}"))?
+        );
+        assert_eq!(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            status(post("/github", Some(&token), &[b'x'; 17]))?
+        );
+        assert_eq!(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            status(post("/github", Some(&token), &[0xff]))?
+        );
+        let get = Request::get("/github")
+            .header(HOST, "hooks.test")
+            .body(Vec::new())?;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, status(get)?);
+
+        let target = Target::open(&conn, Some("hooks"), None, None)?;
+        let received = messages::receive(
+            &exec,
+            &target,
+            ReceiveMode::ReceiveAndDelete,
+            5,
+            Duration::from_secs(1),
+        )?;
+        assert_eq!(2, received.len());
+        assert_eq!("{\"a\":1}", received[0].get_body_raw());
+        assert_eq!(Some(&json!("push")), received[0].properties.get("event"));
+        assert!(!received[0].properties.contains_key("x-delivery"));
+
+        // Nothing is accepted that Service Bus didn't take.
+        let missing = Gateway::new(
+            QueueClient::with_conn_and_queue(&conn, "missing")?,
+            "webhook",
+            KEY,
+        );
+        assert!(missing
+            .handle(&exec, post("/github", Some(&token), b"{}"))
+            .is_err());
+        Ok(())
+    }
+}
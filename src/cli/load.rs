@@ -0,0 +1,507 @@
+//! Sending and receiving at a steady rate to see what an entity can take, as
+//! `sbload` does it.
+//!
+//! Senders share the target rate between them and each records how long its
+//! sends take. Receivers settle what they get and record how long each message
+//! waited, from its `EnqueuedTimeUtc` to when it was received. The broker
+//! writes that time in whole seconds, so those latencies are only as precise
+//! as the clocks involved and that second. Messages already on the entity
+//! count too, so start with an empty one.
+
+use super::messages::{self, ReceiveMode, Target};
+use crate::core::clock::{parse_http_date, Clock};
+use crate::core::exec::Executor;
+use crate::servicebus::archive::{Pacer, Pacing};
+use crate::servicebus::brokeredmessage::BrokeredMessage;
+use crate::QueueClient;
+use eyre::{eyre, Report};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// How long a receiver waits after its first failure, doubling up to the max.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What to send and receive, and for how long.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    /// Messages a second across all senders. Without a rate they send as fast
+    /// as they can.
+    pub rate: Option<f64>,
+    /// Bytes in each message body.
+    pub size: usize,
+    pub senders: usize,
+    pub receivers: usize,
+    /// How long to send for.
+    pub duration: Duration,
+    /// Peek lock receives complete each message, receive and delete ones
+    /// don't need to.
+    pub mode: ReceiveMode,
+    /// Once sending stops, how long receivers wait for the last messages.
+    pub drain: Duration,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            rate: None,
+            size: 1024,
+            senders: 1,
+            receivers: 1,
+            duration: Duration::from_secs(10),
+            mode: ReceiveMode::PeekLock,
+            drain: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A set of measured durations, sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Latencies(Vec<Duration>);
+
+impl From<Vec<Duration>> for Latencies {
+    fn from(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        Latencies(latencies)
+    }
+}
+
+impl Latencies {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The latency `percent` of the measurements are at or below.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let rank = (percent / 100.0 * self.0.len() as f64).ceil() as usize;
+        self.0.get(rank.max(1) - 1).copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.0.last().copied()
+    }
+
+    fn to_json(&self) -> Value {
+        let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+        json!({
+            "count": self.len(),
+            "p50": ms(self.percentile(50.0)),
+            "p90": ms(self.percentile(90.0)),
+            "p99": ms(self.percentile(99.0)),
+            "max": ms(self.max()),
+        })
+    }
+}
+
+/// What a load run measured.
+#[derive(Clone, Debug, Default)]
+pub struct LoadReport {
+    pub sent: usize,
+    pub received: usize,
+    /// Sends, receives and settlements that failed.
+    pub errors: usize,
+    pub last_error: Option<String>,
+    /// From the start until the last send.
+    pub sending: Duration,
+    /// From the start until the last receive.
+    pub receiving: Duration,
+    /// How long each send request took.
+    pub send_latency: Latencies,
+    /// How long each message waited between being enqueued and received.
+    pub end_to_end: Latencies,
+}
+
+fn per_second(count: usize, over: Duration) -> f64 {
+    if over.as_secs_f64() > 0.0 {
+        count as f64 / over.as_secs_f64()
+    } else {
+        0.0
+    }
+}
+
+impl LoadReport {
+    pub fn send_rate(&self) -> f64 {
+        per_second(self.sent, self.sending)
+    }
+
+    pub fn receive_rate(&self) -> f64 {
+        per_second(self.received, self.receiving)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "sent": self.sent,
+            "received": self.received,
+            "errors": self.errors,
+            "lastError": self.last_error,
+            "sendRate": self.send_rate(),
+            "receiveRate": self.receive_rate(),
+            "sendLatencyMs": self.send_latency.to_json(),
+            "endToEndLatencyMs": self.end_to_end.to_json(),
+        })
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "sent      {} in {:.1}s, {:.1}/s",
+            self.sent,
+            self.sending.as_secs_f64(),
+            self.send_rate()
+        )?;
+        writeln!(
+            f,
+            "received  {} in {:.1}s, {:.1}/s",
+            self.received,
+            self.receiving.as_secs_f64(),
+            self.receive_rate()
+        )?;
+        writeln!(f, "errors    {}", self.errors)?;
+        if let Some(error) = &self.last_error {
+            writeln!(f, "          last: {}", error)?;
+        }
+        writeln!(
+            f,
+            "\n{:<12} {:>9} {:>9} {:>9} {:>9}",
+            "LATENCY", "P50", "P90", "P99", "MAX"
+        )?;
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:.1}ms", d.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        for (name, latencies) in &[
+            ("send", &self.send_latency),
+            ("end to end", &self.end_to_end),
+        ] {
+            writeln!(
+                f,
+                "{:<12} {:>9} {:>9} {:>9} {:>9}",
+                name,
+                ms(latencies.percentile(50.0)),
+                ms(latencies.percentile(90.0)),
+                ms(latencies.percentile(99.0)),
+                ms(latencies.max())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// What one sender or receiver thread counted.
+#[derive(Default)]
+struct Tally {
+    count: usize,
+    errors: usize,
+    last_error: Option<String>,
+    latencies: Vec<Duration>,
+    finished: Option<Duration>,
+}
+
+impl Tally {
+    fn error(&mut self, error: Report) {
+        self.errors += 1;
+        self.last_error = Some(format!("{:#}", error));
+    }
+}
+
+// Progress the senders and receivers share.
+struct Shared {
+    start: Instant,
+    done_sending: AtomicBool,
+    sent: AtomicUsize,
+    received: AtomicUsize,
+}
+
+/// Sends to `sender` and receives from `receiver` as `options` say. Either
+/// may be left out to only send or only receive; receiving alone goes on for
+/// `duration` and then until the entity is empty.
+pub fn run<E: Executor + Sync + ?Sized>(
+    exec: &E,
+    sender: Option<&QueueClient>,
+    receiver: Option<&Target>,
+    options: &LoadOptions,
+    clock: &dyn Clock,
+) -> Result<LoadReport, Report> {
+    if sender.is_none() && receiver.is_none() {
+        return Err(eyre!("There is nothing to send to or receive from."));
+    }
+    let shared = Shared {
+        start: Instant::now(),
+        done_sending: AtomicBool::new(false),
+        sent: AtomicUsize::new(0),
+        received: AtomicUsize::new(0),
+    };
+    let body = "0123456789abcdef".repeat(options.size / 16 + 1)[..options.size].to_string();
+    let message = &BrokeredMessage::with_body_and_props(&body, Default::default());
+    let progress = &shared;
+
+    let (sends, receives) = std::thread::scope(|scope| {
+        let senders = match sender {
+            Some(queue) => (0..options.senders)
+                .map(|_| scope.spawn(move || send(exec, queue, message, options, progress)))
+                .collect(),
+            None => Vec::new(),
+        };
+        let receivers = match receiver {
+            Some(target) => (0..options.receivers)
+                .map(|_| {
+                    let counts_sent = sender.is_some();
+                    scope
+                        .spawn(move || receive(exec, target, counts_sent, options, clock, progress))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        if senders.is_empty() {
+            std::thread::sleep(options.duration);
+        }
+        let sends = join(senders);
+        progress.done_sending.store(true, Ordering::SeqCst);
+        (sends, join(receivers))
+    });
+
+    let mut report = LoadReport::default();
+    let (sent, sending, send_latency) = merge(sends, &mut report);
+    let (received, receiving, end_to_end) = merge(receives, &mut report);
+    Ok(LoadReport {
+        sent,
+        received,
+        sending,
+        receiving,
+        send_latency,
+        end_to_end,
+        ..report
+    })
+}
+
+// Adds up the threads of one kind, keeping their errors in the report.
+fn merge(tallies: Vec<Tally>, report: &mut LoadReport) -> (usize, Duration, Latencies) {
+    let mut count = 0;
+    let mut finished = Duration::default();
+    let mut latencies = Vec::new();
+    for tally in tallies {
+        count += tally.count;
+        finished = finished.max(tally.finished.unwrap_or_default());
+        latencies.extend(tally.latencies);
+        report.errors += tally.errors;
+        if tally.last_error.is_some() {
+            report.last_error = tally.last_error;
+        }
+    }
+    (count, finished, latencies.into())
+}
+
+fn join(handles: Vec<std::thread::ScopedJoinHandle<'_, Tally>>) -> Vec<Tally> {
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap_or_default())
+        .collect()
+}
+
+fn send<E: Executor + ?Sized>(
+    exec: &E,
+    queue: &QueueClient,
+    message: &BrokeredMessage,
+    options: &LoadOptions,
+    shared: &Shared,
+) -> Tally {
+    let mut tally = Tally::default();
+    let mut pacer = Pacer::new(Pacing {
+        rate: options
+            .rate
+            .map(|rate| rate / options.senders.max(1) as f64),
+        speed: None,
+    });
+    let deadline = shared.start + options.duration;
+    loop {
+        // Without a rate every message is due at the start, so the clock
+        // decides when to stop.
+        let due = match shared.start.checked_add(pacer.offset(None)) {
+            Some(due) => due,
+            None => return tally,
+        };
+        let now = Instant::now();
+        if due.max(now) >= deadline {
+            return tally;
+        }
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        let sending = Instant::now();
+        match messages::send(exec, queue, Some(message.clone())) {
+            Ok(_) => {
+                tally.latencies.push(sending.elapsed());
+                tally.count += 1;
+                tally.finished = Some(shared.start.elapsed());
+                shared.sent.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => tally.error(e),
+        }
+    }
+}
+
+fn receive<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    counts_sent: bool,
+    options: &LoadOptions,
+    clock: &dyn Clock,
+    shared: &Shared,
+) -> Tally {
+    let mut tally = Tally::default();
+    let mut drain_until = None;
+    let mut backoff = Duration::ZERO;
+    loop {
+        let done = shared.done_sending.load(Ordering::SeqCst);
+        if done
+            && counts_sent
+            && shared.received.load(Ordering::SeqCst) >= shared.sent.load(Ordering::SeqCst)
+        {
+            return tally;
+        }
+        let wait = if done {
+            let until = *drain_until.get_or_insert_with(|| Instant::now() + options.drain);
+            match until.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => left,
+                _ => return tally,
+            }
+        } else {
+            Duration::from_secs(1)
+        };
+        // Failing receives back off rather than spin until the drain is over.
+        if !backoff.is_zero() {
+            std::thread::sleep(backoff.min(wait));
+        }
+        let message = match messages::receive(exec, target, options.mode, 1, wait) {
+            Ok(mut received) => received.pop(),
+            Err(e) => {
+                tally.error(e);
+                backoff = (backoff * 2).clamp(RETRY_BACKOFF, MAX_RETRY_BACKOFF);
+                continue;
+            }
+        };
+        backoff = Duration::ZERO;
+        let message = match message {
+            Some(message) => message,
+            None if done => return tally,
+            None => continue,
+        };
+        let enqueued = message
+            .props
+            .EnqueuedTimeUtc
+            .as_deref()
+            .and_then(parse_http_date);
+        if let Some(enqueued) = enqueued {
+            let waited = clock.now().duration_since(enqueued).unwrap_or_default();
+            tally.latencies.push(waited);
+        }
+        if options.mode == ReceiveMode::PeekLock {
+            if let Err(e) = messages::complete(exec, target, message) {
+                tally.error(e);
+                backoff = RETRY_BACKOFF;
+                continue;
+            }
+        }
+        tally.count += 1;
+        tally.finished = Some(shared.start.elapsed());
+        shared.received.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::SystemClock;
+    use crate::emulator::Emulator;
+    use crate::servicebus::description::{Entity, QueueDescription};
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let latencies = Latencies::from(
+            (1..=10)
+                .rev()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(Some(Duration::from_millis(5)), latencies.percentile(50.0));
+        assert_eq!(Some(Duration::from_millis(9)), latencies.percentile(90.0));
+        assert_eq!(Some(Duration::from_millis(10)), latencies.percentile(99.0));
+        assert_eq!(Some(Duration::from_millis(1)), latencies.percentile(0.0));
+        assert_eq!(None, Latencies::default().percentile(50.0));
+    }
+
+    #[test]
+    fn everything_sent_is_received() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("load")))
+            .start()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let target = Target::open(&conn, Some("load"), None, None)?;
+        let options = LoadOptions {
+            rate: Some(40.0),
+            size: 100,
+            senders: 2,
+            receivers: 2,
+            duration: Duration::from_millis(500),
+            drain: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let report = run(
+            &exec,
+            Some(&target.sender()),
+            Some(&target),
+            &options,
+            &SystemClock,
+        )?;
+
+        assert_eq!(None, report.last_error);
+        assert!((15..=21).contains(&report.sent), "sent {}", report.sent);
+        assert_eq!(report.sent, report.received);
+        assert_eq!(report.sent, report.send_latency.len());
+        assert_eq!(report.sent, report.end_to_end.len());
+        assert!(report.to_string().contains("end to end"));
+        assert_eq!(0, messages::purge(&exec, &target)?);
+
+        // Without a rate, sending alone stops when the time is up.
+        let flat_out = LoadOptions {
+            rate: None,
+            ..options
+        };
+        let report = run(&exec, Some(&target.sender()), None, &flat_out, &SystemClock)?;
+        assert!(report.sent > 0);
+        assert_eq!(0, report.received);
+        assert_eq!(report.sent, messages::purge(&exec, &target)?);
+        Ok(())
+    }
+
+    #[test]
+    fn failing_receivers_stop_after_the_drain() -> Result<(), Report> {
+        let emulator = Emulator::builder().start()?;
+        let exec = emulator.executor();
+        let missing = Target::open(&emulator.connection_string(), Some("missing"), None, None)?;
+        let options = LoadOptions {
+            receivers: 2,
+            duration: Duration::from_millis(300),
+            drain: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let report = run(&exec, None, Some(&missing), &options, &SystemClock)?;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(0, report.received);
+        assert!(report.last_error.is_some());
+        // Two receivers backing off from 50ms get well under 40 tries in.
+        assert!((2..40).contains(&report.errors), "{} errors", report.errors);
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! Sending, receiving, peeking, settling and purging messages, as the `sbctl`
+//! subcommands of the same names do them.
+
+use super::execute;
+use crate::core::exec::Executor;
+use crate::servicebus::brokeredmessage::{BrokerProperties, BrokeredMessage};
+use crate::{QueueClient, SubscriptionClient};
+use eyre::{eyre, Report};
+use hyper::{Request, StatusCode};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Where messages are received from: a queue or a subscription, or the dead
+/// letter queue of either.
+pub enum Target {
+    Queue(QueueClient),
+    Subscription(SubscriptionClient),
+}
+
+/// How a receive treats the message it hands out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiveMode {
+    /// The message stays on the entity, locked, until it is completed or
+    /// abandoned or the lock runs out.
+    PeekLock,
+    /// The message is deleted as it is received.
+    ReceiveAndDelete,
+}
+
+impl Target {
+    /// Opens `queue`, or `subscription` of `topic`.
+    pub fn open(
+        connection_string: &str,
+        queue: Option<&str>,
+        topic: Option<&str>,
+        subscription: Option<&str>,
+    ) -> Result<Target, Report> {
+        match (queue, topic, subscription) {
+            (Some(queue), None, None) => Ok(Target::Queue(QueueClient::with_conn_and_queue(
+                connection_string,
+                queue,
+            )?)),
+            (None, Some(topic), Some(subscription)) => Ok(Target::Subscription(
+                SubscriptionClient::with_conn_topic_and_subscr(
+                    connection_string,
+                    topic,
+                    subscription,
+                )?,
+            )),
+            _ => Err(eyre!(
+                "Name either a queue, or a topic and one of its subscriptions."
+            )),
+        }
+    }
+
+    pub fn dead_letter_queue(&self) -> Target {
+        match self {
+            Target::Queue(queue) => Target::Queue(queue.dead_letter_queue()),
+            Target::Subscription(sub) => Target::Subscription(sub.dead_letter_queue()),
+        }
+    }
+
+    /// Whether this is the dead letter queue of a queue or subscription.
+    pub fn is_dead_letter_queue(&self) -> bool {
+        let name = match self {
+            Target::Queue(q) => q.queue(),
+            Target::Subscription(s) => s.subscription(),
+        };
+        name.ends_with("/$DeadLetterQueue")
+    }
+
+    /// A client that sends to the queue, or to the topic of a subscription.
+    /// A dead letter queue sends to the entity it belongs to.
+    pub fn sender(&self) -> QueueClient {
+        let (endpoint, name, credential) = match self {
+            Target::Queue(q) => (q.endpoint(), q.queue(), q.token_provider()),
+            Target::Subscription(s) => (s.endpoint(), s.topic(), s.token_provider()),
+        };
+        let name = name.trim_end_matches("/$DeadLetterQueue");
+        QueueClient::with_token_provider(endpoint.clone(), name, credential.clone())
+    }
+
+    pub fn receive(&self, mode: ReceiveMode, timeout: Duration) -> Result<Request<()>, Report> {
+        match (self, mode) {
+            (Target::Queue(q), ReceiveMode::PeekLock) => q.receive_with_timeout(timeout),
+            (Target::Queue(q), ReceiveMode::ReceiveAndDelete) => {
+                q.receive_and_delete_with_timeout(timeout)
+            }
+            (Target::Subscription(s), ReceiveMode::PeekLock) => s.receive_with_timeout(timeout),
+            (Target::Subscription(s), ReceiveMode::ReceiveAndDelete) => {
+                s.receive_and_delete_with_timeout(timeout)
+            }
+        }
+    }
+
+    pub fn complete_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
+        match self {
+            Target::Queue(q) => q.complete_message(message),
+            Target::Subscription(s) => s.complete_message(message),
+        }
+    }
+
+    pub fn abandon_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
+        match self {
+            Target::Queue(q) => q.abandon_message(message),
+            Target::Subscription(s) => s.abandon_message(message),
+        }
+    }
+
+    pub fn renew_message(&self, message: &BrokeredMessage) -> Result<Request<()>, Report> {
+        match self {
+            Target::Queue(q) => q.renew_message(message),
+            Target::Subscription(s) => s.renew_message(message),
+        }
+    }
+}
+
+/// What every message of one `sbctl send` has in common.
+#[derive(Clone, Debug, Default)]
+pub struct MessageTemplate {
+    /// Send bodies as they are instead of as a DataContract string.
+    pub raw: bool,
+    pub props: BrokerProperties,
+    pub properties: BTreeMap<String, Value>,
+}
+
+impl MessageTemplate {
+    pub fn message(&self, body: &str) -> BrokeredMessage {
+        let mut message = if self.raw {
+            BrokeredMessage::with_body_and_props(body, self.props.clone())
+        } else {
+            let mut message = BrokeredMessage::with_body(body);
+            *message.props = self.props.clone();
+            message
+        };
+        message.properties = self.properties.clone();
+        message
+    }
+}
+
+/// Reads a `name=value` property. Values that are JSON keep their type, so
+/// `priority=2` is a number and `region=west` is a string.
+pub fn parse_property(s: &str) -> Result<(String, Value), Report> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("Properties are written name=value, not {}.", s))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+    Ok((name.trim().to_string(), value))
+}
+
+/// A message as `sbctl` prints it: the body, unwrapped when it is a
+/// DataContract string, the broker properties and the custom properties.
+pub fn to_json(message: &BrokeredMessage) -> Value {
+    let body = message
+        .get_body()
+        .unwrap_or_else(|_| message.get_body_raw().to_string());
+    json!({
+        "body": body,
+        "brokerProperties": &*message.props,
+        "properties": &message.properties,
+    })
+}
+
+/// A stand-in for a message received earlier, enough to complete or abandon
+/// it: the lock token plus the sequence number or message id.
+pub fn locked_message(
+    lock_token: &str,
+    sequence_number: Option<usize>,
+    message_id: Option<&str>,
+) -> Result<BrokeredMessage, Report> {
+    if sequence_number.is_none() && message_id.is_none() {
+        return Err(eyre!(
+            "A locked message is identified by its sequence number or its message id."
+        ));
+    }
+    let props = BrokerProperties {
+        LockToken: Some(lock_token.to_string()),
+        SequenceNumber: sequence_number,
+        MessageId: message_id.map(str::to_string),
+        ..Default::default()
+    };
+    Ok(BrokeredMessage::with_body_and_props("", props))
+}
+
+fn empty(request: Request<()>) -> Request<String> {
+    request.map(|()| String::new())
+}
+
+/// Sends the messages to a queue or topic, one after the other. Returns how
+/// many were sent.
+pub fn send<E, I>(exec: &E, queue: &QueueClient, messages: I) -> Result<usize, Report>
+where
+    E: Executor + ?Sized,
+    I: IntoIterator<Item = BrokeredMessage>,
+{
+    let mut sent = 0;
+    for message in messages {
+        execute(exec, queue.send(message)?)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Receives up to `max` messages. Waiting for the first message takes up to
+/// `timeout`; after that it stops as soon as the entity is empty.
+pub fn receive<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    mode: ReceiveMode,
+    max: usize,
+    timeout: Duration,
+) -> Result<Vec<BrokeredMessage>, Report> {
+    let mut messages = Vec::new();
+    while messages.len() < max {
+        let wait = if messages.is_empty() {
+            timeout
+        } else {
+            Duration::from_secs(0)
+        };
+        let response = execute(exec, empty(target.receive(mode, wait)?))?;
+        if response.status() == StatusCode::NO_CONTENT {
+            break;
+        }
+        messages.push(BrokeredMessage::with_response(response));
+    }
+    Ok(messages)
+}
+
+/// Looks at up to `max` messages without taking them. The REST api has no
+/// real peek, so they are received under a lock and abandoned straight away,
+/// which counts as a delivery. A message on its last delivery is dead
+/// lettered by that, so anything but a dead letter queue is refused unless
+/// `force` is set.
+pub fn peek<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    max: usize,
+    timeout: Duration,
+    force: bool,
+) -> Result<Vec<BrokeredMessage>, Report> {
+    if !force && !target.is_dead_letter_queue() {
+        return Err(eyre!(
+            "Peeking counts as a delivery, so it dead letters messages that are on their \
+             last one. Peek the dead letter queue, or use --force to peek anyway."
+        ));
+    }
+    let messages = receive(exec, target, ReceiveMode::PeekLock, max, timeout)?;
+    for message in &messages {
+        execute(exec, empty(target.abandon_message(message.clone())?))?;
+    }
+    Ok(messages)
+}
+
+pub fn complete<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    message: BrokeredMessage,
+) -> Result<(), Report> {
+    execute(exec, empty(target.complete_message(message)?))?;
+    Ok(())
+}
+
+pub fn abandon<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    message: BrokeredMessage,
+) -> Result<(), Report> {
+    execute(exec, empty(target.abandon_message(message)?))?;
+    Ok(())
+}
+
+/// Keeps the lock on a message received under a peek lock for another lock
+/// duration.
+pub fn renew<E: Executor + ?Sized>(
+    exec: &E,
+    target: &Target,
+    message: &BrokeredMessage,
+) -> Result<(), Report> {
+    execute(exec, empty(target.renew_message(message)?))?;
+    Ok(())
+}
+
+/// Deletes every message that can be received right now. Messages locked by
+/// another receiver are left alone. Returns how many were deleted.
+pub fn purge<E: Executor + ?Sized>(exec: &E, target: &Target) -> Result<usize, Report> {
+    let mut purged = 0;
+    loop {
+        let request = empty(target.receive(ReceiveMode::ReceiveAndDelete, Duration::from_secs(0))?);
+        if execute(exec, request)?.status() == StatusCode::NO_CONTENT {
+            return Ok(purged);
+        }
+        purged += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::servicebus::description::{
+        Entity, QueueDescription, SubscriptionDescription, TopicDescription,
+    };
+
+    fn emulator() -> Result<Emulator, Report> {
+        Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .entity(Entity::Topic(TopicDescription::new("events")))
+            .entity(Entity::Subscription(SubscriptionDescription::new(
+                "events", "audit",
+            )))
+            .start()
+    }
+
+    #[test]
+    fn properties_keep_their_json_type() -> Result<(), Report> {
+        assert_eq!(
+            ("priority".to_string(), json!(2)),
+            parse_property("priority=2")?
+        );
+        assert_eq!(
+            ("region".to_string(), json!("west europe")),
+            parse_property("region=west europe")?
+        );
+        assert_eq!(("empty".to_string(), json!("")), parse_property("empty=")?);
+        assert!(parse_property("priority").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn send_receive_and_settle() -> Result<(), Report> {
+        let emulator = emulator()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let queue = QueueClient::with_conn_and_queue(&conn, "orders")?;
+        let target = Target::open(&conn, Some("orders"), None, None)?;
+
+        let mut template = MessageTemplate::default();
+        template.props.Label = Some("order".to_string());
+        template.properties.insert("priority".to_string(), json!(2));
+        let bodies = ["order 1", "order 2", "order 3"];
+        assert_eq!(
+            3,
+            send(&exec, &queue, bodies.iter().map(|b| template.message(b)))?
+        );
+
+        assert!(peek(&exec, &target, 10, Duration::from_secs(1), false).is_err());
+        let dead_letters = target.dead_letter_queue();
+        assert!(peek(&exec, &dead_letters, 10, Duration::from_millis(100), false)?.is_empty());
+        let peeked = peek(&exec, &target, 10, Duration::from_secs(1), true)?;
+        assert_eq!(3, peeked.len());
+        let printed = to_json(&peeked[0]);
+        assert_eq!(json!("order 1"), printed["body"]);
+        assert_eq!(json!("order"), printed["brokerProperties"]["Label"]);
+        assert_eq!(json!(2), printed["properties"]["priority"]);
+
+        let received = receive(
+            &exec,
+            &target,
+            ReceiveMode::PeekLock,
+            2,
+            Duration::from_secs(1),
+        )?;
+        assert_eq!(2, received.len());
+        let locked = |m: &BrokeredMessage| {
+            locked_message(
+                m.props.LockToken.as_deref().unwrap(),
+                m.props.SequenceNumber,
+                None,
+            )
+        };
+        complete(&exec, &target, locked(&received[0])?)?;
+        abandon(&exec, &target, locked(&received[1])?)?;
+        assert!(complete(&exec, &target, locked(&received[0])?).is_err());
+
+        // The abandoned message and the one never received are left.
+        assert_eq!(2, purge(&exec, &target)?);
+        assert_eq!(0, purge(&exec, &target)?);
+        Ok(())
+    }
+
+    #[test]
+    fn raw_messages_to_a_subscription() -> Result<(), Report> {
+        let emulator = emulator()?;
+        let exec = emulator.executor();
+        let conn = emulator.connection_string();
+        let topic = QueueClient::with_conn_and_queue(&conn, "events")?;
+        let template = MessageTemplate {
+            raw: true,
+            ..Default::default()
+        };
+        send(&exec, &topic, Some(template.message("{\"id\":1}")))?;
+
+        let target = Target::open(&conn, None, Some("events"), Some("audit"))?;
+        let received = receive(
+            &exec,
+            &target,
+            ReceiveMode::ReceiveAndDelete,
+            5,
+            Duration::from_secs(1),
+        )?;
+        assert_eq!(1, received.len());
+        assert_eq!(json!("{\"id\":1}"), to_json(&received[0])["body"]);
+        assert!(Target::open(&conn, Some("orders"), Some("events"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn works_over_http() -> Result<(), Report> {
+        let emulator = emulator()?;
+        let conn = emulator.connection_string();
+        let exec = crate::cli::HttpExecutor::new();
+        let queue = QueueClient::with_conn_and_queue(&conn, "orders")?;
+        send(&exec, &queue, Some(BrokeredMessage::with_body("hello")))?;
+        let target = Target::open(&conn, Some("orders"), None, None)?;
+        assert_eq!(1, purge(&exec, &target)?);
+        Ok(())
+    }
+}
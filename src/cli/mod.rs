@@ -0,0 +1,211 @@
+//! The pieces the `sbctl` binary is made of, kept in the library so they can
+//! be tested against the emulator and reused by other tools.
+//!
+//! `HttpExecutor` puts requests on the wire with a blocking http client.
+//! `connection_string` works out which namespace to talk to: a connection
+//! string given on the command line or in `SERVICEBUS_CONNECTION_STRING`, or
+//! a named profile from a TOML file such as
+//!
+//! ```toml
+//! default = "dev"
+//!
+//! [profiles.dev]
+//! connection_string = "Endpoint=sb://localhost:5300/;SharedAccessKeyName=RootManageSharedAccessKey;SharedAccessKey=SAS_KEY_VALUE;UseDevelopmentEmulator=true"
+//!
+//! [profiles.prod]
+//! connection_string = "Endpoint=sb://my-namespace.servicebus.windows.net/;SharedAccessKeyName=listen;SharedAccessKey=..."
+//! ```
+//!
+//! Only available with the `cli` feature.
+
+pub mod admin;
+pub mod archive;
+pub mod browse;
+#[cfg(feature = "exporter")]
+pub mod exporter;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod load;
+pub mod messages;
+
+use crate::core::exec::Executor;
+use crate::servicebus::interpret_results;
+use eyre::{eyre, Report, WrapErr};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+/// Environment variable with the connection string to use when none is given.
+pub const CONNECTION_STRING_ENV: &str = "SERVICEBUS_CONNECTION_STRING";
+/// Environment variable naming the profile to use when none is given.
+pub const PROFILE_ENV: &str = "SBCTL_PROFILE";
+/// Environment variable with the path of the profile file.
+pub const PROFILES_ENV: &str = "SBCTL_PROFILES";
+
+/// Sends requests with a blocking `reqwest` client. It can't be used from
+/// inside an async runtime.
+#[derive(Clone, Default)]
+pub struct HttpExecutor {
+    client: reqwest::blocking::Client,
+}
+
+impl HttpExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Executor for HttpExecutor {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        let resp = self.client.execute(request.try_into()?)?;
+        let mut builder = Response::builder().status(resp.status().as_u16());
+        for (name, value) in resp.headers() {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        Ok(builder.body(resp.text()?)?)
+    }
+}
+
+// Sends a request and turns anything but success into an error that says
+// what the service answered.
+pub(crate) fn execute<E: Executor + ?Sized>(
+    exec: &E,
+    request: Request<String>,
+) -> Result<Response<String>, Report> {
+    let response = exec.execute(request)?;
+    if response.status() != StatusCode::NO_CONTENT {
+        interpret_results(response.status())
+            .wrap_err_with(|| format!("{} {}", response.status(), response.body().trim()))?;
+    }
+    Ok(response)
+}
+
+/// The named connection strings in a profile file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct Profiles {
+    /// The profile used when none is named.
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Profile {
+    pub connection_string: String,
+}
+
+impl Profiles {
+    pub fn from_toml(s: &str) -> Result<Profiles, Report> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Profiles, Report> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+        Self::from_toml(&contents).wrap_err_with(|| format!("Could not parse {}", path.display()))
+    }
+
+    /// `$SBCTL_PROFILES`, or `sbctl/profiles.toml` in the user's config
+    /// directory.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os(PROFILES_ENV) {
+            return Some(PathBuf::from(path));
+        }
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config.join("sbctl").join("profiles.toml"))
+    }
+
+    /// The connection string of the named profile, or of the default one.
+    pub fn connection_string(&self, name: Option<&str>) -> Result<&str, Report> {
+        let name = name
+            .or(self.default.as_deref())
+            .ok_or_else(|| eyre!("No profile was named and the profile file has no default."))?;
+        self.profiles
+            .get(name)
+            .map(|profile| profile.connection_string.as_str())
+            .ok_or_else(|| eyre!("There is no profile called {}.", name))
+    }
+}
+
+/// Picks the connection string to use. A connection string or profile given
+/// on the command line wins over the environment, and a connection string
+/// wins over a profile.
+pub fn connection_string(
+    connection_string: Option<String>,
+    profile: Option<String>,
+    profiles_file: Option<PathBuf>,
+) -> Result<String, Report> {
+    if let Some(conn) = connection_string {
+        return Ok(conn);
+    }
+    if profile.is_none() {
+        if let Ok(conn) = std::env::var(CONNECTION_STRING_ENV) {
+            return Ok(conn);
+        }
+    }
+    let profile = profile.or_else(|| std::env::var(PROFILE_ENV).ok());
+    let path = profiles_file
+        .or_else(Profiles::default_path)
+        .ok_or_else(|| {
+            eyre!(
+                "No connection string. Pass --connection-string or set {}.",
+                CONNECTION_STRING_ENV
+            )
+        })?;
+    if profile.is_none() && !path.exists() {
+        return Err(eyre!(
+            "No connection string. Pass --connection-string, set {} or add profiles to {}.",
+            CONNECTION_STRING_ENV,
+            path.display()
+        ));
+    }
+    let profiles = Profiles::from_path(&path)?;
+    Ok(profiles.connection_string(profile.as_deref())?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"
+default = "dev"
+
+[profiles.dev]
+connection_string = "Endpoint=sb://localhost:5300/;SharedAccessKeyName=dev;SharedAccessKey=a2V5"
+
+[profiles.prod]
+connection_string = "Endpoint=sb://prod.servicebus.windows.net/;SharedAccessKeyName=listen;SharedAccessKey=a2V5"
+"#;
+
+    #[test]
+    fn profiles_pick_the_named_or_default_connection_string() -> Result<(), Report> {
+        let profiles = Profiles::from_toml(PROFILES)?;
+        assert!(profiles.connection_string(None)?.contains("localhost"));
+        assert!(profiles.connection_string(Some("prod"))?.contains("prod"));
+        assert!(profiles.connection_string(Some("staging")).is_err());
+        assert!(Profiles::default().connection_string(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_connection_strings_win() -> Result<(), Report> {
+        let path = std::env::temp_dir().join(format!("sbctl-profiles-{}.toml", std::process::id()));
+        std::fs::write(&path, PROFILES)?;
+        let picked = connection_string(None, Some("prod".to_string()), Some(path.clone()));
+        let explicit = connection_string(
+            Some("Endpoint=sb://other/".to_string()),
+            Some("prod".to_string()),
+            Some(path.clone()),
+        );
+        std::fs::remove_file(&path)?;
+        assert!(picked?.contains("prod.servicebus"));
+        assert_eq!("Endpoint=sb://other/", explicit?);
+        Ok(())
+    }
+}
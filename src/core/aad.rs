@@ -0,0 +1,785 @@
+//! Azure Active Directory (Entra ID) credentials.
+//!
+//! Each credential only knows how to build the request for a new access
+//! token. `AadTokenProvider` sends those requests through an `Executor`,
+//! caches the result and hands out `Authorization: Bearer` headers to the
+//! clients, so no shared access key ever has to be deployed.
+
+use super::clock::{Clock, SystemClock};
+use super::exec::Executor;
+use super::token::{AccessToken, TokenCache, TokenProvider, DEFAULT_REFRESH_MARGIN};
+use eyre::{eyre, Report};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response, Uri};
+use serde::Deserialize;
+use std::fmt::{self, Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// The scope that grants access to every Service Bus namespace the identity
+/// has a role on.
+pub const SERVICE_BUS_SCOPE: &str = "https://servicebus.azure.net/.default";
+
+/// The public cloud authority.
+pub const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+
+/// The instance metadata service every Azure VM can reach.
+pub const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+const IMDS_API_VERSION: &str = "2018-02-01";
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Something that can ask an identity provider for an access token.
+pub trait TokenCredential: Send + Sync {
+    /// Builds the request that fetches a token for `scope`. The response is
+    /// expected to be the usual OAuth2 JSON with an `access_token`. `clock` is
+    /// the provider's, for credentials that put the time in the request.
+    fn token_request(&self, scope: &str, clock: &dyn Clock) -> Result<Request<String>, Report>;
+
+    /// A short name used when reporting why the credential failed.
+    fn name(&self) -> &str {
+        "custom credential"
+    }
+}
+
+/// Gets tokens for an app registration with a client secret.
+#[derive(Clone)]
+pub struct ClientSecretCredential {
+    tenant_id: String,
+    client_id: String,
+    secret: Zeroizing<String>,
+    authority_host: String,
+}
+
+impl ClientSecretCredential {
+    pub fn new(tenant_id: &str, client_id: &str, secret: &str) -> Self {
+        ClientSecretCredential {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            secret: Zeroizing::new(secret.to_string()),
+            authority_host: DEFAULT_AUTHORITY_HOST.to_string(),
+        }
+    }
+
+    /// Reads `AZURE_TENANT_ID`, `AZURE_CLIENT_ID`, `AZURE_CLIENT_SECRET` and
+    /// optionally `AZURE_AUTHORITY_HOST`.
+    pub fn from_env() -> Result<Self, Report> {
+        let cred = Self::new(
+            &env("AZURE_TENANT_ID")?,
+            &env("AZURE_CLIENT_ID")?,
+            &env("AZURE_CLIENT_SECRET")?,
+        );
+        Ok(match std::env::var("AZURE_AUTHORITY_HOST") {
+            Ok(host) => cred.with_authority_host(&host),
+            Err(_) => cred,
+        })
+    }
+
+    /// Sends token requests somewhere other than the public cloud, such as a
+    /// sovereign cloud or a local stand-in server.
+    pub fn with_authority_host(mut self, host: &str) -> Self {
+        self.authority_host = host.trim_end_matches('/').to_string();
+        self
+    }
+}
+
+impl Debug for ClientSecretCredential {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ClientSecretCredential")
+            .field("tenant_id", &self.tenant_id)
+            .field("client_id", &self.client_id)
+            .field("secret", &"<redacted>")
+            .field("authority_host", &self.authority_host)
+            .finish()
+    }
+}
+
+impl TokenCredential for ClientSecretCredential {
+    fn token_request(&self, scope: &str, _: &dyn Clock) -> Result<Request<String>, Report> {
+        aad_request(
+            &self.authority_host,
+            &self.tenant_id,
+            &[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.secret),
+                ("scope", scope),
+            ],
+        )
+    }
+
+    fn name(&self) -> &str {
+        "client secret"
+    }
+}
+
+/// Gets tokens for an app registration with a certificate, by signing a
+/// client assertion with the certificate's private key.
+#[cfg(feature = "certificate")]
+pub struct ClientCertificateCredential {
+    tenant_id: String,
+    client_id: String,
+    key: rsa::RsaPrivateKey,
+    thumbprint: Vec<u8>,
+    authority_host: String,
+    assertion_lifetime: Duration,
+}
+
+#[cfg(feature = "certificate")]
+impl ClientCertificateCredential {
+    /// Takes a PEM file holding the private key (PKCS#1 or PKCS#8) and the
+    /// certificate registered with the app, as the azure cli exports them.
+    pub fn from_pem(tenant_id: &str, client_id: &str, pem: &str) -> Result<Self, Report> {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+        use rsa::pkcs8::DecodePrivateKey;
+
+        let pkcs8 = pem_section(pem, "PRIVATE KEY");
+        let pkcs1 = pem_section(pem, "RSA PRIVATE KEY");
+        let key = if !pkcs8.is_empty() {
+            rsa::RsaPrivateKey::from_pkcs8_pem(pkcs8)?
+        } else if !pkcs1.is_empty() {
+            rsa::RsaPrivateKey::from_pkcs1_pem(pkcs1)?
+        } else {
+            return Err(eyre!("No private key in the certificate file."));
+        };
+        let cert = pem_block(pem, "CERTIFICATE")
+            .ok_or_else(|| eyre!("No certificate in the certificate file."))??;
+        Ok(Self::new(tenant_id, client_id, key, &cert))
+    }
+
+    /// Reads the PEM file at `AZURE_CLIENT_CERTIFICATE_PATH` along with
+    /// `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and optionally `AZURE_AUTHORITY_HOST`.
+    pub fn from_env() -> Result<Self, Report> {
+        let pem = std::fs::read_to_string(env("AZURE_CLIENT_CERTIFICATE_PATH")?)?;
+        let cred = Self::from_pem(&env("AZURE_TENANT_ID")?, &env("AZURE_CLIENT_ID")?, &pem)?;
+        Ok(match std::env::var("AZURE_AUTHORITY_HOST") {
+            Ok(host) => cred.with_authority_host(&host),
+            Err(_) => cred,
+        })
+    }
+
+    /// `certificate` is the DER encoding of the registered certificate, it is
+    /// only used for its thumbprint.
+    pub fn new(
+        tenant_id: &str,
+        client_id: &str,
+        key: rsa::RsaPrivateKey,
+        certificate: &[u8],
+    ) -> Self {
+        use crypto::digest::Digest;
+
+        let mut sha1 = crypto::sha1::Sha1::new();
+        sha1.input(certificate);
+        let mut thumbprint = vec![0; sha1.output_bytes()];
+        sha1.result(&mut thumbprint);
+
+        ClientCertificateCredential {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            key,
+            thumbprint,
+            authority_host: DEFAULT_AUTHORITY_HOST.to_string(),
+            assertion_lifetime: Duration::from_secs(600),
+        }
+    }
+
+    pub fn with_authority_host(mut self, host: &str) -> Self {
+        self.authority_host = host.trim_end_matches('/').to_string();
+        self
+    }
+
+    // A JWT signed with RS256, identifying the certificate by its SHA-1 thumbprint.
+    fn assertion(&self, clock: &dyn Clock) -> Result<String, Report> {
+        use rsa::signature::{SignatureEncoding, Signer};
+
+        let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let now = clock.unix_now();
+        let header = serde_json::json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "x5t": encode(&self.thumbprint),
+        });
+        let claims = serde_json::json!({
+            "aud": token_endpoint(&self.authority_host, &self.tenant_id),
+            "iss": self.client_id,
+            "sub": self.client_id,
+            "jti": format!("{:032x}", rand::random::<u128>()),
+            "nbf": now,
+            "exp": now + self.assertion_lifetime.as_secs(),
+        });
+        let message = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+
+        let signer = rsa::pkcs1v15::SigningKey::<rsa::sha2::Sha256>::new(self.key.clone());
+        let signature = signer.try_sign(message.as_bytes())?.to_bytes();
+        Ok(format!("{}.{}", message, encode(&signature)))
+    }
+}
+
+#[cfg(feature = "certificate")]
+impl TokenCredential for ClientCertificateCredential {
+    fn token_request(&self, scope: &str, clock: &dyn Clock) -> Result<Request<String>, Report> {
+        let assertion = self.assertion(clock)?;
+        aad_request(
+            &self.authority_host,
+            &self.tenant_id,
+            &[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_assertion_type", JWT_BEARER),
+                ("client_assertion", &assertion),
+                ("scope", scope),
+            ],
+        )
+    }
+
+    fn name(&self) -> &str {
+        "client certificate"
+    }
+}
+
+/// Gets tokens for the managed identity of the machine from the instance
+/// metadata service (IMDS).
+#[derive(Clone, Debug)]
+pub struct ManagedIdentityCredential {
+    endpoint: String,
+    client_id: Option<String>,
+}
+
+impl ManagedIdentityCredential {
+    /// Uses the system assigned identity.
+    pub fn new() -> Self {
+        ManagedIdentityCredential {
+            endpoint: DEFAULT_IMDS_ENDPOINT.to_string(),
+            client_id: None,
+        }
+    }
+
+    /// Picks one of several user assigned identities.
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_string());
+        self
+    }
+
+    /// Asks a different token endpoint, such as a local stand-in for IMDS.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+}
+
+impl Default for ManagedIdentityCredential {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenCredential for ManagedIdentityCredential {
+    fn token_request(&self, scope: &str, _: &dyn Clock) -> Result<Request<String>, Report> {
+        // IMDS wants the resource, not the v2 scope.
+        let resource = scope.trim_end_matches(".default");
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        query.append_pair("api-version", IMDS_API_VERSION);
+        query.append_pair("resource", resource);
+        if let Some(id) = &self.client_id {
+            query.append_pair("client_id", id);
+        }
+        let uri: Uri = format!("{}?{}", self.endpoint, query.finish()).parse()?;
+
+        Ok(Request::get(uri)
+            .header("Metadata", HeaderValue::from_static("true"))
+            .body(String::new())?)
+    }
+
+    fn name(&self) -> &str {
+        "managed identity"
+    }
+}
+
+/// Exchanges a federated token that an orchestrator such as Kubernetes keeps
+/// in a file for an AAD token. The file is read again for every request so
+/// rotated tokens are picked up.
+#[derive(Clone, Debug)]
+pub struct WorkloadIdentityCredential {
+    tenant_id: String,
+    client_id: String,
+    token_file: PathBuf,
+    authority_host: String,
+}
+
+impl WorkloadIdentityCredential {
+    pub fn new(tenant_id: &str, client_id: &str, token_file: impl AsRef<Path>) -> Self {
+        WorkloadIdentityCredential {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            token_file: token_file.as_ref().to_path_buf(),
+            authority_host: DEFAULT_AUTHORITY_HOST.to_string(),
+        }
+    }
+
+    /// Reads the variables the workload identity webhook injects:
+    /// `AZURE_TENANT_ID`, `AZURE_CLIENT_ID`, `AZURE_FEDERATED_TOKEN_FILE`
+    /// and `AZURE_AUTHORITY_HOST`.
+    pub fn from_env() -> Result<Self, Report> {
+        let cred = Self::new(
+            &env("AZURE_TENANT_ID")?,
+            &env("AZURE_CLIENT_ID")?,
+            env("AZURE_FEDERATED_TOKEN_FILE")?,
+        );
+        Ok(match std::env::var("AZURE_AUTHORITY_HOST") {
+            Ok(host) => cred.with_authority_host(&host),
+            Err(_) => cred,
+        })
+    }
+
+    pub fn with_authority_host(mut self, host: &str) -> Self {
+        self.authority_host = host.trim_end_matches('/').to_string();
+        self
+    }
+}
+
+impl TokenCredential for WorkloadIdentityCredential {
+    fn token_request(&self, scope: &str, _: &dyn Clock) -> Result<Request<String>, Report> {
+        let assertion = std::fs::read_to_string(&self.token_file)?;
+        aad_request(
+            &self.authority_host,
+            &self.tenant_id,
+            &[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_assertion_type", JWT_BEARER),
+                ("client_assertion", assertion.trim()),
+                ("scope", scope),
+            ],
+        )
+    }
+
+    fn name(&self) -> &str {
+        "workload identity"
+    }
+}
+
+/// A `TokenProvider` backed by one or more AAD credentials.
+///
+/// Credentials are tried in order until one of them gets a token, after
+/// which the provider sticks with that credential.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use hyper::{Request, Response};
+/// # use azure_service_bus::core::aad::*;
+/// # use azure_service_bus::QueueClient;
+/// # fn exec(_: Request<String>) -> Result<Response<String>, eyre::Report> { unimplemented!() }
+/// # fn main() -> Result<(), eyre::Report> {
+/// let provider = AadTokenProvider::from_env(exec);
+/// let queue = QueueClient::with_token_provider(
+///     "https://my-namespace.servicebus.windows.net/".parse()?,
+///     "orders",
+///     Arc::new(provider),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub struct AadTokenProvider {
+    credentials: Vec<Box<dyn TokenCredential>>,
+    exec: Box<dyn Executor + Send + Sync>,
+    scope: String,
+    selected: Mutex<Option<usize>>,
+    cache: TokenCache,
+    clock: Arc<dyn Clock>,
+}
+
+impl AadTokenProvider {
+    pub fn new<C, E>(credential: C, exec: E) -> Self
+    where
+        C: TokenCredential + 'static,
+        E: Executor + Send + Sync + 'static,
+    {
+        Self::chain(vec![Box::new(credential)], exec)
+    }
+
+    /// Tries each of `credentials` in order.
+    pub fn chain<E>(credentials: Vec<Box<dyn TokenCredential>>, exec: E) -> Self
+    where
+        E: Executor + Send + Sync + 'static,
+    {
+        AadTokenProvider {
+            credentials,
+            exec: Box::new(exec),
+            scope: SERVICE_BUS_SCOPE.to_string(),
+            selected: Mutex::new(None),
+            cache: TokenCache::new(DEFAULT_REFRESH_MARGIN),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// The usual chain for services: a client secret or certificate from the
+    /// environment, then workload identity, then managed identity.
+    pub fn from_env<E>(exec: E) -> Self
+    where
+        E: Executor + Send + Sync + 'static,
+    {
+        let mut credentials: Vec<Box<dyn TokenCredential>> = Vec::new();
+        if let Ok(cred) = ClientSecretCredential::from_env() {
+            credentials.push(Box::new(cred));
+        }
+        #[cfg(feature = "certificate")]
+        if let Ok(cred) = ClientCertificateCredential::from_env() {
+            credentials.push(Box::new(cred));
+        }
+        if let Ok(cred) = WorkloadIdentityCredential::from_env() {
+            credentials.push(Box::new(cred));
+        }
+        let mut imds = ManagedIdentityCredential::new();
+        if let Ok(id) = std::env::var("AZURE_CLIENT_ID") {
+            imds = imds.with_client_id(&id);
+        }
+        credentials.push(Box::new(imds));
+        Self::chain(credentials, exec)
+    }
+
+    /// Asks for tokens with a different scope than `SERVICE_BUS_SCOPE`.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = scope.to_string();
+        self
+    }
+
+    /// How long before expiry a new token is fetched.
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.cache = self.cache.with_margin(margin);
+        self
+    }
+
+    /// What token expiry times are worked out from and compared against,
+    /// and what certificate assertions are dated with.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    fn fetch_with(&self, credential: &dyn TokenCredential) -> Result<AccessToken, Report> {
+        let request = credential.token_request(&self.scope, &*self.clock)?;
+        let (token, expires_on) = parse_token_response(self.exec.execute(request)?, &*self.clock)?;
+        Ok(AccessToken {
+            header: format!("Bearer {}", token),
+            expires_on,
+        })
+    }
+
+    fn fetch(&self) -> Result<AccessToken, Report> {
+        let mut selected = super::lock(&self.selected);
+        if let Some(idx) = *selected {
+            return self.fetch_with(self.credentials[idx].as_ref());
+        }
+
+        let mut failures = Vec::new();
+        for (idx, credential) in self.credentials.iter().enumerate() {
+            match self.fetch_with(credential.as_ref()) {
+                Ok(token) => {
+                    *selected = Some(idx);
+                    return Ok(token);
+                }
+                Err(e) => failures.push(format!("{}: {}", credential.name(), e)),
+            }
+        }
+        Err(eyre!(
+            "No credential could get a token. {}",
+            failures.join("; ")
+        ))
+    }
+}
+
+impl TokenProvider for AadTokenProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        self.cache.get_or_refresh(|| self.fetch())
+    }
+
+    fn invalidate(&self) {
+        self.cache.clear()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.cache.correct_skew(server_time)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    expires_in: Option<serde_json::Value>,
+    expires_on: Option<serde_json::Value>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Reads the access token and the unix time it expires at out of an OAuth2
+/// token response. AAD sends `expires_in` as a number while IMDS sends
+/// `expires_on` as a string, both are understood. `expires_in` counts from
+/// `clock`'s now.
+pub fn parse_token_response(
+    response: Response<String>,
+    clock: &dyn Clock,
+) -> Result<(String, u64), Report> {
+    let status = response.status();
+    let body: TokenResponse = serde_json::from_str(response.body())
+        .map_err(|e| eyre!("Token response ({}) is not json: {}", status, e))?;
+    if !status.is_success() {
+        return Err(eyre!(
+            "Token request failed with {}: {}",
+            status,
+            body.error_description
+                .or(body.error)
+                .unwrap_or_else(|| "no description".to_string())
+        ));
+    }
+
+    let seconds = |v: serde_json::Value| match v {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    };
+    let expires_in = body.expires_in;
+    let expires_on = body
+        .expires_on
+        .and_then(seconds)
+        .or_else(|| expires_in.and_then(seconds).map(|s| clock.unix_now() + s))
+        .ok_or_else(|| eyre!("Token response has no expiry."))?;
+    let token = body
+        .access_token
+        .ok_or_else(|| eyre!("Token response has no access_token."))?;
+    Ok((token, expires_on))
+}
+
+fn token_endpoint(authority_host: &str, tenant_id: &str) -> String {
+    format!("{}/{}/oauth2/v2.0/token", authority_host, tenant_id)
+}
+
+fn aad_request(
+    authority_host: &str,
+    tenant_id: &str,
+    form: &[(&str, &str)],
+) -> Result<Request<String>, Report> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    Ok(Request::builder()
+        .method(Method::POST)
+        .uri(token_endpoint(authority_host, tenant_id))
+        .header(CONTENT_TYPE, FORM_CONTENT_TYPE)
+        .body(body)?)
+}
+
+fn env(name: &str) -> Result<String, Report> {
+    std::env::var(name).map_err(|_| eyre!("{} is not set.", name))
+}
+
+// The base64 body of the first `-----BEGIN {label}-----` block, decoded.
+#[cfg(feature = "certificate")]
+fn pem_block(pem: &str, label: &str) -> Option<Result<Vec<u8>, Report>> {
+    let section = pem_section(pem, label);
+    if section.is_empty() {
+        return None;
+    }
+    let body: String = section
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .map(str::trim)
+        .collect();
+    Some(base64::decode(body).map_err(Report::from))
+}
+
+// The whole `-----BEGIN {label}----- .. -----END {label}-----` block.
+#[cfg(feature = "certificate")]
+fn pem_section<'a>(pem: &'a str, label: &str) -> &'a str {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    match (pem.find(&begin), pem.find(&end)) {
+        (Some(b), Some(e)) if b < e => &pem[b..e + end.len()],
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+    use hyper::StatusCode;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn form(request: &Request<String>) -> HashMap<String, String> {
+        url::form_urlencoded::parse(request.body().as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    fn respond(status: StatusCode, body: &str) -> Response<String> {
+        Response::builder()
+            .status(status)
+            .body(body.to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn client_secret_against_stand_in_authority() -> Result<(), Report> {
+        let cred = ClientSecretCredential::new("tenant", "app", "s3cret")
+            .with_authority_host("http://localhost:8400/");
+        let server = |req: Request<String>| -> Result<Response<String>, Report> {
+            assert_eq!(
+                "http://localhost:8400/tenant/oauth2/v2.0/token",
+                req.uri().to_string()
+            );
+            let form = form(&req);
+            assert_eq!("client_credentials", form["grant_type"]);
+            assert_eq!("s3cret", form["client_secret"]);
+            assert_eq!(SERVICE_BUS_SCOPE, form["scope"]);
+            Ok(respond(
+                StatusCode::OK,
+                r#"{"token_type":"Bearer","expires_in":3599,"access_token":"tok"}"#,
+            ))
+        };
+
+        let clock = Arc::new(ManualClock::at(1_700_000_000));
+        let provider = AadTokenProvider::new(cred, server).with_clock(clock);
+        assert_eq!("Bearer tok", provider.authorization()?);
+        assert_eq!(1_700_000_000 + 3599, provider.token()?.expires_on);
+        Ok(())
+    }
+
+    #[test]
+    fn managed_identity_uses_imds_shape() -> Result<(), Report> {
+        let cred = ManagedIdentityCredential::new()
+            .with_client_id("user-assigned")
+            .with_endpoint("http://127.0.0.1:9000/token");
+        let req = cred.token_request(SERVICE_BUS_SCOPE, &SystemClock)?;
+        assert_eq!("true", req.headers()["Metadata"]);
+        let query: HashMap<String, String> =
+            url::form_urlencoded::parse(req.uri().query().unwrap().as_bytes())
+                .into_owned()
+                .collect();
+        assert_eq!("https://servicebus.azure.net/", query["resource"]);
+        assert_eq!("user-assigned", query["client_id"]);
+
+        let (token, expires_on) = parse_token_response(
+            respond(
+                StatusCode::OK,
+                r#"{"access_token":"imds","expires_on":"1700000000","expires_in":"86399"}"#,
+            ),
+            &SystemClock,
+        )?;
+        assert_eq!(("imds", 1_700_000_000), (token.as_str(), expires_on));
+        Ok(())
+    }
+
+    #[test]
+    fn workload_identity_reads_the_token_file_each_time() -> Result<(), Report> {
+        let path = std::env::temp_dir().join(format!("sb-federated-{}", std::process::id()));
+        std::fs::write(&path, "first\n")?;
+        let cred = WorkloadIdentityCredential::new("tenant", "app", &path);
+        assert_eq!(
+            "first",
+            form(&cred.token_request(SERVICE_BUS_SCOPE, &SystemClock)?)["client_assertion"]
+        );
+        std::fs::write(&path, "rotated")?;
+        assert_eq!(
+            "rotated",
+            form(&cred.token_request(SERVICE_BUS_SCOPE, &SystemClock)?)["client_assertion"]
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn chain_falls_through_and_remembers_the_winner() -> Result<(), Report> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let server = move |req: Request<String>| -> Result<Response<String>, Report> {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if req.uri().host() == Some("169.254.169.254") {
+                return Ok(respond(
+                    StatusCode::OK,
+                    r#"{"access_token":"mi","expires_in":"60"}"#,
+                ));
+            }
+            Ok(respond(
+                StatusCode::UNAUTHORIZED,
+                r#"{"error":"invalid_client","error_description":"AADSTS7000215: bad secret"}"#,
+            ))
+        };
+        let provider = AadTokenProvider::chain(
+            vec![
+                Box::new(ClientSecretCredential::new("t", "c", "wrong")),
+                Box::new(ManagedIdentityCredential::new()),
+            ],
+            server,
+        );
+
+        assert_eq!("Bearer mi", provider.authorization()?);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        provider.invalidate();
+        provider.token()?;
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn errors_carry_the_aad_description() {
+        let provider = AadTokenProvider::new(
+            ClientSecretCredential::new("t", "c", "wrong"),
+            |_| -> Result<Response<String>, Report> {
+                Ok(respond(
+                    StatusCode::BAD_REQUEST,
+                    r#"{"error":"invalid_client","error_description":"AADSTS7000215: bad secret"}"#,
+                ))
+            },
+        );
+        let err = provider.token().unwrap_err().to_string();
+        assert!(
+            err.contains("client secret: Token request failed with 400"),
+            "{}",
+            err
+        );
+        assert!(err.contains("AADSTS7000215"), "{}", err);
+    }
+
+    #[cfg(feature = "certificate")]
+    #[test]
+    fn certificate_assertion_is_a_signed_jwt() -> Result<(), Report> {
+        use rsa::signature::Verifier;
+        use std::convert::TryFrom;
+
+        let key = rsa::RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024)?;
+        let public = rsa::pkcs1v15::VerifyingKey::<rsa::sha2::Sha256>::new(key.to_public_key());
+        let cred = ClientCertificateCredential::new("tenant", "app", key, b"not really a cert");
+
+        let clock = ManualClock::at(1_700_000_000);
+        let assertion =
+            form(&cred.token_request(SERVICE_BUS_SCOPE, &clock)?)["client_assertion"].clone();
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(3, parts.len());
+
+        let decode = |s: &str| base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&decode(parts[0]))?;
+        let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1]))?;
+        assert_eq!("RS256", header["alg"]);
+        assert_eq!("app", claims["iss"]);
+        assert_eq!(1_700_000_000, claims["nbf"]);
+        assert_eq!(1_700_000_600, claims["exp"]);
+        assert_eq!(
+            "https://login.microsoftonline.com/tenant/oauth2/v2.0/token",
+            claims["aud"]
+        );
+
+        let signature = rsa::pkcs1v15::Signature::try_from(decode(parts[2]).as_slice())?;
+        public.verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)?;
+        Ok(())
+    }
+}
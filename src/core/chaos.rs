@@ -0,0 +1,371 @@
+//! Failure injection for testing code that processes messages. Wrap the
+//! executor requests go through in a `FaultInjector` and it makes some of
+//! them fail the way Service Bus and the network do: `503 Server Busy`,
+//! expired locks, timeouts, connection resets, slow responses and messages
+//! that are delivered twice.
+//!
+//! Every decision comes from a random number generator seeded by the caller,
+//! so a run that uncovers a bug can be repeated by reusing its seed, as long
+//! as the same requests are made in the same order.
+//!
+//! ```
+//! # use azure_service_bus::core::chaos::{Fault, FaultInjector};
+//! # use azure_service_bus::core::exec::Executor;
+//! # use hyper::{Request, Response};
+//! # fn main() -> Result<(), eyre::Report> {
+//! let service = |_: Request<String>| -> Result<Response<String>, eyre::Report> {
+//!     Ok(Response::new(String::new()))
+//! };
+//! let exec = FaultInjector::new(service, 42).with(Fault::ServerBusy, 0.5);
+//! for _ in 0..10 {
+//!     let _ = exec.execute(Request::post("https://ns/orders/messages").body(String::new())?);
+//! }
+//! println!("{:?}", exec.injected());
+//! # Ok(())
+//! # }
+//! ```
+
+use super::exec::Executor;
+use super::lock;
+use eyre::Report;
+use hyper::{Method, Request, Response, StatusCode};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A failure `FaultInjector` can make a request run into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fault {
+    /// The connection is reset before the request is sent. Reported as an
+    /// `io::Error` of kind `ConnectionReset`.
+    ConnectionReset,
+    /// `503 Service Unavailable` without the request being sent, as a
+    /// throttled namespace answers.
+    ServerBusy,
+    /// The request is sent but its response never arrives, so the caller
+    /// can't tell whether it took effect. Reported as an `io::Error` of kind
+    /// `TimedOut`.
+    Timeout,
+    /// `404 Not Found` without the request being sent, as Service Bus answers
+    /// completing, abandoning or renewing a message whose lock has expired.
+    /// Only applies to those requests.
+    LockLost,
+    /// A message that was received is handed out again by the next receive
+    /// from the same entity, lock token and all. Only applies to receives.
+    DuplicateDelivery,
+}
+
+/// A fault that was injected, and the request it was injected into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Injection {
+    /// The method and path of the request, e.g. `DELETE /orders/messages/head`.
+    pub request: String,
+    pub fault: Fault,
+}
+
+/// Wraps an executor and injects faults into the requests passing through it.
+pub struct FaultInjector<E> {
+    inner: E,
+    faults: BTreeMap<Fault, f64>,
+    latency: Option<Range<Duration>>,
+    rng: Mutex<StdRng>,
+    // Received messages waiting to be delivered again, by entity path.
+    duplicates: Mutex<BTreeMap<String, Response<String>>>,
+    injected: Mutex<Vec<Injection>>,
+}
+
+impl<E: Executor> FaultInjector<E> {
+    /// Passes every request through untouched until faults are added with `with`.
+    pub fn new(inner: E, seed: u64) -> Self {
+        FaultInjector {
+            inner,
+            faults: BTreeMap::new(),
+            latency: None,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            duplicates: Mutex::new(BTreeMap::new()),
+            injected: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Injects `fault` into the requests it applies to with the given
+    /// probability, from 0 for never to 1 for always. A probability that
+    /// isn't a finite number counts as never. When several faults hit the
+    /// same request, the first in `Fault`'s declaration order wins.
+    pub fn with(mut self, fault: Fault, probability: f64) -> Self {
+        let probability = if probability.is_finite() {
+            probability.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.faults.insert(fault, probability);
+        self
+    }
+
+    /// Delays every request by a random time in `range` before it is sent.
+    pub fn with_latency(mut self, range: Range<Duration>) -> Self {
+        self.latency = Some(range);
+        self
+    }
+
+    /// Every fault injected so far, in order.
+    pub fn injected(&self) -> Vec<Injection> {
+        lock(&self.injected).clone()
+    }
+
+    fn record(&self, request: &Request<String>, fault: Fault) {
+        lock(&self.injected).push(Injection {
+            request: format!("{} {}", request.method(), request.uri().path()),
+            fault,
+        });
+    }
+
+    // Rolls for every configured fault, so each request takes the same
+    // number of draws whatever happens to it.
+    fn roll(&self, kind: Kind) -> (Option<Fault>, Duration) {
+        let mut rng = lock(&self.rng);
+        let mut hit = None;
+        for (fault, probability) in &self.faults {
+            let drawn = rng.gen_bool(*probability);
+            if drawn && hit.is_none() && applies(*fault, kind) {
+                hit = Some(*fault);
+            }
+        }
+        let delay = match &self.latency {
+            Some(range) if !range.is_empty() => rng.gen_range(range.clone()),
+            _ => Duration::default(),
+        };
+        (hit, delay)
+    }
+}
+
+impl<E: Executor> Executor for FaultInjector<E> {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        let kind = Kind::of(&request);
+        let (fault, delay) = self.roll(kind);
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+
+        let path = request.uri().path().to_string();
+        if kind == Kind::Receive {
+            if let Some(duplicate) = lock(&self.duplicates).remove(&path) {
+                self.record(&request, Fault::DuplicateDelivery);
+                return Ok(duplicate);
+            }
+        }
+
+        // A duplicate is recorded when it is delivered, not when it is kept.
+        if let Some(fault) = fault.filter(|f| *f != Fault::DuplicateDelivery) {
+            self.record(&request, fault);
+        }
+        match fault {
+            Some(Fault::ConnectionReset) => Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset (injected)",
+            )
+            .into()),
+            Some(Fault::ServerBusy) => status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(Fault::LockLost) => status(StatusCode::NOT_FOUND),
+            Some(Fault::Timeout) => {
+                self.inner.execute(request)?;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out (injected)").into())
+            }
+            Some(Fault::DuplicateDelivery) => {
+                let response = self.inner.execute(request)?;
+                if response.status().is_success() && response.status() != StatusCode::NO_CONTENT {
+                    lock(&self.duplicates).insert(path, copy(&response)?);
+                }
+                Ok(response)
+            }
+            None => self.inner.execute(request),
+        }
+    }
+}
+
+// What a request does, as far as which faults apply to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Receive,
+    Settle,
+    Other,
+}
+
+impl Kind {
+    fn of(request: &Request<String>) -> Kind {
+        let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+        let after_messages = segments
+            .iter()
+            .position(|s| s.eq_ignore_ascii_case("messages"))
+            .map(|i| &segments[i + 1..]);
+        match (request.method(), after_messages) {
+            (&Method::POST, Some([head])) | (&Method::DELETE, Some([head]))
+                if head.eq_ignore_ascii_case("head") =>
+            {
+                Kind::Receive
+            }
+            (_, Some([_, _])) => Kind::Settle,
+            _ => Kind::Other,
+        }
+    }
+}
+
+fn applies(fault: Fault, kind: Kind) -> bool {
+    match fault {
+        Fault::LockLost => kind == Kind::Settle,
+        Fault::DuplicateDelivery => kind == Kind::Receive,
+        _ => true,
+    }
+}
+
+fn status(status: StatusCode) -> Result<Response<String>, Report> {
+    Ok(Response::builder().status(status).body(String::new())?)
+}
+
+fn copy(response: &Response<String>) -> Result<Response<String>, Report> {
+    let mut copy = Response::builder()
+        .status(response.status())
+        .version(response.version())
+        .body(response.body().clone())?;
+    *copy.headers_mut() = response.headers().clone();
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::servicebus::brokeredmessage::BrokeredMessage;
+    use crate::servicebus::description::{Entity, QueueDescription};
+    use crate::QueueClient;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn ok(req: Request<String>) -> Result<Response<String>, Report> {
+        Ok(Response::new(req.into_body()))
+    }
+
+    fn request(method: Method, path: &str) -> Request<String> {
+        Request::builder()
+            .method(method)
+            .uri(format!("https://ns{}", path))
+            .body(String::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn the_same_seed_injects_the_same_faults() {
+        let run = |seed| {
+            let exec = FaultInjector::new(ok, seed)
+                .with(Fault::ServerBusy, 0.3)
+                .with(Fault::Timeout, 0.2)
+                .with(Fault::LockLost, 0.5);
+            let outcomes: Vec<Option<StatusCode>> = (0..50)
+                .map(|i| {
+                    let req = if i % 2 == 0 {
+                        request(Method::POST, "/orders/messages")
+                    } else {
+                        request(Method::DELETE, "/orders/messages/7/token")
+                    };
+                    exec.execute(req).ok().map(|r| r.status())
+                })
+                .collect();
+            (outcomes, exec.injected())
+        };
+        let (outcomes, injected) = run(7);
+        assert_eq!((outcomes.clone(), injected.clone()), run(7));
+        assert_ne!(injected, run(8).1);
+
+        // Lock loss only hits settles.
+        assert!(injected
+            .iter()
+            .filter(|i| i.fault == Fault::LockLost)
+            .all(|i| i.request == "DELETE /orders/messages/7/token"));
+        assert!(outcomes.contains(&Some(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(outcomes.contains(&None));
+    }
+
+    #[test]
+    fn probabilities_that_are_not_numbers_never_fire() {
+        let exec = FaultInjector::new(ok, 7)
+            .with(Fault::ServerBusy, f64::NAN)
+            .with(Fault::Timeout, f64::INFINITY);
+        for _ in 0..10 {
+            assert!(exec
+                .execute(request(Method::POST, "/orders/messages"))
+                .is_ok());
+        }
+        assert!(exec.injected().is_empty());
+    }
+
+    #[test]
+    fn faults_surface_like_the_real_thing() {
+        let sent = AtomicUsize::new(0);
+        let counting = |req: Request<String>| {
+            sent.fetch_add(1, Ordering::SeqCst);
+            ok(req)
+        };
+
+        let reset = FaultInjector::new(&counting, 1).with(Fault::ConnectionReset, 1.0);
+        let err = reset
+            .execute(request(Method::POST, "/orders/messages"))
+            .unwrap_err();
+        let io = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io::ErrorKind::ConnectionReset, io.kind());
+        assert_eq!(0, sent.load(Ordering::SeqCst));
+
+        // A timed out request still reached the service.
+        let timeout = FaultInjector::new(&counting, 1).with(Fault::Timeout, 1.0);
+        let err = timeout
+            .execute(request(Method::POST, "/orders/messages"))
+            .unwrap_err();
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            err.downcast_ref::<io::Error>().unwrap().kind()
+        );
+        assert_eq!(1, sent.load(Ordering::SeqCst));
+
+        let slow = FaultInjector::new(&counting, 1)
+            .with_latency(Duration::from_millis(20)..Duration::from_millis(30));
+        let started = std::time::Instant::now();
+        slow.execute(request(Method::GET, "/orders")).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert!(slow.injected().is_empty());
+    }
+
+    #[test]
+    fn duplicate_deliveries_repeat_a_received_message() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .start()?;
+        let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+        let exec = FaultInjector::new(emulator.executor(), 3).with(Fault::DuplicateDelivery, 1.0);
+        for body in &["a", "b"] {
+            exec.execute(queue.send(BrokeredMessage::with_body(body))?)?;
+        }
+
+        let mut bodies = Vec::new();
+        for _ in 0..3 {
+            let response = exec.execute(queue.receive()?.map(|()| String::new()))?;
+            bodies.push(BrokeredMessage::with_response(response).into_body());
+        }
+        assert_eq!(
+            vec![
+                "<string>a</string>",
+                "<string>a</string>",
+                "<string>b</string>"
+            ],
+            bodies
+        );
+        assert_eq!(
+            vec![Injection {
+                request: "POST /orders/messages/head".to_string(),
+                fault: Fault::DuplicateDelivery,
+            }],
+            exec.injected()
+        );
+        Ok(())
+    }
+}
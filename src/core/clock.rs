@@ -0,0 +1,112 @@
+//! Where the crate gets the time from.
+//!
+//! Token expiry, message locks and scheduled messages all depend on what time
+//! it is. Everything that asks takes a `Clock`, which is the system clock
+//! unless a test or a skew correction says otherwise.
+//!
+//! ```
+//! # use azure_service_bus::core::clock::{Clock, ManualClock};
+//! # use azure_service_bus::core::token::{SasKeyProvider, TokenProvider};
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! let clock = Arc::new(ManualClock::at(1_700_000_000));
+//! let provider = SasKeyProvider::new("sb://ns.servicebus.windows.net/", "send", "key")
+//!     .with_clock(clock.clone());
+//! assert_eq!(1_700_000_360, provider.token().unwrap().expires_on);
+//!
+//! // Tokens are signed again once they get close to expiring.
+//! clock.advance(Duration::from_secs(350));
+//! assert_eq!(1_700_000_710, provider.token().unwrap().expires_on);
+//! ```
+
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// The current time in whole seconds since the unix epoch.
+    fn unix_now(&self) -> u64 {
+        unix_time(self.now())
+    }
+}
+
+/// The clock of the machine the code runs on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when it is told to, for tests.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    /// Starts the clock at the given unix time.
+    pub fn at(unix_time: u64) -> Self {
+        ManualClock {
+            now: Mutex::new(UNIX_EPOCH + Duration::from_secs(unix_time)),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.lock() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SystemTime> {
+        super::lock(&self.now)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.lock()
+    }
+}
+
+pub(crate) fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Formats a time the way http `Date` headers and the `EnqueuedTimeUtc`,
+/// `LockedUntilUtc` and `ScheduledEnqueueTimeUtc` broker properties are
+/// written, e.g. `Wed, 05 Dec 2012 10:52:48 GMT`.
+pub fn http_date(t: SystemTime) -> String {
+    time::OffsetDateTime::from_unix_timestamp(unix_time(t) as i64).format(HTTP_DATE)
+}
+
+/// Reads a time written by `http_date`.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let t = time::PrimitiveDateTime::parse(s.trim(), HTTP_DATE).ok()?;
+    let secs = u64::try_from(t.assume_utc().unix_timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_dates_round_trip() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_354_704_768);
+        assert_eq!("Wed, 05 Dec 2012 10:52:48 GMT", http_date(t));
+        assert_eq!(Some(t), parse_http_date("Wed, 05 Dec 2012 10:52:48 GMT"));
+        assert_eq!(None, parse_http_date("tomorrow"));
+    }
+}
@@ -0,0 +1,331 @@
+use super::clock::{Clock, SystemClock};
+use super::error::ConnectionStringError;
+use hyper::Uri;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use zeroize::Zeroize;
+
+/// How a client would like to talk to the namespace. This crate only speaks
+/// HTTPS, but the value is kept so connection strings round trip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportType {
+    Amqp,
+    AmqpWebSockets,
+}
+
+impl Display for TransportType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TransportType::Amqp => "Amqp",
+            TransportType::AmqpWebSockets => "AmqpWebSockets",
+        })
+    }
+}
+
+impl FromStr for TransportType {
+    type Err = ConnectionStringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "amqp" => Ok(TransportType::Amqp),
+            "amqpwebsockets" => Ok(TransportType::AmqpWebSockets),
+            _ => Err(ConnectionStringError::InvalidValue {
+                key: "TransportType",
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// A parsed Service Bus connection string, as copied from the azure portal:
+///
+/// `Endpoint=sb://{namespace}.servicebus.windows.net/;SharedAccessKeyName={name};SharedAccessKey={key}`
+///
+/// Keys are matched case insensitively and unknown keys are ignored. A
+/// connection string has to carry either a key name and key, or a
+/// pre-issued `SharedAccessSignature`. The `Debug` output hides both.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConnectionString {
+    endpoint: String,
+    uri: Uri,
+    pub shared_access_key_name: Option<String>,
+    pub shared_access_key: Option<String>,
+    pub shared_access_signature: Option<String>,
+    pub entity_path: Option<String>,
+    pub transport_type: Option<TransportType>,
+    pub use_development_emulator: bool,
+}
+
+impl ConnectionString {
+    /// The `Endpoint` exactly as it was written, e.g. `sb://ns.servicebus.windows.net/`.
+    /// This is the resource SAS tokens for the namespace are signed for.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// The url REST requests are sent to. The `sb` scheme becomes `https`,
+    /// or `http` when talking to a development emulator.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Builds the `Authorization` header value for this namespace and the unix
+    /// time it expires at. Keys are signed for `duration` from now, a
+    /// pre-issued `SharedAccessSignature` is used as is with its own expiry.
+    pub fn generate_sas(&self, duration: Duration) -> (String, usize) {
+        self.generate_sas_with_clock(duration, &SystemClock)
+    }
+
+    /// `generate_sas`, with now according to `clock`.
+    pub fn generate_sas_with_clock(
+        &self,
+        duration: Duration,
+        clock: &dyn Clock,
+    ) -> (String, usize) {
+        match (&self.shared_access_key_name, &self.shared_access_key) {
+            (Some(name), Some(key)) => {
+                super::sign(&self.endpoint, name, key, clock.unix_now(), duration)
+            }
+            _ => {
+                let sas = self.shared_access_signature.clone().unwrap_or_default();
+                let expiry = super::token::sas_expiry(&sas).map_or(usize::MAX, |se| se as usize);
+                (sas, expiry)
+            }
+        }
+    }
+
+    fn parse_endpoint(endpoint: &str, emulator: bool) -> Result<Uri, ConnectionStringError> {
+        let invalid = || ConnectionStringError::InvalidEndpoint(endpoint.to_string());
+        let uri: Uri = endpoint.parse().map_err(|_| invalid())?;
+        let scheme = match uri.scheme_str() {
+            Some("sb") | Some("https") | Some("http") if emulator => "http",
+            Some("sb") | Some("https") => "https",
+            _ => return Err(invalid()),
+        };
+        let authority = uri.authority().ok_or_else(invalid)?;
+
+        Uri::builder()
+            .scheme(scheme)
+            .authority(authority.as_str())
+            .path_and_query("/")
+            .build()
+            .map_err(|_| invalid())
+    }
+}
+
+impl Drop for ConnectionString {
+    fn drop(&mut self) {
+        self.shared_access_key.zeroize();
+        self.shared_access_signature.zeroize();
+    }
+}
+
+impl FromStr for ConnectionString {
+    type Err = ConnectionStringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ConnectionStringError::*;
+
+        let mut endpoint = None;
+        let mut key_name = None;
+        let mut key = None;
+        let mut signature = None;
+        let mut entity_path = None;
+        let mut transport = None;
+        let mut emulator = None;
+
+        for segment in s.split(';').map(str::trim).filter(|seg| !seg.is_empty()) {
+            // Split at the first '=' only, base64 keys end with padding.
+            let idx = segment
+                .find('=')
+                .ok_or_else(|| MalformedSegment(segment.to_string()))?;
+            let (k, v) = (segment[..idx].trim(), segment[idx + 1..].trim());
+
+            let (name, slot): (&'static str, &mut Option<&str>) =
+                match k.to_ascii_lowercase().as_str() {
+                    "endpoint" => ("Endpoint", &mut endpoint),
+                    "sharedaccesskeyname" => ("SharedAccessKeyName", &mut key_name),
+                    "sharedaccesskey" => ("SharedAccessKey", &mut key),
+                    "sharedaccesssignature" => ("SharedAccessSignature", &mut signature),
+                    "entitypath" => ("EntityPath", &mut entity_path),
+                    "transporttype" => ("TransportType", &mut transport),
+                    "usedevelopmentemulator" => ("UseDevelopmentEmulator", &mut emulator),
+                    _ => continue,
+                };
+            if slot.is_some() {
+                return Err(DuplicateKey(name));
+            }
+            *slot = Some(v);
+        }
+
+        let use_development_emulator = match emulator {
+            None => false,
+            Some(v) if v.eq_ignore_ascii_case("true") => true,
+            Some(v) if v.eq_ignore_ascii_case("false") => false,
+            Some(v) => {
+                return Err(InvalidValue {
+                    key: "UseDevelopmentEmulator",
+                    value: v.to_string(),
+                })
+            }
+        };
+
+        let endpoint = endpoint
+            .filter(|e| !e.is_empty())
+            .ok_or(MissingKey("Endpoint"))?;
+        let uri = Self::parse_endpoint(endpoint, use_development_emulator)?;
+
+        match (key_name, key, signature) {
+            (Some(_), Some(_), Some(_)) => return Err(ConflictingCredentials),
+            (Some(_), Some(_), None) | (None, None, Some(_)) => {}
+            (None, Some(_), _) => return Err(MissingKey("SharedAccessKeyName")),
+            (Some(_), None, _) => return Err(MissingKey("SharedAccessKey")),
+            (None, None, None) => return Err(MissingKey("SharedAccessKey")),
+        }
+
+        Ok(ConnectionString {
+            endpoint: endpoint.to_string(),
+            uri,
+            shared_access_key_name: key_name.map(str::to_string),
+            shared_access_key: key.map(str::to_string),
+            shared_access_signature: signature.map(str::to_string),
+            entity_path: entity_path.filter(|p| !p.is_empty()).map(str::to_string),
+            transport_type: transport.map(str::parse).transpose()?,
+            use_development_emulator,
+        })
+    }
+}
+
+impl Display for ConnectionString {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Endpoint={}", self.endpoint)?;
+        if let Some(name) = &self.shared_access_key_name {
+            write!(f, ";SharedAccessKeyName={}", name)?;
+        }
+        if let Some(key) = &self.shared_access_key {
+            write!(f, ";SharedAccessKey={}", key)?;
+        }
+        if let Some(sas) = &self.shared_access_signature {
+            write!(f, ";SharedAccessSignature={}", sas)?;
+        }
+        if let Some(path) = &self.entity_path {
+            write!(f, ";EntityPath={}", path)?;
+        }
+        if let Some(transport) = &self.transport_type {
+            write!(f, ";TransportType={}", transport)?;
+        }
+        if self.use_development_emulator {
+            f.write_str(";UseDevelopmentEmulator=true")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for ConnectionString {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let redacted = |v: &Option<String>| v.as_ref().map(|_| "<redacted>");
+        f.debug_struct("ConnectionString")
+            .field("endpoint", &self.endpoint)
+            .field("shared_access_key_name", &self.shared_access_key_name)
+            .field("shared_access_key", &redacted(&self.shared_access_key))
+            .field(
+                "shared_access_signature",
+                &redacted(&self.shared_access_signature),
+            )
+            .field("entity_path", &self.entity_path)
+            .field("transport_type", &self.transport_type)
+            .field("use_development_emulator", &self.use_development_emulator)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONN: &str = "Endpoint=sb://test.servicebus.windows.net/;\
+                        SharedAccessKeyName=RootManageSharedAccessKey;SharedAccessKey=c2VjcmV0a2V5PQ==";
+
+    #[test]
+    fn parses_portal_connection_string() {
+        let conn: ConnectionString = CONN.parse().unwrap();
+        assert_eq!("sb://test.servicebus.windows.net/", conn.endpoint());
+        assert_eq!(
+            "https://test.servicebus.windows.net/",
+            conn.uri().to_string()
+        );
+        assert_eq!(Some("c2VjcmV0a2V5PQ=="), conn.shared_access_key.as_deref());
+        assert_eq!(CONN, conn.to_string());
+    }
+
+    #[test]
+    fn optional_keys() {
+        let conn: ConnectionString = format!(
+            "{};entitypath=orders;TransportType=AmqpWebSockets;Unknown=1;",
+            CONN
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(Some("orders"), conn.entity_path.as_deref());
+        assert_eq!(Some(TransportType::AmqpWebSockets), conn.transport_type);
+
+        let emulator: ConnectionString =
+            "Endpoint=sb://localhost:5300;SharedAccessSignature=sig;UseDevelopmentEmulator=true"
+                .parse()
+                .unwrap();
+        assert!(emulator.use_development_emulator);
+        assert_eq!("http://localhost:5300/", emulator.uri().to_string());
+    }
+
+    #[test]
+    fn errors() {
+        use ConnectionStringError::*;
+        let parse = |s: &str| s.parse::<ConnectionString>().unwrap_err();
+        assert_eq!(MissingKey("Endpoint"), parse("SharedAccessSignature=x"));
+        assert_eq!(
+            DuplicateKey("SharedAccessKey"),
+            parse(&format!("{};SharedAccessKey=other", CONN))
+        );
+        assert_eq!(
+            MissingKey("SharedAccessKey"),
+            parse("Endpoint=sb://a.b/;SharedAccessKeyName=x")
+        );
+        assert_eq!(
+            ConflictingCredentials,
+            parse(&format!("{};SharedAccessSignature=sig", CONN))
+        );
+        assert_eq!(
+            MalformedSegment("garbage".to_string()),
+            parse(&format!("{};garbage", CONN))
+        );
+        assert_eq!(
+            InvalidEndpoint("ftp://a.b/".to_string()),
+            parse("Endpoint=ftp://a.b/;SharedAccessSignature=x")
+        );
+    }
+
+    #[test]
+    fn pre_issued_signature_keeps_its_expiry() {
+        let sas = "SharedAccessSignature sr=x&sig=abc&se=1700000000&skn=send";
+        let conn: ConnectionString = format!("Endpoint=sb://a.b/;SharedAccessSignature={}", sas)
+            .parse()
+            .unwrap();
+        assert_eq!(
+            (sas.to_string(), 1_700_000_000),
+            conn.generate_sas(Duration::from_secs(60))
+        );
+
+        let conn: ConnectionString = CONN.parse().unwrap();
+        let (token, _) = conn.generate_sas(Duration::from_secs(60));
+        assert!(token.contains("skn=RootManageSharedAccessKey"));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let conn: ConnectionString = CONN.parse().unwrap();
+        let debug = format!("{:?}", conn);
+        assert!(!debug.contains("c2VjcmV0a2V5PQ=="));
+        assert!(debug.contains("RootManageSharedAccessKey"));
+    }
+}
@@ -21,8 +21,8 @@ impl Error for AzureRequestError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         use self::AzureRequestError::*;
         match self {
-            &UnknownError(ref e) => Some(e.as_ref()),
-            &HyperError(ref e) => Some(e),
+            UnknownError(e) => Some(e.as_ref()),
+            HyperError(e) => Some(e),
             _ => None,
         }
     }
@@ -32,23 +32,23 @@ impl Display for AzureRequestError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::AzureRequestError::*;
         let s = match self {
-            &BadRequest => "Remote returned code 400.",
-            &AuthorizationFailure => "Remote returned 401. Check your connection string.",
-            &ResourceFailure => {
+            BadRequest => "Remote returned code 400.",
+            AuthorizationFailure => "Remote returned 401. Check your connection string.",
+            ResourceFailure => {
                 "Message failed to send. The message may be too large or the queue is full."
             }
-            &ResourceNotFound => "The requested queue does not exist or could not be found.",
-            &InternalError => "Remote returned 500 - Internal server error",
-            &UnknownError(_) => "Something unexpected happened",
-            &HyperError(_) => "Hyper had an issue making a web request",
-            &LocalMessage => {
+            ResourceNotFound => "The requested queue does not exist or could not be found.",
+            InternalError => "Remote returned 500 - Internal server error",
+            UnknownError(_) => "Something unexpected happened",
+            HyperError(_) => "Hyper had an issue making a web request",
+            LocalMessage => {
                 "The message doesn't exist on the server. This happens when you try and \
                  delete/lock a message you created locally."
             }
-            &EmptyBus => {
+            EmptyBus => {
                 "Service Bus Queue/Subscription didn't have any messages before receive timed out."
             }
-            &NonSerializedBody => {
+            NonSerializedBody => {
                 "Parsing the body failed. This happens if the message sender doesn't serialize the \
                  message. Call message.get_body_raw() to extract the body."
             }
@@ -63,3 +63,67 @@ impl From<hyper::Error> for AzureRequestError {
         AzureRequestError::HyperError(err)
     }
 }
+
+/// Reasons a connection string can be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStringError {
+    MissingKey(&'static str),   // A required key wasn't there.
+    DuplicateKey(&'static str), // A key showed up more than once.
+    MalformedSegment(String),   // A segment without an '='.
+    InvalidEndpoint(String),    // The Endpoint isn't an sb:// or https:// url.
+    InvalidValue { key: &'static str, value: String },
+    ConflictingCredentials, // Both a key and a SharedAccessSignature.
+}
+
+impl Error for ConnectionStringError {}
+
+impl Display for ConnectionStringError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::ConnectionStringError::*;
+        match self {
+            MissingKey(k) => write!(f, "The connection string is missing {}.", k),
+            DuplicateKey(k) => write!(f, "{} appears more than once in the connection string.", k),
+            MalformedSegment(s) => write!(f, "Connection string segment '{}' is not key=value.", s),
+            InvalidEndpoint(e) => {
+                write!(f, "Endpoint '{}' is not a valid sb:// or https:// url.", e)
+            }
+            InvalidValue { key, value } => write!(f, "'{}' is not a valid {}.", value, key),
+            ConflictingCredentials => f.write_str(
+                "The connection string has both a SharedAccessKey and a SharedAccessSignature.",
+            ),
+        }
+    }
+}
+
+/// Reasons a shared access signature can be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SasError {
+    Malformed(String),              // Not `SharedAccessSignature k=v&k=v..`.
+    MissingParameter(&'static str), // One of sig, se, skn or sr is missing.
+    Expired(u64),                   // The token expired at this unix time.
+    InvalidSignature,               // The signature doesn't match the key.
+    ResourceNotCovered(String),     // The token is scoped to something else.
+}
+
+impl Error for SasError {}
+
+impl Display for SasError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::SasError::*;
+        match self {
+            Malformed(s) => write!(f, "'{}' is not a shared access signature.", s),
+            MissingParameter(p) => write!(f, "The shared access signature has no {}.", p),
+            Expired(se) => write!(f, "The shared access signature expired at {}.", se),
+            InvalidSignature => {
+                f.write_str("The shared access signature was not signed with this key.")
+            }
+            ResourceNotCovered(r) => {
+                write!(
+                    f,
+                    "The shared access signature does not grant access to {}.",
+                    r
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use super::clock::{parse_http_date, unix_time};
+use super::token::TokenProvider;
+use eyre::Report;
+use hyper::header::{AUTHORIZATION, DATE};
+use hyper::{Request, Response, StatusCode};
+use std::sync::Arc;
+
+/// The clients in this crate only build requests, they never send them.
+/// Anything that needs to make more than one round trip on its own (paging
+/// through a feed, for example) is handed an `Executor` that puts a request on
+/// the wire and hands back the response with its body read into a string.
+///
+/// Any closure with the right signature is an executor, so wiring in an http
+/// client is usually a one liner:
+///
+/// ```no_run
+/// # use std::convert::TryInto;
+/// # use hyper::{Request, Response};
+/// let client = reqwest::blocking::Client::new();
+/// let exec = move |req: Request<String>| -> Result<Response<String>, eyre::Report> {
+///     let resp = client.execute(req.try_into()?)?;
+///     let mut builder = Response::builder().status(resp.status().as_u16());
+///     for (name, value) in resp.headers() {
+///         builder = builder.header(name.as_str(), value.as_bytes());
+///     }
+///     Ok(builder.body(resp.text()?)?)
+/// };
+/// ```
+pub trait Executor {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report>;
+}
+
+impl<F> Executor for F
+where
+    F: Fn(Request<String>) -> Result<Response<String>, Report>,
+{
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        self(request)
+    }
+}
+
+/// Wraps an executor and sends a request again when it comes back
+/// `401 Unauthorized` and the token provider has another key to offer, so
+/// rotating a key doesn't fail requests that are in flight.
+///
+/// When the service says the token had expired, the provider is told what
+/// time the service's `Date` header gave instead, so a provider that signs
+/// its own tokens can correct for the local clock being off and try again.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use hyper::{Request, Response};
+/// # use azure_service_bus::core::{exec::RetryUnauthorized, rotation::KeyPairSasProvider};
+/// # use azure_service_bus::QueueClient;
+/// # fn send(_: Request<String>) -> Result<Response<String>, eyre::Report> { unimplemented!() }
+/// # fn main() -> Result<(), eyre::Report> {
+/// # let (primary, secondary) = ("", "");
+/// let keys = Arc::new(KeyPairSasProvider::from_connection_strings(primary, secondary)?);
+/// let queue = QueueClient::with_token_provider(
+///     "https://my-namespace.servicebus.windows.net/".parse()?,
+///     "orders",
+///     keys.clone(),
+/// );
+/// let exec = RetryUnauthorized::new(send, keys);
+/// # Ok(())
+/// # }
+/// ```
+pub struct RetryUnauthorized<E> {
+    inner: E,
+    provider: Arc<dyn TokenProvider>,
+    max_retries: usize,
+}
+
+impl<E: Executor> RetryUnauthorized<E> {
+    pub fn new(inner: E, provider: Arc<dyn TokenProvider>) -> Self {
+        RetryUnauthorized {
+            inner,
+            provider,
+            max_retries: 1,
+        }
+    }
+
+    /// How many times one request may be re-signed and sent again. The
+    /// default of one is enough to move from one key to the other.
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+}
+
+impl<E: Executor> Executor for RetryUnauthorized<E> {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        let (mut parts, body) = request.into_parts();
+        let mut attempt = 0;
+        loop {
+            let mut request = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version)
+                .body(body.clone())?;
+            *request.headers_mut() = parts.headers.clone();
+
+            let response = self.inner.execute(request)?;
+            if response.status() != StatusCode::UNAUTHORIZED || attempt >= self.max_retries {
+                return Ok(response);
+            }
+            let retry = match server_time_if_expired(&response) {
+                Some(server_time) => self.provider.on_expired(server_time),
+                None => parts
+                    .headers
+                    .get(AUTHORIZATION)
+                    .is_some_and(|rejected| self.provider.on_unauthorized(rejected)),
+            };
+            if !retry {
+                return Ok(response);
+            }
+            parts
+                .headers
+                .insert(AUTHORIZATION, self.provider.authorization()?);
+            attempt += 1;
+        }
+    }
+}
+
+// Service Bus explains a 401 in the body, e.g. `<Detail>ExpiredToken: The
+// token is expired. ..</Detail>`. For those, the time the service thinks it is.
+fn server_time_if_expired(response: &Response<String>) -> Option<u64> {
+    let body = response.body();
+    if !body.contains("ExpiredToken") && !body.contains("token is expired") {
+        return None;
+    }
+    let date = response.headers().get(DATE)?.to_str().ok()?;
+    parse_http_date(date).map(unix_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::rotation::KeyPairSasProvider;
+    use crate::core::rotation::KeyType;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn unauthorized_requests_are_resigned_with_the_other_key() -> Result<(), Report> {
+        let keys = Arc::new(KeyPairSasProvider::new(
+            "sb://ns/", "root", "revoked", "good",
+        ));
+        let good = crate::core::sas::SasToken::builder("sb://ns/").sign("root", "good");
+        let attempts = AtomicUsize::new(0);
+        let service = |req: Request<String>| -> Result<Response<String>, Report> {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            let auth = req.headers()[AUTHORIZATION].to_str()?;
+            let token: crate::core::sas::SasToken = auth.parse()?;
+            let status = match crate::core::sas::verify(auth, "good", token.expiry() - 1) {
+                Ok(_) => StatusCode::CREATED,
+                Err(_) => StatusCode::UNAUTHORIZED,
+            };
+            assert_eq!(good.resource(), token.resource());
+            Ok(Response::builder().status(status).body(req.into_body())?)
+        };
+
+        let exec = RetryUnauthorized::new(&service, keys.clone());
+        let request = Request::post("https://ns/orders/messages")
+            .header(AUTHORIZATION, keys.authorization()?)
+            .body("hello".to_string())?;
+        let response = exec.execute(request)?;
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!("hello", response.body());
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_eq!(KeyType::Secondary, keys.active_key());
+        Ok(())
+    }
+
+    #[test]
+    fn expired_tokens_correct_for_clock_skew() -> Result<(), Report> {
+        use crate::core::clock::{http_date, ManualClock};
+        use crate::core::token::SasKeyProvider;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        // The local clock is two hours behind the service's.
+        let server_now = 1_700_007_200;
+        let provider = Arc::new(
+            SasKeyProvider::new("sb://ns/", "root", "key")
+                .with_clock(Arc::new(ManualClock::at(1_700_000_000))),
+        );
+        let attempts = AtomicUsize::new(0);
+        let service = |req: Request<String>| -> Result<Response<String>, Report> {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            let auth = req.headers()[AUTHORIZATION].to_str()?;
+            let (status, body) = match crate::core::sas::verify(auth, "key", server_now) {
+                Ok(_) => (StatusCode::CREATED, ""),
+                Err(_) => (
+                    StatusCode::UNAUTHORIZED,
+                    "<Error><Code>401</Code><Detail>ExpiredToken: The token is expired.</Detail></Error>",
+                ),
+            };
+            let date = http_date(UNIX_EPOCH + Duration::from_secs(server_now));
+            Ok(Response::builder()
+                .status(status)
+                .header(DATE, date)
+                .body(body.to_string())?)
+        };
+
+        let exec = RetryUnauthorized::new(&service, provider.clone());
+        let request = Request::post("https://ns/orders/messages")
+            .header(AUTHORIZATION, provider.authorization()?)
+            .body(String::new())?;
+        assert_eq!(StatusCode::CREATED, exec.execute(request)?.status());
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_eq!(server_now + 360, provider.token()?.expires_on);
+
+        // Once the clocks agree, another expired answer isn't retried.
+        assert!(!provider.on_expired(server_now));
+        Ok(())
+    }
+}
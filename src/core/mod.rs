@@ -1,9 +1,13 @@
+pub mod aad;
+pub mod chaos;
+pub mod clock;
+pub mod connection_string;
 pub mod error;
-
-use crypto::hmac::Hmac;
-use crypto::mac::Mac;
-use crypto::sha2::*;
-use percent_encoding::utf8_percent_encode;
+pub mod exec;
+pub mod rotation;
+pub mod sas;
+pub mod secret;
+pub mod token;
 
 // space, double quote ("), hash (#), inequality qualifiers (<), (>), backtick (`), question mark (?),
 // and curly brackets ({), (}), forward slash (/), colon (:), semi-colon (;), equality
@@ -34,49 +38,38 @@ const CUSTOM_ENCODE_SET: percent_encoding::AsciiSet =
 
 /// This function generates an SAS token for authenticating into azure
 /// using the connection string provided on portal.azure.com.
-/// It will not raise an error if there is a mistake in the connection string,
-/// but the token will be invalid.
-pub fn generate_sas(connection_string: &str, duration: std::time::Duration) -> (String, usize) {
-    let mut key = "";
-    let mut endpoint = "";
-    let mut name = "";
+/// Returns the token and the unix time it expires at, or an error if the
+/// connection string can't be parsed. Use `sas::SasToken` to pick the
+/// resource and expiry yourself.
+pub fn generate_sas(
+    connection_string: &str,
+    duration: std::time::Duration,
+) -> Result<(String, usize), error::ConnectionStringError> {
+    let conn: connection_string::ConnectionString = connection_string.parse()?;
+    Ok(conn.generate_sas(duration))
+}
 
-    let params = connection_string.split(";");
-    for param in params {
-        let idx = param.find("=").unwrap_or(0);
-        let (mut k, mut value) = param.split_at(idx);
-        k = k.trim();
-        value = value.trim();
-        // cut out the equal sign if there was one.
-        if value.len() > 0 {
-            value = &value[1..]
-        }
-        match k {
-            "Endpoint" => endpoint = value,
-            "SharedAccessKey" => key = value,
-            "SharedAccessKeyName" => name = value,
-            _ => {}
-        };
+// Locks `mutex`, carrying on with the data if another thread panicked while
+// holding it. Everything kept behind these mutexes stays consistent between
+// statements, so a panic elsewhere is no reason to fail here too.
+pub(crate) fn lock<T>(mutex: &std::sync::Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poison) => poison.into_inner(),
     }
+}
 
-    let mut h = Hmac::new(Sha256::new(), key.as_bytes());
-
-    let encoded_url = utf8_percent_encode(endpoint, &USERINFO_ENCODE_SET).collect::<String>();
-    let expiry = (std::time::SystemTime::now() + duration)
-        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        .unwrap_or(std::time::Duration::from_secs(0))
-        .as_secs();
-
-    let message = format!("{}\n{}", encoded_url, expiry);
-    h.input(message.as_bytes());
-
-    let mut sig = base64::encode(h.result().code());
-    sig = utf8_percent_encode(&sig, &CUSTOM_ENCODE_SET).collect::<String>();
-
-    let sas = format!(
-        "SharedAccessSignature sig={}&se={}&skn={}&sr={}",
-        sig, expiry, name, encoded_url
-    );
-
-    (sas, expiry as usize)
+// Signs `endpoint` with the named key, valid for `duration` from `now`.
+pub(crate) fn sign(
+    endpoint: &str,
+    name: &str,
+    key: &str,
+    now: u64,
+    duration: std::time::Duration,
+) -> (String, usize) {
+    let token = sas::SasToken::builder(endpoint)
+        .issued_at(now)
+        .valid_for(duration)
+        .sign(name, key);
+    (token.to_string(), token.expiry() as usize)
 }
@@ -0,0 +1,372 @@
+//! Token providers that survive key rotation.
+//!
+//! Rotating a shared access key invalidates every token signed with it. These
+//! providers keep a second key to fall back on, either the other key of the
+//! rule or whatever is in a key file now, and switch over when the service
+//! starts answering 401. Pair them with `exec::RetryUnauthorized` so the
+//! rejected request is sent again with the new key.
+
+use super::clock::Clock;
+use super::connection_string::ConnectionString;
+use super::lock;
+use super::token::{
+    AccessToken, TokenCache, TokenProvider, DEFAULT_LIFETIME, DEFAULT_REFRESH_MARGIN,
+};
+use eyre::{eyre, Report};
+use hyper::header::HeaderValue;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use zeroize::Zeroizing;
+
+/// Which of a rule's two keys to use or regenerate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Primary,
+    Secondary,
+}
+
+fn sign(resource: &str, key_name: &str, key: &str, now: u64, lifetime: Duration) -> AccessToken {
+    let (header, expiry) = super::sign(resource, key_name, key, now, lifetime);
+    AccessToken {
+        header,
+        expires_on: expiry as u64,
+    }
+}
+
+/// Signs with the primary key of a rule and moves to the secondary key when
+/// the primary is rejected, and back again if the secondary is rejected later.
+pub struct KeyPairSasProvider {
+    resource: String,
+    key_name: String,
+    primary: Zeroizing<String>,
+    secondary: Zeroizing<String>,
+    active: Mutex<KeyType>,
+    // Held while a rejection is handled, so two requests rejected with the
+    // same token switch keys once. Taken before the cache, never with `active`.
+    switching: Mutex<()>,
+    lifetime: Duration,
+    cache: TokenCache,
+}
+
+impl KeyPairSasProvider {
+    pub fn new(resource: &str, key_name: &str, primary: &str, secondary: &str) -> Self {
+        KeyPairSasProvider {
+            resource: resource.to_string(),
+            key_name: key_name.to_string(),
+            primary: Zeroizing::new(primary.to_string()),
+            secondary: Zeroizing::new(secondary.to_string()),
+            active: Mutex::new(KeyType::Primary),
+            switching: Mutex::new(()),
+            lifetime: DEFAULT_LIFETIME,
+            cache: TokenCache::new(DEFAULT_REFRESH_MARGIN),
+        }
+    }
+
+    /// Builds the provider from the primary and secondary connection strings
+    /// the portal shows for a rule.
+    pub fn from_connection_strings(primary: &str, secondary: &str) -> Result<Self, Report> {
+        let primary: ConnectionString = primary.parse()?;
+        let secondary: ConnectionString = secondary.parse()?;
+        if primary.endpoint() != secondary.endpoint()
+            || primary.shared_access_key_name != secondary.shared_access_key_name
+        {
+            return Err(eyre!(
+                "The connection strings are for different namespaces or rules."
+            ));
+        }
+        match (
+            &primary.shared_access_key_name,
+            &primary.shared_access_key,
+            &secondary.shared_access_key,
+        ) {
+            (Some(name), Some(p), Some(s)) => Ok(Self::new(primary.endpoint(), name, p, s)),
+            _ => Err(eyre!("Both connection strings need a SharedAccessKey.")),
+        }
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.cache = self.cache.with_margin(margin);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock);
+        self
+    }
+
+    /// The key tokens are currently signed with.
+    pub fn active_key(&self) -> KeyType {
+        *lock(&self.active)
+    }
+}
+
+impl TokenProvider for KeyPairSasProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        self.cache.get_or_refresh(|| {
+            let key = match self.active_key() {
+                KeyType::Primary => &self.primary,
+                KeyType::Secondary => &self.secondary,
+            };
+            Ok(sign(
+                &self.resource,
+                &self.key_name,
+                key,
+                self.cache.now(),
+                self.lifetime,
+            ))
+        })
+    }
+
+    fn invalidate(&self) {
+        self.cache.clear()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.cache.correct_skew(server_time)
+    }
+
+    fn on_unauthorized(&self, rejected: &HeaderValue) -> bool {
+        // `token` locks `active` while holding the cache, so `active` is never
+        // held here while the cache is touched.
+        let _switching = lock(&self.switching);
+        // Another request may already have switched keys, in which case the
+        // current token is different and worth a try.
+        let stale = match self.cache.current() {
+            Some(current) => current.header.as_bytes() == rejected.as_bytes(),
+            None => true,
+        };
+        if stale {
+            {
+                let mut active = lock(&self.active);
+                *active = match *active {
+                    KeyType::Primary => KeyType::Secondary,
+                    KeyType::Secondary => KeyType::Primary,
+                };
+            }
+            self.cache.clear();
+        }
+        true
+    }
+}
+
+struct LoadedKey {
+    modified: Option<SystemTime>,
+    key_name: String,
+    key: Zeroizing<String>,
+}
+
+/// Signs with a key read from a file, such as a mounted Kubernetes secret,
+/// and reads the file again whenever it changes or a token is rejected.
+///
+/// The file holds either the bare key, used with the key name given here, or
+/// a whole connection string, whose key name wins.
+pub struct KeyFileSasProvider {
+    resource: String,
+    path: PathBuf,
+    loaded: Mutex<LoadedKey>,
+    lifetime: Duration,
+    cache: TokenCache,
+}
+
+impl KeyFileSasProvider {
+    pub fn new(resource: &str, key_name: &str, path: impl AsRef<Path>) -> Result<Self, Report> {
+        let provider = KeyFileSasProvider {
+            resource: resource.to_string(),
+            path: path.as_ref().to_path_buf(),
+            loaded: Mutex::new(LoadedKey {
+                modified: None,
+                key_name: key_name.to_string(),
+                key: Zeroizing::new(String::new()),
+            }),
+            lifetime: DEFAULT_LIFETIME,
+            cache: TokenCache::new(DEFAULT_REFRESH_MARGIN),
+        };
+        provider.reload(true)?;
+        Ok(provider)
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.cache = self.cache.with_margin(margin);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock);
+        self
+    }
+
+    /// The key name tokens are currently signed with.
+    pub fn key_name(&self) -> String {
+        lock(&self.loaded).key_name.clone()
+    }
+
+    /// When the key in use was written to the file.
+    pub fn key_modified(&self) -> Option<SystemTime> {
+        lock(&self.loaded).modified
+    }
+
+    // Reads the file if it changed since last time, or always when `force`d.
+    // Returns whether the key is different now.
+    fn reload(&self, force: bool) -> Result<bool, Report> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        let mut loaded = lock(&self.loaded);
+        if !force && modified.is_some() && modified == loaded.modified {
+            return Ok(false);
+        }
+
+        let contents = Zeroizing::new(std::fs::read_to_string(&self.path)?);
+        let contents = contents.trim();
+        let (key_name, key) = if contents.contains('=') && contents.contains(';') {
+            let conn: ConnectionString = contents.parse()?;
+            match (&conn.shared_access_key_name, &conn.shared_access_key) {
+                (Some(name), Some(key)) => (name.clone(), Zeroizing::new(key.clone())),
+                _ => return Err(eyre!("The key file has no SharedAccessKey.")),
+            }
+        } else {
+            (
+                loaded.key_name.clone(),
+                Zeroizing::new(contents.to_string()),
+            )
+        };
+        if key.is_empty() {
+            return Err(eyre!("The key file {} is empty.", self.path.display()));
+        }
+
+        let changed = key != loaded.key || key_name != loaded.key_name;
+        *loaded = LoadedKey {
+            modified,
+            key_name,
+            key,
+        };
+        Ok(changed)
+    }
+}
+
+impl TokenProvider for KeyFileSasProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        if self.reload(false)? {
+            self.cache.clear();
+        }
+        self.cache.get_or_refresh(|| {
+            let loaded = lock(&self.loaded);
+            Ok(sign(
+                &self.resource,
+                &loaded.key_name,
+                &loaded.key,
+                self.cache.now(),
+                self.lifetime,
+            ))
+        })
+    }
+
+    fn invalidate(&self) {
+        self.cache.clear()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.cache.correct_skew(server_time)
+    }
+
+    fn on_unauthorized(&self, rejected: &HeaderValue) -> bool {
+        match self.reload(true) {
+            Ok(true) => {
+                self.cache.clear();
+                true
+            }
+            // Same key as before, unless another request got here first.
+            Ok(false) => self
+                .cache
+                .current()
+                .is_some_and(|t| t.header.as_bytes() != rejected.as_bytes()),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_fails_over_once_per_rejected_token() {
+        let provider = KeyPairSasProvider::new("sb://ns/", "root", "old", "new");
+        let first = provider.authorization().unwrap();
+        assert_eq!(KeyType::Primary, provider.active_key());
+
+        assert!(provider.on_unauthorized(&first));
+        assert_eq!(KeyType::Secondary, provider.active_key());
+        let second = provider.authorization().unwrap();
+        assert_ne!(first, second);
+
+        // A second request that was signed with the old key must not flip back.
+        assert!(provider.on_unauthorized(&first));
+        assert_eq!(KeyType::Secondary, provider.active_key());
+        assert_eq!(second, provider.authorization().unwrap());
+    }
+
+    #[test]
+    fn pair_refreshes_and_fails_over_concurrently() {
+        let provider = Arc::new(KeyPairSasProvider::new("sb://ns/", "root", "old", "new"));
+        let (done, finished) = std::sync::mpsc::channel();
+        for _ in 0..4 {
+            let provider = provider.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10_000 {
+                    let token = provider.authorization().unwrap();
+                    provider.on_unauthorized(&token);
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("token and on_unauthorized deadlocked");
+        }
+    }
+
+    #[test]
+    fn pair_from_portal_connection_strings() {
+        let conn = |key: &str| {
+            format!(
+                "Endpoint=sb://ns.servicebus.windows.net/;SharedAccessKeyName=root;SharedAccessKey={}",
+                key
+            )
+        };
+        assert!(KeyPairSasProvider::from_connection_strings(&conn("a"), &conn("b")).is_ok());
+        let other = "Endpoint=sb://other.servicebus.windows.net/;SharedAccessKeyName=root;SharedAccessKey=b";
+        assert!(KeyPairSasProvider::from_connection_strings(&conn("a"), other).is_err());
+    }
+
+    #[test]
+    fn key_file_is_reloaded_on_rejection() -> Result<(), Report> {
+        let path = std::env::temp_dir().join(format!("sb-key-{}", std::process::id()));
+        std::fs::write(&path, "first-key\n")?;
+        let provider = KeyFileSasProvider::new("sb://ns/", "root", &path)?;
+        let first = provider.authorization()?;
+
+        assert!(!provider.on_unauthorized(&first));
+        std::fs::write(
+            &path,
+            "Endpoint=sb://ns/;SharedAccessKeyName=rotated;SharedAccessKey=second-key",
+        )?;
+        assert!(provider.on_unauthorized(&first));
+        assert_eq!("rotated", provider.key_name());
+        assert_ne!(first, provider.authorization()?);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use super::error::SasError;
+use super::token::unix_now;
+use super::{CUSTOM_ENCODE_SET, USERINFO_ENCODE_SET};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+const PREFIX: &str = "SharedAccessSignature";
+
+/// A shared access signature, as sent in the `Authorization` header:
+///
+/// `SharedAccessSignature sig={signature}&se={expiry}&skn={key name}&sr={resource}`
+///
+/// A token grants access to its resource and everything below it, so a token
+/// for `sb://ns.servicebus.windows.net/` works for the whole namespace while
+/// one for `sb://ns.servicebus.windows.net/orders` only works for that queue.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SasToken {
+    // Kept exactly as they were signed or received, the signature covers the
+    // encoded resource so re-encoding could change it.
+    encoded_resource: String,
+    signature: String,
+    key_name: String,
+    expiry: u64,
+}
+
+impl SasToken {
+    /// Starts a token for `resource`, e.g. a namespace endpoint or the url of
+    /// a single queue.
+    pub fn builder(resource: &str) -> SasTokenBuilder {
+        SasTokenBuilder {
+            resource: resource.to_string(),
+            expiry: Expiry::In(super::token::DEFAULT_LIFETIME),
+            issued_at: None,
+        }
+    }
+
+    /// The resource the token is scoped to.
+    pub fn resource(&self) -> String {
+        percent_decode_str(&self.encoded_resource)
+            .decode_utf8_lossy()
+            .into_owned()
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// The unix time the token stops being accepted.
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expiry
+    }
+
+    /// Whether the token's resource is `uri` or a parent of it. The scheme
+    /// is ignored, since tokens are scoped to `sb://` urls but requests are
+    /// sent over `https://`.
+    pub fn grants(&self, uri: &str) -> bool {
+        let strip = |s: &str| -> String {
+            let s = s.split_once("://").map_or(s, |(_, rest)| rest);
+            s.trim_end_matches('/').to_ascii_lowercase()
+        };
+        let (scope, target) = (strip(&self.resource()), strip(uri));
+        target == scope || (target.starts_with(&scope) && target[scope.len()..].starts_with('/'))
+    }
+
+    fn expected_signature(&self, key: &str) -> String {
+        let mut h = Hmac::new(Sha256::new(), key.as_bytes());
+        h.input(format!("{}\n{}", self.encoded_resource, self.expiry).as_bytes());
+        base64::encode(h.result().code())
+    }
+}
+
+impl FromStr for SasToken {
+    type Err = SasError;
+
+    /// Parses a token with or without the `SharedAccessSignature ` prefix.
+    /// Parameters may come in any order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let params = s.trim().strip_prefix(PREFIX).unwrap_or(s).trim();
+        let (mut sig, mut se, mut skn, mut sr) = (None, None, None, None);
+        for param in params.split('&').filter(|p| !p.is_empty()) {
+            let (k, v) = param
+                .split_once('=')
+                .ok_or_else(|| SasError::Malformed(s.to_string()))?;
+            match k {
+                "sig" => sig = Some(v),
+                "se" => se = Some(v),
+                "skn" => skn = Some(v),
+                "sr" => sr = Some(v),
+                _ => {}
+            }
+        }
+
+        let decode = |v: &str| percent_decode_str(v).decode_utf8_lossy().into_owned();
+        let se = se.ok_or(SasError::MissingParameter("se"))?;
+        Ok(SasToken {
+            encoded_resource: sr.ok_or(SasError::MissingParameter("sr"))?.to_string(),
+            signature: decode(sig.ok_or(SasError::MissingParameter("sig"))?),
+            key_name: decode(skn.ok_or(SasError::MissingParameter("skn"))?),
+            expiry: se.parse().map_err(|_| SasError::Malformed(s.to_string()))?,
+        })
+    }
+}
+
+impl Display for SasToken {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} sig={}&se={}&skn={}&sr={}",
+            PREFIX,
+            utf8_percent_encode(&self.signature, &CUSTOM_ENCODE_SET),
+            self.expiry,
+            self.key_name,
+            self.encoded_resource
+        )
+    }
+}
+
+impl Debug for SasToken {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SasToken")
+            .field("resource", &self.resource())
+            .field("key_name", &self.key_name)
+            .field("expiry", &self.expiry)
+            .field("signature", &"<redacted>")
+            .finish()
+    }
+}
+
+enum Expiry {
+    In(Duration),
+    At(u64),
+}
+
+/// Configures and signs a `SasToken`.
+pub struct SasTokenBuilder {
+    resource: String,
+    expiry: Expiry,
+    issued_at: Option<u64>,
+}
+
+impl SasTokenBuilder {
+    /// The token expires `lifetime` after it is signed. The default is six minutes.
+    pub fn valid_for(mut self, lifetime: Duration) -> Self {
+        self.expiry = Expiry::In(lifetime);
+        self
+    }
+
+    /// The token expires at the given unix time.
+    pub fn expires_at(mut self, unix_time: u64) -> Self {
+        self.expiry = Expiry::At(unix_time);
+        self
+    }
+
+    /// Counts `valid_for` from the given unix time rather than from the
+    /// system clock, e.g. `clock.unix_now()`.
+    pub fn issued_at(mut self, unix_time: u64) -> Self {
+        self.issued_at = Some(unix_time);
+        self
+    }
+
+    /// Signs the token with the shared access rule `key_name` and its key.
+    pub fn sign(self, key_name: &str, key: &str) -> SasToken {
+        let expiry = match self.expiry {
+            Expiry::In(lifetime) => self.issued_at.unwrap_or_else(unix_now) + lifetime.as_secs(),
+            Expiry::At(at) => at,
+        };
+        let mut token = SasToken {
+            encoded_resource: utf8_percent_encode(&self.resource, &USERINFO_ENCODE_SET).to_string(),
+            signature: String::new(),
+            key_name: key_name.to_string(),
+            expiry,
+        };
+        token.signature = token.expected_signature(key);
+        token
+    }
+}
+
+/// Checks that `token` was signed with `key` and has not expired at `now`,
+/// returning the parsed token so the caller can check its `key_name`. Use
+/// `verify_for` to check what it grants as well.
+pub fn verify(token: &str, key: &str, now: u64) -> Result<SasToken, SasError> {
+    let token: SasToken = token.parse()?;
+    let expected = token.expected_signature(key);
+    if !fixed_time_eq(expected.as_bytes(), token.signature.as_bytes()) {
+        return Err(SasError::InvalidSignature);
+    }
+    if token.is_expired(now) {
+        return Err(SasError::Expired(token.expiry));
+    }
+    Ok(token)
+}
+
+/// Like `verify`, but also checks that the token `grants` access to
+/// `resource`, the url that was called.
+pub fn verify_for(token: &str, key: &str, now: u64, resource: &str) -> Result<SasToken, SasError> {
+    let token = verify(token, key, now)?;
+    if !token.grants(resource) {
+        return Err(SasError::ResourceNotCovered(resource.to_string()));
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "c2VjcmV0a2V5PQ==";
+
+    #[test]
+    fn sign_parse_round_trip() {
+        let token = SasToken::builder("sb://ns.servicebus.windows.net/orders")
+            .expires_at(1_700_000_000)
+            .sign("send-only", KEY);
+        let header = token.to_string();
+        assert!(header.starts_with("SharedAccessSignature sig="));
+        assert!(header.contains("&sr=sb%3A%2F%2Fns.servicebus.windows.net%2Forders"));
+
+        let parsed: SasToken = header.parse().unwrap();
+        assert_eq!(token, parsed);
+        assert_eq!("sb://ns.servicebus.windows.net/orders", parsed.resource());
+        assert_eq!("send-only", parsed.key_name());
+        assert_eq!(1_700_000_000, parsed.expiry());
+    }
+
+    #[test]
+    fn matches_the_legacy_signer() {
+        let (header, expiry) = super::super::sign(
+            "sb://ns/",
+            "root",
+            KEY,
+            1_700_000_000,
+            Duration::from_secs(60),
+        );
+        assert_eq!(1_700_000_060, expiry);
+        let token = SasToken::builder("sb://ns/")
+            .expires_at(expiry as u64)
+            .sign("root", KEY);
+        assert_eq!(header, token.to_string());
+    }
+
+    #[test]
+    fn verify_checks_key_and_expiry() {
+        let header = SasToken::builder("sb://ns/")
+            .expires_at(100)
+            .sign("root", KEY)
+            .to_string();
+        assert!(verify(&header, KEY, 99).is_ok());
+        assert_eq!(Err(SasError::Expired(100)), verify(&header, KEY, 100));
+        assert_eq!(
+            Err(SasError::InvalidSignature),
+            verify(&header, "other", 99)
+        );
+
+        let tampered = header.replace("se=100", "se=200");
+        assert_eq!(Err(SasError::InvalidSignature), verify(&tampered, KEY, 99));
+        assert_eq!(
+            Err(SasError::MissingParameter("sig")),
+            verify("SharedAccessSignature se=1&skn=a&sr=b", KEY, 0)
+        );
+    }
+
+    #[test]
+    fn verify_for_checks_the_resource() {
+        let header = SasToken::builder("sb://ns/orders")
+            .expires_at(100)
+            .sign("root", KEY)
+            .to_string();
+        assert!(verify_for(&header, KEY, 99, "https://ns/orders/messages").is_ok());
+        assert_eq!(
+            Err(SasError::ResourceNotCovered("ns/payments".to_string())),
+            verify_for(&header, KEY, 99, "ns/payments")
+        );
+        assert_eq!(
+            Err(SasError::Expired(100)),
+            verify_for(&header, KEY, 100, "ns/payments")
+        );
+    }
+
+    #[test]
+    fn scope_covers_children_only() {
+        let token = SasToken::builder("sb://ns.servicebus.windows.net/orders").sign("send", KEY);
+        assert!(token.grants("https://ns.servicebus.windows.net/orders/messages"));
+        assert!(token.grants("https://NS.servicebus.windows.net/orders"));
+        assert!(!token.grants("https://ns.servicebus.windows.net/orders-archive"));
+        assert!(!token.grants("https://ns.servicebus.windows.net/"));
+    }
+}
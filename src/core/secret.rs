@@ -0,0 +1,340 @@
+//! Where connection strings come from.
+//!
+//! Rather than handing every client a connection string, a `SecretSource`
+//! says where to read it from: an environment variable, a file such as a
+//! mounted Kubernetes secret, or the output of a command like a vault cli.
+//! `SecretTokenProvider` reads the source again every so often, so a key that
+//! is rotated outside the process is picked up without a restart.
+
+use super::connection_string::ConnectionString;
+use super::token::{AccessToken, TokenProvider};
+use eyre::{eyre, Report};
+use hyper::header::HeaderValue;
+use hyper::Uri;
+use std::ffi::OsString;
+use std::fmt::{self, Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// How often `SecretTokenProvider` reads its source unless configured otherwise.
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A piece of key material. The memory is wiped when it is dropped and the
+/// `Debug` output never shows it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+/// Somewhere a secret can be read from, as often as needed.
+pub trait SecretSource: Send + Sync {
+    fn load(&self) -> Result<Secret, Report>;
+
+    /// Where the secret comes from, for error messages. Never the secret itself.
+    fn describe(&self) -> String;
+}
+
+/// Reads an environment variable.
+#[derive(Clone, Debug)]
+pub struct EnvSecret {
+    var: String,
+}
+
+impl EnvSecret {
+    pub fn new(var: &str) -> Self {
+        EnvSecret {
+            var: var.to_string(),
+        }
+    }
+}
+
+impl SecretSource for EnvSecret {
+    fn load(&self) -> Result<Secret, Report> {
+        let value = Zeroizing::new(
+            std::env::var(&self.var).map_err(|_| eyre!("{} is not set.", self.describe()))?,
+        );
+        Ok(Secret::new(value.trim().to_string()))
+    }
+
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+}
+
+/// Reads a whole file, without surrounding whitespace.
+#[derive(Clone, Debug)]
+pub struct FileSecret {
+    path: PathBuf,
+}
+
+impl FileSecret {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        FileSecret {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl SecretSource for FileSecret {
+    fn load(&self) -> Result<Secret, Report> {
+        let contents = Zeroizing::new(
+            std::fs::read_to_string(&self.path)
+                .map_err(|e| eyre!("Reading {}: {}", self.describe(), e))?,
+        );
+        Ok(Secret::new(contents.trim().to_string()))
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+/// Runs a command and takes what it prints, e.g.
+/// `az keyvault secret show --vault-name v --name sb --query value -o tsv`.
+#[derive(Clone, Debug)]
+pub struct CommandSecret {
+    program: OsString,
+    args: Vec<OsString>,
+}
+
+impl CommandSecret {
+    pub fn new<I, A>(program: impl Into<OsString>, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<OsString>,
+    {
+        CommandSecret {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl SecretSource for CommandSecret {
+    fn load(&self) -> Result<Secret, Report> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .output()
+            .map_err(|e| eyre!("Running {}: {}", self.describe(), e))?;
+        let stdout = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            return Err(eyre!(
+                "{} exited with {}: {}",
+                self.describe(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let value = std::str::from_utf8(&stdout)
+            .map_err(|_| eyre!("{} printed something that isn't utf-8.", self.describe()))?;
+        Ok(Secret::new(value.trim().to_string()))
+    }
+
+    fn describe(&self) -> String {
+        format!("command {}", self.program.to_string_lossy())
+    }
+}
+
+struct Loaded {
+    secret: Secret,
+    at: Instant,
+    uri: Uri,
+    provider: Box<dyn TokenProvider>,
+}
+
+/// Signs requests with a connection string read from a `SecretSource`.
+///
+/// The source is read again once `reload_interval` has passed and whenever
+/// the service rejects a token. If the connection string changed, tokens are
+/// signed with the new one from then on.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use azure_service_bus::core::secret::{FileSecret, SecretTokenProvider};
+/// # use azure_service_bus::QueueClient;
+/// # fn main() -> Result<(), eyre::Report> {
+/// let provider = SecretTokenProvider::new(FileSecret::new("/var/run/secrets/servicebus"))?;
+/// let queue = QueueClient::with_token_provider(provider.uri(), "orders", Arc::new(provider));
+/// # Ok(())
+/// # }
+/// ```
+pub struct SecretTokenProvider {
+    source: Box<dyn SecretSource>,
+    reload_interval: Duration,
+    loaded: Mutex<Loaded>,
+}
+
+impl SecretTokenProvider {
+    /// Reads the source once up front so a missing or malformed secret is
+    /// reported straight away.
+    pub fn new<S: SecretSource + 'static>(source: S) -> Result<Self, Report> {
+        let loaded = Self::load(&source)?;
+        Ok(SecretTokenProvider {
+            source: Box::new(source),
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    /// The https url of the namespace the connection string points at.
+    pub fn uri(&self) -> Uri {
+        self.lock().uri.clone()
+    }
+
+    /// Reads the source now. Returns whether the connection string changed.
+    pub fn reload(&self) -> Result<bool, Report> {
+        let mut loaded = self.lock();
+        self.reload_locked(&mut loaded)
+    }
+
+    fn reload_locked(&self, loaded: &mut Loaded) -> Result<bool, Report> {
+        let secret = self.source.load()?;
+        if secret == loaded.secret {
+            loaded.at = Instant::now();
+            return Ok(false);
+        }
+        *loaded = Self::load_secret(secret, self.source.as_ref())?;
+        Ok(true)
+    }
+
+    fn load(source: &dyn SecretSource) -> Result<Loaded, Report> {
+        Self::load_secret(source.load()?, source)
+    }
+
+    fn load_secret(secret: Secret, source: &dyn SecretSource) -> Result<Loaded, Report> {
+        let conn: ConnectionString = secret.expose().parse().map_err(|e| {
+            eyre!(
+                "The connection string in {} is invalid: {}",
+                source.describe(),
+                e
+            )
+        })?;
+        Ok(Loaded {
+            uri: conn.uri().clone(),
+            provider: conn.token_provider(),
+            secret,
+            at: Instant::now(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Loaded> {
+        super::lock(&self.loaded)
+    }
+}
+
+impl TokenProvider for SecretTokenProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        let mut loaded = self.lock();
+        if loaded.at.elapsed() >= self.reload_interval {
+            // Keep signing with what we have if the source is briefly unreadable.
+            let _ = self.reload_locked(&mut loaded);
+        }
+        loaded.provider.token()
+    }
+
+    fn invalidate(&self) {
+        self.lock().provider.invalidate()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.lock().provider.on_expired(server_time)
+    }
+
+    fn on_unauthorized(&self, rejected: &HeaderValue) -> bool {
+        let mut loaded = self.lock();
+        match self.reload_locked(&mut loaded) {
+            Ok(true) => true,
+            _ => loaded.provider.on_unauthorized(rejected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(key: &str) -> String {
+        format!(
+            "Endpoint=sb://ns.servicebus.windows.net/;SharedAccessKeyName=root;SharedAccessKey={}",
+            key
+        )
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let secret = Secret::new("hunter2".to_string());
+        assert_eq!("Secret(<redacted>)", format!("{:?}", secret));
+        assert_eq!("hunter2", secret.expose());
+    }
+
+    #[test]
+    fn env_and_command_sources() -> Result<(), Report> {
+        // Tests run in parallel, so no other test may use these variables.
+        std::env::set_var("SB_SECRET_ENV_SOURCE_TEST", " from-env \n");
+        assert_eq!(
+            "from-env",
+            EnvSecret::new("SB_SECRET_ENV_SOURCE_TEST").load()?.expose()
+        );
+        assert!(EnvSecret::new("SB_SECRET_ENV_SOURCE_TEST_UNSET")
+            .load()
+            .is_err());
+
+        assert_eq!(
+            "from-command",
+            CommandSecret::new("echo", ["from-command"])
+                .load()?
+                .expose()
+        );
+        let failing = CommandSecret::new("sh", ["-c", "echo denied >&2; exit 3"]).load();
+        assert!(failing.unwrap_err().to_string().contains("denied"));
+        Ok(())
+    }
+
+    #[test]
+    fn provider_picks_up_rotated_file() -> Result<(), Report> {
+        let path = std::env::temp_dir().join(format!("sb-secret-{}", std::process::id()));
+        std::fs::write(&path, conn("old"))?;
+        let provider = SecretTokenProvider::new(FileSecret::new(&path))?
+            .with_reload_interval(Duration::from_secs(3600));
+        assert_eq!(
+            "https://ns.servicebus.windows.net/",
+            provider.uri().to_string()
+        );
+        let old = provider.authorization()?;
+
+        std::fs::write(&path, conn("new"))?;
+        // Not due for a reload yet.
+        assert_eq!(old, provider.authorization()?);
+        // A rejected token forces one.
+        assert!(provider.on_unauthorized(&old));
+        assert_ne!(old, provider.authorization()?);
+        assert!(!provider.reload()?);
+
+        std::fs::write(&path, "not a connection string")?;
+        assert!(provider.reload().is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
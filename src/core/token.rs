@@ -0,0 +1,411 @@
+use super::clock::{Clock, SystemClock};
+use super::connection_string::ConnectionString;
+use eyre::{eyre, Report};
+use hyper::header::HeaderValue;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// How long generated SAS tokens are valid for unless configured otherwise.
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 6);
+
+/// How long before expiry a cached token is replaced unless configured otherwise.
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(15);
+
+/// A credential along with the unix time it stops being valid.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    /// The full value of the `Authorization` header,
+    /// e.g. `SharedAccessSignature sig=..` or `Bearer ey..`.
+    pub header: String,
+    pub expires_on: u64,
+}
+
+impl std::fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("header", &"<redacted>")
+            .field("expires_on", &self.expires_on)
+            .finish()
+    }
+}
+
+/// Supplies the `Authorization` header for every request a client builds.
+///
+/// Clients hold their provider in an `Arc`, so one provider can be shared by
+/// any number of queue, subscription and namespace clients and the token is
+/// only refreshed once for all of them.
+///
+/// Anything that can produce an `AccessToken` can be a provider, a closure
+/// included:
+///
+/// ```
+/// # use azure_service_bus::core::token::{AccessToken, TokenProvider};
+/// let provider = || -> Result<AccessToken, eyre::Report> {
+///     Ok(AccessToken { header: "Bearer from-somewhere".to_string(), expires_on: u64::MAX })
+/// };
+/// assert_eq!("Bearer from-somewhere", provider.authorization().unwrap());
+/// ```
+pub trait TokenProvider: Send + Sync {
+    /// Returns a token that is valid for at least a little while longer.
+    fn token(&self) -> Result<AccessToken, Report>;
+
+    /// Forgets any cached token so the next call to `token` gets a new one.
+    fn invalidate(&self) {}
+
+    /// Called when the service answered 401 to a request sent with the
+    /// `rejected` header. Providers that have another key to fall back on
+    /// switch to it. Returns whether the request is worth sending again with
+    /// a fresh `authorization`.
+    fn on_unauthorized(&self, rejected: &HeaderValue) -> bool {
+        let _ = rejected;
+        false
+    }
+
+    /// Called when the service answered 401 because the token had expired,
+    /// with the unix time the service's `Date` header says it is. Providers
+    /// that work out expiry themselves use it to make up for the local clock
+    /// being off. Returns whether a fresh `authorization` is worth a try.
+    fn on_expired(&self, server_time: u64) -> bool {
+        let _ = server_time;
+        false
+    }
+
+    /// The current token as a header value.
+    fn authorization(&self) -> Result<HeaderValue, Report> {
+        Ok(HeaderValue::from_str(&self.token()?.header)?)
+    }
+}
+
+impl<F> TokenProvider for F
+where
+    F: Fn() -> Result<AccessToken, Report> + Send + Sync,
+{
+    fn token(&self) -> Result<AccessToken, Report> {
+        self()
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemClock.unix_now()
+}
+
+// The `se` parameter of a `SharedAccessSignature ..` token.
+pub(crate) fn sas_expiry(token: &str) -> Option<u64> {
+    token
+        .trim_start_matches("SharedAccessSignature")
+        .trim()
+        .split('&')
+        .find_map(|param| param.strip_prefix("se="))
+        .and_then(|se| se.parse().ok())
+}
+
+/// Holds on to the last token until it is within `margin` of expiring.
+pub(crate) struct TokenCache {
+    margin: Duration,
+    clock: Arc<dyn Clock>,
+    // How many seconds the service's clock is ahead of `clock`, as learnt
+    // from a token it said had expired.
+    skew: AtomicI64,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl TokenCache {
+    pub(crate) fn new(margin: Duration) -> Self {
+        TokenCache {
+            margin,
+            clock: Arc::new(SystemClock),
+            skew: AtomicI64::new(0),
+            token: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn with_margin(self, margin: Duration) -> Self {
+        TokenCache { margin, ..self }
+    }
+
+    pub(crate) fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        TokenCache { clock, ..self }
+    }
+
+    /// The time according to the service, as far as we know it.
+    pub(crate) fn now(&self) -> u64 {
+        let now = self.clock.unix_now() as i64 + self.skew.load(Ordering::Relaxed);
+        now.max(0) as u64
+    }
+
+    /// Lines `now` up with the service's clock and forgets the cached token.
+    /// Returns whether that moved `now` by more than the second `Date`
+    /// headers are rounded to, i.e. whether a new token could fare better.
+    pub(crate) fn correct_skew(&self, server_time: u64) -> bool {
+        let skew = server_time as i64 - self.clock.unix_now() as i64;
+        let previous = self.skew.swap(skew, Ordering::Relaxed);
+        self.clear();
+        (skew - previous).abs() > 1
+    }
+
+    pub(crate) fn get_or_refresh<F>(&self, refresh: F) -> Result<AccessToken, Report>
+    where
+        F: FnOnce() -> Result<AccessToken, Report>,
+    {
+        let mut cached = super::lock(&self.token);
+        let now = self.now();
+        match &*cached {
+            Some(token) if now + self.margin.as_secs() < token.expires_on => Ok(token.clone()),
+            _ => {
+                let token = refresh()?;
+                *cached = Some(token.clone());
+                Ok(token)
+            }
+        }
+    }
+
+    /// The cached token, if there is one, without refreshing it.
+    pub(crate) fn current(&self) -> Option<AccessToken> {
+        super::lock(&self.token).clone()
+    }
+
+    pub(crate) fn clear(&self) {
+        *super::lock(&self.token) = None;
+    }
+}
+
+/// Signs SAS tokens locally with a shared access key.
+pub struct SasKeyProvider {
+    resource: String,
+    key_name: String,
+    key: Zeroizing<String>,
+    lifetime: Duration,
+    cache: TokenCache,
+}
+
+impl SasKeyProvider {
+    /// Signs tokens for `resource`, usually the namespace endpoint such as
+    /// `sb://ns.servicebus.windows.net/`.
+    pub fn new(resource: &str, key_name: &str, key: &str) -> Self {
+        SasKeyProvider {
+            resource: resource.to_string(),
+            key_name: key_name.to_string(),
+            key: Zeroizing::new(key.to_string()),
+            lifetime: DEFAULT_LIFETIME,
+            cache: TokenCache::new(DEFAULT_REFRESH_MARGIN),
+        }
+    }
+
+    /// How long each generated token is valid for.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// How long before expiry a new token is generated.
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.cache = self.cache.with_margin(margin);
+        self
+    }
+
+    /// Where the time tokens are signed at comes from.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock);
+        self
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+}
+
+impl TokenProvider for SasKeyProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        self.cache.get_or_refresh(|| {
+            let (header, expiry) = super::sign(
+                &self.resource,
+                &self.key_name,
+                &self.key,
+                self.cache.now(),
+                self.lifetime,
+            );
+            Ok(AccessToken {
+                header,
+                expires_on: expiry as u64,
+            })
+        })
+    }
+
+    fn invalidate(&self) {
+        self.cache.clear()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.cache.correct_skew(server_time)
+    }
+}
+
+/// A SAS token issued by someone else, used until it expires.
+pub struct StaticSasProvider {
+    token: AccessToken,
+    clock: Arc<dyn Clock>,
+}
+
+impl StaticSasProvider {
+    /// Takes a `SharedAccessSignature sr=..&sig=..&se=..&skn=..` token.
+    /// The expiry is read from its `se` parameter.
+    pub fn new(token: &str) -> Self {
+        let expires_on = sas_expiry(token).unwrap_or(u64::MAX);
+        StaticSasProvider {
+            token: AccessToken {
+                header: token.to_string(),
+                expires_on,
+            },
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// What the token's expiry is checked against.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl TokenProvider for StaticSasProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        if self.clock.unix_now() >= self.token.expires_on {
+            return Err(eyre!("The shared access signature has expired."));
+        }
+        Ok(self.token.clone())
+    }
+}
+
+type Fetch = Box<dyn Fn() -> Result<(String, u64), Report> + Send + Sync>;
+
+/// Sends `Authorization: Bearer` tokens fetched from somewhere else, for
+/// example Azure Active Directory. `fetch` returns the raw access token and
+/// the unix time it expires at, and is only called when the cached token is
+/// about to expire.
+pub struct BearerTokenProvider {
+    fetch: Fetch,
+    cache: TokenCache,
+}
+
+impl BearerTokenProvider {
+    pub fn new<F>(fetch: F) -> Self
+    where
+        F: Fn() -> Result<(String, u64), Report> + Send + Sync + 'static,
+    {
+        BearerTokenProvider {
+            fetch: Box::new(fetch),
+            cache: TokenCache::new(DEFAULT_REFRESH_MARGIN),
+        }
+    }
+
+    /// How long before expiry a new token is fetched.
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.cache = self.cache.with_margin(margin);
+        self
+    }
+
+    /// What expiry times are compared against.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock);
+        self
+    }
+}
+
+impl TokenProvider for BearerTokenProvider {
+    fn token(&self) -> Result<AccessToken, Report> {
+        self.cache.get_or_refresh(|| {
+            let (token, expires_on) = (self.fetch)()?;
+            Ok(AccessToken {
+                header: format!("Bearer {}", token),
+                expires_on,
+            })
+        })
+    }
+
+    fn invalidate(&self) {
+        self.cache.clear()
+    }
+
+    fn on_expired(&self, server_time: u64) -> bool {
+        self.cache.correct_skew(server_time)
+    }
+}
+
+impl ConnectionString {
+    /// The provider the clients use for this connection string: a
+    /// `SasKeyProvider` when it has a key, otherwise a `StaticSasProvider`.
+    pub fn token_provider(&self) -> Box<dyn TokenProvider> {
+        match (&self.shared_access_key_name, &self.shared_access_key) {
+            (Some(name), Some(key)) => Box::new(SasKeyProvider::new(self.endpoint(), name, key)),
+            _ => Box::new(StaticSasProvider::new(
+                self.shared_access_signature.as_deref().unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn sas_tokens_are_cached_until_the_margin() {
+        let clock = Arc::new(ManualClock::at(1_700_000_000));
+        let provider = SasKeyProvider::new("sb://a.b/", "send", "key")
+            .with_lifetime(Duration::from_secs(3600))
+            .with_refresh_margin(Duration::from_secs(300))
+            .with_clock(clock.clone());
+        let first = provider.token().unwrap();
+        assert!(first.header.contains("skn=send"));
+        assert_eq!(1_700_003_600, first.expires_on);
+
+        // Up to the margin before expiry the cached token is handed out.
+        clock.advance(Duration::from_secs(3000));
+        assert_eq!(first, provider.token().unwrap());
+        clock.advance(Duration::from_secs(400));
+        let second = provider.token().unwrap();
+        assert_ne!(first.header, second.header);
+        assert_eq!(1_700_007_000, second.expires_on);
+
+        // A margin longer than the lifetime means every call signs again.
+        let provider = SasKeyProvider::new("sb://a.b/", "send", "key")
+            .with_lifetime(Duration::from_secs(10))
+            .with_refresh_margin(Duration::from_secs(60))
+            .with_clock(clock.clone());
+        let first = provider.token().unwrap();
+        clock.advance(Duration::from_secs(1));
+        let second = provider.token().unwrap();
+        assert_ne!(first.header, second.header);
+        assert_eq!(first.expires_on + 1, second.expires_on);
+    }
+
+    #[test]
+    fn bearer_tokens_fetch_once_until_invalidated() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let provider = BearerTokenProvider::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(("abc".to_string(), unix_now() + 3600))
+        });
+
+        assert_eq!("Bearer abc", provider.authorization().unwrap());
+        provider.token().unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        provider.invalidate();
+        provider.token().unwrap();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn expired_static_sas_is_an_error() {
+        let provider = StaticSasProvider::new("SharedAccessSignature sr=x&sig=y&se=10&skn=z");
+        assert_eq!(10, provider.token.expires_on);
+        assert!(provider.token().is_err());
+    }
+}
@@ -0,0 +1,981 @@
+// Subscription rules: the SQL-92 subset Service Bus accepts in `SqlFilter`
+// and `SqlRuleAction`, and correlation filters. Expressions are parsed when a
+// rule is created, so a bad one is turned down with a 400 like Service Bus
+// does, and again for every message, which is plenty fast for an emulator.
+//
+// Comparisons follow SQL's three valued logic. A missing property is NULL,
+// and so is comparing a string with a number, so neither ever matches. The
+// only thing that fails evaluation outright is dividing by zero.
+
+use super::store::Envelope;
+use crate::servicebus::description::{CorrelationFilter, RuleAction, RuleFilter};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+// Service Bus turns down filters and actions longer than this.
+const MAX_EXPRESSION_LENGTH: usize = 1024;
+// How deeply parentheses, NOT and signs may nest. The parser and evaluation
+// recurse, so this keeps a hostile rule from overflowing the stack.
+const MAX_NESTING: usize = 64;
+
+/// A rule's filter, ready to be matched against messages.
+#[derive(Debug)]
+pub(crate) struct Filter(Kind);
+
+#[derive(Debug)]
+enum Kind {
+    Sql(Expr),
+    Correlation(CorrelationFilter),
+    Constant(bool),
+}
+
+/// A rule's action, run on every message the filter matched.
+#[derive(Debug)]
+pub(crate) struct Action(Vec<Statement>);
+
+impl Filter {
+    pub fn parse(filter: &RuleFilter) -> Result<Filter, String> {
+        Ok(Filter(match filter {
+            RuleFilter::Sql { expression } => {
+                let mut parser = Parser::new(expression)?;
+                let expr = parser.expression()?;
+                parser.end()?;
+                Kind::Sql(expr)
+            }
+            RuleFilter::Correlation(c) => Kind::Correlation(c.clone()),
+            RuleFilter::True => Kind::Constant(true),
+            RuleFilter::False => Kind::Constant(false),
+        }))
+    }
+
+    pub fn matches(&self, message: &Envelope) -> Result<bool, String> {
+        match &self.0 {
+            Kind::Sql(expr) => Ok(expr.eval(message)? == Val::Bool(true)),
+            Kind::Correlation(c) => Ok(correlates(c, message)),
+            Kind::Constant(b) => Ok(*b),
+        }
+    }
+}
+
+impl Action {
+    pub fn parse(action: &RuleAction) -> Result<Action, String> {
+        let RuleAction::Sql { expression } = action;
+        let mut parser = Parser::new(expression)?;
+        let mut statements = Vec::new();
+        loop {
+            if parser.keyword("SET") {
+                let property = parser.property()?;
+                parser.expect("=")?;
+                statements.push(Statement::Set(property, parser.expression()?));
+            } else if parser.keyword("REMOVE") {
+                statements.push(Statement::Remove(parser.property()?));
+            } else {
+                return Err(parser.unexpected());
+            }
+            if !parser.symbol(";") || parser.at_end() {
+                break;
+            }
+        }
+        parser.end()?;
+        Ok(Action(statements))
+    }
+
+    pub fn apply(&self, message: &mut Envelope) -> Result<(), String> {
+        for statement in &self.0 {
+            match statement {
+                Statement::Set(property, expr) => {
+                    let value = expr.eval(message)?;
+                    property.set(message, value);
+                }
+                Statement::Remove(property) => property.set(message, Val::Null),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Every property that is set in the filter has to be equal. User properties
+// are compared by their text, as correlation filters only hold strings.
+fn correlates(filter: &CorrelationFilter, message: &Envelope) -> bool {
+    let props = &message.props;
+    let system = [
+        (&filter.correlation_id, &props.CorrelationId),
+        (&filter.message_id, &props.MessageId),
+        (&filter.to, &props.To),
+        (&filter.reply_to, &props.ReplyTo),
+        (&filter.label, &props.Label),
+        (&filter.session_id, &props.SessionId),
+        (&filter.reply_to_session_id, &props.ReplyToSessionId),
+        (&filter.content_type, &message.content_type),
+    ];
+    system
+        .iter()
+        .all(|(want, have)| want.is_none() || want == have)
+        && filter
+            .properties
+            .iter()
+            .all(|(name, want)| match user_property(message, name) {
+                Some(Value::String(have)) => have == want,
+                Some(have) => &have.to_string() == want,
+                None => false,
+            })
+}
+
+fn user_property<'a>(message: &'a Envelope, name: &str) -> Option<&'a Value> {
+    message
+        .properties
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Val {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Val {
+    fn from_json(value: &Value) -> Val {
+        match value {
+            Value::Bool(b) => Val::Bool(*b),
+            Value::Number(n) => n
+                .as_i64()
+                .map(Val::Int)
+                .or_else(|| n.as_f64().map(Val::Float))
+                .unwrap_or(Val::Null),
+            Value::String(s) => Val::Str(s.clone()),
+            _ => Val::Null,
+        }
+    }
+
+    fn into_json(self) -> Value {
+        match self {
+            Val::Null => Value::Null,
+            Val::Bool(b) => Value::Bool(b),
+            Val::Int(i) => Value::from(i),
+            Val::Float(f) => Value::from(f),
+            Val::Str(s) => Value::String(s),
+        }
+    }
+
+    fn into_text(self) -> Option<String> {
+        match self {
+            Val::Null => None,
+            Val::Bool(b) => Some(b.to_string()),
+            Val::Int(i) => Some(i.to_string()),
+            Val::Float(f) => Some(f.to_string()),
+            Val::Str(s) => Some(s),
+        }
+    }
+
+    // `None` is SQL's unknown.
+    fn truth(&self) -> Option<bool> {
+        match self {
+            Val::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn compare(&self, other: &Val) -> Option<Ordering> {
+        match (self, other) {
+            (Val::Int(a), Val::Int(b)) => Some(a.cmp(b)),
+            (Val::Int(a), Val::Float(b)) => (*a as f64).partial_cmp(b),
+            (Val::Float(a), Val::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Val::Float(a), Val::Float(b)) => a.partial_cmp(b),
+            (Val::Str(a), Val::Str(b)) => Some(a.cmp(b)),
+            (Val::Bool(a), Val::Bool(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+fn unknown_or(b: Option<bool>) -> Val {
+    b.map_or(Val::Null, Val::Bool)
+}
+
+// The system properties filters and actions can use, as `sys.Label`.
+const SYSTEM_PROPERTIES: &[&str] = &[
+    "MessageId",
+    "CorrelationId",
+    "Label",
+    "To",
+    "ReplyTo",
+    "SessionId",
+    "ReplyToSessionId",
+    "ContentType",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Property {
+    System(&'static str),
+    User(String),
+}
+
+impl Property {
+    fn get(&self, message: &Envelope) -> Val {
+        let props = &message.props;
+        let text = match self {
+            Property::User(name) => {
+                return user_property(message, name).map_or(Val::Null, Val::from_json)
+            }
+            Property::System("MessageId") => &props.MessageId,
+            Property::System("CorrelationId") => &props.CorrelationId,
+            Property::System("Label") => &props.Label,
+            Property::System("To") => &props.To,
+            Property::System("ReplyTo") => &props.ReplyTo,
+            Property::System("SessionId") => &props.SessionId,
+            Property::System("ReplyToSessionId") => &props.ReplyToSessionId,
+            Property::System(_) => &message.content_type,
+        };
+        text.clone().map_or(Val::Null, Val::Str)
+    }
+
+    // Setting NULL removes the property.
+    fn set(&self, message: &mut Envelope, value: Val) {
+        let props = &mut message.props;
+        let slot = match self {
+            Property::User(name) => {
+                let existing = message
+                    .properties
+                    .keys()
+                    .find(|k| k.eq_ignore_ascii_case(name))
+                    .cloned();
+                let name = existing.unwrap_or_else(|| name.to_ascii_lowercase());
+                match value {
+                    Val::Null => message.properties.remove(&name),
+                    value => message.properties.insert(name, value.into_json()),
+                };
+                return;
+            }
+            Property::System("MessageId") => &mut props.MessageId,
+            Property::System("CorrelationId") => &mut props.CorrelationId,
+            Property::System("Label") => &mut props.Label,
+            Property::System("To") => &mut props.To,
+            Property::System("ReplyTo") => &mut props.ReplyTo,
+            Property::System("SessionId") => &mut props.SessionId,
+            Property::System("ReplyToSessionId") => &mut props.ReplyToSessionId,
+            Property::System(_) => &mut message.content_type,
+        };
+        *slot = value.into_text();
+    }
+}
+
+#[derive(Debug)]
+enum Statement {
+    Set(Property, Expr),
+    Remove(Property),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Pattern {
+    Any,
+    One,
+    Char(char),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Val),
+    Property(Property),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Vec<Expr>, bool),
+    Like(Box<Expr>, Vec<Pattern>, bool),
+    IsNull(Box<Expr>, bool),
+    Exists(Property),
+}
+
+impl Expr {
+    fn eval(&self, message: &Envelope) -> Result<Val, String> {
+        Ok(match self {
+            Expr::Literal(v) => v.clone(),
+            Expr::Property(p) => p.get(message),
+            Expr::Negate(e) => match e.eval(message)? {
+                Val::Int(i) => i.checked_neg().map_or(Val::Null, Val::Int),
+                Val::Float(f) => Val::Float(-f),
+                _ => Val::Null,
+            },
+            Expr::Not(e) => unknown_or(e.eval(message)?.truth().map(|b| !b)),
+            Expr::Binary(Op::And, a, b) => {
+                match (a.eval(message)?.truth(), b.eval(message)?.truth()) {
+                    (Some(false), _) | (_, Some(false)) => Val::Bool(false),
+                    (Some(true), Some(true)) => Val::Bool(true),
+                    _ => Val::Null,
+                }
+            }
+            Expr::Binary(Op::Or, a, b) => {
+                match (a.eval(message)?.truth(), b.eval(message)?.truth()) {
+                    (Some(true), _) | (_, Some(true)) => Val::Bool(true),
+                    (Some(false), Some(false)) => Val::Bool(false),
+                    _ => Val::Null,
+                }
+            }
+            Expr::Binary(op, a, b) => binary(*op, a.eval(message)?, b.eval(message)?)?,
+            Expr::In(e, list, negated) => {
+                let value = e.eval(message)?;
+                if value == Val::Null {
+                    return Ok(Val::Null);
+                }
+                let mut found = Some(false);
+                for item in list {
+                    match value.compare(&item.eval(message)?) {
+                        Some(Ordering::Equal) => {
+                            found = Some(true);
+                            break;
+                        }
+                        Some(_) => {}
+                        None => found = None,
+                    }
+                }
+                unknown_or(found.map(|f| f != *negated))
+            }
+            Expr::Like(e, pattern, negated) => match e.eval(message)? {
+                Val::Str(s) => Val::Bool(like(pattern, &s.chars().collect::<Vec<_>>()) != *negated),
+                _ => Val::Null,
+            },
+            Expr::IsNull(e, negated) => Val::Bool((e.eval(message)? == Val::Null) != *negated),
+            Expr::Exists(p) => Val::Bool(p.get(message) != Val::Null),
+        })
+    }
+}
+
+fn binary(op: Op, a: Val, b: Val) -> Result<Val, String> {
+    let ordering = a.compare(&b);
+    let compared = |test: fn(Ordering) -> bool| unknown_or(ordering.map(test));
+    Ok(match op {
+        Op::Eq => compared(|o| o == Ordering::Equal),
+        Op::Ne => compared(|o| o != Ordering::Equal),
+        Op::Lt => compared(|o| o == Ordering::Less),
+        Op::Gt => compared(|o| o == Ordering::Greater),
+        Op::Le => compared(|o| o != Ordering::Greater),
+        Op::Ge => compared(|o| o != Ordering::Less),
+        _ => match (a, b) {
+            (Val::Str(a), Val::Str(b)) if op == Op::Add => Val::Str(a + &b),
+            (Val::Int(_), Val::Int(0)) | (Val::Float(_), Val::Int(0))
+                if op == Op::Div || op == Op::Rem =>
+            {
+                return Err("Division by zero.".to_string())
+            }
+            (Val::Int(a), Val::Int(b)) => {
+                let result = match op {
+                    Op::Add => a.checked_add(b),
+                    Op::Sub => a.checked_sub(b),
+                    Op::Mul => a.checked_mul(b),
+                    Op::Div => a.checked_div(b),
+                    _ => a.checked_rem(b),
+                };
+                result.map_or(Val::Null, Val::Int)
+            }
+            (a, b) => match (as_float(&a), as_float(&b)) {
+                (Some(a), Some(b)) => Val::Float(match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    _ => a % b,
+                }),
+                _ => Val::Null,
+            },
+        },
+    })
+}
+
+fn as_float(v: &Val) -> Option<f64> {
+    match v {
+        Val::Int(i) => Some(*i as f64),
+        Val::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+// Wildcard matching that backtracks to the last `%` only.
+fn like(pattern: &[Pattern], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(Pattern::Any) => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(Pattern::One) => {
+                p += 1;
+                t += 1;
+            }
+            Some(Pattern::Char(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == Pattern::Any)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    // An identifier or keyword.
+    Word(String),
+    // A `[bracketed name]`, never a keyword.
+    Quoted(String),
+    Str(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "<>", "!=", "=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",", ";",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                        s.push('\'');
+                        i += 2;
+                    }
+                    Some('\'') => break,
+                    Some(c) => {
+                        s.push(*c);
+                        i += 1;
+                    }
+                    None => return Err("Unterminated string.".to_string()),
+                }
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else if c == '[' {
+            let end = chars[i..]
+                .iter()
+                .position(|c| *c == ']')
+                .ok_or("Unterminated [name].")?;
+            tokens.push(Token::Quoted(chars[i + 1..i + end].iter().collect()));
+            i += end + 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_$.".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(**s))
+                .ok_or_else(|| format!("Unexpected character '{}'.", c))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+const KEYWORDS: &[&str] = &[
+    "AND", "OR", "NOT", "IN", "LIKE", "ESCAPE", "IS", "NULL", "TRUE", "FALSE", "EXISTS", "SET",
+    "REMOVE",
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Parser, String> {
+        if source.chars().count() > MAX_EXPRESSION_LENGTH {
+            return Err(format!(
+                "The expression is longer than {} characters.",
+                MAX_EXPRESSION_LENGTH
+            ));
+        }
+        Ok(Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            depth: 0,
+        })
+    }
+
+    // Parses with `parse` one level further down.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth == MAX_NESTING {
+            return Err(format!(
+                "The expression nests more than {} levels deep.",
+                MAX_NESTING
+            ));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn end(&self) -> Result<(), String> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(token) => format!("Unexpected {:?}.", token),
+            None => "Unexpected end of expression.".to_string(),
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(word) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.keyword("OR") {
+            left = Expr::Binary(Op::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.not()?;
+        while self.keyword("AND") {
+            left = Expr::Binary(Op::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.nested(Self::not)?)))
+        } else {
+            self.predicate()
+        }
+    }
+
+    fn predicate(&mut self) -> Result<Expr, String> {
+        let left = self.additive()?;
+        let comparisons = [
+            ("=", Op::Eq),
+            ("<>", Op::Ne),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        for (symbol, op) in &comparisons {
+            if self.symbol(symbol) {
+                return Ok(Expr::Binary(
+                    *op,
+                    Box::new(left),
+                    Box::new(self.additive()?),
+                ));
+            }
+        }
+
+        if self.keyword("IS") {
+            let negated = self.keyword("NOT");
+            if !self.keyword("NULL") {
+                return Err(self.unexpected());
+            }
+            return Ok(Expr::IsNull(Box::new(left), negated));
+        }
+        let negated = self.keyword("NOT");
+        if self.keyword("IN") {
+            self.expect("(")?;
+            let mut list = vec![self.additive()?];
+            while self.symbol(",") {
+                list.push(self.additive()?);
+            }
+            self.expect(")")?;
+            Ok(Expr::In(Box::new(left), list, negated))
+        } else if self.keyword("LIKE") {
+            let pattern = self.string()?;
+            let escape = if self.keyword("ESCAPE") {
+                let escape = self.string()?;
+                let mut chars = escape.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => return Err("ESCAPE takes a single character.".to_string()),
+                }
+            } else {
+                None
+            };
+            Ok(Expr::Like(
+                Box::new(left),
+                like_pattern(&pattern, escape)?,
+                negated,
+            ))
+        } else if negated {
+            Err(self.unexpected())
+        } else {
+            Ok(left)
+        }
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.symbol("+") {
+                Op::Add
+            } else if self.symbol("-") {
+                Op::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.symbol("*") {
+                Op::Mul
+            } else if self.symbol("/") {
+                Op::Div
+            } else if self.symbol("%") {
+                Op::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.symbol("-") {
+            Ok(Expr::Negate(Box::new(self.nested(Self::unary)?)))
+        } else if self.symbol("+") {
+            self.nested(Self::unary)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.symbol("(") {
+            let expr = self.nested(Self::expression)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if self.keyword("TRUE") {
+            return Ok(Expr::Literal(Val::Bool(true)));
+        }
+        if self.keyword("FALSE") {
+            return Ok(Expr::Literal(Val::Bool(false)));
+        }
+        if self.keyword("NULL") {
+            return Ok(Expr::Literal(Val::Null));
+        }
+        if self.keyword("EXISTS") {
+            self.expect("(")?;
+            let property = self.property()?;
+            self.expect(")")?;
+            return Ok(Expr::Exists(property));
+        }
+        match self.peek() {
+            Some(Token::Str(_)) => Ok(Expr::Literal(Val::Str(self.string()?))),
+            Some(Token::Number(n)) => {
+                let value = match n.parse::<i64>() {
+                    Ok(i) => Val::Int(i),
+                    Err(_) => Val::Float(n.parse().map_err(|_| format!("Bad number {}.", n))?),
+                };
+                self.pos += 1;
+                Ok(Expr::Literal(value))
+            }
+            _ => Ok(Expr::Property(self.property()?)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Str(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // `name`, `[name]`, `user.name` or `sys.Name`.
+    fn property(&mut self) -> Result<Property, String> {
+        let word = match self.peek() {
+            Some(Token::Quoted(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                return Ok(Property::User(name));
+            }
+            Some(Token::Word(w)) if !KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(w)) => {
+                w.clone()
+            }
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        let (scope, name) = match word.split_once('.') {
+            Some((scope, name)) if scope.eq_ignore_ascii_case("sys") => (Some(true), name),
+            Some((scope, name)) if scope.eq_ignore_ascii_case("user") => (Some(false), name),
+            _ => (None, word.as_str()),
+        };
+        match scope {
+            Some(true) => SYSTEM_PROPERTIES
+                .iter()
+                .find(|p| p.eq_ignore_ascii_case(name))
+                .map(|p| Property::System(p))
+                .ok_or_else(|| format!("Unknown system property {}.", name)),
+            _ if name.is_empty() => Err(format!("Bad property name {}.", word)),
+            _ => Ok(Property::User(name.to_string())),
+        }
+    }
+}
+
+fn like_pattern(pattern: &str, escape: Option<char>) -> Result<Vec<Pattern>, String> {
+    let mut out = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        out.push(match c {
+            c if Some(c) == escape => Pattern::Char(
+                chars
+                    .next()
+                    .ok_or("The pattern ends with its escape character.")?,
+            ),
+            '%' => Pattern::Any,
+            '_' => Pattern::One,
+            c => Pattern::Char(c),
+        });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servicebus::brokeredmessage::BrokerProperties;
+
+    fn message() -> Envelope {
+        Envelope {
+            props: BrokerProperties {
+                Label: Some("order".to_string()),
+                CorrelationId: Some("abc".to_string()),
+                ..Default::default()
+            },
+            content_type: Some("application/json".to_string()),
+            properties: vec![
+                ("color".to_string(), Value::from("blue")),
+                ("priority".to_string(), Value::from(5)),
+                ("weight".to_string(), Value::from(2.5)),
+            ]
+            .into_iter()
+            .collect(),
+            body: String::new(),
+        }
+    }
+
+    fn sql(expression: &str) -> Result<bool, String> {
+        Filter::parse(&RuleFilter::Sql {
+            expression: expression.to_string(),
+        })?
+        .matches(&message())
+    }
+
+    #[test]
+    fn sql_filters() {
+        let matching = [
+            "color = 'blue'",
+            "Color = 'blue' AND priority > 3",
+            "user.priority * 2 + 1 = 11",
+            "weight < 3 OR missing = 1",
+            "sys.Label = 'order' AND sys.ContentType LIKE '%json'",
+            "color IN ('red', 'blue')",
+            "priority NOT IN (1, 2, 3)",
+            "color LIKE 'b_u%'",
+            "'50%' LIKE '50!%' ESCAPE '!'",
+            "missing IS NULL AND color IS NOT NULL",
+            "EXISTS(color) AND NOT EXISTS(missing)",
+            "NOT (missing = 1) OR TRUE",
+            "[color] <> 'red'",
+        ];
+        for expression in &matching {
+            assert_eq!(Ok(true), sql(expression), "{}", expression);
+        }
+        let not_matching = [
+            "color = 'red'",
+            "color = 5",
+            "missing = 1",
+            "NOT (missing = 1)",
+            "missing NOT IN (1)",
+            "color NOT LIKE 'b%'",
+            "FALSE",
+        ];
+        for expression in &not_matching {
+            assert_eq!(Ok(false), sql(expression), "{}", expression);
+        }
+
+        assert!(sql("priority / 0 = 1").is_err());
+        for bad in &[
+            "color =",
+            "color = 'blue",
+            "sys.Nope = 1",
+            "a b",
+            "(a = 1",
+            "a NOT = 1",
+        ] {
+            assert!(sql(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn correlation_filters() {
+        let mut filter = CorrelationFilter {
+            correlation_id: Some("abc".to_string()),
+            ..Default::default()
+        };
+        filter
+            .properties
+            .insert("priority".to_string(), "5".to_string());
+        let matches = |f: &CorrelationFilter| {
+            Filter::parse(&RuleFilter::Correlation(f.clone()))
+                .unwrap()
+                .matches(&message())
+                .unwrap()
+        };
+        assert!(matches(&filter));
+        filter.label = Some("invoice".to_string());
+        assert!(!matches(&filter));
+    }
+
+    #[test]
+    fn actions() {
+        let action = Action::parse(&RuleAction::Sql {
+            expression: "SET priority = priority + 1; SET sys.Label = 'routed'; REMOVE color; SET Source = 'rule';"
+                .to_string(),
+        })
+        .unwrap();
+        let mut m = message();
+        action.apply(&mut m).unwrap();
+        assert_eq!(Some(&Value::from(6)), m.properties.get("priority"));
+        assert_eq!(Some(&Value::from("rule")), m.properties.get("source"));
+        assert_eq!(None, m.properties.get("color"));
+        assert_eq!(Some("routed"), m.props.Label.as_deref());
+
+        for bad in &["SET = 1", "DROP color", "SET a = 1 SET b = 2"] {
+            let action = RuleAction::Sql {
+                expression: bad.to_string(),
+            };
+            assert!(Action::parse(&action).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn deep_and_long_expressions_are_turned_down() {
+        let sql = |expression: String| RuleFilter::Sql { expression };
+        let deep = format!("{}a = 1{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert!(Filter::parse(&sql(deep))
+            .unwrap_err()
+            .contains("longer than"));
+        let nots = format!("{}a = 1", "NOT ".repeat(10_000));
+        assert!(Filter::parse(&sql(nots))
+            .unwrap_err()
+            .contains("longer than"));
+
+        for nested in &[
+            format!("{}a = 1{}", "(".repeat(100), ")".repeat(100)),
+            format!("{}a = 1", "NOT ".repeat(100)),
+            format!("a = {}1", "-".repeat(100)),
+        ] {
+            let error = Filter::parse(&sql(nested.clone())).unwrap_err();
+            assert!(error.contains("levels deep"), "{}", error);
+        }
+        let action = RuleAction::Sql {
+            expression: format!("SET a = {}1{}", "(".repeat(100), ")".repeat(100)),
+        };
+        assert!(Action::parse(&action).is_err());
+
+        let fine = format!("{}a = 1{}", "(".repeat(30), ")".repeat(30));
+        assert!(Filter::parse(&sql(fine)).is_ok());
+    }
+}
@@ -0,0 +1,698 @@
+//! An in-memory Service Bus namespace served over http on localhost, so the
+//! request builders in this crate can be exercised without an azure account.
+//!
+//! The emulator speaks the parts of the REST api the clients use: sending to
+//! queues and topics, peek-lock and receive-and-delete receives, completing,
+//! abandoning and renewing locks, the dead letter queues, and the ATOM
+//! management api for creating, reading, listing and deleting entities. Locks
+//! expire, delivery counts go up with every receive, messages past their time
+//! to live are dropped or dead lettered, and a message that keeps being
+//! abandoned is dead lettered after `MaxDeliveryCount` attempts.
+//!
+//! Topics hand each subscription a copy for every rule whose SQL or
+//! correlation filter matches, with the rule's action applied. `ForwardTo`
+//! and `ForwardDeadLetteredMessagesTo` are followed for up to
+//! `MAX_TRANSFER_HOPS` hops, entities that require duplicate detection drop
+//! a `MessageId` they have seen within their window, and messages with a
+//! `ScheduledEnqueueTimeUtc` stay hidden until then.
+//!
+//! With `persist_to` the namespace is saved to a directory shortly after it
+//! changes and when the emulator shuts down, and loaded again on the next
+//! start. The `servicebus-emulator` binary runs one that way for local
+//! development.
+//!
+//! ```
+//! # use azure_service_bus::core::exec::Executor;
+//! # use azure_service_bus::emulator::Emulator;
+//! # use azure_service_bus::servicebus::brokeredmessage::BrokeredMessage;
+//! # use azure_service_bus::servicebus::description::{Entity, QueueDescription};
+//! # use azure_service_bus::QueueClient;
+//! # fn main() -> Result<(), eyre::Report> {
+//! let emulator = Emulator::builder()
+//!     .entity(Entity::Queue(QueueDescription::new("orders")))
+//!     .start()?;
+//! let exec = emulator.executor();
+//!
+//! let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+//! exec.execute(queue.send(BrokeredMessage::with_body("hello"))?)?;
+//! let received = exec.execute(queue.receive()?.map(|()| String::new()))?;
+//! assert_eq!("<string>hello</string>", received.body());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only available with the `emulator` feature.
+
+mod filter;
+mod persist;
+mod server;
+mod store;
+
+pub use store::{
+    DEFAULT_DUPLICATE_DETECTION_WINDOW, DEFAULT_LOCK_DURATION, DEFAULT_MAX_DELIVERY_COUNT,
+    FILTER_EVALUATION_EXCEPTION, MAX_DELIVERY_COUNT_EXCEEDED, MAX_TRANSFER_HOPS,
+    MAX_TRANSFER_HOP_COUNT_EXCEEDED, TTL_EXPIRED,
+};
+
+use crate::core::clock::{Clock, SystemClock};
+use crate::core::exec::Executor;
+use crate::servicebus::brokeredmessage::BrokeredMessage;
+use crate::servicebus::description::Entity;
+use crate::servicebus::topology::Topology;
+use eyre::{eyre, Report};
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, Uri};
+use server::Shared;
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use store::{EntityPath, Envelope, Fault, Store};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+// How long requests in flight get to finish when the emulator is dropped.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+// How often changes are written to the data directory, at most.
+const SAVE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The key name the emulator accepts unless configured otherwise.
+pub const DEFAULT_KEY_NAME: &str = "RootManageSharedAccessKey";
+
+/// Configures an `Emulator` before it starts listening.
+pub struct EmulatorBuilder {
+    addr: SocketAddr,
+    key_name: String,
+    key: String,
+    topology: Topology,
+    entities: Vec<Entity>,
+    data_dir: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+}
+
+impl EmulatorBuilder {
+    /// Where to listen. The default is a free port on `127.0.0.1`.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// The shared access rule tokens have to be signed with. By default the
+    /// name is `RootManageSharedAccessKey` and the key is random.
+    pub fn key(mut self, key_name: &str, key: &str) -> Self {
+        self.key_name = key_name.to_string();
+        self.key = key.to_string();
+        self
+    }
+
+    /// An entity that exists from the start. Topics have to come before their
+    /// subscriptions.
+    pub fn entity(mut self, entity: Entity) -> Self {
+        self.entities.push(entity);
+        self
+    }
+
+    /// Entities that exist from the start, such as a topology file that is
+    /// also applied to the real namespace. Entities that were saved with
+    /// different settings are updated to match.
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Saves every entity and message to `dir`, and picks up whatever was
+    /// saved there by a previous run.
+    pub fn persist_to(mut self, dir: impl AsRef<Path>) -> Self {
+        self.data_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Where the emulator gets the time from, for token expiry, locks and
+    /// scheduled messages. Handy for testing what happens when clocks
+    /// disagree. Receives still wait in real time.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn start(self) -> Result<Emulator, Report> {
+        let mut store = match &self.data_dir {
+            Some(dir) => persist::load(dir)?.unwrap_or_default(),
+            None => Store::default(),
+        };
+        store
+            .apply(&self.topology)
+            .map_err(|fault| fault_report("The topology", fault))?;
+        for entity in self.entities {
+            let path = entity.path();
+            store
+                .upsert(entity)
+                .map_err(|fault| fault_report(&path, fault))?;
+        }
+        if let Some(dir) = &self.data_dir {
+            persist::save(dir, &store)?;
+        }
+
+        let listener = TcpListener::bind(self.addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::new(
+            addr,
+            &self.key_name,
+            &self.key,
+            self.data_dir,
+            self.clock,
+            store,
+        ));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let server = {
+            let _context = runtime.enter();
+            let shared = shared.clone();
+            Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+                let shared = shared.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| server::handle(shared.clone(), req)))
+                }
+            }))
+        };
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let handle = runtime.handle().clone();
+        let thread = std::thread::Builder::new()
+            .name("servicebus-emulator".to_string())
+            .spawn(move || {
+                let (closing, closed) = oneshot::channel::<()>();
+                let graceful = server.with_graceful_shutdown(async {
+                    let _ = stopped.await;
+                    let _ = closing.send(());
+                });
+                // A client can hold a keep-alive connection open for far longer
+                // than a test wants to wait, so connections get a moment to
+                // finish and are then dropped with the runtime.
+                runtime.block_on(async {
+                    let serving = tokio::spawn(graceful);
+                    let _ = closed.await;
+                    let _ = tokio::time::timeout(SHUTDOWN_GRACE, serving).await;
+                });
+            })?;
+        let saver = match shared.data_dir {
+            Some(_) => Some(spawn_saver(shared.clone())?),
+            None => None,
+        };
+
+        Ok(Emulator {
+            shared,
+            runtime: handle,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+            saver,
+        })
+    }
+}
+
+/// A running emulator. It stops listening when dropped.
+pub struct Emulator {
+    shared: Arc<Shared>,
+    runtime: Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    saver: Option<(mpsc::Sender<()>, JoinHandle<()>)>,
+}
+
+// Writes the store out every `SAVE_INTERVAL` while it keeps changing, until
+// the sender is dropped.
+fn spawn_saver(shared: Arc<Shared>) -> Result<(mpsc::Sender<()>, JoinHandle<()>), Report> {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = std::thread::Builder::new()
+        .name("servicebus-emulator-saver".to_string())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SAVE_INTERVAL) {
+                // Failures are kept for `last_save_error` and retried.
+                let _ = shared.flush();
+            }
+        })?;
+    Ok((stop, thread))
+}
+
+impl Emulator {
+    pub fn builder() -> EmulatorBuilder {
+        let key: [u8; 32] = rand::random();
+        EmulatorBuilder {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            key_name: DEFAULT_KEY_NAME.to_string(),
+            key: base64::encode(key),
+            topology: Topology::default(),
+            entities: Vec::new(),
+            data_dir: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Starts an empty emulator on a free port.
+    pub fn start() -> Result<Emulator, Report> {
+        Self::builder().start()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.shared.addr
+    }
+
+    /// The http url of the namespace.
+    pub fn endpoint(&self) -> Uri {
+        format!("http://{}/", self.shared.addr)
+            .parse()
+            .expect("a socket address is a valid authority")
+    }
+
+    /// A connection string for the emulator, with `UseDevelopmentEmulator`
+    /// set so the clients talk plain http.
+    pub fn connection_string(&self) -> String {
+        format!(
+            "Endpoint=sb://{}/;SharedAccessKeyName={};SharedAccessKey={};UseDevelopmentEmulator=true",
+            self.shared.addr,
+            self.shared.key_name,
+            self.shared.key.as_str()
+        )
+    }
+
+    /// Where the emulator saves its state, if anywhere.
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.shared.data_dir.as_deref()
+    }
+
+    /// Why the state could not be saved the last time it was tried, if it
+    /// couldn't. Saving is retried until it works, which clears this.
+    pub fn last_save_error(&self) -> Option<String> {
+        self.shared.save_error()
+    }
+
+    /// Stops listening and saves the state one last time, returning why that
+    /// failed if it did. Dropping the emulator does the same, but can't say.
+    pub fn shutdown(mut self) -> Result<(), Report> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), Report> {
+        // Receives that are waiting for a message give up straight away.
+        self.shared.closing.store(true, Ordering::Relaxed);
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Nothing changes the store any more, so this write is the last.
+        if let Some((stop, saver)) = self.saver.take() {
+            drop(stop);
+            let _ = saver.join();
+        }
+        self.shared.flush()
+    }
+
+    /// Creates an entity, the same as a PUT through the management api would.
+    pub fn create(&self, entity: Entity) -> Result<(), Report> {
+        let path = entity.path();
+        let mut store = self.shared.store();
+        store
+            .put(entity, false)
+            .map_err(|fault| fault_report(&path, fault))?;
+        self.shared.changed();
+        Ok(())
+    }
+
+    /// Looks up an entity by its path, e.g. `orders` or
+    /// `events/Subscriptions/audit`, with its message counts filled in.
+    pub fn get(&self, path: &str) -> Option<Entity> {
+        self.shared.store().get(&EntityPath::parse(path)?)
+    }
+
+    /// Puts a message straight into a queue or topic without going over http.
+    pub fn send(&self, entity: &str, message: BrokeredMessage) -> Result<(), Report> {
+        let envelope = Envelope {
+            props: message.props.as_ref().clone(),
+            content_type: None,
+            properties: message.properties.clone(),
+            body: message.into_body(),
+        };
+        let mut store = self.shared.store();
+        store
+            .send(entity, envelope, self.shared.clock.now())
+            .map_err(|fault| fault_report(entity, fault))?;
+        self.shared.changed();
+        Ok(())
+    }
+
+    /// An executor that sends requests to this emulator. It blocks, so it
+    /// can't be used from inside an async runtime.
+    pub fn executor(&self) -> EmulatorExecutor {
+        EmulatorExecutor {
+            client: Client::new(),
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+fn fault_report(path: &str, fault: Fault) -> Report {
+    match fault {
+        Fault::NotFound => eyre!("{} does not exist.", path),
+        Fault::Conflict => eyre!("{} already exists.", path),
+        Fault::LockLost => eyre!("The lock on the message was lost."),
+        Fault::Invalid(reason) => eyre!("{}: {}", path, reason),
+    }
+}
+
+/// Sends requests to an `Emulator` over http.
+#[derive(Clone)]
+pub struct EmulatorExecutor {
+    client: Client<HttpConnector>,
+    runtime: Handle,
+}
+
+impl Executor for EmulatorExecutor {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        self.runtime.block_on(async {
+            let response = self.client.request(request.map(Body::from)).await?;
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            Ok(Response::from_parts(
+                parts,
+                String::from_utf8(body.to_vec())?,
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::exec::RetryUnauthorized;
+    use crate::core::token::SasKeyProvider;
+    use crate::servicebus::description::{
+        QueueDescription, RuleDescription, RuleFilter, SubscriptionDescription, TopicDescription,
+    };
+    use crate::servicebus::interpret_results;
+    use crate::{NamespaceClient, QueueClient, SubscriptionClient};
+    use hyper::StatusCode;
+    use std::time::Duration;
+
+    fn receive(exec: &EmulatorExecutor, req: Request<()>) -> Result<Response<String>, Report> {
+        exec.execute(req.map(|()| String::new()))
+    }
+
+    #[test]
+    fn queue_round_trip() -> Result<(), Report> {
+        let mut q = QueueDescription::new("orders");
+        q.max_delivery_count = Some(1);
+        let emulator = Emulator::builder().entity(Entity::Queue(q)).start()?;
+        let exec = emulator.executor();
+        let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+
+        let sent = exec.execute(queue.send(BrokeredMessage::with_body("hello"))?)?;
+        assert_eq!(StatusCode::CREATED, sent.status());
+        let received = BrokeredMessage::with_response(receive(&exec, queue.receive()?)?);
+        assert_eq!("hello", received.get_body()?);
+        assert_eq!(Some(1), received.props.DeliveryCount);
+        interpret_results(receive(&exec, queue.renew_message(&received)?)?.status())?;
+
+        // One delivery is all this queue allows, so the abandoned message is dead lettered.
+        interpret_results(receive(&exec, queue.abandon_message(received)?)?.status())?;
+        let empty = receive(&exec, queue.receive_with_timeout(Duration::from_secs(0))?)?;
+        assert_eq!(StatusCode::NO_CONTENT, empty.status());
+
+        let dlq = queue.dead_letter_queue();
+        let dead = receive(&exec, dlq.receive()?)?;
+        assert_eq!(
+            Some("\"MaxDeliveryCountExceeded\""),
+            dead.headers()
+                .get("DeadLetterReason")
+                .and_then(|h| h.to_str().ok())
+        );
+        let dead = BrokeredMessage::with_response(dead);
+        interpret_results(receive(&exec, dlq.complete_message(dead.clone())?)?.status())?;
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            receive(&exec, dlq.complete_message(dead)?)?.status()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn long_poll_waits_for_a_message() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .start()?;
+        let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+        let request = queue.receive_and_delete_with_timeout(Duration::from_secs(5))?;
+        let exec = emulator.executor();
+        let waiting = std::thread::spawn(move || receive(&exec, request));
+
+        std::thread::sleep(Duration::from_millis(100));
+        emulator.send("orders", BrokeredMessage::with_body("late"))?;
+        let response = waiting.join().unwrap()?;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("<string>late</string>", response.body());
+        Ok(())
+    }
+
+    #[test]
+    fn huge_timeouts_and_locks_are_capped() -> Result<(), Report> {
+        let mut q = QueueDescription::new("orders");
+        q.lock_duration = Some(Duration::from_secs(u64::MAX));
+        let emulator = Emulator::builder().entity(Entity::Queue(q)).start()?;
+        let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+        let exec = emulator.executor();
+        emulator.send("orders", BrokeredMessage::with_body("hello"))?;
+
+        let request = queue.receive_with_timeout(Duration::from_secs(u64::MAX))?;
+        let response = receive(&exec, request)?;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let received = BrokeredMessage::with_response(response);
+        interpret_results(receive(&exec, queue.renew_message(&received)?)?.status())?;
+        interpret_results(receive(&exec, queue.complete_message(received)?)?.status())?;
+        Ok(())
+    }
+
+    #[test]
+    fn topics_and_management() -> Result<(), Report> {
+        let emulator = Emulator::start()?;
+        let exec = emulator.executor();
+        let namespace = NamespaceClient::with_conn(&emulator.connection_string())?;
+        for request in [
+            namespace.create(&TopicDescription::new("events"))?,
+            namespace.create(&SubscriptionDescription::new("events", "audit"))?,
+            namespace.create(&SubscriptionDescription::new("events", "billing"))?,
+        ] {
+            assert_eq!(StatusCode::CREATED, exec.execute(request)?.status());
+        }
+        let conflict = exec.execute(namespace.create(&TopicDescription::new("events"))?)?;
+        assert_eq!(StatusCode::CONFLICT, conflict.status());
+
+        let topic = QueueClient::with_conn_and_queue(&emulator.connection_string(), "events")?;
+        exec.execute(topic.send(BrokeredMessage::with_body("hi"))?)?;
+
+        let subs = namespace
+            .subscriptions("events", &exec)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(2, subs.len());
+        assert!(subs.iter().all(|s| s.message_count == Some(1)));
+
+        let audit = SubscriptionClient::with_conn_topic_and_subscr(
+            &emulator.connection_string(),
+            "events",
+            "audit",
+        )?;
+        let message = BrokeredMessage::with_response(receive(&exec, audit.receive()?)?);
+        interpret_results(receive(&exec, audit.complete_message(message)?)?.status())?;
+
+        exec.execute(namespace.delete(&TopicDescription::new("events"))?)?;
+        let gone = receive(&exec, audit.receive()?)?;
+        assert_eq!(StatusCode::GONE, gone.status());
+        Ok(())
+    }
+
+    #[test]
+    fn rules_filter_on_custom_properties() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .entity(Entity::Topic(TopicDescription::new("events")))
+            .entity(Entity::Subscription(SubscriptionDescription::new(
+                "events", "urgent",
+            )))
+            .start()?;
+        let exec = emulator.executor();
+        let namespace = NamespaceClient::with_conn(&emulator.connection_string())?;
+        let default = RuleDescription::new("events", "urgent", "$Default", RuleFilter::True);
+        interpret_results(exec.execute(namespace.delete(&default)?)?.status())?;
+        let urgent = RuleDescription::new(
+            "events",
+            "urgent",
+            "urgent",
+            RuleFilter::Sql {
+                expression: "priority > 3 AND sys.Label = 'order'".to_string(),
+            },
+        );
+        assert_eq!(
+            StatusCode::CREATED,
+            exec.execute(namespace.create(&urgent)?)?.status()
+        );
+
+        let topic = QueueClient::with_conn_and_queue(&emulator.connection_string(), "events")?;
+        for priority in 1..=5 {
+            let mut message = BrokeredMessage::with_body(&priority.to_string())
+                .with_property("priority", priority);
+            message.props.Label = Some("order".to_string());
+            exec.execute(topic.send(message)?)?;
+        }
+
+        let sub = SubscriptionClient::with_conn_topic_and_subscr(
+            &emulator.connection_string(),
+            "events",
+            "urgent",
+        )?;
+        let mut received = Vec::new();
+        loop {
+            let response = receive(
+                &exec,
+                sub.receive_and_delete_with_timeout(Duration::from_secs(0))?,
+            )?;
+            if response.status() == StatusCode::NO_CONTENT {
+                break;
+            }
+            let message = BrokeredMessage::with_response(response);
+            received.push(message.properties["priority"].clone());
+        }
+        assert_eq!(vec![serde_json::json!(4), serde_json::json!(5)], received);
+        Ok(())
+    }
+
+    #[test]
+    fn state_survives_a_restart() -> Result<(), Report> {
+        let dir = std::env::temp_dir().join(format!("sb-emulator-{}", std::process::id()));
+        let topology = Topology::from_json(r#"{"queues": [{"name": "orders"}]}"#)?;
+        let start = || {
+            Emulator::builder()
+                .key("root", "key")
+                .topology(topology.clone())
+                .persist_to(&dir)
+                .start()
+        };
+
+        let emulator = start()?;
+        let conn = emulator.connection_string();
+        let queue = QueueClient::with_conn_and_queue(&conn, "orders")?;
+        let exec = emulator.executor();
+        for body in &["first", "second"] {
+            exec.execute(queue.send(BrokeredMessage::with_body(body))?)?;
+        }
+        let locked = BrokeredMessage::with_response(receive(&exec, queue.receive()?)?);
+        emulator.shutdown()?;
+
+        let emulator = start()?;
+        let queue = QueueClient::with_conn_and_queue(&emulator.connection_string(), "orders")?;
+        let exec = emulator.executor();
+        let second = receive(&exec, queue.receive_and_delete()?)?;
+        assert_eq!("<string>second</string>", second.body());
+        interpret_results(receive(&exec, queue.complete_message(locked)?)?.status())?;
+        match emulator.get("orders") {
+            Some(Entity::Queue(q)) => assert_eq!(Some(0), q.message_count),
+            other => panic!("{:?}", other),
+        }
+        drop(emulator);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn save_errors_are_reported() -> Result<(), Report> {
+        let dir = std::env::temp_dir().join(format!("sb-emulator-broken-{}", std::process::id()));
+        let emulator = Emulator::builder()
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .persist_to(&dir)
+            .start()?;
+        assert_eq!(None, emulator.last_save_error());
+
+        // A file where the directory was makes every save fail.
+        std::fs::remove_dir_all(&dir)?;
+        std::fs::write(&dir, "in the way")?;
+        emulator.send("orders", BrokeredMessage::with_body("lost"))?;
+        std::thread::sleep(SAVE_INTERVAL * 3);
+        assert!(emulator.last_save_error().is_some());
+        assert!(emulator.shutdown().is_err());
+        std::fs::remove_file(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_keys() -> Result<(), Report> {
+        let emulator = Emulator::builder()
+            .key("root", "right")
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .start()?;
+        let wrong = Arc::new(SasKeyProvider::new(
+            &format!("sb://{}/", emulator.addr()),
+            "root",
+            "wrong",
+        ));
+        let queue = QueueClient::with_token_provider(emulator.endpoint(), "orders", wrong.clone());
+        let exec = RetryUnauthorized::new(emulator.executor(), wrong);
+        let response = exec.execute(queue.send(BrokeredMessage::with_body("x"))?)?;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        Ok(())
+    }
+
+    #[test]
+    fn clients_catch_up_with_the_emulators_clock() -> Result<(), Report> {
+        use crate::core::clock::ManualClock;
+
+        // The emulator is two hours ahead of the machine the client runs on,
+        // so every token the client signs has already expired.
+        let clock = Arc::new(ManualClock::at(SystemClock.unix_now() + 7200));
+        let emulator = Emulator::builder()
+            .clock(clock.clone())
+            .entity(Entity::Queue(QueueDescription::new("orders")))
+            .start()?;
+        let conn: crate::core::connection_string::ConnectionString =
+            emulator.connection_string().parse()?;
+        let provider: Arc<dyn crate::core::token::TokenProvider> = Arc::from(conn.token_provider());
+        let queue =
+            QueueClient::with_token_provider(emulator.endpoint(), "orders", provider.clone());
+        let exec = RetryUnauthorized::new(emulator.executor(), provider);
+
+        let message = BrokeredMessage::with_body("later")
+            .with_enqueue_delay(Duration::from_secs(60), &*clock);
+        assert_eq!(
+            StatusCode::CREATED,
+            exec.execute(queue.send(message)?)?.status()
+        );
+
+        // Scheduled by the emulator's clock, not the client's.
+        let peek = || {
+            receive(
+                &emulator.executor(),
+                queue.receive_with_timeout(Duration::from_secs(0))?,
+            )
+        };
+        assert_eq!(StatusCode::NO_CONTENT, peek()?.status());
+        clock.advance(Duration::from_secs(61));
+        let response = peek()?;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let message = BrokeredMessage::with_response(response);
+        assert_eq!("later", message.get_body()?);
+        assert!(!message.lock_expires_within(Duration::from_secs(10), &*clock));
+        clock.advance(Duration::from_secs(55));
+        assert!(message.lock_expires_within(Duration::from_secs(10), &*clock));
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+// Keeps the emulator's state in a directory so it survives restarts. The
+// whole namespace is written out shortly after it changes, by a thread of its
+// own so requests never wait on the disk. That is plenty for the message rates
+// of local development. The file is synced before it replaces the old one, so
+// a crash leaves either the old state or the new one, never half a file.
+
+use super::store::{Fault, Store};
+use eyre::{eyre, Report, WrapErr};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "namespace.json";
+
+fn state_file(dir: &Path) -> PathBuf {
+    dir.join(STATE_FILE)
+}
+
+/// Reads the store saved in `dir`, or `None` if nothing has been saved there yet.
+pub(crate) fn load(dir: &Path) -> Result<Option<Store>, Report> {
+    let path = state_file(dir);
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Could not read {}", path.display())),
+    };
+    Store::from_json(&json)
+        .map(Some)
+        .map_err(|fault| match fault {
+            Fault::Invalid(reason) => eyre!("{} is corrupt: {}", path.display(), reason),
+            other => eyre!("{} is corrupt: {:?}", path.display(), other),
+        })
+}
+
+/// Writes the store to a temporary file and moves it over the old state.
+pub(crate) fn save(dir: &Path, store: &Store) -> Result<(), Report> {
+    write(dir, &store.to_json()?)
+}
+
+/// Like `save`, for a store that was already turned into json.
+pub(crate) fn write(dir: &Path, json: &str) -> Result<(), Report> {
+    std::fs::create_dir_all(dir).wrap_err_with(|| format!("Could not create {}", dir.display()))?;
+    let path = state_file(dir);
+    let partial = path.with_extension("json.partial");
+    let file = File::create(&partial)
+        .and_then(|mut file| file.write_all(json.as_bytes()).map(|_| file))
+        .wrap_err_with(|| format!("Could not write {}", partial.display()))?;
+    file.sync_all()
+        .wrap_err_with(|| format!("Could not sync {}", partial.display()))?;
+    drop(file);
+    std::fs::rename(&partial, &path)
+        .wrap_err_with(|| format!("Could not replace {}", path.display()))?;
+    // The rename itself only lasts once the directory is synced too.
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .wrap_err_with(|| format!("Could not sync {}", dir.display()))?;
+    Ok(())
+}
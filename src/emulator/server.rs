@@ -0,0 +1,551 @@
+// The http side of the emulator: works out which operation a request is,
+// checks its token and turns the store's answer into the response Service Bus
+// would have sent.
+
+use super::persist;
+use super::store::{EntityPath, Envelope, Fault, Store, Target};
+use crate::core::clock::{http_date, Clock};
+use crate::core::error::SasError;
+use crate::core::lock;
+use crate::core::sas;
+use crate::servicebus::atom::{Entry, ATOM_NS};
+use crate::servicebus::brokeredmessage::{
+    read_properties, write_properties, BrokerProperties, BROKER_PROPERTIES_HEADER,
+};
+use crate::servicebus::description::*;
+use eyre::Report;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, DATE, HOST, IF_MATCH};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use zeroize::Zeroizing;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// What Service Bus waits for when a receive doesn't say, and the longest a
+// receive may ask to wait.
+const DEFAULT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEAD_LETTER_QUEUE: &str = "$DeadLetterQueue";
+const ENTRY_CONTENT_TYPE: &str = "application/atom+xml;type=entry;charset=utf-8";
+const FEED_CONTENT_TYPE: &str = "application/atom+xml;type=feed;charset=utf-8";
+
+/// What the server threads and the `Emulator` handle share.
+pub(crate) struct Shared {
+    pub addr: SocketAddr,
+    pub key_name: String,
+    pub key: Zeroizing<String>,
+    pub closing: AtomicBool,
+    pub data_dir: Option<PathBuf>,
+    pub clock: Arc<dyn Clock>,
+    store: Mutex<Store>,
+    // Set when the store has changed since it was last written out.
+    dirty: AtomicBool,
+    // Why the last write failed, until one succeeds.
+    save_error: Mutex<Option<String>>,
+}
+
+impl Shared {
+    pub fn new(
+        addr: SocketAddr,
+        key_name: &str,
+        key: &str,
+        data_dir: Option<PathBuf>,
+        clock: Arc<dyn Clock>,
+        store: Store,
+    ) -> Self {
+        Shared {
+            addr,
+            key_name: key_name.to_string(),
+            key: Zeroizing::new(key.to_string()),
+            closing: AtomicBool::new(false),
+            data_dir,
+            clock,
+            store: Mutex::new(store),
+            dirty: AtomicBool::new(false),
+            save_error: Mutex::new(None),
+        }
+    }
+
+    /// Notes that the store needs writing out on the next `flush`.
+    pub fn changed(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Writes the store to the data directory if it changed since the last
+    /// time. Only the serializing happens under the lock, not the disk I/O.
+    pub fn flush(&self) -> Result<(), Report> {
+        let dir = match &self.data_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let json = self.store().to_json();
+        let written = json
+            .map_err(Report::from)
+            .and_then(|json| persist::write(dir, &json));
+        *lock(&self.save_error) = written.as_ref().err().map(|e| format!("{:#}", e));
+        if written.is_err() {
+            // Try again next time rather than lose the change.
+            self.changed();
+        }
+        written
+    }
+
+    pub fn save_error(&self) -> Option<String> {
+        lock(&self.save_error).clone()
+    }
+
+    pub fn store(&self) -> MutexGuard<'_, Store> {
+        lock(&self.store)
+    }
+}
+
+/// Answers one request. Receives on an empty queue are retried until the
+/// request's `timeout` runs out, as Service Bus long polls them.
+pub(crate) async fn handle(
+    shared: Arc<Shared>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST).map(Body::from)),
+    };
+    let request = Request::from_parts(parts, body);
+
+    let deadline = Instant::now() + receive_timeout(&request);
+    let mut response = loop {
+        match respond(&shared, &request) {
+            Some(response) => break response,
+            None if Instant::now() < deadline && !shared.closing.load(Ordering::Relaxed) => {
+                tokio::time::sleep(POLL_INTERVAL).await
+            }
+            None => break status(StatusCode::NO_CONTENT),
+        }
+    };
+    // Clients correct their clocks with this, so it has to be the emulator's
+    // time rather than the one hyper would fill in.
+    if let Ok(date) = HeaderValue::from_str(&http_date(shared.clock.now())) {
+        response.headers_mut().insert(DATE, date);
+    }
+    Ok(response.map(Body::from))
+}
+
+fn receive_timeout(request: &Request<String>) -> Duration {
+    request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|p| p.strip_prefix("timeout="))
+        .and_then(|t| t.parse().ok())
+        .map_or(DEFAULT_RECEIVE_TIMEOUT, Duration::from_secs)
+        .min(DEFAULT_RECEIVE_TIMEOUT)
+}
+
+fn status(status: StatusCode) -> Response<String> {
+    let mut response = Response::new(String::new());
+    *response.status_mut() = status;
+    response
+}
+
+// Returns `None` for a receive that found nothing.
+fn respond(shared: &Shared, request: &Request<String>) -> Option<Response<String>> {
+    if let Err(detail) = check_token(shared, request) {
+        let mut response = status(StatusCode::UNAUTHORIZED);
+        *response.body_mut() =
+            format!("<Error><Code>401</Code><Detail>{}</Detail></Error>", detail);
+        return Some(response);
+    }
+
+    let path = request.uri().path().trim_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    let mut store = shared.store();
+    let response = match segments
+        .iter()
+        .position(|s| s.eq_ignore_ascii_case("messages"))
+    {
+        Some(i) => messaging(
+            &mut store,
+            request,
+            &segments[..i],
+            &segments[i + 1..],
+            shared.clock.now(),
+        )?,
+        None => management(shared, &mut store, request, path, &segments),
+    };
+    // Anything but a read may have changed the store.
+    if request.method() != Method::GET {
+        shared.changed();
+    }
+    Some(response)
+}
+
+// The token has to be signed with the emulator's key, cover the url and not
+// have expired. Like Service Bus, the error says which it was.
+fn check_token(shared: &Shared, request: &Request<String>) -> Result<(), &'static str> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or("MissingToken: The request has no authorization header.")?;
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map_or_else(|| shared.addr.to_string(), str::to_string);
+    let resource = format!("{}{}", host, request.uri().path());
+    let token =
+        sas::verify_for(header, &shared.key, shared.clock.unix_now(), &resource).map_err(|e| {
+            match e {
+                SasError::Expired(_) => "ExpiredToken: The token is expired.",
+                SasError::ResourceNotCovered(_) => {
+                    "InvalidAudience: The token does not grant access to this entity."
+                }
+                _ => "InvalidSignature: The token has an invalid signature.",
+            }
+        })?;
+    if token.key_name() != shared.key_name {
+        return Err("InvalidAudience: The token does not grant access to this entity.");
+    }
+    Ok(())
+}
+
+fn messaging(
+    store: &mut Store,
+    request: &Request<String>,
+    entity: &[&str],
+    rest: &[&str],
+    now: SystemTime,
+) -> Option<Response<String>> {
+    let is_dlq = |s: &&str| s.eq_ignore_ascii_case(DEAD_LETTER_QUEUE);
+    let (target, dead_letter) = match entity {
+        [name] => (Target::Queue(name), false),
+        [name, dlq] if is_dlq(dlq) => (Target::Queue(name), true),
+        [topic, subs, name] if subs.eq_ignore_ascii_case("subscriptions") => {
+            (Target::Subscription(topic, name), false)
+        }
+        [topic, subs, name, dlq] if subs.eq_ignore_ascii_case("subscriptions") && is_dlq(dlq) => {
+            (Target::Subscription(topic, name), true)
+        }
+        _ => return Some(status(StatusCode::NOT_FOUND)),
+    };
+
+    let settled = match (request.method(), rest, target) {
+        (&Method::POST, [], Target::Queue(name)) if !dead_letter => {
+            let headers = request.headers();
+            let envelope = Envelope {
+                props: headers
+                    .get(BROKER_PROPERTIES_HEADER)
+                    .and_then(|h| serde_json::from_str(h.to_str().ok()?).ok())
+                    .unwrap_or_default(),
+                content_type: headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|h| h.to_str().ok())
+                    .map(str::to_string),
+                properties: read_properties(headers),
+                body: request.body().clone(),
+            };
+            store
+                .send(name, envelope, now)
+                .map(|()| StatusCode::CREATED)
+        }
+        (&Method::POST, [head], _) | (&Method::DELETE, [head], _)
+            if head.eq_ignore_ascii_case("head") =>
+        {
+            let peek_lock = request.method() == Method::POST;
+            return match store.receive(target, dead_letter, peek_lock, now) {
+                Ok(Some(delivery)) => {
+                    let mut response = Response::new(delivery.body);
+                    *response.status_mut() = if peek_lock {
+                        StatusCode::CREATED
+                    } else {
+                        StatusCode::OK
+                    };
+                    let headers = response.headers_mut();
+                    if let Ok(props) = serde_json::to_string(&delivery.props) {
+                        if let Ok(value) = HeaderValue::from_str(&props) {
+                            headers.insert(BROKER_PROPERTIES_HEADER, value);
+                        }
+                    }
+                    if let Some(value) = delivery
+                        .content_type
+                        .and_then(|c| HeaderValue::from_str(&c).ok())
+                    {
+                        headers.insert(CONTENT_TYPE, value);
+                    }
+                    // Custom properties travel as headers with JSON values.
+                    if write_properties(headers, &delivery.properties).is_err() {
+                        return Some(status(StatusCode::INTERNAL_SERVER_ERROR));
+                    }
+                    if let Some(reason) = delivery.dead_letter_reason {
+                        if let Ok(value) = HeaderValue::from_str(&format!("\"{}\"", reason)) {
+                            headers.insert("DeadLetterReason", value);
+                        }
+                    }
+                    Some(response)
+                }
+                Ok(None) => None,
+                Err(fault) => Some(status(message_fault(fault))),
+            };
+        }
+        (&Method::DELETE, [id, token], _) => store
+            .complete(target, dead_letter, id, token, now)
+            .map(|()| StatusCode::OK),
+        (&Method::PUT, [id, token], _) => store
+            .abandon(target, dead_letter, id, token, now)
+            .map(|()| StatusCode::OK),
+        (&Method::POST, [id, token], _) => {
+            return Some(match store.renew(target, dead_letter, id, token, now) {
+                Ok(until) => {
+                    let props = BrokerProperties {
+                        LockedUntilUtc: Some(http_date(until)),
+                        ..Default::default()
+                    };
+                    let mut response = status(StatusCode::OK);
+                    if let Ok(value) = HeaderValue::from_str(&serde_json::to_string(&props).ok()?) {
+                        response
+                            .headers_mut()
+                            .insert(BROKER_PROPERTIES_HEADER, value);
+                    }
+                    response
+                }
+                Err(fault) => status(message_fault(fault)),
+            });
+        }
+        _ => Ok(StatusCode::NOT_FOUND),
+    };
+    Some(status(settled.unwrap_or_else(message_fault)))
+}
+
+fn message_fault(fault: Fault) -> StatusCode {
+    match fault {
+        // What the clients map to `ResourceNotFound`.
+        Fault::NotFound => StatusCode::GONE,
+        Fault::LockLost => StatusCode::NOT_FOUND,
+        Fault::Conflict => StatusCode::CONFLICT,
+        Fault::Invalid(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn management_fault(fault: Fault) -> Response<String> {
+    match fault {
+        Fault::NotFound | Fault::LockLost => status(StatusCode::NOT_FOUND),
+        Fault::Conflict => status(StatusCode::CONFLICT),
+        Fault::Invalid(reason) => {
+            let mut response = status(StatusCode::BAD_REQUEST);
+            *response.body_mut() = reason;
+            response
+        }
+    }
+}
+
+fn management(
+    shared: &Shared,
+    store: &mut Store,
+    request: &Request<String>,
+    path: &str,
+    segments: &[&str],
+) -> Response<String> {
+    let word = |i: usize, w: &str| segments[i].eq_ignore_ascii_case(w);
+
+    if request.method() == Method::GET {
+        let listing = match segments.len() {
+            2 if word(0, "$Resources") && word(1, "Queues") => Some(store.queues()),
+            2 if word(0, "$Resources") && word(1, "Topics") => Some(store.topics()),
+            2 if word(1, "Subscriptions") => store.subscriptions(segments[0]),
+            4 if word(1, "Subscriptions") && word(3, "Rules") => {
+                store.rules(segments[0], segments[2])
+            }
+            _ => None,
+        };
+        if let Some(entities) = listing {
+            return feed(shared, request, path, entities);
+        }
+    }
+
+    let entity_path = match EntityPath::parse(path) {
+        Some(p) => p,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    match *request.method() {
+        Method::GET => match store.get(&entity_path) {
+            Some(entity) => entry_response(shared, StatusCode::OK, &entity),
+            None => status(StatusCode::NOT_FOUND),
+        },
+        Method::PUT => {
+            let update = request.headers().contains_key(IF_MATCH);
+            let result = parse_entity(&entity_path, request.body())
+                .and_then(|entity| store.put(entity, update));
+            match result {
+                Ok(entity) => {
+                    let created = if update {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::CREATED
+                    };
+                    entry_response(shared, created, &entity)
+                }
+                Err(fault) => management_fault(fault),
+            }
+        }
+        Method::DELETE => match store.delete(&entity_path) {
+            Ok(()) => status(StatusCode::OK),
+            Err(fault) => management_fault(fault),
+        },
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// Reads the description out of a PUT. The name always comes from the url.
+fn parse_entity(path: &EntityPath, body: &str) -> Result<Entity, Fault> {
+    let invalid = |e: eyre::Report| Fault::Invalid(e.to_string());
+    let entry = Entry::parse(body).map_err(invalid)?;
+    let content = entry
+        .content
+        .ok_or_else(|| Fault::Invalid("The entry has no content.".to_string()))?;
+    let entity = match (path, content.name.as_str()) {
+        (EntityPath::Top(name), QueueDescription::ELEMENT) => {
+            Entity::Queue(QueueDescription::from_xml(name, &content).map_err(invalid)?)
+        }
+        (EntityPath::Top(name), TopicDescription::ELEMENT) => {
+            Entity::Topic(TopicDescription::from_xml(name, &content).map_err(invalid)?)
+        }
+        (EntityPath::Subscription(topic, name), SubscriptionDescription::ELEMENT) => {
+            let mut d = SubscriptionDescription::from_xml(name, &content).map_err(invalid)?;
+            d.topic = topic.clone();
+            Entity::Subscription(d)
+        }
+        (EntityPath::Rule(topic, subscription, name), RuleDescription::ELEMENT) => {
+            let mut d = RuleDescription::from_xml(name, &content).map_err(invalid)?;
+            d.topic = topic.clone();
+            d.subscription = subscription.clone();
+            Entity::Rule(d)
+        }
+        (_, element) => {
+            return Err(Fault::Invalid(format!(
+                "A {} can't be created at this path.",
+                element
+            )))
+        }
+    };
+    Ok(entity)
+}
+
+fn entity_name(entity: &Entity) -> &str {
+    match entity {
+        Entity::Queue(d) => &d.name,
+        Entity::Topic(d) => &d.name,
+        Entity::Subscription(d) => &d.name,
+        Entity::Rule(d) => &d.name,
+    }
+}
+
+// `to_xml` only writes what can be set, the counts are added on the way out.
+fn describe(entity: &Entity) -> String {
+    let xml = entity.to_xml();
+    let (element, counts) = match entity {
+        Entity::Queue(d) => (
+            QueueDescription::ELEMENT,
+            counts_xml(d.message_count, &d.count_details),
+        ),
+        Entity::Subscription(d) => (
+            SubscriptionDescription::ELEMENT,
+            counts_xml(d.message_count, &d.count_details),
+        ),
+        Entity::Topic(d) => (
+            TopicDescription::ELEMENT,
+            d.subscription_count
+                .map(|n| format!("<SubscriptionCount>{}</SubscriptionCount>", n))
+                .unwrap_or_default(),
+        ),
+        Entity::Rule(_) => return xml,
+    };
+    let close = format!("</{}>", element);
+    match xml.rfind(&close) {
+        Some(at) => format!("{}{}{}", &xml[..at], counts, &xml[at..]),
+        None => xml,
+    }
+}
+
+fn counts_xml(message_count: Option<u64>, details: &Option<CountDetails>) -> String {
+    let mut xml = String::new();
+    if let Some(n) = message_count {
+        xml.push_str(&format!("<MessageCount>{}</MessageCount>", n));
+    }
+    if let Some(d) = details {
+        xml.push_str(&format!(
+            "<CountDetails><ActiveMessageCount>{}</ActiveMessageCount>\
+             <DeadLetterMessageCount>{}</DeadLetterMessageCount>\
+             <ScheduledMessageCount>{}</ScheduledMessageCount>\
+             <TransferMessageCount>{}</TransferMessageCount>\
+             <TransferDeadLetterMessageCount>{}</TransferDeadLetterMessageCount></CountDetails>",
+            d.active_message_count,
+            d.dead_letter_message_count,
+            d.scheduled_message_count,
+            d.transfer_message_count,
+            d.transfer_dead_letter_message_count
+        ));
+    }
+    xml
+}
+
+fn entry(shared: &Shared, entity: &Entity) -> String {
+    format!(
+        "<entry xmlns=\"{}\"><id>http://{}/{}</id><title type=\"text\">{}</title>\
+         <content type=\"application/xml\">{}</content></entry>",
+        ATOM_NS,
+        shared.addr,
+        entity.path(),
+        entity_name(entity),
+        describe(entity)
+    )
+}
+
+fn entry_response(shared: &Shared, code: StatusCode, entity: &Entity) -> Response<String> {
+    let mut response = Response::new(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>{}",
+        entry(shared, entity)
+    ));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(ENTRY_CONTENT_TYPE));
+    response
+}
+
+// A page of a listing, honouring `$skip` and `$top`.
+fn feed(
+    shared: &Shared,
+    request: &Request<String>,
+    path: &str,
+    entities: Vec<Entity>,
+) -> Response<String> {
+    let query = request.uri().query().unwrap_or("");
+    let param = |name: &str| -> Option<usize> {
+        query
+            .split('&')
+            .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|v| v.parse().ok())
+    };
+    let entries: String = entities
+        .iter()
+        .skip(param("$skip").unwrap_or(0))
+        .take(param("$top").unwrap_or(usize::MAX))
+        .map(|e| entry(shared, e))
+        .collect();
+    let mut response = Response::new(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><feed xmlns=\"{}\">\
+         <title type=\"text\">{}</title>{}</feed>",
+        ATOM_NS, path, entries
+    ));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(FEED_CONTENT_TYPE));
+    response
+}
@@ -0,0 +1,1545 @@
+// Everything the emulator keeps in memory, with no http in sight. Every
+// operation takes the current time so the tests can move the clock by hand.
+
+use super::filter::{Action, Filter};
+use crate::core::clock::{http_date, parse_http_date};
+use crate::servicebus::brokeredmessage::BrokerProperties;
+use crate::servicebus::description::*;
+use crate::servicebus::topology::{forward_target, SubscriptionTopology, TopicTopology, Topology};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Service Bus defaults for entities created without the setting.
+pub const DEFAULT_LOCK_DURATION: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_DELIVERY_COUNT: u32 = 10;
+pub const DEFAULT_DUPLICATE_DETECTION_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// The longest lock Service Bus hands out. Longer `LockDuration`s are cut
+/// down to this.
+pub const MAX_LOCK_DURATION: Duration = Duration::from_secs(5 * 60);
+/// How many times a message can be auto forwarded before it is dead lettered.
+pub const MAX_TRANSFER_HOPS: u32 = 4;
+
+/// The reasons Service Bus gives when it moves a message to the dead letter queue.
+pub const MAX_DELIVERY_COUNT_EXCEEDED: &str = "MaxDeliveryCountExceeded";
+pub const TTL_EXPIRED: &str = "TTLExpiredException";
+pub const FILTER_EVALUATION_EXCEPTION: &str = "FilterEvaluationException";
+pub const MAX_TRANSFER_HOP_COUNT_EXCEEDED: &str = "MaxTransferHopCountExceeded";
+
+// The custom property a forwarded dead letter carries its reason in.
+const DEAD_LETTER_REASON_PROPERTY: &str = "deadletterreason";
+
+/// Why an operation was turned down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// The queue, topic or subscription doesn't exist.
+    NotFound,
+    /// The lock token is unknown or the lock has expired.
+    LockLost,
+    /// The entity already exists.
+    Conflict,
+    Invalid(String),
+}
+
+/// Which message store a receive or settle goes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Target<'a> {
+    Queue(&'a str),
+    Subscription(&'a str, &'a str),
+}
+
+/// A message as the sender wrote it. It is what filters look at and what
+/// rule actions change.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub props: BrokerProperties,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, Value>,
+    pub body: String,
+}
+
+/// A message as it is handed to a receiver.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Delivery {
+    pub props: BrokerProperties,
+    pub content_type: Option<String>,
+    pub properties: BTreeMap<String, Value>,
+    pub body: String,
+    pub dead_letter_reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Message {
+    #[serde(flatten)]
+    envelope: Envelope,
+    sequence: usize,
+    enqueued: SystemTime,
+    expires: Option<SystemTime>,
+    delivery_count: u32,
+    dead_letter_reason: Option<String>,
+    // How many times it has been auto forwarded.
+    #[serde(default)]
+    hops: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Lock {
+    token: String,
+    until: SystemTime,
+    message: Message,
+}
+
+// Messages in sequence order, plus the ones that are locked by a receiver.
+#[derive(Default, Serialize, Deserialize)]
+struct Messages {
+    active: VecDeque<Message>,
+    locked: Vec<Lock>,
+}
+
+impl Messages {
+    fn insert(&mut self, message: Message) {
+        let at = self
+            .active
+            .iter()
+            .position(|m| m.sequence > message.sequence)
+            .unwrap_or(self.active.len());
+        self.active.insert(at, message);
+    }
+
+    fn unlock(&mut self, id: &str, token: &str, now: SystemTime) -> Result<Message, Fault> {
+        let idx = self
+            .locked
+            .iter()
+            .position(|l| {
+                l.token.eq_ignore_ascii_case(token)
+                    && l.until > now
+                    && (l.message.sequence.to_string() == id
+                        || l.message.envelope.props.MessageId.as_deref() == Some(id))
+            })
+            .ok_or(Fault::LockLost)?;
+        Ok(self.locked.swap_remove(idx).message)
+    }
+
+    fn expire_locks(&mut self, now: SystemTime) -> Vec<Message> {
+        let (expired, held): (Vec<Lock>, Vec<Lock>) = std::mem::take(&mut self.locked)
+            .into_iter()
+            .partition(|l| l.until <= now);
+        self.locked = held;
+        expired.into_iter().map(|l| l.message).collect()
+    }
+
+    fn len(&self) -> u64 {
+        (self.active.len() + self.locked.len()) as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    lock_duration: Duration,
+    max_delivery_count: u32,
+    default_ttl: Option<Duration>,
+    dead_letter_on_expiration: bool,
+    forward_dead_letters: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            lock_duration: DEFAULT_LOCK_DURATION,
+            max_delivery_count: DEFAULT_MAX_DELIVERY_COUNT,
+            default_ttl: None,
+            dead_letter_on_expiration: false,
+            forward_dead_letters: false,
+        }
+    }
+}
+
+/// A queue or a subscription: somewhere messages can be received from.
+/// Only the messages are saved, the settings come from the description.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MessageEntity {
+    #[serde(skip)]
+    settings: Settings,
+    messages: Messages,
+    dead_letter: Messages,
+    // Sent with a `ScheduledEnqueueTimeUtc` that hasn't come yet.
+    #[serde(default)]
+    scheduled: Vec<Message>,
+    // Dead letters on their way to `ForwardDeadLetteredMessagesTo`. The store
+    // empties it after every operation, so it is never saved.
+    #[serde(skip)]
+    outbox: Vec<Message>,
+    next_sequence: usize,
+}
+
+impl MessageEntity {
+    fn new(settings: Settings) -> Self {
+        MessageEntity {
+            settings,
+            messages: Messages::default(),
+            dead_letter: Messages::default(),
+            scheduled: Vec::new(),
+            outbox: Vec::new(),
+            next_sequence: 1,
+        }
+    }
+
+    // Gives the message its sequence number and expiry. A scheduled message
+    // counts as enqueued at its scheduled time.
+    fn enqueue(&mut self, envelope: Envelope, hops: u32, now: SystemTime) -> Message {
+        let enqueued = scheduled_time(&envelope.props)
+            .ok()
+            .flatten()
+            .map_or(now, |at| at.max(now));
+        let ttl = envelope
+            .props
+            .TimeToLive
+            .map(|s| Duration::from_secs(s as u64));
+        let ttl = match (ttl, self.settings.default_ttl) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let message = Message {
+            envelope,
+            sequence: self.next_sequence,
+            enqueued,
+            expires: ttl.and_then(|ttl| enqueued.checked_add(ttl)),
+            delivery_count: 0,
+            dead_letter_reason: None,
+            hops,
+        };
+        self.next_sequence += 1;
+        message
+    }
+
+    fn send(&mut self, envelope: Envelope, hops: u32, now: SystemTime) {
+        let message = self.enqueue(envelope, hops, now);
+        if message.enqueued > now {
+            self.scheduled.push(message);
+        } else {
+            self.messages.active.push_back(message);
+        }
+    }
+
+    // Dead letters a message that never made it into the queue.
+    fn reject(&mut self, envelope: Envelope, hops: u32, reason: &str, now: SystemTime) {
+        let message = self.enqueue(envelope, hops, now);
+        self.dead_letter(message, reason);
+    }
+
+    // Releases expired locks, enqueues scheduled messages that are due and
+    // drops or dead letters expired messages.
+    fn sweep(&mut self, now: SystemTime) {
+        for message in self.messages.expire_locks(now) {
+            self.release(message);
+        }
+        let (due, later): (Vec<Message>, Vec<Message>) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|m| m.enqueued <= now);
+        self.scheduled = later;
+        for message in due {
+            self.messages.insert(message);
+        }
+        for message in self.dead_letter.expire_locks(now) {
+            self.dead_letter.insert(message);
+        }
+
+        let (expired, live): (VecDeque<Message>, VecDeque<Message>) =
+            std::mem::take(&mut self.messages.active)
+                .into_iter()
+                .partition(|m| m.expires.is_some_and(|e| e <= now));
+        self.messages.active = live;
+        if self.settings.dead_letter_on_expiration {
+            for message in expired {
+                self.dead_letter(message, TTL_EXPIRED);
+            }
+        }
+    }
+
+    // A message that was locked goes back to the queue, unless it has been
+    // delivered too many times already.
+    fn release(&mut self, message: Message) {
+        if message.delivery_count >= self.settings.max_delivery_count {
+            self.dead_letter(message, MAX_DELIVERY_COUNT_EXCEEDED);
+        } else {
+            self.messages.insert(message);
+        }
+    }
+
+    fn dead_letter(&mut self, mut message: Message, reason: &str) {
+        message.dead_letter_reason = Some(reason.to_string());
+        if self.settings.forward_dead_letters && message.hops < MAX_TRANSFER_HOPS {
+            self.outbox.push(message);
+        } else {
+            self.dead_letter.insert(message);
+        }
+    }
+
+    fn store(&mut self, dead_letter: bool) -> &mut Messages {
+        if dead_letter {
+            &mut self.dead_letter
+        } else {
+            &mut self.messages
+        }
+    }
+
+    fn receive(&mut self, dead_letter: bool, peek_lock: bool, now: SystemTime) -> Option<Delivery> {
+        self.sweep(now);
+        let lock_duration = self.settings.lock_duration;
+        let store = self.store(dead_letter);
+        let mut message = store.active.pop_front()?;
+        message.delivery_count += 1;
+
+        let mut props = message.envelope.props.clone();
+        props.SequenceNumber = Some(message.sequence);
+        props.EnqueuedSequenceNumber = Some(message.sequence);
+        props.DeliveryCount = Some(message.delivery_count as usize);
+        props.EnqueuedTimeUtc = Some(http_date(message.enqueued));
+        props.State = Some(
+            if dead_letter {
+                "Deadlettered"
+            } else {
+                "Active"
+            }
+            .to_string(),
+        );
+        if let Some(expires) = message.expires {
+            let ttl = expires.duration_since(message.enqueued).unwrap_or_default();
+            props.TimeToLive = Some(ttl.as_secs() as usize);
+        }
+        let delivery = Delivery {
+            props,
+            content_type: message.envelope.content_type.clone(),
+            properties: message.envelope.properties.clone(),
+            body: message.envelope.body.clone(),
+            dead_letter_reason: message.dead_letter_reason.clone(),
+        };
+
+        if !peek_lock {
+            return Some(delivery);
+        }
+        let until = now + lock_duration;
+        let token = lock_token();
+        let mut delivery = delivery;
+        delivery.props.LockToken = Some(token.clone());
+        delivery.props.LockedUntilUtc = Some(http_date(until));
+        store.locked.push(Lock {
+            token,
+            until,
+            message,
+        });
+        Some(delivery)
+    }
+
+    fn complete(
+        &mut self,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<(), Fault> {
+        self.sweep(now);
+        self.store(dead_letter).unlock(id, token, now).map(drop)
+    }
+
+    fn abandon(
+        &mut self,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<(), Fault> {
+        self.sweep(now);
+        let message = self.store(dead_letter).unlock(id, token, now)?;
+        if dead_letter {
+            self.dead_letter.insert(message);
+        } else {
+            self.release(message);
+        }
+        Ok(())
+    }
+
+    fn renew(
+        &mut self,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<SystemTime, Fault> {
+        self.sweep(now);
+        let until = now + self.settings.lock_duration;
+        let store = self.store(dead_letter);
+        let message = store.unlock(id, token, now)?;
+        store.locked.push(Lock {
+            token: token.to_string(),
+            until,
+            message,
+        });
+        Ok(until)
+    }
+
+    fn counts(&self) -> CountDetails {
+        CountDetails {
+            active_message_count: self.messages.len(),
+            dead_letter_message_count: self.dead_letter.len(),
+            scheduled_message_count: self.scheduled.len() as u64,
+            ..Default::default()
+        }
+    }
+}
+
+impl Settings {
+    fn for_queue(d: &QueueDescription) -> Self {
+        Settings {
+            lock_duration: d
+                .lock_duration
+                .unwrap_or(DEFAULT_LOCK_DURATION)
+                .min(MAX_LOCK_DURATION),
+            max_delivery_count: d.max_delivery_count.unwrap_or(DEFAULT_MAX_DELIVERY_COUNT),
+            default_ttl: d.default_message_time_to_live,
+            dead_letter_on_expiration: d.dead_lettering_on_message_expiration.unwrap_or(false),
+            forward_dead_letters: d.forward_dead_lettered_messages_to.is_some(),
+        }
+    }
+
+    fn for_subscription(d: &SubscriptionDescription) -> Self {
+        Settings {
+            lock_duration: d
+                .lock_duration
+                .unwrap_or(DEFAULT_LOCK_DURATION)
+                .min(MAX_LOCK_DURATION),
+            max_delivery_count: d.max_delivery_count.unwrap_or(DEFAULT_MAX_DELIVERY_COUNT),
+            default_ttl: d.default_message_time_to_live,
+            dead_letter_on_expiration: d.dead_lettering_on_message_expiration.unwrap_or(false),
+            forward_dead_letters: d.forward_dead_lettered_messages_to.is_some(),
+        }
+    }
+}
+
+// The message ids a queue or topic has seen within its duplicate detection window.
+#[derive(Default, Serialize, Deserialize)]
+struct History(BTreeMap<String, SystemTime>);
+
+impl History {
+    // Remembers the message and says whether it is a duplicate.
+    fn is_duplicate(
+        &mut self,
+        enabled: Option<bool>,
+        window: Option<Duration>,
+        envelope: &Envelope,
+        now: SystemTime,
+    ) -> bool {
+        let id = match (enabled, &envelope.props.MessageId) {
+            (Some(true), Some(id)) => id,
+            _ => return false,
+        };
+        let window = window.unwrap_or(DEFAULT_DUPLICATE_DETECTION_WINDOW);
+        self.0
+            .retain(|_, seen| seen.checked_add(window).is_some_and(|until| until > now));
+        if self.0.contains_key(id) {
+            return true;
+        }
+        self.0.insert(id.clone(), now);
+        false
+    }
+}
+
+struct QueueState {
+    description: QueueDescription,
+    entity: MessageEntity,
+    history: History,
+}
+
+struct TopicState {
+    description: TopicDescription,
+    subscriptions: BTreeMap<String, SubscriptionState>,
+    history: History,
+}
+
+struct SubscriptionState {
+    description: SubscriptionDescription,
+    rules: BTreeMap<String, RuleDescription>,
+    entity: MessageEntity,
+}
+
+/// An entity named by its management path, e.g. `orders/Subscriptions/audit`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum EntityPath {
+    /// A queue or a topic, they share one namespace.
+    Top(String),
+    Subscription(String, String),
+    Rule(String, String, String),
+}
+
+impl EntityPath {
+    pub fn parse(path: &str) -> Option<EntityPath> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let is = |i: usize, word: &str| segments[i].eq_ignore_ascii_case(word);
+        match segments.len() {
+            1 if !segments[0].is_empty() => Some(EntityPath::Top(segments[0].to_string())),
+            3 if is(1, "subscriptions") => Some(EntityPath::Subscription(
+                segments[0].to_string(),
+                segments[2].to_string(),
+            )),
+            5 if is(1, "subscriptions") && is(3, "rules") => Some(EntityPath::Rule(
+                segments[0].to_string(),
+                segments[2].to_string(),
+                segments[4].to_string(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+// What `Store::to_json` writes. The messages and the duplicate detection
+// history are keyed by entity path.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<M, H> {
+    topology: Topology,
+    messages: BTreeMap<String, M>,
+    #[serde(default = "BTreeMap::new", skip_serializing_if = "BTreeMap::is_empty")]
+    history: BTreeMap<String, H>,
+}
+
+// Entity names are case insensitive.
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Every entity in the emulated namespace.
+#[derive(Default)]
+pub(crate) struct Store {
+    queues: BTreeMap<String, QueueState>,
+    topics: BTreeMap<String, TopicState>,
+}
+
+impl Store {
+    /// Creates an entity, or with `update` changes the settings of an
+    /// existing one. Messages already in a queue or subscription are kept.
+    pub fn put(&mut self, entity: Entity, update: bool) -> Result<Entity, Fault> {
+        match entity {
+            Entity::Queue(d) => {
+                if self.topics.contains_key(&key(&d.name)) {
+                    return Err(Fault::Conflict);
+                }
+                let settings = Settings::for_queue(&d);
+                match (self.queues.get_mut(&key(&d.name)), update) {
+                    (Some(_), false) => return Err(Fault::Conflict),
+                    (None, true) => return Err(Fault::NotFound),
+                    (Some(q), true) => {
+                        q.entity.settings = settings;
+                        q.description = d.clone();
+                    }
+                    (None, false) => {
+                        let state = QueueState {
+                            description: d.clone(),
+                            entity: MessageEntity::new(settings),
+                            history: History::default(),
+                        };
+                        self.queues.insert(key(&d.name), state);
+                    }
+                }
+                Ok(Entity::Queue(d))
+            }
+            Entity::Topic(d) => {
+                if self.queues.contains_key(&key(&d.name)) {
+                    return Err(Fault::Conflict);
+                }
+                match (self.topics.get_mut(&key(&d.name)), update) {
+                    (Some(_), false) => return Err(Fault::Conflict),
+                    (None, true) => return Err(Fault::NotFound),
+                    (Some(t), true) => t.description = d.clone(),
+                    (None, false) => {
+                        let state = TopicState {
+                            description: d.clone(),
+                            subscriptions: BTreeMap::new(),
+                            history: History::default(),
+                        };
+                        self.topics.insert(key(&d.name), state);
+                    }
+                }
+                Ok(Entity::Topic(d))
+            }
+            Entity::Subscription(d) => {
+                let topic = self.topics.get_mut(&key(&d.topic)).ok_or(Fault::NotFound)?;
+                let settings = Settings::for_subscription(&d);
+                match (topic.subscriptions.get_mut(&key(&d.name)), update) {
+                    (Some(_), false) => return Err(Fault::Conflict),
+                    (None, true) => return Err(Fault::NotFound),
+                    (Some(s), true) => {
+                        s.entity.settings = settings;
+                        s.description = d.clone();
+                    }
+                    (None, false) => {
+                        // Like Service Bus, a new subscription gets every message
+                        // until its rules are changed.
+                        let default =
+                            RuleDescription::new(&d.topic, &d.name, "$Default", RuleFilter::True);
+                        let state = SubscriptionState {
+                            description: d.clone(),
+                            rules: std::iter::once((key(&default.name), default)).collect(),
+                            entity: MessageEntity::new(settings),
+                        };
+                        topic.subscriptions.insert(key(&d.name), state);
+                    }
+                }
+                Ok(Entity::Subscription(d))
+            }
+            Entity::Rule(d) => {
+                Filter::parse(&d.filter).map_err(Fault::Invalid)?;
+                if let Some(action) = &d.action {
+                    Action::parse(action).map_err(Fault::Invalid)?;
+                }
+                let sub = self.subscription_mut(&d.topic, &d.subscription)?;
+                match (sub.rules.contains_key(&key(&d.name)), update) {
+                    (true, false) => return Err(Fault::Conflict),
+                    (false, true) => return Err(Fault::NotFound),
+                    _ => {}
+                }
+                sub.rules.insert(key(&d.name), d.clone());
+                Ok(Entity::Rule(d))
+            }
+        }
+    }
+
+    /// Looks up an entity, with its message counts filled in.
+    pub fn get(&self, path: &EntityPath) -> Option<Entity> {
+        match path {
+            EntityPath::Top(name) => {
+                if let Some(q) = self.queues.get(&key(name)) {
+                    return Some(Entity::Queue(q.describe()));
+                }
+                self.topics
+                    .get(&key(name))
+                    .map(|t| Entity::Topic(t.describe()))
+            }
+            EntityPath::Subscription(topic, name) => self
+                .topics
+                .get(&key(topic))?
+                .subscriptions
+                .get(&key(name))
+                .map(|s| Entity::Subscription(s.describe())),
+            EntityPath::Rule(topic, sub, name) => self
+                .topics
+                .get(&key(topic))?
+                .subscriptions
+                .get(&key(sub))?
+                .rules
+                .get(&key(name))
+                .map(|r| Entity::Rule(r.clone())),
+        }
+    }
+
+    /// Deletes an entity and everything below it.
+    pub fn delete(&mut self, path: &EntityPath) -> Result<(), Fault> {
+        let removed = match path {
+            EntityPath::Top(name) => {
+                self.queues.remove(&key(name)).is_some() || self.topics.remove(&key(name)).is_some()
+            }
+            EntityPath::Subscription(topic, name) => self
+                .topics
+                .get_mut(&key(topic))
+                .and_then(|t| t.subscriptions.remove(&key(name)))
+                .is_some(),
+            EntityPath::Rule(topic, sub, name) => self
+                .subscription_mut(topic, sub)?
+                .rules
+                .remove(&key(name))
+                .is_some(),
+        };
+        if removed {
+            Ok(())
+        } else {
+            Err(Fault::NotFound)
+        }
+    }
+
+    pub fn queues(&self) -> Vec<Entity> {
+        self.queues
+            .values()
+            .map(|q| Entity::Queue(q.describe()))
+            .collect()
+    }
+
+    pub fn topics(&self) -> Vec<Entity> {
+        self.topics
+            .values()
+            .map(|t| Entity::Topic(t.describe()))
+            .collect()
+    }
+
+    pub fn subscriptions(&self, topic: &str) -> Option<Vec<Entity>> {
+        let topic = self.topics.get(&key(topic))?;
+        Some(
+            topic
+                .subscriptions
+                .values()
+                .map(|s| Entity::Subscription(s.describe()))
+                .collect(),
+        )
+    }
+
+    pub fn rules(&self, topic: &str, subscription: &str) -> Option<Vec<Entity>> {
+        let sub = self
+            .topics
+            .get(&key(topic))?
+            .subscriptions
+            .get(&key(subscription))?;
+        Some(sub.rules.values().cloned().map(Entity::Rule).collect())
+    }
+
+    /// Sends a message to a queue, or to the subscriptions of a topic whose
+    /// rules it matches, following `ForwardTo` along the way. A duplicate is
+    /// accepted and dropped, as Service Bus does.
+    pub fn send(&mut self, name: &str, envelope: Envelope, now: SystemTime) -> Result<(), Fault> {
+        scheduled_time(&envelope.props)?;
+        if !self.deliver(name, envelope, 0, now) {
+            return Err(Fault::NotFound);
+        }
+        self.forward_dead_letters(now);
+        Ok(())
+    }
+
+    // Returns false if there is no queue or topic called `name`.
+    fn deliver(&mut self, name: &str, envelope: Envelope, hops: u32, now: SystemTime) -> bool {
+        if let Some(q) = self.queues.get_mut(&key(name)) {
+            let d = &q.description;
+            let (enabled, window) = (
+                d.requires_duplicate_detection,
+                d.duplicate_detection_history_time_window,
+            );
+            if q.history.is_duplicate(enabled, window, &envelope, now) {
+                return true;
+            }
+            let target = d.forward_to.as_deref().map(forward_target);
+            if target.is_some() && hops >= MAX_TRANSFER_HOPS {
+                q.entity
+                    .reject(envelope, hops, MAX_TRANSFER_HOP_COUNT_EXCEEDED, now);
+                return true;
+            }
+            // A forwarding target that has been deleted leaves messages where they are.
+            match target.filter(|t| self.exists(t)) {
+                Some(target) => return self.deliver(&target, envelope, hops + 1, now),
+                None => {
+                    if let Some(q) = self.queues.get_mut(&key(name)) {
+                        q.entity.send(envelope, hops, now);
+                    }
+                }
+            }
+            return true;
+        }
+
+        let topic = match self.topics.get_mut(&key(name)) {
+            Some(topic) => topic,
+            None => return false,
+        };
+        let d = &topic.description;
+        let (enabled, window) = (
+            d.requires_duplicate_detection,
+            d.duplicate_detection_history_time_window,
+        );
+        if topic.history.is_duplicate(enabled, window, &envelope, now) {
+            return true;
+        }
+        let mut envelope = envelope;
+        if let Some(ttl) = topic.description.default_message_time_to_live {
+            let ttl = ttl.as_secs() as usize;
+            let props = &mut envelope.props;
+            props.TimeToLive = Some(props.TimeToLive.map_or(ttl, |t| t.min(ttl)));
+        }
+
+        let mut forwards = Vec::new();
+        for (sub_key, sub) in topic.subscriptions.iter_mut() {
+            let forward_to = sub.description.forward_to.as_deref().map(forward_target);
+            for copy in sub.copies(&envelope) {
+                match (copy, &forward_to) {
+                    (Ok(copy), Some(_)) if hops >= MAX_TRANSFER_HOPS => {
+                        sub.entity
+                            .reject(copy, hops, MAX_TRANSFER_HOP_COUNT_EXCEEDED, now);
+                    }
+                    (Ok(copy), Some(target)) => {
+                        forwards.push((sub_key.clone(), target.clone(), copy))
+                    }
+                    (Ok(copy), None) => sub.entity.send(copy, hops, now),
+                    (Err(_), _) => {
+                        if sub
+                            .description
+                            .dead_lettering_on_filter_evaluation_exceptions
+                            .unwrap_or(false)
+                        {
+                            sub.entity.reject(
+                                envelope.clone(),
+                                hops,
+                                FILTER_EVALUATION_EXCEPTION,
+                                now,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let topic_key = key(name);
+        for (sub_key, target, copy) in forwards {
+            if !self.exists(&target) || !self.deliver(&target, copy.clone(), hops + 1, now) {
+                if let Ok(sub) = self.subscription_mut(&topic_key, &sub_key) {
+                    sub.entity.send(copy, hops, now);
+                }
+            }
+        }
+        true
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.queues.contains_key(&key(name)) || self.topics.contains_key(&key(name))
+    }
+
+    // Moves dead letters to the entities named by `ForwardDeadLetteredMessagesTo`.
+    // A message only ever travels `MAX_TRANSFER_HOPS` times, so this ends.
+    fn forward_dead_letters(&mut self, now: SystemTime) {
+        loop {
+            let mut pending = Vec::new();
+            for q in self.queues.values_mut() {
+                if !q.entity.outbox.is_empty() {
+                    let target = q.description.forward_dead_lettered_messages_to.clone();
+                    let messages = std::mem::take(&mut q.entity.outbox);
+                    pending.push((q.description.path(), target, messages));
+                }
+            }
+            for t in self.topics.values_mut() {
+                for s in t.subscriptions.values_mut() {
+                    if !s.entity.outbox.is_empty() {
+                        let target = s.description.forward_dead_lettered_messages_to.clone();
+                        let messages = std::mem::take(&mut s.entity.outbox);
+                        pending.push((s.description.path(), target, messages));
+                    }
+                }
+            }
+            if pending.is_empty() {
+                return;
+            }
+
+            for (source, target, messages) in pending {
+                let target = target.as_deref().map(forward_target).unwrap_or_default();
+                for message in messages {
+                    let mut envelope = message.envelope.clone();
+                    envelope.props.DeadLetterSource = Some(source.clone());
+                    if let Some(reason) = &message.dead_letter_reason {
+                        envelope.properties.insert(
+                            DEAD_LETTER_REASON_PROPERTY.to_string(),
+                            Value::from(reason.as_str()),
+                        );
+                    }
+                    if !self.exists(&target)
+                        || !self.deliver(&target, envelope, message.hops + 1, now)
+                    {
+                        if let Some(entity) = self.entity_at(&source) {
+                            entity.dead_letter.insert(message);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the next message, locking it for the receiver when `peek_lock`
+    /// and deleting it otherwise.
+    pub fn receive(
+        &mut self,
+        target: Target,
+        dead_letter: bool,
+        peek_lock: bool,
+        now: SystemTime,
+    ) -> Result<Option<Delivery>, Fault> {
+        let delivery = self
+            .entity_mut(target)?
+            .receive(dead_letter, peek_lock, now);
+        self.forward_dead_letters(now);
+        Ok(delivery)
+    }
+
+    pub fn complete(
+        &mut self,
+        target: Target,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<(), Fault> {
+        let result = self
+            .entity_mut(target)?
+            .complete(dead_letter, id, token, now);
+        self.forward_dead_letters(now);
+        result
+    }
+
+    pub fn abandon(
+        &mut self,
+        target: Target,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<(), Fault> {
+        let result = self
+            .entity_mut(target)?
+            .abandon(dead_letter, id, token, now);
+        self.forward_dead_letters(now);
+        result
+    }
+
+    /// Extends a lock by the entity's lock duration and returns when it runs out now.
+    pub fn renew(
+        &mut self,
+        target: Target,
+        dead_letter: bool,
+        id: &str,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<SystemTime, Fault> {
+        let result = self.entity_mut(target)?.renew(dead_letter, id, token, now);
+        self.forward_dead_letters(now);
+        result
+    }
+
+    /// Every entity as a topology, rules included.
+    pub fn topology(&self) -> Topology {
+        Topology {
+            queues: self
+                .queues
+                .values()
+                .map(|q| q.description.clone())
+                .collect(),
+            topics: self
+                .topics
+                .values()
+                .map(|t| TopicTopology {
+                    description: t.description.clone(),
+                    subscriptions: t
+                        .subscriptions
+                        .values()
+                        .map(|s| SubscriptionTopology {
+                            description: s.description.clone(),
+                            rules: Some(s.rules.values().cloned().collect()),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Creates the entities of a topology, or updates them if they already
+    /// exist. Where a subscription lists its rules they replace the current ones.
+    pub fn apply(&mut self, topology: &Topology) -> Result<(), Fault> {
+        for q in &topology.queues {
+            self.upsert(Entity::Queue(q.clone()))?;
+        }
+        for t in &topology.topics {
+            self.upsert(Entity::Topic(t.description.clone()))?;
+            for s in &t.subscriptions {
+                self.upsert(Entity::Subscription(s.description.clone()))?;
+                if let Some(rules) = &s.rules {
+                    let sub = self.subscription_mut(&s.description.topic, &s.description.name)?;
+                    sub.rules = rules.iter().map(|r| (key(&r.name), r.clone())).collect();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an entity, or changes its settings if it already exists.
+    pub fn upsert(&mut self, entity: Entity) -> Result<Entity, Fault> {
+        let exists = EntityPath::parse(&entity.path()).is_some_and(|p| self.get(&p).is_some());
+        self.put(entity, exists)
+    }
+
+    /// Serializes the entities and every message in them, locked ones included.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        let mut messages = BTreeMap::new();
+        let mut history = BTreeMap::new();
+        for q in self.queues.values() {
+            messages.insert(q.description.path(), &q.entity);
+            if !q.history.0.is_empty() {
+                history.insert(q.description.path(), &q.history);
+            }
+        }
+        for t in self.topics.values() {
+            if !t.history.0.is_empty() {
+                history.insert(t.description.path(), &t.history);
+            }
+            for s in t.subscriptions.values() {
+                messages.insert(s.description.path(), &s.entity);
+            }
+        }
+        serde_json::to_string(&Snapshot {
+            topology: self.topology(),
+            messages,
+            history,
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Store, Fault> {
+        let invalid = |e: String| Fault::Invalid(e);
+        let mut snapshot: Snapshot<MessageEntity, History> =
+            serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
+        snapshot.topology.link_parents();
+        let mut store = Store::default();
+        store.apply(&snapshot.topology)?;
+        for (path, mut saved) in snapshot.messages {
+            let entity = store
+                .entity_at(&path)
+                .ok_or_else(|| invalid(format!("Messages saved for unknown entity {}.", path)))?;
+            saved.settings = entity.settings;
+            *entity = saved;
+        }
+        for (name, saved) in snapshot.history {
+            let history = match (
+                store.queues.get_mut(&key(&name)),
+                store.topics.get_mut(&key(&name)),
+            ) {
+                (Some(q), _) => &mut q.history,
+                (_, Some(t)) => &mut t.history,
+                _ => {
+                    return Err(invalid(format!(
+                        "History saved for unknown entity {}.",
+                        name
+                    )))
+                }
+            };
+            *history = saved;
+        }
+        Ok(store)
+    }
+
+    // The queue or subscription at a path like `events/Subscriptions/audit`.
+    fn entity_at(&mut self, path: &str) -> Option<&mut MessageEntity> {
+        match EntityPath::parse(path)? {
+            EntityPath::Top(name) => self.entity_mut(Target::Queue(&name)).ok(),
+            EntityPath::Subscription(topic, name) => {
+                self.entity_mut(Target::Subscription(&topic, &name)).ok()
+            }
+            EntityPath::Rule(..) => None,
+        }
+    }
+
+    fn entity_mut(&mut self, target: Target) -> Result<&mut MessageEntity, Fault> {
+        match target {
+            Target::Queue(name) => self
+                .queues
+                .get_mut(&key(name))
+                .map(|q| &mut q.entity)
+                .ok_or(Fault::NotFound),
+            Target::Subscription(topic, name) => {
+                self.subscription_mut(topic, name).map(|s| &mut s.entity)
+            }
+        }
+    }
+
+    fn subscription_mut(
+        &mut self,
+        topic: &str,
+        name: &str,
+    ) -> Result<&mut SubscriptionState, Fault> {
+        self.topics
+            .get_mut(&key(topic))
+            .and_then(|t| t.subscriptions.get_mut(&key(name)))
+            .ok_or(Fault::NotFound)
+    }
+}
+
+impl QueueState {
+    fn describe(&self) -> QueueDescription {
+        let counts = self.entity.counts();
+        QueueDescription {
+            message_count: Some(counts.active_message_count + counts.dead_letter_message_count),
+            count_details: Some(counts),
+            ..self.description.clone()
+        }
+    }
+}
+
+impl TopicState {
+    fn describe(&self) -> TopicDescription {
+        TopicDescription {
+            subscription_count: Some(self.subscriptions.len() as u64),
+            ..self.description.clone()
+        }
+    }
+}
+
+impl SubscriptionState {
+    // A copy of the message for every rule whose filter matches it, changed by
+    // that rule's action. With `$Default` still in place next to other rules
+    // that means duplicates, just like on Service Bus.
+    fn copies(&self, envelope: &Envelope) -> Vec<Result<Envelope, String>> {
+        let copy = |rule: &RuleDescription| -> Result<Option<Envelope>, String> {
+            if !Filter::parse(&rule.filter)?.matches(envelope)? {
+                return Ok(None);
+            }
+            let mut copy = envelope.clone();
+            if let Some(action) = &rule.action {
+                Action::parse(action)?.apply(&mut copy)?;
+            }
+            Ok(Some(copy))
+        };
+        self.rules
+            .values()
+            .filter_map(|rule| copy(rule).transpose())
+            .collect()
+    }
+
+    fn describe(&self) -> SubscriptionDescription {
+        let counts = self.entity.counts();
+        SubscriptionDescription {
+            message_count: Some(counts.active_message_count + counts.dead_letter_message_count),
+            count_details: Some(counts),
+            ..self.description.clone()
+        }
+    }
+}
+
+fn scheduled_time(props: &BrokerProperties) -> Result<Option<SystemTime>, Fault> {
+    props
+        .ScheduledEnqueueTimeUtc
+        .as_deref()
+        .map(|s| {
+            parse_http_date(s)
+                .ok_or_else(|| Fault::Invalid(format!("Bad ScheduledEnqueueTimeUtc {}.", s)))
+        })
+        .transpose()
+}
+
+fn lock_token() -> String {
+    let b: [u8; 16] = rand::random();
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    fn store_with_queue(q: QueueDescription) -> Store {
+        let mut store = Store::default();
+        store.put(Entity::Queue(q), false).unwrap();
+        store
+    }
+
+    fn envelope(props: BrokerProperties, body: &str) -> Envelope {
+        Envelope {
+            props,
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn send(store: &mut Store, name: &str, body: &str, now: SystemTime) {
+        store
+            .send(name, envelope(BrokerProperties::default(), body), now)
+            .unwrap();
+    }
+
+    fn settle_ids(d: &Delivery) -> (String, String) {
+        (
+            d.props.SequenceNumber.unwrap().to_string(),
+            d.props.LockToken.clone().unwrap(),
+        )
+    }
+
+    #[test]
+    fn peek_lock_abandon_and_complete() {
+        let mut store = store_with_queue(QueueDescription::new("orders"));
+        let q = Target::Queue("ORDERS");
+        send(&mut store, "orders", "a", at(0));
+        send(&mut store, "orders", "b", at(0));
+
+        let first = store.receive(q, false, true, at(1)).unwrap().unwrap();
+        assert_eq!("a", first.body);
+        assert_eq!(Some(1), first.props.DeliveryCount);
+        let (id, token) = settle_ids(&first);
+        store.abandon(q, false, &id, &token, at(2)).unwrap();
+        assert_eq!(
+            Err(Fault::LockLost),
+            store.complete(q, false, &id, &token, at(2))
+        );
+
+        // The abandoned message keeps its place at the head of the queue.
+        let again = store.receive(q, false, true, at(3)).unwrap().unwrap();
+        assert_eq!("a", again.body);
+        assert_eq!(Some(2), again.props.DeliveryCount);
+        let (id, token) = settle_ids(&again);
+        store.complete(q, false, &id, &token, at(4)).unwrap();
+
+        let second = store.receive(q, false, false, at(5)).unwrap().unwrap();
+        assert_eq!("b", second.body);
+        assert_eq!(None, second.props.LockToken);
+        assert_eq!(None, store.receive(q, false, false, at(6)).unwrap());
+        assert_eq!(
+            Err(Fault::NotFound),
+            store.receive(Target::Queue("nope"), false, false, at(6))
+        );
+    }
+
+    #[test]
+    fn expired_locks_count_towards_max_delivery() {
+        let mut q = QueueDescription::new("orders");
+        q.lock_duration = Some(Duration::from_secs(10));
+        q.max_delivery_count = Some(2);
+        let mut store = store_with_queue(q);
+        let q = Target::Queue("orders");
+        send(&mut store, "orders", "poison", at(0));
+
+        let first = store.receive(q, false, true, at(0)).unwrap().unwrap();
+        let (id, token) = settle_ids(&first);
+        let until = store.renew(q, false, &id, &token, at(5)).unwrap();
+        assert_eq!(at(15), until);
+        assert_eq!(None, store.receive(q, false, true, at(14)).unwrap());
+
+        let second = store.receive(q, false, true, at(15)).unwrap().unwrap();
+        assert_eq!(Some(2), second.props.DeliveryCount);
+        let (id, token) = settle_ids(&second);
+        assert_eq!(
+            Err(Fault::LockLost),
+            store.complete(q, false, &id, &token, at(25))
+        );
+
+        assert_eq!(None, store.receive(q, false, true, at(26)).unwrap());
+        let dead = store.receive(q, true, true, at(26)).unwrap().unwrap();
+        assert_eq!("poison", dead.body);
+        assert_eq!(
+            Some(MAX_DELIVERY_COUNT_EXCEEDED),
+            dead.dead_letter_reason.as_deref()
+        );
+        let (id, token) = settle_ids(&dead);
+        store.complete(q, true, &id, &token, at(27)).unwrap();
+    }
+
+    #[test]
+    fn locks_are_capped() {
+        let mut q = QueueDescription::new("orders");
+        q.lock_duration = Some(Duration::from_secs(u64::MAX));
+        let mut store = store_with_queue(q);
+        let q = Target::Queue("orders");
+        send(&mut store, "orders", "hello", at(0));
+
+        let first = store.receive(q, false, true, at(0)).unwrap().unwrap();
+        let (id, token) = settle_ids(&first);
+        let until = store.renew(q, false, &id, &token, at(5)).unwrap();
+        assert_eq!(at(5) + MAX_LOCK_DURATION, until);
+    }
+
+    #[test]
+    fn messages_expire() {
+        let mut kept = QueueDescription::new("kept");
+        kept.default_message_time_to_live = Some(Duration::from_secs(60));
+        kept.dead_lettering_on_message_expiration = Some(true);
+        let mut store = store_with_queue(kept);
+        store
+            .put(Entity::Queue(QueueDescription::new("dropped")), false)
+            .unwrap();
+
+        let short = BrokerProperties {
+            TimeToLive: Some(5),
+            ..Default::default()
+        };
+        store
+            .send("kept", envelope(short.clone(), "short"), at(0))
+            .unwrap();
+        send(&mut store, "kept", "long", at(0));
+        store
+            .send("dropped", envelope(short, "short"), at(0))
+            .unwrap();
+
+        let long = store
+            .receive(Target::Queue("kept"), false, false, at(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!("long", long.body);
+        assert_eq!(Some(60), long.props.TimeToLive);
+        let expired = store
+            .receive(Target::Queue("kept"), true, false, at(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(TTL_EXPIRED), expired.dead_letter_reason.as_deref());
+
+        assert_eq!(
+            None,
+            store
+                .receive(Target::Queue("dropped"), false, false, at(10))
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            store
+                .receive(Target::Queue("dropped"), true, false, at(10))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn topics_copy_to_every_subscription() {
+        let mut store = Store::default();
+        store
+            .put(Entity::Topic(TopicDescription::new("events")), false)
+            .unwrap();
+        for name in &["audit", "billing"] {
+            store
+                .put(
+                    Entity::Subscription(SubscriptionDescription::new("events", name)),
+                    false,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            Err(Fault::Conflict),
+            store.put(Entity::Queue(QueueDescription::new("Events")), false)
+        );
+        send(&mut store, "events", "hello", at(0));
+
+        let path = EntityPath::parse("events/subscriptions/audit").unwrap();
+        match store.get(&path) {
+            Some(Entity::Subscription(s)) => assert_eq!(Some(1), s.message_count),
+            other => panic!("{:?}", other),
+        }
+        let rules = store.rules("events", "audit").unwrap();
+        assert_eq!(1, rules.len());
+
+        for name in &["audit", "billing"] {
+            let d = store
+                .receive(Target::Subscription("events", name), false, false, at(1))
+                .unwrap()
+                .unwrap();
+            assert_eq!("hello", d.body);
+        }
+
+        store.delete(&EntityPath::parse("events").unwrap()).unwrap();
+        assert_eq!(None, store.get(&path));
+        assert_eq!(Err(Fault::NotFound), store.delete(&path));
+    }
+
+    fn topic_with(store: &mut Store, topic: &str, subs: Vec<SubscriptionDescription>) {
+        store
+            .put(Entity::Topic(TopicDescription::new(topic)), false)
+            .unwrap();
+        for sub in subs {
+            store.put(Entity::Subscription(sub), false).unwrap();
+        }
+    }
+
+    fn bodies(
+        store: &mut Store,
+        target: Target,
+        dead_letter: bool,
+        now: SystemTime,
+    ) -> Vec<String> {
+        std::iter::from_fn(|| store.receive(target, dead_letter, false, now).unwrap())
+            .map(|d| d.body)
+            .collect()
+    }
+
+    #[test]
+    fn rules_pick_and_change_copies() {
+        let mut store = Store::default();
+        let mut strict = SubscriptionDescription::new("events", "strict");
+        strict.dead_lettering_on_filter_evaluation_exceptions = Some(true);
+        topic_with(
+            &mut store,
+            "events",
+            vec![SubscriptionDescription::new("events", "blue"), strict],
+        );
+        let default = EntityPath::parse("events/subscriptions/blue/rules/$Default").unwrap();
+        store.delete(&default).unwrap();
+        let mut rule = RuleDescription::new(
+            "events",
+            "blue",
+            "blue",
+            RuleFilter::Sql {
+                expression: "color = 'blue'".to_string(),
+            },
+        );
+        rule.action = Some(RuleAction::Sql {
+            expression: "SET sys.Label = 'matched'".to_string(),
+        });
+        store.put(Entity::Rule(rule), false).unwrap();
+        let division = RuleDescription::new(
+            "events",
+            "strict",
+            "division",
+            RuleFilter::Sql {
+                expression: "1 / zero = 1".to_string(),
+            },
+        );
+        store.put(Entity::Rule(division), false).unwrap();
+        let bad = RuleDescription::new(
+            "events",
+            "blue",
+            "bad",
+            RuleFilter::Sql {
+                expression: "color = ".to_string(),
+            },
+        );
+        assert!(matches!(
+            store.put(Entity::Rule(bad), false),
+            Err(Fault::Invalid(_))
+        ));
+
+        for color in &["blue", "red"] {
+            let mut message = envelope(BrokerProperties::default(), color);
+            message
+                .properties
+                .insert("color".to_string(), Value::from(*color));
+            message
+                .properties
+                .insert("zero".to_string(), Value::from(0));
+            store.send("events", message, at(0)).unwrap();
+        }
+
+        let blue = Target::Subscription("events", "blue");
+        let d = store.receive(blue, false, false, at(1)).unwrap().unwrap();
+        assert_eq!("blue", d.body);
+        assert_eq!(Some("matched"), d.props.Label.as_deref());
+        assert_eq!(None, store.receive(blue, false, false, at(1)).unwrap());
+
+        // `$Default` copies both, the failing rule dead letters both.
+        let strict = Target::Subscription("events", "strict");
+        assert_eq!(
+            vec!["blue", "red"],
+            bodies(&mut store, strict, false, at(1))
+        );
+        let dead = store.receive(strict, true, false, at(1)).unwrap().unwrap();
+        assert_eq!(
+            Some(FILTER_EVALUATION_EXCEPTION),
+            dead.dead_letter_reason.as_deref()
+        );
+    }
+
+    #[test]
+    fn forwarding_chains_and_dead_letters() {
+        let mut store = Store::default();
+        let mut hop = |name: &str, to: &str| {
+            let mut q = QueueDescription::new(name);
+            q.forward_to = Some(format!("https://ns.servicebus.windows.net/{}", to));
+            store.put(Entity::Queue(q), false).unwrap();
+        };
+        hop("a", "b");
+        hop("b", "c");
+        hop("loop1", "loop2");
+        hop("loop2", "loop1");
+        let mut c = QueueDescription::new("c");
+        c.max_delivery_count = Some(1);
+        c.forward_dead_lettered_messages_to = Some("poison".to_string());
+        store.put(Entity::Queue(c), false).unwrap();
+        store
+            .put(Entity::Queue(QueueDescription::new("poison")), false)
+            .unwrap();
+
+        send(&mut store, "a", "chained", at(0));
+        assert_eq!(
+            None,
+            store
+                .receive(Target::Queue("a"), false, false, at(1))
+                .unwrap()
+        );
+        let c = Target::Queue("c");
+        let d = store.receive(c, false, true, at(1)).unwrap().unwrap();
+        assert_eq!("chained", d.body);
+        let (id, token) = settle_ids(&d);
+        store.abandon(c, false, &id, &token, at(2)).unwrap();
+
+        // Abandoned past its max delivery count, it moves on to `poison`.
+        assert_eq!(None, store.receive(c, true, false, at(3)).unwrap());
+        let poisoned = store
+            .receive(Target::Queue("poison"), false, false, at(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("c"), poisoned.props.DeadLetterSource.as_deref());
+        assert_eq!(
+            Some(&Value::from(MAX_DELIVERY_COUNT_EXCEEDED)),
+            poisoned.properties.get(DEAD_LETTER_REASON_PROPERTY)
+        );
+
+        send(&mut store, "loop1", "dizzy", at(4));
+        let looped = [Target::Queue("loop1"), Target::Queue("loop2")]
+            .iter()
+            .filter_map(|q| store.receive(*q, true, false, at(5)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(1, looped.len());
+        assert_eq!(
+            Some(MAX_TRANSFER_HOP_COUNT_EXCEEDED),
+            looped[0].dead_letter_reason.as_deref()
+        );
+    }
+
+    #[test]
+    fn duplicates_and_scheduled_messages() {
+        let mut q = QueueDescription::new("orders");
+        q.requires_duplicate_detection = Some(true);
+        q.duplicate_detection_history_time_window = Some(Duration::from_secs(60));
+        let mut store = store_with_queue(q);
+        let with_id = |id: &str| BrokerProperties {
+            MessageId: Some(id.to_string()),
+            ..Default::default()
+        };
+        for (id, body, t) in &[
+            ("1", "first", 0),
+            ("1", "again", 30),
+            ("2", "other", 30),
+            ("1", "later", 61),
+        ] {
+            store
+                .send("orders", envelope(with_id(id), body), at(*t))
+                .unwrap();
+        }
+        let orders = Target::Queue("orders");
+        assert_eq!(
+            vec!["first", "other", "later"],
+            bodies(&mut store, orders, false, at(62))
+        );
+
+        let scheduled = BrokerProperties {
+            ScheduledEnqueueTimeUtc: Some(http_date(at(100))),
+            ..Default::default()
+        };
+        store
+            .send("orders", envelope(scheduled, "scheduled"), at(62))
+            .unwrap();
+        match store.get(&EntityPath::parse("orders").unwrap()) {
+            Some(Entity::Queue(q)) => {
+                assert_eq!(Some(1), q.count_details.map(|c| c.scheduled_message_count))
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(None, store.receive(orders, false, false, at(99)).unwrap());
+        let d = store
+            .receive(orders, false, false, at(100))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(http_date(at(100))), d.props.EnqueuedTimeUtc);
+
+        let bad = BrokerProperties {
+            ScheduledEnqueueTimeUtc: Some("tomorrow".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.send("orders", envelope(bad, "x"), at(100)),
+            Err(Fault::Invalid(_))
+        ));
+
+        let json = store.to_json().unwrap();
+        let mut store = Store::from_json(&json).unwrap();
+        store
+            .send("orders", envelope(with_id("1"), "replayed"), at(101))
+            .unwrap();
+        assert_eq!(None, store.receive(orders, false, false, at(101)).unwrap());
+    }
+}
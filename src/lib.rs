@@ -8,4 +8,20 @@ pub mod core;
 /// They communicate messages through the BrokeredMessage struct.
 ///
 pub mod servicebus;
-pub use servicebus::{queue::QueueClient, subscription::SubscriptionClient};
+
+/// Recording and scripted stand-ins for Service Bus, for unit testing code
+/// that uses the clients.
+pub mod testing;
+
+/// An in-memory Service Bus to run the clients against in tests.
+/// Requires the `emulator` feature.
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+
+/// The building blocks of the `sbctl` command line tool.
+/// Requires the `cli` feature.
+#[cfg(feature = "cli")]
+pub mod cli;
+pub use servicebus::{
+    namespace::NamespaceClient, queue::QueueClient, subscription::SubscriptionClient,
+};
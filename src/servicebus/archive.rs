@@ -0,0 +1,253 @@
+//! Messages written to and read back from JSON Lines files, one message a
+//! line, so they can be captured from one entity and replayed into another.
+//!
+//! ```json
+//! {"recordedAt":"Wed, 05 Dec 2012 10:52:48 GMT","body":"<string>order 1</string>","brokerProperties":{"MessageId":"1","EnqueuedTimeUtc":"Wed, 05 Dec 2012 10:52:40 GMT"},"properties":{"priority":2}}
+//! ```
+//!
+//! The body is kept as it travelled, without the DataContract wrapper taken
+//! off. It is written as text, or as base64 under `bodyBase64` for bodies
+//! that should survive byte for byte whatever reads the file in between.
+
+use super::brokeredmessage::{BrokerProperties, BrokeredMessage};
+use crate::core::clock::{http_date, parse_http_date};
+use eyre::{eyre, Report, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::time::{Duration, SystemTime};
+
+/// How a recorded body is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyEncoding {
+    Text,
+    Base64,
+}
+
+/// The body of a recorded message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Body {
+    #[serde(rename = "body")]
+    Text(String),
+    #[serde(rename = "bodyBase64")]
+    Base64(String),
+}
+
+/// One line of a recording: a received message with everything the broker
+/// said about it, and when it was recorded.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedMessage {
+    /// When the message was recorded, in the same format as the broker
+    /// properties' times.
+    pub recorded_at: String,
+    #[serde(flatten)]
+    pub body: Body,
+    pub broker_properties: BrokerProperties,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, Value>,
+}
+
+impl ArchivedMessage {
+    pub fn record(
+        message: &BrokeredMessage,
+        encoding: BodyEncoding,
+        recorded_at: SystemTime,
+    ) -> ArchivedMessage {
+        let raw = message.get_body_raw();
+        ArchivedMessage {
+            recorded_at: http_date(recorded_at),
+            body: match encoding {
+                BodyEncoding::Text => Body::Text(raw.to_string()),
+                BodyEncoding::Base64 => Body::Base64(base64::encode(raw)),
+            },
+            broker_properties: (*message.props).clone(),
+            properties: message.properties.clone(),
+        }
+    }
+
+    /// The message as it was received, broker assigned properties included.
+    pub fn message(&self) -> Result<BrokeredMessage, Report> {
+        let body = match &self.body {
+            Body::Text(text) => text.clone(),
+            Body::Base64(encoded) => String::from_utf8(base64::decode(encoded)?)
+                .map_err(|_| eyre!("The body is not UTF-8."))?,
+        };
+        let mut message =
+            BrokeredMessage::with_body_and_props(&body, self.broker_properties.clone());
+        message.properties = self.properties.clone();
+        Ok(message)
+    }
+
+    /// When the message was originally sent: its enqueued time, or when it
+    /// was recorded if the broker didn't say.
+    pub fn sent_at(&self) -> Option<SystemTime> {
+        self.broker_properties
+            .EnqueuedTimeUtc
+            .as_deref()
+            .and_then(parse_http_date)
+            .or_else(|| parse_http_date(&self.recorded_at))
+    }
+}
+
+/// Writes one message as a line of JSON.
+pub fn write_line<W: Write>(out: &mut W, message: &ArchivedMessage) -> Result<(), Report> {
+    serde_json::to_writer(&mut *out, message)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Reads the messages of a JSON Lines file in order. Blank lines are skipped
+/// and errors say which line they are on.
+pub fn read_lines<R: BufRead>(input: R) -> impl Iterator<Item = Result<ArchivedMessage, Report>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line?;
+            serde_json::from_str(&line).wrap_err_with(|| format!("Line {} is not a message", i + 1))
+        })
+}
+
+/// How fast recorded messages are replayed. With neither a rate nor a speed
+/// they are sent as fast as they can be.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pacing {
+    /// The most messages to send a second.
+    pub rate: Option<f64>,
+    /// Keep the gaps between the messages' original send times, divided by
+    /// this. 1 replays in real time, 60 turns an hour into a minute.
+    pub speed: Option<f64>,
+}
+
+/// Works out when each message of a replay is due.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    pacing: Pacing,
+    first: Option<SystemTime>,
+    last: Option<Duration>,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing) -> Pacer {
+        Pacer {
+            pacing,
+            first: None,
+            last: None,
+        }
+    }
+
+    /// How long after the start of the replay the next message, originally
+    /// sent at `sent_at`, should go. A tiny speed or rate can put that further
+    /// off than a `Duration` reaches, in which case it is `Duration::MAX`.
+    pub fn offset(&mut self, sent_at: Option<SystemTime>) -> Duration {
+        let compressed = match (self.pacing.speed, sent_at) {
+            (Some(speed), Some(sent_at)) if speed > 0.0 => {
+                let first = *self.first.get_or_insert(sent_at);
+                let gap = sent_at.duration_since(first).unwrap_or_default();
+                secs_or_max(gap.as_secs_f64() / speed)
+            }
+            _ => Duration::default(),
+        };
+        let earliest = match (self.last, self.pacing.rate) {
+            (Some(last), Some(rate)) if rate > 0.0 => last.saturating_add(secs_or_max(1.0 / rate)),
+            (Some(last), _) => last,
+            (None, _) => Duration::default(),
+        };
+        let offset = compressed.max(earliest);
+        self.last = Some(offset);
+        offset
+    }
+}
+
+fn secs_or_max(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn recordings_round_trip() -> Result<(), Report> {
+        let props = BrokerProperties {
+            MessageId: Some("1".to_string()),
+            EnqueuedTimeUtc: Some("Wed, 05 Dec 2012 10:52:40 GMT".to_string()),
+            ..Default::default()
+        };
+        let message = BrokeredMessage::with_body_and_props("line one\nline two", props)
+            .with_property("priority", 2);
+        let at = UNIX_EPOCH + Duration::from_secs(1_354_704_768);
+
+        let mut file = Vec::new();
+        write_line(
+            &mut file,
+            &ArchivedMessage::record(&message, BodyEncoding::Text, at),
+        )?;
+        file.extend_from_slice(b"\n");
+        write_line(
+            &mut file,
+            &ArchivedMessage::record(&message, BodyEncoding::Base64, at),
+        )?;
+        let text = String::from_utf8(file.clone())?;
+        assert!(text.starts_with(
+            "{\"recordedAt\":\"Wed, 05 Dec 2012 10:52:48 GMT\",\"body\":\"line one\\nline two\","
+        ));
+        assert!(text.contains("\"bodyBase64\":\"bGluZSBvbmUKbGluZSB0d28=\""));
+
+        let read = read_lines(&file[..]).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(2, read.len());
+        for archived in read {
+            assert_eq!(message, archived.message()?);
+            assert_eq!(
+                parse_http_date("Wed, 05 Dec 2012 10:52:40 GMT"),
+                archived.sent_at()
+            );
+        }
+
+        let error = read_lines(&b"{}\n\nnot json\n"[..])
+            .nth(1)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!("Line 3 is not a message", error.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn pacing_compresses_time_and_limits_the_rate() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        let mut fast = Pacer::new(Pacing::default());
+        assert_eq!(Duration::default(), fast.offset(at(100)));
+        assert_eq!(Duration::default(), fast.offset(at(160)));
+
+        let mut compressed = Pacer::new(Pacing {
+            rate: None,
+            speed: Some(60.0),
+        });
+        assert_eq!(Duration::default(), compressed.offset(at(100)));
+        assert_eq!(Duration::from_secs(1), compressed.offset(at(160)));
+        // Messages that are out of order don't go back in time.
+        assert_eq!(Duration::from_secs(1), compressed.offset(at(130)));
+        assert_eq!(Duration::from_secs(2), compressed.offset(at(220)));
+
+        let mut limited = Pacer::new(Pacing {
+            rate: Some(2.0),
+            speed: Some(60.0),
+        });
+        assert_eq!(Duration::default(), limited.offset(at(100)));
+        assert_eq!(Duration::from_millis(500), limited.offset(at(100)));
+        assert_eq!(Duration::from_secs(1), limited.offset(at(160)));
+        assert_eq!(Duration::from_millis(1500), limited.offset(None));
+
+        let mut crawling = Pacer::new(Pacing {
+            rate: Some(1e-300),
+            speed: Some(1e-300),
+        });
+        assert_eq!(Duration::default(), crawling.offset(at(100)));
+        assert_eq!(Duration::MAX, crawling.offset(at(160)));
+        assert_eq!(Duration::MAX, crawling.offset(None));
+    }
+}
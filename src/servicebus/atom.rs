@@ -0,0 +1,399 @@
+// The management half of the Service Bus REST api speaks ATOM. Entity
+// descriptions are wrapped in `<entry>` elements and listings come back as a
+// `<feed>` of entries. This module has just enough of an XML tree to pick
+// those apart, plus the helpers the descriptions use to read and write
+// their fields.
+
+use eyre::{eyre, Report};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::time::Duration;
+
+pub(crate) const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+pub(crate) const SB_NS: &str =
+    "http://schemas.microsoft.com/netservices/2010/10/servicebus/connect";
+pub(crate) const XSI_NS: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+/// A parsed XML element. Names are stored without their namespace prefix
+/// since Service Bus is not consistent about which prefixes it uses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlElement>,
+    pub text: String,
+}
+
+impl XmlElement {
+    /// Parses a document and returns its root element.
+    pub fn parse(document: &str) -> Result<XmlElement, Report> {
+        let mut reader = Reader::from_str(document);
+        reader.config_mut().trim_text(true);
+
+        let mut stack: Vec<XmlElement> = Vec::new();
+        loop {
+            match reader.read_event()? {
+                Event::Start(start) => stack.push(Self::from_start(&start)?),
+                Event::Empty(start) => {
+                    let el = Self::from_start(&start)?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(el),
+                        None => return Ok(el),
+                    }
+                }
+                Event::End(_) => {
+                    let el = stack
+                        .pop()
+                        .ok_or_else(|| eyre!("Unbalanced closing tag in XML document."))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(el),
+                        None => return Ok(el),
+                    }
+                }
+                Event::Text(text) => {
+                    if let Some(el) = stack.last_mut() {
+                        el.text.push_str(&text.unescape()?);
+                    }
+                }
+                Event::CData(data) => {
+                    if let Some(el) = stack.last_mut() {
+                        el.text.push_str(std::str::from_utf8(&data)?);
+                    }
+                }
+                Event::Eof => return Err(eyre!("XML document ended before the root closed.")),
+                _ => {}
+            }
+        }
+    }
+
+    fn from_start(start: &BytesStart) -> Result<XmlElement, Report> {
+        let name = std::str::from_utf8(start.local_name().as_ref())?.to_string();
+        let mut attributes = Vec::new();
+        for attr in start.attributes() {
+            let attr = attr?;
+            let key = std::str::from_utf8(attr.key.local_name().as_ref())?.to_string();
+            attributes.push((key, attr.unescape_value()?.into_owned()));
+        }
+
+        Ok(XmlElement {
+            name,
+            attributes,
+            ..Default::default()
+        })
+    }
+
+    /// The first child with the given name.
+    pub fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// Every child with the given name.
+    pub fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlElement> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The text of a child element. Missing and empty elements are both `None`.
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name)
+            .map(|c| c.text.as_str())
+            .filter(|t| !t.is_empty())
+    }
+
+    pub(crate) fn parse_child<T>(&self, name: &str) -> Result<Option<T>, Report>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.child_text(name)
+            .map(|t| {
+                t.parse::<T>()
+                    .map_err(|e| eyre!("Could not parse <{}>{}</{}>: {}", name, t, name, e))
+            })
+            .transpose()
+    }
+
+    pub(crate) fn duration_child(&self, name: &str) -> Result<Option<Duration>, Report> {
+        self.child_text(name).map(parse_duration).transpose()
+    }
+}
+
+/// A single `<entry>` out of a feed, or the response to a GET/PUT on an entity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The entity name. For subscriptions and rules this is just the last path segment.
+    pub title: String,
+    pub id: Option<String>,
+    /// The description element inside `<content>`.
+    pub content: Option<XmlElement>,
+}
+
+impl Entry {
+    pub fn from_element(el: &XmlElement) -> Result<Entry, Report> {
+        if el.name != "entry" {
+            return Err(eyre!("Expected an ATOM <entry>, found <{}>.", el.name));
+        }
+
+        Ok(Entry {
+            title: el
+                .child("title")
+                .map(|t| t.text.clone())
+                .unwrap_or_default(),
+            id: el.child_text("id").map(str::to_string),
+            content: el
+                .child("content")
+                .and_then(|c| c.children.first())
+                .cloned(),
+        })
+    }
+
+    pub fn parse(document: &str) -> Result<Entry, Report> {
+        Self::from_element(&XmlElement::parse(document)?)
+    }
+}
+
+/// Parses an ATOM `<feed>` into its entries. An empty feed is not an error.
+pub fn parse_feed(document: &str) -> Result<Vec<Entry>, Report> {
+    let root = XmlElement::parse(document)?;
+    if root.name != "feed" {
+        return Err(eyre!("Expected an ATOM <feed>, found <{}>.", root.name));
+    }
+
+    root.children_named("entry")
+        .map(Entry::from_element)
+        .collect()
+}
+
+/// Wraps a serialized description in an ATOM entry, ready to be PUT.
+pub fn wrap_entry(content: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <entry xmlns=\"{}\"><content type=\"application/xml\">{}</content></entry>",
+        ATOM_NS, content
+    )
+}
+
+/// Builds up a description element. Service Bus uses DataContract
+/// serialization so the order elements are written in matters, and callers
+/// are expected to write them in schema order.
+pub(crate) struct XmlWriter {
+    buf: String,
+}
+
+impl XmlWriter {
+    pub fn new() -> XmlWriter {
+        XmlWriter { buf: String::new() }
+    }
+
+    pub fn text(&mut self, name: &str, value: &str) -> &mut Self {
+        self.buf
+            .push_str(&format!("<{}>{}</{}>", name, escape(value), name));
+        self
+    }
+
+    pub fn opt<T: ToString>(&mut self, name: &str, value: &Option<T>) -> &mut Self {
+        if let Some(v) = value {
+            self.text(name, &v.to_string());
+        }
+        self
+    }
+
+    pub fn opt_duration(&mut self, name: &str, value: &Option<Duration>) -> &mut Self {
+        if let Some(v) = value {
+            self.text(name, &format_duration(*v));
+        }
+        self
+    }
+
+    /// Appends already serialized XML.
+    pub fn raw(&mut self, xml: &str) -> &mut Self {
+        self.buf.push_str(xml);
+        self
+    }
+
+    /// Wraps everything written so far in an element with no attributes.
+    pub fn plain(&self, name: &str) -> String {
+        format!("<{}>{}</{}>", name, self.buf, name)
+    }
+
+    /// Wraps everything written so far in an element with an `i:type` attribute,
+    /// which is how DataContract marks which subclass is being sent.
+    pub fn typed(&self, name: &str, xsi_type: &str) -> String {
+        format!("<{} i:type=\"{}\">{}</{}>", name, xsi_type, self.buf, name)
+    }
+
+    /// Wraps everything written so far in a description element.
+    pub fn finish(&self, name: &str) -> String {
+        format!(
+            "<{} xmlns=\"{}\" xmlns:i=\"{}\">{}</{}>",
+            name, SB_NS, XSI_NS, self.buf, name
+        )
+    }
+}
+
+/// Parses an ISO 8601 duration as used by the xs:duration fields in entity
+/// descriptions, e.g. `PT1M` or `P10675199DT2H48M5.4775807S`. Years and
+/// months are not supported since Service Bus never sends them.
+pub fn parse_duration(s: &str) -> Result<Duration, Report> {
+    let err = || eyre!("Invalid ISO 8601 duration: {}", s);
+    let rest = s.trim().strip_prefix('P').ok_or_else(err)?;
+
+    let mut total = Duration::from_secs(0);
+    let mut in_time = false;
+    let mut num = String::new();
+    for ch in rest.chars() {
+        match ch {
+            'T' if !in_time && num.is_empty() => in_time = true,
+            '0'..='9' | '.' => num.push(ch),
+            unit => {
+                let value: f64 = num.parse().map_err(|_| err())?;
+                let secs = match (in_time, unit) {
+                    (false, 'W') => 7.0 * 86_400.0,
+                    (false, 'D') => 86_400.0,
+                    (true, 'H') => 3_600.0,
+                    (true, 'M') => 60.0,
+                    (true, 'S') => 1.0,
+                    _ => return Err(err()),
+                };
+                // Whole units are added exactly so that large values like
+                // TimeSpan.MaxValue survive the trip through f64. Anything
+                // that does not fit in a Duration is an error, not a panic.
+                let too_long = || eyre!("ISO 8601 duration {} is too long", s);
+                if value.trunc() >= u64::MAX as f64 {
+                    return Err(too_long());
+                }
+                let whole = Duration::from_secs(value.trunc() as u64);
+                let fract =
+                    Duration::try_from_secs_f64(value.fract() * secs).map_err(|_| too_long())?;
+                total = whole
+                    .checked_mul(secs as u32)
+                    .and_then(|d| d.checked_add(fract))
+                    .and_then(|d| total.checked_add(d))
+                    .ok_or_else(too_long)?;
+                num.clear();
+            }
+        }
+    }
+
+    if !num.is_empty() {
+        return Err(err());
+    }
+    Ok(total)
+}
+
+/// Formats a duration the way Service Bus does, e.g. `P1DT2H3M4.5S`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86_400,
+        (secs % 86_400) / 3_600,
+        (secs % 3_600) / 60,
+        secs % 60,
+    );
+
+    let mut out = String::from("P");
+    if days > 0 {
+        out.push_str(&format!("{}D", days));
+    }
+    let nanos = d.subsec_nanos();
+    if hours > 0 || minutes > 0 || seconds > 0 || nanos > 0 || days == 0 {
+        out.push('T');
+        if hours > 0 {
+            out.push_str(&format!("{}H", hours));
+        }
+        if minutes > 0 {
+            out.push_str(&format!("{}M", minutes));
+        }
+        if seconds > 0 || nanos > 0 || (hours == 0 && minutes == 0) {
+            if nanos > 0 {
+                let frac = format!("{:09}", nanos);
+                out.push_str(&format!("{}.{}S", seconds, frac.trim_end_matches('0')));
+            } else {
+                out.push_str(&format!("{}S", seconds));
+            }
+        }
+    }
+    out
+}
+
+/// Serde support for `Option<Duration>` fields written as ISO 8601 durations,
+/// so config files can say `lock_duration: PT1M` like the portal does.
+pub mod iso8601 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => s.serialize_str(&super::format_duration(*d)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| super::parse_duration(&s).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_round_trip() {
+        for s in &["PT1M", "PT30S", "P14D", "P1DT2H3M4.5S", "PT0S"] {
+            assert_eq!(*s, format_duration(parse_duration(s).unwrap()));
+        }
+        let max = parse_duration("P10675199DT2H48M5.4775807S").unwrap();
+        assert_eq!(max.as_secs(), 922_337_203_685);
+        assert!(parse_duration("1M").is_err());
+        assert!(parse_duration("PT5").is_err());
+    }
+
+    #[test]
+    fn feed_parses_entries() {
+        let feed = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <title type="text">Queues</title>
+            <entry>
+              <id>https://ns.servicebus.windows.net/q1</id>
+              <title type="text">q1</title>
+              <content type="application/xml">
+                <QueueDescription xmlns="http://schemas.microsoft.com/netservices/2010/10/servicebus/connect">
+                  <LockDuration>PT1M</LockDuration>
+                  <CountDetails xmlns:d2p1="http://schemas.microsoft.com/netservices/2011/06/servicebus">
+                    <d2p1:ActiveMessageCount>3</d2p1:ActiveMessageCount>
+                  </CountDetails>
+                </QueueDescription>
+              </content>
+            </entry>
+        </feed>"#;
+        let entries = parse_feed(feed).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!("q1", entries[0].title);
+        let content = entries[0].content.as_ref().unwrap();
+        assert_eq!("QueueDescription", content.name);
+        assert_eq!(Some("PT1M"), content.child_text("LockDuration"));
+        assert_eq!(
+            Some("3"),
+            content
+                .child("CountDetails")
+                .and_then(|c| c.child_text("ActiveMessageCount"))
+        );
+    }
+
+    #[test]
+    fn empty_feed() {
+        let feed =
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title type="text">Queues</title></feed>"#;
+        assert!(parse_feed(feed).unwrap().is_empty());
+        assert!(parse_feed("<entry/>").is_err());
+    }
+}
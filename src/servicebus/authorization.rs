@@ -0,0 +1,446 @@
+use super::atom::{Entry, XmlElement, XmlWriter};
+use super::description::{EntityDescription, QueueDescription, TopicDescription};
+use super::interpret_results;
+use super::namespace::NamespaceClient;
+use crate::core::exec::Executor;
+pub use crate::core::rotation::KeyType;
+use crate::core::sas::SasToken;
+use eyre::{eyre, Report};
+use hyper::Uri;
+use rand::RngCore;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The claims a shared access rule can grant. Manage implies the other two.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AccessRight {
+    Manage,
+    Send,
+    Listen,
+}
+
+impl Display for AccessRight {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessRight::Manage => "Manage",
+            AccessRight::Send => "Send",
+            AccessRight::Listen => "Listen",
+        })
+    }
+}
+
+impl FromStr for AccessRight {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Manage" => Ok(AccessRight::Manage),
+            "Send" => Ok(AccessRight::Send),
+            "Listen" => Ok(AccessRight::Listen),
+            other => Err(eyre!("Unknown access right {}.", other)),
+        }
+    }
+}
+
+/// A `SharedAccessAuthorizationRule`: a named pair of keys granting some set of
+/// claims on a queue or topic. The `Debug` output leaves out the keys.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthorizationRule {
+    pub key_name: String,
+    pub rights: Vec<AccessRight>,
+    pub primary_key: String,
+    pub secondary_key: String,
+    pub created_time: Option<String>,
+    pub modified_time: Option<String>,
+}
+
+impl Debug for AuthorizationRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("AuthorizationRule")
+            .field("key_name", &self.key_name)
+            .field("rights", &self.rights)
+            .field("created_time", &self.created_time)
+            .field("modified_time", &self.modified_time)
+            .finish()
+    }
+}
+
+impl AuthorizationRule {
+    /// A new rule with freshly generated primary and secondary keys.
+    /// Manage rules must also grant Send and Listen, which is added for you.
+    pub fn new(key_name: &str, rights: &[AccessRight]) -> AuthorizationRule {
+        let mut rights = rights.to_vec();
+        if rights.contains(&AccessRight::Manage) {
+            rights.extend(&[AccessRight::Send, AccessRight::Listen]);
+        }
+        rights.sort();
+        rights.dedup();
+
+        AuthorizationRule {
+            key_name: key_name.to_string(),
+            rights,
+            primary_key: generate_key(),
+            secondary_key: generate_key(),
+            created_time: None,
+            modified_time: None,
+        }
+    }
+
+    pub fn key(&self, key: KeyType) -> &str {
+        match key {
+            KeyType::Primary => &self.primary_key,
+            KeyType::Secondary => &self.secondary_key,
+        }
+    }
+
+    /// Replaces one of the keys with a new random one. Rotate one key at a
+    /// time so clients can move to the other key in between.
+    pub fn regenerate(&mut self, key: KeyType) {
+        match key {
+            KeyType::Primary => self.primary_key = generate_key(),
+            KeyType::Secondary => self.secondary_key = generate_key(),
+        }
+    }
+
+    /// A connection string that uses this rule to talk to `entity_path` in the
+    /// namespace at `endpoint`.
+    pub fn connection_string(&self, endpoint: &Uri, entity_path: &str, key: KeyType) -> String {
+        format!(
+            "Endpoint=sb://{}/;SharedAccessKeyName={};SharedAccessKey={};EntityPath={}",
+            endpoint.host().unwrap_or_default(),
+            self.key_name,
+            self.key(key),
+            entity_path
+        )
+    }
+
+    fn from_xml(el: &XmlElement) -> Result<AuthorizationRule, Report> {
+        let rights = el
+            .child("Rights")
+            .map(|r| {
+                r.children
+                    .iter()
+                    .map(|right| right.text.parse())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(AuthorizationRule {
+            key_name: el
+                .parse_child("KeyName")?
+                .ok_or_else(|| eyre!("Authorization rule without a KeyName."))?,
+            rights,
+            primary_key: el.parse_child("PrimaryKey")?.unwrap_or_default(),
+            secondary_key: el.parse_child("SecondaryKey")?.unwrap_or_default(),
+            created_time: el.parse_child("CreatedTime")?,
+            modified_time: el.parse_child("ModifiedTime")?,
+        })
+    }
+
+    fn to_xml(&self) -> String {
+        let mut rights = XmlWriter::new();
+        for right in &self.rights {
+            rights.text("AccessRights", &right.to_string());
+        }
+
+        XmlWriter::new()
+            .text("ClaimType", "SharedAccessKey")
+            .text("ClaimValue", "None")
+            .raw(&rights.plain("Rights"))
+            .text("KeyName", &self.key_name)
+            .text("PrimaryKey", &self.primary_key)
+            .text("SecondaryKey", &self.secondary_key)
+            .typed("AuthorizationRule", "SharedAccessAuthorizationRule")
+    }
+}
+
+// Service Bus keys are 256 bits, base64 encoded.
+fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    base64::encode(key)
+}
+
+pub(crate) fn parse_rules(el: &XmlElement) -> Result<Option<Vec<AuthorizationRule>>, Report> {
+    el.child("AuthorizationRules")
+        .map(|rules| {
+            rules
+                .children_named("AuthorizationRule")
+                .filter(|r| r.attribute("type") == Some("SharedAccessAuthorizationRule"))
+                .map(AuthorizationRule::from_xml)
+                .collect()
+        })
+        .transpose()
+}
+
+pub(crate) fn write_rules(rules: &Option<Vec<AuthorizationRule>>) -> String {
+    match rules {
+        Some(rules) => {
+            let mut out = XmlWriter::new();
+            for rule in rules {
+                out.raw(&rule.to_xml());
+            }
+            out.plain("AuthorizationRules")
+        }
+        None => String::new(),
+    }
+}
+
+/// Queues and topics are the entities that can carry their own shared access rules.
+pub trait AuthorizedEntity: EntityDescription {
+    fn named(name: &str) -> Self;
+    fn authorization_rules_mut(&mut self) -> &mut Option<Vec<AuthorizationRule>>;
+}
+
+impl AuthorizedEntity for QueueDescription {
+    fn named(name: &str) -> Self {
+        QueueDescription::new(name)
+    }
+
+    fn authorization_rules_mut(&mut self) -> &mut Option<Vec<AuthorizationRule>> {
+        &mut self.authorization_rules
+    }
+}
+
+impl AuthorizedEntity for TopicDescription {
+    fn named(name: &str) -> Self {
+        TopicDescription::new(name)
+    }
+
+    fn authorization_rules_mut(&mut self) -> &mut Option<Vec<AuthorizationRule>> {
+        &mut self.authorization_rules
+    }
+}
+
+/// Manages the shared access rules of one queue or topic. Every change reads
+/// the current description, edits its rules and writes it back, so the other
+/// settings of the entity are left as they are.
+///
+/// ```no_run
+/// # use hyper::{Request, Response};
+/// # use azure_service_bus::servicebus::authorization::{AccessRight, AuthorizationRule, KeyType};
+/// # fn exec(_: Request<String>) -> Result<Response<String>, eyre::Report> { unimplemented!() }
+/// # fn main() -> Result<(), eyre::Report> {
+/// # let namespace: azure_service_bus::NamespaceClient = unimplemented!();
+/// let rules = namespace.queue_authorization("orders", &exec);
+/// let sender = rules.add(AuthorizationRule::new("partner-send", &[AccessRight::Send]))?;
+/// println!("{}", rules.connection_string(&sender, KeyType::Primary));
+/// # Ok(())
+/// # }
+/// ```
+pub struct EntityAuthorization<'a, E: ?Sized, D> {
+    client: &'a NamespaceClient,
+    exec: &'a E,
+    name: String,
+    _entity: std::marker::PhantomData<D>,
+}
+
+impl NamespaceClient {
+    /// Shared access rules of a queue.
+    pub fn queue_authorization<'a, E: Executor + ?Sized>(
+        &'a self,
+        queue: &str,
+        exec: &'a E,
+    ) -> EntityAuthorization<'a, E, QueueDescription> {
+        EntityAuthorization::new(self, exec, queue)
+    }
+
+    /// Shared access rules of a topic.
+    pub fn topic_authorization<'a, E: Executor + ?Sized>(
+        &'a self,
+        topic: &str,
+        exec: &'a E,
+    ) -> EntityAuthorization<'a, E, TopicDescription> {
+        EntityAuthorization::new(self, exec, topic)
+    }
+}
+
+impl<'a, E: Executor + ?Sized, D: AuthorizedEntity> EntityAuthorization<'a, E, D> {
+    fn new(client: &'a NamespaceClient, exec: &'a E, name: &str) -> Self {
+        EntityAuthorization {
+            client,
+            exec,
+            name: name.to_string(),
+            _entity: std::marker::PhantomData,
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<AuthorizationRule>, Report> {
+        let mut desc = self.fetch()?;
+        Ok(desc.authorization_rules_mut().take().unwrap_or_default())
+    }
+
+    /// Adds a rule. Fails if a rule with the same key name exists.
+    pub fn add(&self, rule: AuthorizationRule) -> Result<AuthorizationRule, Report> {
+        self.modify(&rule.key_name.clone(), |rules, existing| match existing {
+            Some(_) => Err(eyre!(
+                "Authorization rule {} already exists.",
+                rule.key_name
+            )),
+            None => {
+                rules.push(rule);
+                Ok(rules.len() - 1)
+            }
+        })
+    }
+
+    /// Regenerates one key of a rule and returns the updated rule.
+    pub fn rotate(&self, key_name: &str, key: KeyType) -> Result<AuthorizationRule, Report> {
+        self.modify(key_name, |rules, existing| {
+            let idx = existing.ok_or_else(|| eyre!("No authorization rule {}.", key_name))?;
+            rules[idx].regenerate(key);
+            Ok(idx)
+        })
+    }
+
+    pub fn remove(&self, key_name: &str) -> Result<(), Report> {
+        let mut desc = self.fetch()?;
+        let rules = desc.authorization_rules_mut().get_or_insert_with(Vec::new);
+        let before = rules.len();
+        rules.retain(|r| r.key_name != key_name);
+        if rules.len() == before {
+            return Err(eyre!("No authorization rule {}.", key_name));
+        }
+        self.store(&desc)
+    }
+
+    /// A connection string for one of this entity's rules, with `EntityPath` set.
+    pub fn connection_string(&self, rule: &AuthorizationRule, key: KeyType) -> String {
+        rule.connection_string(self.client.endpoint(), &self.name, key)
+    }
+
+    /// A token signed with one of this entity's rules and scoped to the entity
+    /// alone, for handing to a device that should only reach this queue or topic.
+    pub fn sas_token(
+        &self,
+        rule: &AuthorizationRule,
+        key: KeyType,
+        lifetime: Duration,
+    ) -> SasToken {
+        let resource = format!(
+            "sb://{}/{}",
+            self.client.endpoint().host().unwrap_or_default(),
+            self.name
+        );
+        SasToken::builder(&resource)
+            .valid_for(lifetime)
+            .sign(&rule.key_name, rule.key(key))
+    }
+
+    fn modify<F>(&self, key_name: &str, edit: F) -> Result<AuthorizationRule, Report>
+    where
+        F: FnOnce(&mut Vec<AuthorizationRule>, Option<usize>) -> Result<usize, Report>,
+    {
+        let mut desc = self.fetch()?;
+        let rules = desc.authorization_rules_mut().get_or_insert_with(Vec::new);
+        let existing = rules.iter().position(|r| r.key_name == key_name);
+        let idx = edit(rules, existing)?;
+        let rule = rules[idx].clone();
+        self.store(&desc)?;
+        Ok(rule)
+    }
+
+    fn fetch(&self) -> Result<D, Report> {
+        let req = self.client.get(&D::named(&self.name))?;
+        let resp = self.exec.execute(req)?;
+        interpret_results(resp.status())?;
+        D::from_entry(&Entry::parse(resp.body())?)
+    }
+
+    fn store(&self, desc: &D) -> Result<(), Report> {
+        let resp = self.exec.execute(self.client.update(desc)?)?;
+        Ok(interpret_results(resp.status())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, Request, Response, StatusCode};
+    use std::cell::RefCell;
+
+    const CONN: &str = "Endpoint=sb://test.servicebus.windows.net/;\
+                        SharedAccessKeyName=RootManageSharedAccessKey;SharedAccessKey=dGVzdA==";
+
+    // Holds a single queue description and answers GETs and PUTs for it.
+    struct FakeQueue {
+        stored: RefCell<String>,
+    }
+
+    impl FakeQueue {
+        fn exec(&self, req: Request<String>) -> Result<Response<String>, Report> {
+            if req.method() == Method::PUT {
+                assert_eq!(
+                    Some("*"),
+                    req.headers().get("If-Match").map(|h| h.to_str().unwrap())
+                );
+                let entry = Entry::parse(req.body())?;
+                let content = entry.content.unwrap();
+                let desc = QueueDescription::from_xml("orders", &content)?;
+                *self.stored.borrow_mut() = format!(
+                    "<entry><title>orders</title><content>{}</content></entry>",
+                    desc.to_xml()
+                );
+            }
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(self.stored.borrow().clone())?)
+        }
+    }
+
+    #[test]
+    fn add_rotate_remove() -> Result<(), Report> {
+        let client = NamespaceClient::with_conn(CONN)?;
+        let fake = FakeQueue {
+            stored: RefCell::new(format!(
+                "<entry><title>orders</title><content>{}</content></entry>",
+                QueueDescription::new("orders").to_xml()
+            )),
+        };
+        let exec = |req| fake.exec(req);
+        let rules = client.queue_authorization("orders", &exec);
+
+        assert!(rules.list()?.is_empty());
+        let added = rules.add(AuthorizationRule::new("listen", &[AccessRight::Listen]))?;
+        assert_eq!(44, added.primary_key.len());
+        assert!(rules
+            .add(AuthorizationRule::new("listen", &[AccessRight::Send]))
+            .is_err());
+
+        let rotated = rules.rotate("listen", KeyType::Secondary)?;
+        assert_eq!(added.primary_key, rotated.primary_key);
+        assert_ne!(added.secondary_key, rotated.secondary_key);
+        assert_eq!(vec![rotated.clone()], rules.list()?);
+
+        assert_eq!(
+            format!(
+                "Endpoint=sb://test.servicebus.windows.net/;SharedAccessKeyName=listen;\
+                 SharedAccessKey={};EntityPath=orders",
+                rotated.secondary_key
+            ),
+            rules.connection_string(&rotated, KeyType::Secondary)
+        );
+
+        let token = rules.sas_token(&rotated, KeyType::Secondary, Duration::from_secs(60));
+        assert!(token.grants("https://test.servicebus.windows.net/orders/messages"));
+        assert!(!token.grants("https://test.servicebus.windows.net/payments"));
+        crate::core::sas::verify(&token.to_string(), &rotated.secondary_key, 0)?;
+
+        rules.remove("listen")?;
+        assert!(rules.list()?.is_empty());
+        assert!(rules.remove("listen").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn manage_implies_send_and_listen() {
+        let rule = AuthorizationRule::new("admin", &[AccessRight::Manage]);
+        assert_eq!(
+            vec![AccessRight::Manage, AccessRight::Send, AccessRight::Listen],
+            rule.rights
+        );
+        assert!(!format!("{:?}", rule).contains(&rule.primary_key));
+    }
+}
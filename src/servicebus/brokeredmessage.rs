@@ -1,9 +1,38 @@
+use crate::core::clock::{http_date, parse_http_date, Clock};
 use crate::core::error::AzureRequestError;
+use eyre::Report;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
 use hyper::Response;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 
 pub(crate) static BROKER_PROPERTIES_HEADER: &str = "BrokerProperties";
 
+// Headers that are part of the http exchange rather than custom properties.
+const STANDARD_HEADERS: &[&str] = &[
+    "accept",
+    "accept-encoding",
+    "authorization",
+    "brokerproperties",
+    "connection",
+    "content-length",
+    "content-type",
+    "date",
+    "expect",
+    "host",
+    "keep-alive",
+    "location",
+    "server",
+    "strict-transport-security",
+    "transfer-encoding",
+    "user-agent",
+];
+
+/// The custom properties Service Bus adds to a message when it dead letters it.
+pub const DEAD_LETTER_PROPERTIES: &[&str] = &["deadletterreason", "deadlettererrordescription"];
+
 /// A list of the properties that the message can have.
 /// This is not all the possible properties exposed, but it's
 /// some of the common ones.
@@ -35,9 +64,27 @@ pub struct BrokerProperties {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub Label: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub CorrelationId: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub SessionId: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub To: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ReplyTo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ReplyToSessionId: Option<String>,
+    /// When the message becomes visible to receivers, in the same
+    /// `Wed, 05 Dec 2012 10:52:48 GMT` format as the other times.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ScheduledEnqueueTimeUtc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub DeadLetterSource: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub State: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub TimeToLive: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LockedUntilUtc: Option<String>,
 }
 
 /// Queues and Topics send and receive brokered messages.
@@ -54,6 +101,9 @@ pub struct BrokerProperties {
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct BrokeredMessage {
     pub props: Box<BrokerProperties>,
+    /// Custom properties. They travel as http headers with JSON values, and
+    /// since http header names are case insensitive they come back lower case.
+    pub properties: BTreeMap<String, Value>,
     body: String,
 }
 
@@ -75,7 +125,8 @@ impl BrokeredMessage {
     pub fn with_body(body: &str) -> BrokeredMessage {
         BrokeredMessage {
             body: format!("<string>{}</string>", body),
-            props: Box::new(Default::default()),
+            props: Box::default(),
+            properties: BTreeMap::new(),
         }
     }
 
@@ -85,6 +136,7 @@ impl BrokeredMessage {
         BrokeredMessage {
             body: body.to_string(),
             props: Box::new(props),
+            properties: BTreeMap::new(),
         }
     }
 
@@ -105,6 +157,91 @@ impl BrokeredMessage {
         BrokeredMessage {
             body,
             props: Box::new(props),
+            properties: read_properties(&parts.headers),
+        }
+    }
+
+    /// Adds a custom property, e.g. `.with_property("priority", 5)`.
+    pub fn with_property(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.properties.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Delivers the message at `at` instead of straight away.
+    pub fn with_scheduled_enqueue_time(mut self, at: SystemTime) -> Self {
+        self.props.ScheduledEnqueueTimeUtc = Some(http_date(at));
+        self
+    }
+
+    /// Delivers the message `delay` after now, according to `clock`.
+    pub fn with_enqueue_delay(self, delay: Duration, clock: &dyn Clock) -> Self {
+        self.with_scheduled_enqueue_time(clock.now() + delay)
+    }
+
+    /// When the message is to be delivered, if it was scheduled.
+    pub fn scheduled_enqueue_time(&self) -> Option<SystemTime> {
+        self.props
+            .ScheduledEnqueueTimeUtc
+            .as_deref()
+            .and_then(parse_http_date)
+    }
+
+    /// When the lock on a received message runs out.
+    pub fn locked_until(&self) -> Option<SystemTime> {
+        self.props
+            .LockedUntilUtc
+            .as_deref()
+            .and_then(parse_http_date)
+    }
+
+    /// Whether the lock runs out within `margin` of now according to `clock`,
+    /// i.e. whether it is time to renew it. Messages that weren't received
+    /// with a lock never need renewing.
+    ///
+    /// ```
+    /// # use azure_service_bus::core::clock::{http_date, ManualClock, Clock};
+    /// # use azure_service_bus::servicebus::brokeredmessage::{BrokeredMessage, BrokerProperties};
+    /// # use std::time::Duration;
+    /// let clock = ManualClock::at(1_700_000_000);
+    /// let message = BrokeredMessage::with_body_and_props("", BrokerProperties {
+    ///     LockedUntilUtc: Some(http_date(clock.now() + Duration::from_secs(60))),
+    ///     ..Default::default()
+    /// });
+    /// assert!(!message.lock_expires_within(Duration::from_secs(10), &clock));
+    /// clock.advance(Duration::from_secs(55));
+    /// assert!(message.lock_expires_within(Duration::from_secs(10), &clock));
+    /// ```
+    pub fn lock_expires_within(&self, margin: Duration, clock: &dyn Clock) -> bool {
+        self.locked_until()
+            .is_some_and(|until| clock.now() + margin >= until)
+    }
+
+    /// A new message with this one's body, the properties its sender set and
+    /// its custom properties, to send again. What the broker added on the way
+    /// (the lock token, sequence number, delivery count, enqueued time and the
+    /// reason it was dead lettered) is left out.
+    pub fn copy_for_resend(&self) -> BrokeredMessage {
+        let received = &self.props;
+        let props = BrokerProperties {
+            MessageId: received.MessageId.clone(),
+            Label: received.Label.clone(),
+            CorrelationId: received.CorrelationId.clone(),
+            SessionId: received.SessionId.clone(),
+            To: received.To.clone(),
+            ReplyTo: received.ReplyTo.clone(),
+            ReplyToSessionId: received.ReplyToSessionId.clone(),
+            TimeToLive: received.TimeToLive,
+            ..Default::default()
+        };
+        BrokeredMessage {
+            body: self.body.clone(),
+            props: Box::new(props),
+            properties: self
+                .properties
+                .iter()
+                .filter(|(name, _)| !DEAD_LETTER_PROPERTIES.contains(&name.as_str()))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
         }
     }
 
@@ -149,10 +286,56 @@ impl BrokeredMessage {
     /// Serializes all of the message properties into JSON. This is mostly used to transmit
     /// over HTTP, but it is exposed to the user of the library as well.
     pub fn props_as_json(&self) -> String {
-        serde_json::to_string(&self.props).unwrap()
+        header_json(&self.props)
     }
 }
 
+/// Picks the custom properties out of the headers of a message. Values that
+/// aren't JSON are kept as strings.
+pub(crate) fn read_properties(headers: &HeaderMap) -> BTreeMap<String, Value> {
+    headers
+        .iter()
+        .filter(|(name, _)| !STANDARD_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+            Some((name.as_str().to_string(), value))
+        })
+        .collect()
+}
+
+/// Adds custom properties to the headers of a request or response.
+pub(crate) fn write_properties(
+    headers: &mut HeaderMap,
+    properties: &BTreeMap<String, Value>,
+) -> Result<(), Report> {
+    for (name, value) in properties {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(&header_json(value))?,
+        );
+    }
+    Ok(())
+}
+
+// Header values can only be read back as text when they are visible ASCII, so
+// anything else is written as a `\u` escape. Outside of strings serde_json
+// only ever writes ASCII, so this can't change what the JSON means.
+fn header_json<T: Serialize + ?Sized>(value: &T) -> String {
+    let json = serde_json::to_string(value).unwrap();
+    let mut out = String::with_capacity(json.len());
+    for ch in json.chars() {
+        if ch.is_ascii() && !ch.is_ascii_control() {
+            out.push(ch);
+        } else {
+            for unit in ch.encode_utf16(&mut [0; 2]) {
+                out.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,6 +360,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn properties_round_trip_through_headers() {
+        let message = BrokeredMessage::with_body("")
+            .with_property("color", "blue")
+            .with_property("priority", 5)
+            .with_property("urgent", true);
+        let mut response = Response::new(String::new());
+        write_properties(response.headers_mut(), &message.properties).unwrap();
+        response.headers_mut().insert(
+            "Date",
+            HeaderValue::from_static("Wed, 05 Dec 2012 10:52:48 GMT"),
+        );
+        response
+            .headers_mut()
+            .insert("Content-Length", HeaderValue::from_static("0"));
+
+        let received = BrokeredMessage::with_response(response);
+        assert_eq!(message.properties, received.properties);
+    }
+
+    #[test]
+    fn header_json_is_ascii() {
+        let message = BrokeredMessage::with_body("").with_property("city", "Zürich 🏔");
+        let mut headers = HeaderMap::new();
+        write_properties(&mut headers, &message.properties).unwrap();
+        assert_eq!(
+            "\"Z\\u00fcrich \\ud83c\\udfd4\"",
+            headers.get("city").unwrap().to_str().unwrap()
+        );
+        assert_eq!(message.properties, read_properties(&headers));
+    }
+
+    #[test]
+    fn scheduled_enqueue_time_uses_the_clock() {
+        use crate::core::clock::ManualClock;
+
+        let clock = ManualClock::at(1_354_704_768);
+        let message =
+            BrokeredMessage::with_body("").with_enqueue_delay(Duration::from_secs(60), &clock);
+        assert_eq!(
+            Some("Wed, 05 Dec 2012 10:53:48 GMT"),
+            message.props.ScheduledEnqueueTimeUtc.as_deref()
+        );
+        assert_eq!(
+            Some(clock.now() + Duration::from_secs(60)),
+            message.scheduled_enqueue_time()
+        );
+    }
+
     #[test]
     fn message_json_test() {
         let message = BrokeredMessage::with_body("{\"Azure\":2}");
@@ -0,0 +1,792 @@
+use super::atom::{iso8601, Entry, XmlElement, XmlWriter};
+use super::authorization::{parse_rules, write_rules, AuthorizationRule};
+use eyre::{eyre, Report};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Message counts broken down by where the messages are sitting.
+/// Service Bus only fills these in on reads, they are ignored when
+/// creating or updating an entity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountDetails {
+    pub active_message_count: u64,
+    pub dead_letter_message_count: u64,
+    pub scheduled_message_count: u64,
+    pub transfer_message_count: u64,
+    pub transfer_dead_letter_message_count: u64,
+}
+
+impl CountDetails {
+    fn from_xml(el: &XmlElement) -> Result<CountDetails, Report> {
+        Ok(CountDetails {
+            active_message_count: el.parse_child("ActiveMessageCount")?.unwrap_or(0),
+            dead_letter_message_count: el.parse_child("DeadLetterMessageCount")?.unwrap_or(0),
+            scheduled_message_count: el.parse_child("ScheduledMessageCount")?.unwrap_or(0),
+            transfer_message_count: el.parse_child("TransferMessageCount")?.unwrap_or(0),
+            transfer_dead_letter_message_count: el
+                .parse_child("TransferDeadLetterMessageCount")?
+                .unwrap_or(0),
+        })
+    }
+}
+
+/// Implemented by the descriptions that come back from the management api.
+pub trait EntityDescription: Sized {
+    /// The element name inside the entry's `<content>`, e.g. `QueueDescription`.
+    const ELEMENT: &'static str;
+
+    /// Builds the description from the contents of an ATOM entry.
+    /// `name` is the title of the entry.
+    fn from_xml(name: &str, el: &XmlElement) -> Result<Self, Report>;
+
+    /// Serializes the writable settings for a create or update. Read only
+    /// fields are left out.
+    fn to_xml(&self) -> String;
+
+    /// Where the entity lives, relative to the namespace endpoint.
+    fn path(&self) -> String;
+
+    fn from_entry(entry: &Entry) -> Result<Self, Report> {
+        let content = entry
+            .content
+            .as_ref()
+            .filter(|c| c.name == Self::ELEMENT)
+            .ok_or_else(|| {
+                eyre!(
+                    "Entry {} does not contain a {}.",
+                    entry.title,
+                    Self::ELEMENT
+                )
+            })?;
+        Self::from_xml(&entry.title, content)
+    }
+}
+
+/// The settings and runtime information of a queue.
+///
+/// Every setting is optional. Fields that are `None` were not returned by the
+/// server, or were left for the server to default.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDescription {
+    pub name: String,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub lock_duration: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size_in_megabytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_duplicate_detection: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_session: Option<bool>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub default_message_time_to_live: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_lettering_on_message_expiration: Option<bool>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub duplicate_detection_history_time_window: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delivery_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_batched_operations: Option<bool>,
+    /// Shared access rules scoped to this queue. Never serialized, to keep keys out of
+    /// config files.
+    #[serde(skip)]
+    pub authorization_rules: Option<Vec<AuthorizationRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_metadata: Option<String>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub auto_delete_on_idle: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_partitioning: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_dead_lettered_messages_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_express: Option<bool>,
+
+    // Runtime information. Read only.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub message_count: Option<u64>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub count_details: Option<CountDetails>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub accessed_at: Option<String>,
+}
+
+impl QueueDescription {
+    pub fn new(name: &str) -> QueueDescription {
+        QueueDescription {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl EntityDescription for QueueDescription {
+    const ELEMENT: &'static str = "QueueDescription";
+
+    fn from_xml(name: &str, el: &XmlElement) -> Result<Self, Report> {
+        Ok(QueueDescription {
+            name: name.to_string(),
+            lock_duration: el.duration_child("LockDuration")?,
+            max_size_in_megabytes: el.parse_child("MaxSizeInMegabytes")?,
+            requires_duplicate_detection: el.parse_child("RequiresDuplicateDetection")?,
+            requires_session: el.parse_child("RequiresSession")?,
+            default_message_time_to_live: el.duration_child("DefaultMessageTimeToLive")?,
+            dead_lettering_on_message_expiration: el
+                .parse_child("DeadLetteringOnMessageExpiration")?,
+            duplicate_detection_history_time_window: el
+                .duration_child("DuplicateDetectionHistoryTimeWindow")?,
+            max_delivery_count: el.parse_child("MaxDeliveryCount")?,
+            enable_batched_operations: el.parse_child("EnableBatchedOperations")?,
+            authorization_rules: parse_rules(el)?,
+            status: el.parse_child("Status")?,
+            forward_to: el.parse_child("ForwardTo")?,
+            user_metadata: el.parse_child("UserMetadata")?,
+            auto_delete_on_idle: el.duration_child("AutoDeleteOnIdle")?,
+            enable_partitioning: el.parse_child("EnablePartitioning")?,
+            forward_dead_lettered_messages_to: el.parse_child("ForwardDeadLetteredMessagesTo")?,
+            enable_express: el.parse_child("EnableExpress")?,
+            size_in_bytes: el.parse_child("SizeInBytes")?,
+            message_count: el.parse_child("MessageCount")?,
+            count_details: el
+                .child("CountDetails")
+                .map(CountDetails::from_xml)
+                .transpose()?,
+            created_at: el.parse_child("CreatedAt")?,
+            updated_at: el.parse_child("UpdatedAt")?,
+            accessed_at: el.parse_child("AccessedAt")?,
+        })
+    }
+
+    fn to_xml(&self) -> String {
+        XmlWriter::new()
+            .opt_duration("LockDuration", &self.lock_duration)
+            .opt("MaxSizeInMegabytes", &self.max_size_in_megabytes)
+            .opt(
+                "RequiresDuplicateDetection",
+                &self.requires_duplicate_detection,
+            )
+            .opt("RequiresSession", &self.requires_session)
+            .opt_duration(
+                "DefaultMessageTimeToLive",
+                &self.default_message_time_to_live,
+            )
+            .opt(
+                "DeadLetteringOnMessageExpiration",
+                &self.dead_lettering_on_message_expiration,
+            )
+            .opt_duration(
+                "DuplicateDetectionHistoryTimeWindow",
+                &self.duplicate_detection_history_time_window,
+            )
+            .opt("MaxDeliveryCount", &self.max_delivery_count)
+            .opt("EnableBatchedOperations", &self.enable_batched_operations)
+            .raw(&write_rules(&self.authorization_rules))
+            .opt("Status", &self.status)
+            .opt("ForwardTo", &self.forward_to)
+            .opt("UserMetadata", &self.user_metadata)
+            .opt_duration("AutoDeleteOnIdle", &self.auto_delete_on_idle)
+            .opt("EnablePartitioning", &self.enable_partitioning)
+            .opt(
+                "ForwardDeadLetteredMessagesTo",
+                &self.forward_dead_lettered_messages_to,
+            )
+            .opt("EnableExpress", &self.enable_express)
+            .finish(Self::ELEMENT)
+    }
+
+    fn path(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// The settings and runtime information of a topic.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicDescription {
+    pub name: String,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub default_message_time_to_live: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size_in_megabytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_duplicate_detection: Option<bool>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub duplicate_detection_history_time_window: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_batched_operations: Option<bool>,
+    /// Shared access rules scoped to this topic. Never serialized, to keep keys out of
+    /// config files.
+    #[serde(skip)]
+    pub authorization_rules: Option<Vec<AuthorizationRule>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub support_ordering: Option<bool>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub auto_delete_on_idle: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_partitioning: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_express: Option<bool>,
+
+    // Runtime information. Read only.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub subscription_count: Option<u64>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub count_details: Option<CountDetails>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub accessed_at: Option<String>,
+}
+
+impl TopicDescription {
+    pub fn new(name: &str) -> TopicDescription {
+        TopicDescription {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl EntityDescription for TopicDescription {
+    const ELEMENT: &'static str = "TopicDescription";
+
+    fn from_xml(name: &str, el: &XmlElement) -> Result<Self, Report> {
+        Ok(TopicDescription {
+            name: name.to_string(),
+            default_message_time_to_live: el.duration_child("DefaultMessageTimeToLive")?,
+            max_size_in_megabytes: el.parse_child("MaxSizeInMegabytes")?,
+            requires_duplicate_detection: el.parse_child("RequiresDuplicateDetection")?,
+            duplicate_detection_history_time_window: el
+                .duration_child("DuplicateDetectionHistoryTimeWindow")?,
+            enable_batched_operations: el.parse_child("EnableBatchedOperations")?,
+            authorization_rules: parse_rules(el)?,
+            status: el.parse_child("Status")?,
+            user_metadata: el.parse_child("UserMetadata")?,
+            support_ordering: el.parse_child("SupportOrdering")?,
+            auto_delete_on_idle: el.duration_child("AutoDeleteOnIdle")?,
+            enable_partitioning: el.parse_child("EnablePartitioning")?,
+            enable_express: el.parse_child("EnableExpress")?,
+            size_in_bytes: el.parse_child("SizeInBytes")?,
+            subscription_count: el.parse_child("SubscriptionCount")?,
+            count_details: el
+                .child("CountDetails")
+                .map(CountDetails::from_xml)
+                .transpose()?,
+            created_at: el.parse_child("CreatedAt")?,
+            updated_at: el.parse_child("UpdatedAt")?,
+            accessed_at: el.parse_child("AccessedAt")?,
+        })
+    }
+
+    fn to_xml(&self) -> String {
+        XmlWriter::new()
+            .opt_duration(
+                "DefaultMessageTimeToLive",
+                &self.default_message_time_to_live,
+            )
+            .opt("MaxSizeInMegabytes", &self.max_size_in_megabytes)
+            .opt(
+                "RequiresDuplicateDetection",
+                &self.requires_duplicate_detection,
+            )
+            .opt_duration(
+                "DuplicateDetectionHistoryTimeWindow",
+                &self.duplicate_detection_history_time_window,
+            )
+            .opt("EnableBatchedOperations", &self.enable_batched_operations)
+            .raw(&write_rules(&self.authorization_rules))
+            .opt("Status", &self.status)
+            .opt("UserMetadata", &self.user_metadata)
+            .opt("SupportOrdering", &self.support_ordering)
+            .opt_duration("AutoDeleteOnIdle", &self.auto_delete_on_idle)
+            .opt("EnablePartitioning", &self.enable_partitioning)
+            .opt("EnableExpress", &self.enable_express)
+            .finish(Self::ELEMENT)
+    }
+
+    fn path(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// The settings and runtime information of a subscription. `topic` is not part
+/// of the description Service Bus sends back, it is filled in from the request.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionDescription {
+    #[serde(skip)]
+    pub topic: String,
+    pub name: String,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub lock_duration: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_session: Option<bool>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub default_message_time_to_live: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_lettering_on_message_expiration: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_lettering_on_filter_evaluation_exceptions: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delivery_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_batched_operations: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_dead_lettered_messages_to: Option<String>,
+    #[serde(default, with = "iso8601", skip_serializing_if = "Option::is_none")]
+    pub auto_delete_on_idle: Option<Duration>,
+
+    // Runtime information. Read only.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub message_count: Option<u64>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub count_details: Option<CountDetails>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub accessed_at: Option<String>,
+}
+
+impl SubscriptionDescription {
+    pub fn new(topic: &str, name: &str) -> SubscriptionDescription {
+        SubscriptionDescription {
+            topic: topic.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl EntityDescription for SubscriptionDescription {
+    const ELEMENT: &'static str = "SubscriptionDescription";
+
+    fn from_xml(name: &str, el: &XmlElement) -> Result<Self, Report> {
+        Ok(SubscriptionDescription {
+            topic: String::new(),
+            name: name.to_string(),
+            lock_duration: el.duration_child("LockDuration")?,
+            requires_session: el.parse_child("RequiresSession")?,
+            default_message_time_to_live: el.duration_child("DefaultMessageTimeToLive")?,
+            dead_lettering_on_message_expiration: el
+                .parse_child("DeadLetteringOnMessageExpiration")?,
+            dead_lettering_on_filter_evaluation_exceptions: el
+                .parse_child("DeadLetteringOnFilterEvaluationExceptions")?,
+            max_delivery_count: el.parse_child("MaxDeliveryCount")?,
+            enable_batched_operations: el.parse_child("EnableBatchedOperations")?,
+            status: el.parse_child("Status")?,
+            forward_to: el.parse_child("ForwardTo")?,
+            user_metadata: el.parse_child("UserMetadata")?,
+            forward_dead_lettered_messages_to: el.parse_child("ForwardDeadLetteredMessagesTo")?,
+            auto_delete_on_idle: el.duration_child("AutoDeleteOnIdle")?,
+            message_count: el.parse_child("MessageCount")?,
+            count_details: el
+                .child("CountDetails")
+                .map(CountDetails::from_xml)
+                .transpose()?,
+            created_at: el.parse_child("CreatedAt")?,
+            updated_at: el.parse_child("UpdatedAt")?,
+            accessed_at: el.parse_child("AccessedAt")?,
+        })
+    }
+
+    fn to_xml(&self) -> String {
+        XmlWriter::new()
+            .opt_duration("LockDuration", &self.lock_duration)
+            .opt("RequiresSession", &self.requires_session)
+            .opt_duration(
+                "DefaultMessageTimeToLive",
+                &self.default_message_time_to_live,
+            )
+            .opt(
+                "DeadLetteringOnMessageExpiration",
+                &self.dead_lettering_on_message_expiration,
+            )
+            .opt(
+                "DeadLetteringOnFilterEvaluationExceptions",
+                &self.dead_lettering_on_filter_evaluation_exceptions,
+            )
+            .opt("MaxDeliveryCount", &self.max_delivery_count)
+            .opt("EnableBatchedOperations", &self.enable_batched_operations)
+            .opt("Status", &self.status)
+            .opt("ForwardTo", &self.forward_to)
+            .opt("UserMetadata", &self.user_metadata)
+            .opt(
+                "ForwardDeadLetteredMessagesTo",
+                &self.forward_dead_lettered_messages_to,
+            )
+            .opt_duration("AutoDeleteOnIdle", &self.auto_delete_on_idle)
+            .finish(Self::ELEMENT)
+    }
+
+    fn path(&self) -> String {
+        format!("{}/Subscriptions/{}", self.topic, self.name)
+    }
+}
+
+/// Decides which of the messages sent to a topic a subscription gets a copy of.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleFilter {
+    /// A SQL-92 style expression over the message properties, e.g. `color = 'blue'`.
+    Sql {
+        expression: String,
+    },
+    /// Matches messages whose properties equal every value that is set.
+    Correlation(CorrelationFilter),
+    #[default]
+    True,
+    False,
+}
+
+/// The system properties a correlation filter can match on, plus user properties.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorrelationFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+/// Modifies the properties of messages that matched the filter. A rule
+/// without an action passes messages through untouched.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// e.g. `SET priority = 'high'; REMOVE temp`
+    Sql { expression: String },
+}
+
+/// A filter and optional action attached to a subscription. `topic` and
+/// `subscription` are filled in from the request like `SubscriptionDescription::topic`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleDescription {
+    #[serde(skip)]
+    pub topic: String,
+    #[serde(skip)]
+    pub subscription: String,
+    pub name: String,
+    #[serde(default)]
+    pub filter: RuleFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<RuleAction>,
+
+    // Runtime information. Read only.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+}
+
+impl RuleDescription {
+    pub fn new(topic: &str, subscription: &str, name: &str, filter: RuleFilter) -> Self {
+        RuleDescription {
+            topic: topic.to_string(),
+            subscription: subscription.to_string(),
+            name: name.to_string(),
+            filter,
+            ..Default::default()
+        }
+    }
+}
+
+const SQL_COMPATIBILITY_LEVEL: &str = "20";
+
+impl EntityDescription for RuleDescription {
+    const ELEMENT: &'static str = "RuleDescription";
+
+    fn from_xml(name: &str, el: &XmlElement) -> Result<Self, Report> {
+        let filter = match el.child("Filter") {
+            None => RuleFilter::True,
+            Some(f) => match f.attribute("type") {
+                Some("SqlFilter") => RuleFilter::Sql {
+                    expression: f.parse_child("SqlExpression")?.unwrap_or_default(),
+                },
+                Some("TrueFilter") | None => RuleFilter::True,
+                Some("FalseFilter") => RuleFilter::False,
+                Some("CorrelationFilter") => RuleFilter::Correlation(CorrelationFilter {
+                    correlation_id: f.parse_child("CorrelationId")?,
+                    message_id: f.parse_child("MessageId")?,
+                    to: f.parse_child("To")?,
+                    reply_to: f.parse_child("ReplyTo")?,
+                    label: f.parse_child("Label")?,
+                    session_id: f.parse_child("SessionId")?,
+                    reply_to_session_id: f.parse_child("ReplyToSessionId")?,
+                    content_type: f.parse_child("ContentType")?,
+                    properties: f
+                        .child("Properties")
+                        .map(|props| {
+                            props
+                                .children
+                                .iter()
+                                .map(|kv| {
+                                    (
+                                        kv.child_text("Key").unwrap_or_default().to_string(),
+                                        kv.child_text("Value").unwrap_or_default().to_string(),
+                                    )
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                }),
+                Some(other) => return Err(eyre!("Unsupported rule filter type {}.", other)),
+            },
+        };
+
+        let action = match el.child("Action") {
+            Some(a) if a.attribute("type") == Some("SqlRuleAction") => Some(RuleAction::Sql {
+                expression: a.parse_child("SqlExpression")?.unwrap_or_default(),
+            }),
+            _ => None,
+        };
+
+        Ok(RuleDescription {
+            topic: String::new(),
+            subscription: String::new(),
+            name: el.parse_child("Name")?.unwrap_or_else(|| name.to_string()),
+            filter,
+            action,
+            created_at: el.parse_child("CreatedAt")?,
+        })
+    }
+
+    fn to_xml(&self) -> String {
+        let filter = match &self.filter {
+            RuleFilter::Sql { expression } => XmlWriter::new()
+                .text("SqlExpression", expression)
+                .text("CompatibilityLevel", SQL_COMPATIBILITY_LEVEL)
+                .typed("Filter", "SqlFilter"),
+            RuleFilter::True => XmlWriter::new()
+                .text("SqlExpression", "1=1")
+                .text("CompatibilityLevel", SQL_COMPATIBILITY_LEVEL)
+                .typed("Filter", "TrueFilter"),
+            RuleFilter::False => XmlWriter::new()
+                .text("SqlExpression", "1=0")
+                .text("CompatibilityLevel", SQL_COMPATIBILITY_LEVEL)
+                .typed("Filter", "FalseFilter"),
+            RuleFilter::Correlation(c) => {
+                let mut props = XmlWriter::new();
+                for (k, v) in &c.properties {
+                    props.raw(&format!(
+                        "<KeyValueOfstringanyType><Key>{}</Key><Value i:type=\"d6p1:string\" \
+                         xmlns:d6p1=\"http://www.w3.org/2001/XMLSchema\">{}</Value>\
+                         </KeyValueOfstringanyType>",
+                        quick_xml::escape::escape(k.as_str()),
+                        quick_xml::escape::escape(v.as_str())
+                    ));
+                }
+                XmlWriter::new()
+                    .opt("CorrelationId", &c.correlation_id)
+                    .opt("MessageId", &c.message_id)
+                    .opt("To", &c.to)
+                    .opt("ReplyTo", &c.reply_to)
+                    .opt("Label", &c.label)
+                    .opt("SessionId", &c.session_id)
+                    .opt("ReplyToSessionId", &c.reply_to_session_id)
+                    .opt("ContentType", &c.content_type)
+                    .raw(&props.plain("Properties"))
+                    .typed("Filter", "CorrelationFilter")
+            }
+        };
+        let action = match &self.action {
+            Some(RuleAction::Sql { expression }) => XmlWriter::new()
+                .text("SqlExpression", expression)
+                .text("CompatibilityLevel", SQL_COMPATIBILITY_LEVEL)
+                .typed("Action", "SqlRuleAction"),
+            None => XmlWriter::new().typed("Action", "EmptyRuleAction"),
+        };
+
+        XmlWriter::new()
+            .raw(&filter)
+            .raw(&action)
+            .text("Name", &self.name)
+            .finish(Self::ELEMENT)
+    }
+
+    fn path(&self) -> String {
+        format!(
+            "{}/Subscriptions/{}/Rules/{}",
+            self.topic, self.subscription, self.name
+        )
+    }
+}
+
+/// Any of the entities that can live in a namespace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entity {
+    Queue(QueueDescription),
+    Topic(TopicDescription),
+    Subscription(SubscriptionDescription),
+    Rule(RuleDescription),
+}
+
+impl Entity {
+    /// A lower case name for the kind of entity, e.g. `queue`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Entity::Queue(_) => "queue",
+            Entity::Topic(_) => "topic",
+            Entity::Subscription(_) => "subscription",
+            Entity::Rule(_) => "rule",
+        }
+    }
+
+    pub fn path(&self) -> String {
+        match self {
+            Entity::Queue(d) => d.path(),
+            Entity::Topic(d) => d.path(),
+            Entity::Subscription(d) => d.path(),
+            Entity::Rule(d) => d.path(),
+        }
+    }
+
+    pub fn to_xml(&self) -> String {
+        match self {
+            Entity::Queue(d) => d.to_xml(),
+            Entity::Topic(d) => d.to_xml(),
+            Entity::Subscription(d) => d.to_xml(),
+            Entity::Rule(d) => d.to_xml(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE_ENTRY: &str = r#"<entry xmlns="http://www.w3.org/2005/Atom">
+      <id>https://ns.servicebus.windows.net/orders?api-version=2017-04</id>
+      <title type="text">orders</title>
+      <content type="application/xml">
+        <QueueDescription xmlns="http://schemas.microsoft.com/netservices/2010/10/servicebus/connect" xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+          <LockDuration>PT1M</LockDuration>
+          <MaxSizeInMegabytes>1024</MaxSizeInMegabytes>
+          <RequiresDuplicateDetection>false</RequiresDuplicateDetection>
+          <RequiresSession>false</RequiresSession>
+          <DefaultMessageTimeToLive>P14D</DefaultMessageTimeToLive>
+          <DeadLetteringOnMessageExpiration>true</DeadLetteringOnMessageExpiration>
+          <MaxDeliveryCount>10</MaxDeliveryCount>
+          <SizeInBytes>512</SizeInBytes>
+          <MessageCount>4</MessageCount>
+          <Status>Active</Status>
+          <ForwardTo/>
+          <CountDetails xmlns:d2p1="http://schemas.microsoft.com/netservices/2011/06/servicebus">
+            <d2p1:ActiveMessageCount>3</d2p1:ActiveMessageCount>
+            <d2p1:DeadLetterMessageCount>1</d2p1:DeadLetterMessageCount>
+            <d2p1:ScheduledMessageCount>0</d2p1:ScheduledMessageCount>
+            <d2p1:TransferMessageCount>0</d2p1:TransferMessageCount>
+            <d2p1:TransferDeadLetterMessageCount>0</d2p1:TransferDeadLetterMessageCount>
+          </CountDetails>
+        </QueueDescription>
+      </content>
+    </entry>"#;
+
+    #[test]
+    fn queue_description_parses() {
+        let entry = Entry::parse(QUEUE_ENTRY).unwrap();
+        let queue = QueueDescription::from_entry(&entry).unwrap();
+        assert_eq!("orders", queue.name);
+        assert_eq!(Some(Duration::from_secs(60)), queue.lock_duration);
+        assert_eq!(Some(1024), queue.max_size_in_megabytes);
+        assert_eq!(Some(true), queue.dead_lettering_on_message_expiration);
+        assert_eq!(Some(10), queue.max_delivery_count);
+        assert_eq!(Some("Active".to_string()), queue.status);
+        assert_eq!(None, queue.forward_to);
+        let counts = queue.count_details.unwrap();
+        assert_eq!(3, counts.active_message_count);
+        assert_eq!(1, counts.dead_letter_message_count);
+    }
+
+    #[test]
+    fn queue_description_round_trips() {
+        let entry = Entry::parse(QUEUE_ENTRY).unwrap();
+        let queue = QueueDescription::from_entry(&entry).unwrap();
+        let xml = queue.to_xml();
+        assert!(xml.starts_with("<QueueDescription xmlns="));
+        // Read only fields are not sent back.
+        assert!(!xml.contains("MessageCount"));
+        let el = XmlElement::parse(&xml).unwrap();
+        let reparsed = QueueDescription::from_xml("orders", &el).unwrap();
+        assert_eq!(queue.lock_duration, reparsed.lock_duration);
+        assert_eq!(queue.max_delivery_count, reparsed.max_delivery_count);
+        assert_eq!(None, reparsed.count_details);
+    }
+
+    #[test]
+    fn rule_description_round_trips() {
+        let mut filter = CorrelationFilter {
+            label: Some("urgent".to_string()),
+            ..Default::default()
+        };
+        filter
+            .properties
+            .insert("region".to_string(), "eu & us".to_string());
+        let rules = vec![
+            RuleDescription::new(
+                "t",
+                "s",
+                "blue",
+                RuleFilter::Sql {
+                    expression: "color = 'blue' AND size > 3".to_string(),
+                },
+            ),
+            RuleDescription::new("t", "s", "all", RuleFilter::True),
+            RuleDescription {
+                action: Some(RuleAction::Sql {
+                    expression: "SET seen = 1".to_string(),
+                }),
+                ..RuleDescription::new("t", "s", "urgent", RuleFilter::Correlation(filter))
+            },
+        ];
+        for rule in rules {
+            let el = XmlElement::parse(&rule.to_xml()).unwrap();
+            let mut reparsed = RuleDescription::from_xml("ignored", &el).unwrap();
+            reparsed.topic = "t".to_string();
+            reparsed.subscription = "s".to_string();
+            assert_eq!(rule, reparsed);
+        }
+    }
+
+    #[test]
+    fn wrong_description_is_an_error() {
+        let entry = Entry::parse(QUEUE_ENTRY).unwrap();
+        assert!(TopicDescription::from_entry(&entry).is_err());
+    }
+}
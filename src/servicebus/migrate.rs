@@ -0,0 +1,291 @@
+use super::description::*;
+use super::namespace::NamespaceClient;
+use super::topology::{Change, Plan, Topology};
+use crate::core::exec::Executor;
+use eyre::Report;
+use hyper::Uri;
+
+/// What to do with entities that already exist in the destination namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExistingEntities {
+    /// Leave them exactly as they are.
+    Skip,
+    /// Update their settings and rules to match the source.
+    Overwrite,
+}
+
+/// Builds the plan that copies every queue, topic, subscription and rule of
+/// `source` into `destination`. Nothing is ever deleted from the destination.
+///
+/// Forwarding targets that point back into the source namespace are rewritten
+/// to plain entity names so they resolve inside the destination. Targets in
+/// other namespaces are left alone.
+pub fn copy_plan(
+    source: &Topology,
+    source_endpoint: &Uri,
+    destination: &Topology,
+    existing: ExistingEntities,
+) -> Result<Plan, Report> {
+    let mut plan = portable(source, source_endpoint).plan(destination, false)?;
+    if existing == ExistingEntities::Skip {
+        plan.changes.retain(|c| match c {
+            // Rules on subscriptions that were already there are part of the
+            // existing entity, leave them be too. New subscriptions come with
+            // a `$Default` rule that may need updating to match the source.
+            Change::Create(Entity::Rule(rule))
+            | Change::Update {
+                entity: Entity::Rule(rule),
+                ..
+            }
+            | Change::Delete(Entity::Rule(rule)) => {
+                !subscription_exists(destination, &rule.topic, &rule.subscription)
+            }
+            Change::Update { .. } => false,
+            _ => true,
+        });
+    }
+    Ok(plan)
+}
+
+/// Reads both namespaces and builds the plan that copies `source` into
+/// `destination`. Print the plan for a dry run, or `apply` it with the
+/// destination client.
+///
+/// ```no_run
+/// # use hyper::{Request, Response};
+/// # use azure_service_bus::servicebus::migrate::{copy_namespace, ExistingEntities};
+/// # fn exec(_: Request<String>) -> Result<Response<String>, eyre::Report> { unimplemented!() }
+/// # fn main() -> Result<(), eyre::Report> {
+/// # let (prod, staging): (azure_service_bus::NamespaceClient, azure_service_bus::NamespaceClient) = unimplemented!();
+/// let plan = copy_namespace(&prod, &exec, &staging, &exec, ExistingEntities::Skip)?;
+/// println!("{}", plan);
+/// plan.apply(&staging, &exec)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn copy_namespace<S, D>(
+    source: &NamespaceClient,
+    source_exec: &S,
+    destination: &NamespaceClient,
+    destination_exec: &D,
+    existing: ExistingEntities,
+) -> Result<Plan, Report>
+where
+    S: Executor + ?Sized,
+    D: Executor + ?Sized,
+{
+    let from = Topology::from_namespace(source, source_exec)?;
+    let to = Topology::from_namespace(destination, destination_exec)?;
+    copy_plan(&from, source.endpoint(), &to, existing)
+}
+
+/// Strips the runtime information and shared access rules out of a live
+/// topology and rewrites forwarding targets inside `endpoint` to plain entity
+/// names, leaving a topology that can be written to a file or applied to
+/// another namespace.
+pub fn portable(live: &Topology, endpoint: &Uri) -> Topology {
+    let rewrite = |target: &mut Option<String>| {
+        if let Some(t) = target {
+            *t = local_entity_name(t, endpoint).unwrap_or_else(|| t.clone());
+        }
+    };
+
+    let mut topology = live.clone();
+    for queue in &mut topology.queues {
+        rewrite(&mut queue.forward_to);
+        rewrite(&mut queue.forward_dead_lettered_messages_to);
+        queue.authorization_rules = None;
+        queue.size_in_bytes = None;
+        queue.message_count = None;
+        queue.count_details = None;
+        queue.created_at = None;
+        queue.updated_at = None;
+        queue.accessed_at = None;
+    }
+    for topic in &mut topology.topics {
+        let desc = &mut topic.description;
+        desc.authorization_rules = None;
+        desc.size_in_bytes = None;
+        desc.subscription_count = None;
+        desc.count_details = None;
+        desc.created_at = None;
+        desc.updated_at = None;
+        desc.accessed_at = None;
+        for sub in &mut topic.subscriptions {
+            let desc = &mut sub.description;
+            rewrite(&mut desc.forward_to);
+            rewrite(&mut desc.forward_dead_lettered_messages_to);
+            desc.message_count = None;
+            desc.count_details = None;
+            desc.created_at = None;
+            desc.updated_at = None;
+            desc.accessed_at = None;
+            for rule in sub.rules.iter_mut().flatten() {
+                rule.created_at = None;
+            }
+        }
+    }
+    topology
+}
+
+// `https://ns.servicebus.windows.net/orders` is `orders` when `endpoint` is ns.
+fn local_entity_name(target: &str, endpoint: &Uri) -> Option<String> {
+    let uri: Uri = target.parse().ok()?;
+    let same_host = uri
+        .host()
+        .zip(endpoint.host())
+        .is_some_and(|(a, b)| a.eq_ignore_ascii_case(b));
+    if !same_host {
+        return None;
+    }
+    Some(uri.path().trim_matches('/').to_string())
+}
+
+fn subscription_exists(topology: &Topology, topic: &str, subscription: &str) -> bool {
+    topology
+        .topics
+        .iter()
+        .filter(|t| t.description.name.eq_ignore_ascii_case(topic))
+        .flat_map(|t| &t.subscriptions)
+        .any(|s| s.description.name.eq_ignore_ascii_case(subscription))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servicebus::topology::{SubscriptionTopology, TopicTopology};
+    use std::time::Duration;
+
+    fn source() -> Topology {
+        let mut orders = QueueDescription::new("orders");
+        orders.lock_duration = Some(Duration::from_secs(60));
+        orders.message_count = Some(10);
+        let mut retries = QueueDescription::new("retries");
+        retries.forward_to = Some("https://prod.servicebus.windows.net/orders".to_string());
+        let mut audit = SubscriptionDescription::new("events", "audit");
+        audit.forward_to = Some("https://elsewhere.servicebus.windows.net/log".to_string());
+        let mut rule = RuleDescription::new("events", "audit", "errors", RuleFilter::False);
+        rule.created_at = Some("2020-01-01T00:00:00Z".to_string());
+
+        Topology {
+            queues: vec![orders, retries],
+            topics: vec![TopicTopology {
+                description: TopicDescription::new("events"),
+                subscriptions: vec![SubscriptionTopology {
+                    description: audit,
+                    rules: Some(vec![rule]),
+                }],
+            }],
+        }
+    }
+
+    fn endpoint() -> Uri {
+        "https://prod.servicebus.windows.net/".parse().unwrap()
+    }
+
+    #[test]
+    fn forwarding_is_rewritten_and_runtime_info_dropped() {
+        let portable = portable(&source(), &endpoint());
+        assert_eq!(Some("orders".to_string()), portable.queues[1].forward_to);
+        assert_eq!(None, portable.queues[0].message_count);
+        assert_eq!(
+            Some("https://elsewhere.servicebus.windows.net/log".to_string()),
+            portable.topics[0].subscriptions[0].description.forward_to
+        );
+    }
+
+    #[test]
+    fn copy_into_empty_namespace_creates_everything() -> Result<(), Report> {
+        let plan = copy_plan(
+            &source(),
+            &endpoint(),
+            &Topology::default(),
+            ExistingEntities::Skip,
+        )?;
+        let creates = plan
+            .changes
+            .iter()
+            .filter(|c| matches!(c, Change::Create(_)))
+            .count();
+        // Two queues, the topic, the subscription and its rule.
+        assert_eq!(5, creates);
+        Ok(())
+    }
+
+    #[test]
+    fn default_rules_are_copied() -> Result<(), Report> {
+        use crate::emulator::Emulator;
+
+        let source = Emulator::builder()
+            .topology(Topology::from_json(
+                r#"{ "topics": [{ "name": "events", "subscriptions": [
+                    { "name": "audit", "rules": [
+                        { "name": "$Default", "filter": { "type": "true" } },
+                        { "name": "errors", "filter": { "type": "sql", "expression": "level = 'error'" } }
+                    ] },
+                    { "name": "billing", "rules": [
+                        { "name": "$Default", "filter": { "type": "sql", "expression": "kind = 'invoice'" } }
+                    ] }
+                ] }] }"#,
+            )?)
+            .start()?;
+        let destination = Emulator::builder().start()?;
+        let (source_exec, destination_exec) = (source.executor(), destination.executor());
+        let from = NamespaceClient::with_conn(&source.connection_string())?;
+        let to = NamespaceClient::with_conn(&destination.connection_string())?;
+
+        let plan = copy_namespace(
+            &from,
+            &source_exec,
+            &to,
+            &destination_exec,
+            ExistingEntities::Skip,
+        )?;
+        plan.apply(&to, &destination_exec)?;
+
+        let copied = Topology::from_namespace(&to, &destination_exec)?;
+        let billing = &copied.topics[0].subscriptions[1];
+        assert_eq!("billing", billing.description.name);
+        assert_eq!(
+            RuleFilter::Sql {
+                expression: "kind = 'invoice'".to_string()
+            },
+            billing.rules.as_ref().unwrap()[0].filter
+        );
+        let again = copy_namespace(
+            &from,
+            &source_exec,
+            &to,
+            &destination_exec,
+            ExistingEntities::Overwrite,
+        )?;
+        assert!(again.is_empty(), "{}", again);
+        Ok(())
+    }
+
+    #[test]
+    fn existing_entities_follow_the_policy() -> Result<(), Report> {
+        let mut destination = portable(&source(), &endpoint());
+        destination.queues[0].lock_duration = Some(Duration::from_secs(5));
+        if let Some(rules) = &mut destination.topics[0].subscriptions[0].rules {
+            rules.clear();
+        }
+
+        let skipped = copy_plan(&source(), &endpoint(), &destination, ExistingEntities::Skip)?;
+        assert!(skipped.is_empty(), "{}", skipped);
+
+        let overwritten = copy_plan(
+            &source(),
+            &endpoint(),
+            &destination,
+            ExistingEntities::Overwrite,
+        )?;
+        assert_eq!(2, overwritten.changes.len(), "{}", overwritten);
+        assert!(matches!(&overwritten.changes[0], Change::Update { .. }));
+        assert!(matches!(
+            &overwritten.changes[1],
+            Change::Create(Entity::Rule(_))
+        ));
+        Ok(())
+    }
+}
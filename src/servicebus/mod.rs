@@ -1,6 +1,13 @@
+pub mod archive;
+pub mod atom;
+pub mod authorization;
 pub mod brokeredmessage;
+pub mod description;
+pub mod migrate;
+pub mod namespace;
 pub mod queue;
 pub mod subscription;
+pub mod topology;
 
 use crate::core::error::AzureRequestError;
 use eyre::eyre;
@@ -0,0 +1,456 @@
+use super::atom::{parse_feed, wrap_entry, Entry};
+use super::description::*;
+use super::interpret_results;
+use crate::core::{connection_string::ConnectionString, exec::Executor, token::TokenProvider};
+use eyre::Report;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, IF_MATCH};
+use hyper::{Method, Request, Uri};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub(crate) const API_VERSION: &str = "2017-04";
+const CONTENT_TYPE_VAL: &str = "application/atom+xml;type=entry;charset=utf-8";
+
+/// Service Bus will not return more than 100 entries in a single page.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Client for the management side of a Service Bus namespace.
+///
+/// Where `QueueClient` and `SubscriptionClient` move messages, the namespace
+/// client describes the entities themselves. Like the other clients it only
+/// builds requests. The listing functions take an `Executor` so they can page
+/// through the ATOM feeds on their own.
+#[derive(Clone)]
+pub struct NamespaceClient {
+    endpoint: Uri,
+    credential: Arc<dyn TokenProvider>,
+    page_size: usize,
+}
+
+impl NamespaceClient {
+    /// Create a namespace client from a connection string copied from the azure portal.
+    /// The key needs the Manage claim for most of the management api.
+    pub fn with_conn(connection_string: &str) -> Result<Self, Report> {
+        let conn: ConnectionString = connection_string.parse()?;
+        let credential = Arc::from(conn.token_provider());
+        Ok(Self::with_token_provider(conn.uri().clone(), credential))
+    }
+
+    /// Create a namespace client that authorizes its requests with
+    /// `credential` instead of a key from a connection string.
+    pub fn with_token_provider(endpoint: Uri, credential: Arc<dyn TokenProvider>) -> Self {
+        NamespaceClient {
+            endpoint,
+            credential,
+            page_size: MAX_PAGE_SIZE,
+        }
+    }
+
+    /// The provider this client gets its `Authorization` header from.
+    pub fn token_provider(&self) -> &Arc<dyn TokenProvider> {
+        &self.credential
+    }
+
+    /// Changes how many entries are requested per page when listing entities.
+    /// Values are clamped to `1..=MAX_PAGE_SIZE`.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+        self
+    }
+
+    pub fn endpoint(&self) -> &Uri {
+        &self.endpoint
+    }
+
+    /// Request a single page of the queues in the namespace.
+    pub fn list_queues_page(&self, skip: usize, top: usize) -> Result<Request<String>, Report> {
+        self.list_page("$Resources/Queues", skip, top)
+    }
+
+    /// Request a single page of the topics in the namespace.
+    pub fn list_topics_page(&self, skip: usize, top: usize) -> Result<Request<String>, Report> {
+        self.list_page("$Resources/Topics", skip, top)
+    }
+
+    /// Request a single page of the subscriptions of a topic.
+    pub fn list_subscriptions_page(
+        &self,
+        topic: &str,
+        skip: usize,
+        top: usize,
+    ) -> Result<Request<String>, Report> {
+        self.list_page(&format!("{}/Subscriptions", topic), skip, top)
+    }
+
+    /// Request a single page of the rules of a subscription.
+    pub fn list_rules_page(
+        &self,
+        topic: &str,
+        subscription: &str,
+        skip: usize,
+        top: usize,
+    ) -> Result<Request<String>, Report> {
+        self.list_page(
+            &format!("{}/Subscriptions/{}/Rules", topic, subscription),
+            skip,
+            top,
+        )
+    }
+
+    /// Request the description of a single queue. The body of the response is an
+    /// ATOM entry, `QueueDescription::from_entry(&Entry::parse(body)?)` reads it.
+    pub fn get_queue(&self, name: &str) -> Result<Request<String>, Report> {
+        self.entity_request(Method::GET, name, String::new())
+    }
+
+    /// Request the description of a single topic.
+    pub fn get_topic(&self, name: &str) -> Result<Request<String>, Report> {
+        self.entity_request(Method::GET, name, String::new())
+    }
+
+    /// Request the description of a single subscription.
+    pub fn get_subscription(&self, topic: &str, name: &str) -> Result<Request<String>, Report> {
+        let path = format!("{}/Subscriptions/{}", topic, name);
+        self.entity_request(Method::GET, &path, String::new())
+    }
+
+    /// Request the current description of the entity described. Only the name
+    /// (and parents' names) of `desc` is used.
+    pub fn get<D: EntityDescription>(&self, desc: &D) -> Result<Request<String>, Report> {
+        self.entity_request(Method::GET, &desc.path(), String::new())
+    }
+
+    /// Creates the entity described. Service Bus fills in defaults for any setting
+    /// that is `None`, and answers 409 if the entity already exists.
+    pub fn create<D: EntityDescription>(&self, desc: &D) -> Result<Request<String>, Report> {
+        self.put_entity(&desc.path(), &desc.to_xml(), false)
+    }
+
+    /// Replaces the settings of an existing entity.
+    pub fn update<D: EntityDescription>(&self, desc: &D) -> Result<Request<String>, Report> {
+        self.put_entity(&desc.path(), &desc.to_xml(), true)
+    }
+
+    /// Deletes the entity described. Only the name (and for subscriptions and
+    /// rules, the parents' names) of the description are used. Deleting a topic
+    /// deletes its subscriptions.
+    pub fn delete<D: EntityDescription>(&self, desc: &D) -> Result<Request<String>, Report> {
+        self.delete_path(&desc.path())
+    }
+
+    /// Every queue in the namespace. Pages are fetched with `exec` as the
+    /// iterator reaches them.
+    ///
+    /// ```no_run
+    /// # use hyper::{Request, Response};
+    /// # fn exec(_: Request<String>) -> Result<Response<String>, eyre::Report> { unimplemented!() }
+    /// # fn main() -> Result<(), eyre::Report> {
+    /// # let namespace: azure_service_bus::NamespaceClient = unimplemented!();
+    /// for queue in namespace.queues(&exec) {
+    ///     let queue = queue?;
+    ///     println!("{} {:?}", queue.name, queue.message_count);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn queues<'a, E: Executor + ?Sized>(
+        &'a self,
+        exec: &'a E,
+    ) -> Pages<'a, E, QueueDescription> {
+        Pages::new(
+            self,
+            exec,
+            "$Resources/Queues".to_string(),
+            Box::new(QueueDescription::from_entry),
+        )
+    }
+
+    /// Every topic in the namespace.
+    pub fn topics<'a, E: Executor + ?Sized>(
+        &'a self,
+        exec: &'a E,
+    ) -> Pages<'a, E, TopicDescription> {
+        Pages::new(
+            self,
+            exec,
+            "$Resources/Topics".to_string(),
+            Box::new(TopicDescription::from_entry),
+        )
+    }
+
+    /// Every subscription of a topic.
+    pub fn subscriptions<'a, E: Executor + ?Sized>(
+        &'a self,
+        topic: &str,
+        exec: &'a E,
+    ) -> Pages<'a, E, SubscriptionDescription> {
+        let topic_name = topic.to_string();
+        Pages::new(
+            self,
+            exec,
+            format!("{}/Subscriptions", topic),
+            Box::new(move |entry| {
+                let mut sub = SubscriptionDescription::from_entry(entry)?;
+                sub.topic = topic_name.clone();
+                Ok(sub)
+            }),
+        )
+    }
+
+    /// Every rule of a subscription.
+    pub fn rules<'a, E: Executor + ?Sized>(
+        &'a self,
+        topic: &str,
+        subscription: &str,
+        exec: &'a E,
+    ) -> Pages<'a, E, RuleDescription> {
+        let (topic_name, subscription_name) = (topic.to_string(), subscription.to_string());
+        Pages::new(
+            self,
+            exec,
+            format!("{}/Subscriptions/{}/Rules", topic, subscription),
+            Box::new(move |entry| {
+                let mut rule = RuleDescription::from_entry(entry)?;
+                rule.topic = topic_name.clone();
+                rule.subscription = subscription_name.clone();
+                Ok(rule)
+            }),
+        )
+    }
+
+    /// Every queue, topic and subscription in the namespace. Queues come first,
+    /// then each topic followed by its subscriptions.
+    pub fn entities<'a, E: Executor + ?Sized>(
+        &'a self,
+        exec: &'a E,
+    ) -> impl Iterator<Item = Result<Entity, Report>> + 'a {
+        let queues = self.queues(exec).map(|q| q.map(Entity::Queue));
+        let topics = self.topics(exec).flat_map(
+            move |topic| -> Box<dyn Iterator<Item = Result<Entity, Report>> + 'a> {
+                match topic {
+                    Ok(topic) => {
+                        let subs = self
+                            .subscriptions(&topic.name, exec)
+                            .map(|s| s.map(Entity::Subscription));
+                        Box::new(std::iter::once(Ok(Entity::Topic(topic))).chain(subs))
+                    }
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                }
+            },
+        );
+        queues.chain(topics)
+    }
+
+    fn list_page(&self, path: &str, skip: usize, top: usize) -> Result<Request<String>, Report> {
+        let sas = self.credential.authorization()?;
+        let mut parts = self.endpoint().clone().into_parts();
+        parts.path_and_query = Some(
+            format!(
+                "/{}?$skip={}&$top={}&api-version={}",
+                path, skip, top, API_VERSION
+            )
+            .parse()?,
+        );
+        let uri = Uri::from_parts(parts)?;
+        Ok(Request::get(uri)
+            .header(AUTHORIZATION, sas)
+            .body(String::new())?)
+    }
+
+    pub(crate) fn put_entity(
+        &self,
+        path: &str,
+        description: &str,
+        update: bool,
+    ) -> Result<Request<String>, Report> {
+        let mut req = self.entity_request(Method::PUT, path, wrap_entry(description))?;
+        req.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_VAL));
+        if update {
+            req.headers_mut()
+                .insert(IF_MATCH, HeaderValue::from_static("*"));
+        }
+        Ok(req)
+    }
+
+    pub(crate) fn delete_path(&self, path: &str) -> Result<Request<String>, Report> {
+        self.entity_request(Method::DELETE, path, String::new())
+    }
+
+    fn entity_request(
+        &self,
+        method: Method,
+        path: &str,
+        body: String,
+    ) -> Result<Request<String>, Report> {
+        let sas = self.credential.authorization()?;
+        let mut parts = self.endpoint().clone().into_parts();
+        parts.path_and_query = Some(format!("/{}?api-version={}", path, API_VERSION).parse()?);
+        let uri = Uri::from_parts(parts)?;
+        Ok(Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, sas)
+            .body(body)?)
+    }
+}
+
+type EntryParser<'a, T> = Box<dyn Fn(&Entry) -> Result<T, Report> + 'a>;
+
+/// Iterator over an ATOM feed that is fetched one page at a time.
+/// A new page is requested whenever the previous one runs out, and iteration
+/// stops after the first page that comes back short. After an error the
+/// iterator is finished.
+pub struct Pages<'a, E: ?Sized, T> {
+    client: &'a NamespaceClient,
+    exec: &'a E,
+    path: String,
+    parse: EntryParser<'a, T>,
+    skip: usize,
+    buffered: VecDeque<T>,
+    done: bool,
+}
+
+impl<'a, E: Executor + ?Sized, T> Pages<'a, E, T> {
+    fn new(
+        client: &'a NamespaceClient,
+        exec: &'a E,
+        path: String,
+        parse: EntryParser<'a, T>,
+    ) -> Self {
+        Pages {
+            client,
+            exec,
+            path,
+            parse,
+            skip: 0,
+            buffered: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn fetch_page(&mut self) -> Result<(), Report> {
+        let top = self.client.page_size;
+        let req = self.client.list_page(&self.path, self.skip, top)?;
+        let resp = self.exec.execute(req)?;
+        interpret_results(resp.status())?;
+
+        let entries = parse_feed(resp.body())?;
+        if entries.len() < top {
+            self.done = true;
+        }
+        self.skip += entries.len();
+        for entry in &entries {
+            self.buffered.push_back((self.parse)(entry)?);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, E: Executor + ?Sized, T> Iterator for Pages<'a, E, T> {
+    type Item = Result<T, Report>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffered.is_empty() && !self.done {
+            if let Err(e) = self.fetch_page() {
+                self.done = true;
+                self.buffered.clear();
+                return Some(Err(e));
+            }
+        }
+
+        self.buffered.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Response, StatusCode};
+    use std::cell::RefCell;
+
+    const CONN: &str = "Endpoint=sb://test.servicebus.windows.net/;\
+                        SharedAccessKeyName=RootManageSharedAccessKey;SharedAccessKey=dGVzdA==";
+
+    fn feed(names: &[String], kind: &str) -> String {
+        let entries: String = names
+            .iter()
+            .map(|n| {
+                format!(
+                    "<entry><title type=\"text\">{}</title><content type=\"application/xml\">\
+                     <{kind}Description><Status>Active</Status></{kind}Description>\
+                     </content></entry>",
+                    n,
+                    kind = kind
+                )
+            })
+            .collect();
+        format!(
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\"><title type=\"text\">{}s</title>{}</feed>",
+            kind, entries
+        )
+    }
+
+    // Serves `total` queues named q0, q1, ... honoring $skip and $top.
+    fn fake_namespace(
+        total: usize,
+        seen: &RefCell<Vec<String>>,
+    ) -> impl Fn(Request<String>) -> Result<Response<String>, Report> + '_ {
+        move |req| {
+            let query = req.uri().query().unwrap_or_default().to_string();
+            seen.borrow_mut().push(query.clone());
+            let param = |name: &str| -> usize {
+                query
+                    .split('&')
+                    .find_map(|kv| kv.strip_prefix(name))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap()
+            };
+            let (skip, top) = (param("$skip="), param("$top="));
+            let names: Vec<String> = (skip..total.min(skip + top))
+                .map(|i| format!("q{}", i))
+                .collect();
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(feed(&names, "Queue"))?)
+        }
+    }
+
+    #[test]
+    fn queues_are_paged() -> Result<(), Report> {
+        let client = NamespaceClient::with_conn(CONN)?.with_page_size(2);
+        let seen = RefCell::new(Vec::new());
+        let names = client
+            .queues(&fake_namespace(5, &seen))
+            .map(|q| q.map(|q| q.name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(vec!["q0", "q1", "q2", "q3", "q4"], names);
+        assert_eq!(3, seen.borrow().len());
+        assert!(seen.borrow()[2].starts_with("$skip=4&$top=2"));
+        Ok(())
+    }
+
+    #[test]
+    fn full_last_page_needs_another_request() -> Result<(), Report> {
+        let client = NamespaceClient::with_conn(CONN)?.with_page_size(2);
+        let seen = RefCell::new(Vec::new());
+        assert_eq!(4, client.queues(&fake_namespace(4, &seen)).count());
+        assert_eq!(3, seen.borrow().len());
+        Ok(())
+    }
+
+    #[test]
+    fn errors_end_iteration() -> Result<(), Report> {
+        let client = NamespaceClient::with_conn(CONN)?;
+        let exec = |_| -> Result<Response<String>, Report> {
+            Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(String::new())?)
+        };
+        let mut queues = client.queues(&exec);
+        assert!(queues.next().unwrap().is_err());
+        assert!(queues.next().is_none());
+        Ok(())
+    }
+}
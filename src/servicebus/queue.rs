@@ -1,14 +1,14 @@
 use super::brokeredmessage::*;
-use crate::core::{error::AzureRequestError, generate_sas};
-use eyre::{eyre, Report};
+use crate::core::connection_string::ConnectionString;
+use crate::core::error::{AzureRequestError, ConnectionStringError};
+use crate::core::token::TokenProvider;
+use eyre::Report;
 use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use hyper::{Request, Uri};
 use std::sync::Arc;
-use std::sync::Mutex;
 use std::time::Duration;
 
-const CONTENT_TYPE_VAL: &'static str = "application/atom+xml;type=entry;charset=utf-8";
-const SAS_BUFFER_TIME: usize = 15;
+const CONTENT_TYPE_VAL: &str = "application/atom+xml;type=entry;charset=utf-8";
 
 /// Client for Service Bus Queues/Topics.
 ///
@@ -31,47 +31,69 @@ const SAS_BUFFER_TIME: usize = 15;
 pub struct QueueClient {
     endpoint: Uri,
     queue_name: String,
-    connection_string: String,
-    sas_info: Arc<Mutex<(String, usize)>>,
+    credential: Arc<dyn TokenProvider>,
 }
 
 impl QueueClient {
+    /// Create a queue client from a connection string copied from the azure
+    /// portal. The connection string must name the queue with `EntityPath`,
+    /// as the ones generated for a queue's own access policies do.
+    pub fn with_conn(connection_string: &str) -> Result<Self, Report> {
+        let conn: ConnectionString = connection_string.parse()?;
+        let queue = conn
+            .entity_path
+            .clone()
+            .ok_or(ConnectionStringError::MissingKey("EntityPath"))?;
+        Self::from_connection_string(conn, &queue)
+    }
+
     pub fn with_conn_and_queue(connection_string: &str, queue: &str) -> Result<Self, Report> {
-        let duration = Duration::from_secs(60 * 6);
-        let mut endpoint = None;
-        for param in connection_string.split(";") {
-            let idx = param.find("=").unwrap_or(0);
-            let (mut k, mut value) = param.split_at(idx);
-            k = k.trim();
-            value = value.trim();
-            // cut out the equal sign if there was one.
-            if value.len() > 0 {
-                value = &value[1..]
-            }
-            match k {
-                "Endpoint" => endpoint = Some(value),
-                _ => {}
-            };
-        }
+        Self::from_connection_string(connection_string.parse()?, queue)
+    }
 
-        let endpoint = endpoint
-            .map(|e| e.replace("sb://", "https://"))
-            .ok_or(eyre!("Endpoint not in connection string."))?;
-        let url = endpoint.parse()?;
-        let (sas_key, expiry) = generate_sas(connection_string, duration);
+    fn from_connection_string(conn: ConnectionString, queue: &str) -> Result<Self, Report> {
+        let credential = Arc::from(conn.token_provider());
+        Ok(Self::with_token_provider(
+            conn.uri().clone(),
+            queue,
+            credential,
+        ))
+    }
 
-        Ok(QueueClient {
-            connection_string: connection_string.to_string(),
+    /// Create a queue client that authorizes its requests with `credential`
+    /// instead of a key from a connection string. `endpoint` is the https url
+    /// of the namespace.
+    pub fn with_token_provider(
+        endpoint: Uri,
+        queue: &str,
+        credential: Arc<dyn TokenProvider>,
+    ) -> Self {
+        QueueClient {
+            endpoint,
             queue_name: queue.to_string(),
-            endpoint: url,
-            sas_info: Arc::new(Mutex::new((sas_key, expiry - SAS_BUFFER_TIME))),
-        })
+            credential,
+        }
+    }
+
+    /// The provider this client gets its `Authorization` header from. Pass it
+    /// to other clients to share one token between them.
+    pub fn token_provider(&self) -> &Arc<dyn TokenProvider> {
+        &self.credential
     }
 
     pub fn queue(&self) -> &str {
         &self.queue_name
     }
 
+    /// A client for the queue's dead letter queue. Messages are received and
+    /// settled there the same way as on the queue itself.
+    pub fn dead_letter_queue(&self) -> QueueClient {
+        QueueClient {
+            queue_name: format!("{}/$DeadLetterQueue", self.queue_name),
+            ..self.clone()
+        }
+    }
+
     pub fn endpoint(&self) -> &Uri {
         &self.endpoint
     }
@@ -114,13 +136,13 @@ impl QueueClient {
         message: BrokeredMessage,
         timeout: Duration,
     ) -> Result<Request<String>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let mut parts = self.endpoint().clone().into_parts();
         parts.path_and_query =
             Some(format!("/{}/messages?timeout={}", self.queue(), timeout.as_secs()).parse()?);
         let uri = Uri::from_parts(parts)?;
 
-        Ok(Request::post(uri)
+        let mut req = Request::post(uri)
             .header(AUTHORIZATION, sas)
             .header(
                 CONTENT_TYPE,
@@ -130,7 +152,10 @@ impl QueueClient {
                 BROKER_PROPERTIES_HEADER,
                 HeaderValue::from_str(&message.props_as_json()).unwrap(),
             )
-            .body(message.into_body())?)
+            .body(String::new())?;
+        write_properties(req.headers_mut(), &message.properties)?;
+        *req.body_mut() = message.into_body();
+        Ok(req)
     }
 
     /// Receive a message from the queue. Returns the deserialized message or an error.
@@ -140,7 +165,7 @@ impl QueueClient {
         &self,
         timeout: Duration,
     ) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let mut parts = self.endpoint().clone().into_parts();
         parts.path_and_query = Some(
             format!(
@@ -159,7 +184,7 @@ impl QueueClient {
     /// `queue_client.complete_message(message)` is called. This is ideal for applications that
     /// can't afford to miss a message. Allows a timeout to be specified for greater control.
     pub fn receive_with_timeout(&self, timeout: Duration) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
 
         let mut parts = self.endpoint().clone().into_parts();
         parts.path_and_query = Some(
@@ -177,7 +202,7 @@ impl QueueClient {
     /// Completes a message that has been received from the Service Bus. This will fail
     /// if the message was created locally. Once a message is created, it cannot be restored
     pub fn complete_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
 
         // Take either the Sequence number or the Message ID
         // Then add the lock token and finally join it into the targer
@@ -192,7 +217,7 @@ impl QueueClient {
     /// This method generally indicates that the message could not be
     /// handled properly and should be attempted at a later time.
     pub fn abandon_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let target = self.get_message_update_path(&message)?;
         Ok(Request::put(target).header(AUTHORIZATION, sas).body(())?)
     }
@@ -218,8 +243,8 @@ impl QueueClient {
     /// # }
     /// ```
     pub fn renew_message(&self, message: &BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
-        let target = self.get_message_update_path(&message)?;
+        let sas = self.credential.authorization()?;
+        let target = self.get_message_update_path(message)?;
         Ok(Request::post(target).header(AUTHORIZATION, sas).body(())?)
     }
 
@@ -243,40 +268,40 @@ impl QueueClient {
             .ok_or(AzureRequestError::LocalMessage);
         target
     }
-
-    fn refresh_sas(&self) -> HeaderValue {
-        let curr_time = std::time::SystemTime::UNIX_EPOCH
-            .elapsed()
-            .expect("unix epoch time comparison")
-            .as_secs();
-        let mut sas_tuple = match self.sas_info.lock() {
-            Ok(guard) => guard,
-            Err(poison) => poison.into_inner(),
-        };
-        if curr_time > (sas_tuple.1 as _) {
-            let duration = Duration::from_secs(60 * 6);
-            let (key, expiry) = generate_sas(&*self.connection_string, duration);
-            sas_tuple.1 = expiry;
-            sas_tuple.0 = key;
-        }
-
-        HeaderValue::from_str(&sas_tuple.0).unwrap()
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::QueueClient;
+    use crate::emulator::Emulator;
     use crate::servicebus::{
         brokeredmessage::{BrokerProperties, BrokeredMessage},
+        description::{Entity, QueueDescription},
         interpret_results,
     };
     use eyre::Report;
     use hyper::Request;
     use std::convert::TryInto;
+    use std::sync::OnceLock;
 
+    // Without a namespace to talk to the tests run against the emulator. Its
+    // queue starts with a message so the receives don't depend on test order.
     fn get_conn_string() -> Result<String, Report> {
-        Ok(std::env::var("AZ_CONNECTION_STRING")?)
+        if let Ok(conn) = std::env::var("AZ_CONNECTION_STRING") {
+            return Ok(conn);
+        }
+        static EMULATOR: OnceLock<Emulator> = OnceLock::new();
+        let emulator = EMULATOR.get_or_init(|| {
+            let emulator = Emulator::builder()
+                .entity(Entity::Queue(QueueDescription::new("test1")))
+                .start()
+                .expect("starting the emulator");
+            emulator
+                .send("test1", BrokeredMessage::with_body("seed"))
+                .expect("seeding test1");
+            emulator
+        });
+        Ok(emulator.connection_string())
     }
 
     trait Exec {
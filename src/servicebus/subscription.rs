@@ -1,24 +1,21 @@
 use super::brokeredmessage::*;
+use crate::core::connection_string::ConnectionString;
 use crate::core::error::AzureRequestError;
-use crate::core::generate_sas;
+use crate::core::token::TokenProvider;
 use eyre::Report;
 use hyper::header::*;
 use hyper::{Request, Uri};
 use std::sync::Arc;
-use std::sync::Mutex;
 use std::time::Duration;
 
-const SAS_BUFFER_TIME: usize = 15;
-
 /// Client for sending and receiving messages from a Service Bus Subscription in Azure.
 /// This cient is `!Sync` because it internally uses a RefCell to keep track of
 /// its authorization token, but it is still ideal for single threaded use.
 pub struct SubscriptionClient {
-    connection_string: String,
     topic_name: String,
     subscription_name: String,
     endpoint: Uri,
-    sas_info: Arc<Mutex<(String, usize)>>,
+    credential: Arc<dyn TokenProvider>,
 }
 
 /// The Subscription Trait is an abstraction over different types of Subscription that
@@ -42,35 +39,36 @@ impl SubscriptionClient {
         topic: &str,
         subscription: &str,
     ) -> Result<SubscriptionClient, Report> {
-        let duration = Duration::from_secs(60 * 6);
-        let mut endpoint = String::new();
-        for param in connection_string.split(";") {
-            let idx = param.find("=").unwrap_or(0);
-            let (mut k, mut value) = param.split_at(idx);
-            k = k.trim();
-            value = value.trim();
-            // cut out the equal sign if there was one.
-            if value.len() > 0 {
-                value = &value[1..]
-            }
-            match k {
-                "Endpoint" => endpoint = value.to_string(),
-                _ => {}
-            };
-        }
-        endpoint = String::new() + "https" + endpoint.split_at(endpoint.find(":").unwrap_or(0)).1;
-        let url = endpoint.parse()?;
-
-        let (sas_key, expiry) = generate_sas(connection_string, duration);
-        let conn_string = connection_string.to_string();
+        let conn: ConnectionString = connection_string.parse()?;
+        let credential = Arc::from(conn.token_provider());
+        Ok(Self::with_token_provider(
+            conn.uri().clone(),
+            topic,
+            subscription,
+            credential,
+        ))
+    }
 
-        Ok(SubscriptionClient {
-            connection_string: conn_string,
-            subscription_name: subscription.to_string(),
+    /// Create a subscription client that authorizes its requests with
+    /// `credential` instead of a key from a connection string. `endpoint` is
+    /// the https url of the namespace.
+    pub fn with_token_provider(
+        endpoint: Uri,
+        topic: &str,
+        subscription: &str,
+        credential: Arc<dyn TokenProvider>,
+    ) -> SubscriptionClient {
+        SubscriptionClient {
+            endpoint,
             topic_name: topic.to_string(),
-            endpoint: url,
-            sas_info: Arc::new(Mutex::new((sas_key, expiry - SAS_BUFFER_TIME))),
-        })
+            subscription_name: subscription.to_string(),
+            credential,
+        }
+    }
+
+    /// The provider this client gets its `Authorization` header from.
+    pub fn token_provider(&self) -> &Arc<dyn TokenProvider> {
+        &self.credential
     }
 
     pub fn subscription(&self) -> &str {
@@ -81,6 +79,17 @@ impl SubscriptionClient {
         &self.topic_name
     }
 
+    /// A client for the subscription's dead letter queue. Messages are
+    /// received and settled there the same way as on the subscription itself.
+    pub fn dead_letter_queue(&self) -> SubscriptionClient {
+        SubscriptionClient {
+            topic_name: self.topic_name.clone(),
+            subscription_name: format!("{}/$DeadLetterQueue", self.subscription_name),
+            endpoint: self.endpoint.clone(),
+            credential: self.credential.clone(),
+        }
+    }
+
     /// The endpoint for the Queue. `http://{namespace}.servicebus.net/`
     pub fn endpoint(&self) -> &Uri {
         &self.endpoint
@@ -110,7 +119,7 @@ impl SubscriptionClient {
         &self,
         timeout: Duration,
     ) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
 
         let mut parts = self.endpoint().clone().into_parts();
         parts.path_and_query = Some(
@@ -132,7 +141,7 @@ impl SubscriptionClient {
     /// `subscription_client.complete_message(message)` is called. This is ideal for applications that
     /// can't afford to miss a message. Allows a timeout to be specified for greater control.
     pub fn receive_with_timeout(&self, timeout: Duration) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let mut parts = self.endpoint().clone().into_parts();
         parts.path_and_query = Some(
             format!(
@@ -151,7 +160,7 @@ impl SubscriptionClient {
     /// Completes a message that has been received from the Service Bus. This will fail
     /// if the message was created locally. Once a message is created, it cannot be restored
     pub fn complete_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let target = self.get_message_update_path(&message)?;
         Ok(Request::delete(target)
             .header(AUTHORIZATION, sas)
@@ -162,7 +171,7 @@ impl SubscriptionClient {
     /// This method generally indicates that the message could not be
     /// handled properly and should be attempted at a later time.
     pub fn abandon_message(&self, message: BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
+        let sas = self.credential.authorization()?;
         let target = self.get_message_update_path(&message)?;
         Ok(Request::put(target).header(AUTHORIZATION, sas).body(())?)
     }
@@ -172,8 +181,8 @@ impl SubscriptionClient {
     /// but not deleted on the Service Bus. This method allows the lock to be renewed
     /// if additional time is needed to finish processing the message.
     pub fn renew_message(&self, message: &BrokeredMessage) -> Result<Request<()>, Report> {
-        let sas = self.refresh_sas();
-        let target = self.get_message_update_path(&message)?;
+        let sas = self.credential.authorization()?;
+        let target = self.get_message_update_path(message)?;
         Ok(Request::post(target).header(AUTHORIZATION, sas).body(())?)
     }
 
@@ -204,23 +213,4 @@ impl SubscriptionClient {
             })
             .ok_or(AzureRequestError::LocalMessage)
     }
-
-    fn refresh_sas(&self) -> HeaderValue {
-        let curr_time = std::time::SystemTime::UNIX_EPOCH
-            .elapsed()
-            .expect("unix epoch time comparison")
-            .as_secs();
-        let mut sas_tuple = match self.sas_info.lock() {
-            Ok(guard) => guard,
-            Err(poison) => poison.into_inner(),
-        };
-        if curr_time > (sas_tuple.1 as _) {
-            let duration = Duration::from_secs(60 * 6);
-            let (key, expiry) = generate_sas(&*self.connection_string, duration);
-            sas_tuple.1 = expiry;
-            sas_tuple.0 = key;
-        }
-
-        HeaderValue::from_str(&sas_tuple.0).unwrap()
-    }
 }
@@ -0,0 +1,885 @@
+use super::atom::format_duration;
+use super::description::*;
+use super::interpret_results;
+use super::namespace::NamespaceClient;
+use crate::core::exec::Executor;
+use eyre::{eyre, Report, WrapErr};
+use hyper::Request;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::time::Duration;
+
+/// A declarative description of the entities that should exist in a namespace.
+///
+/// Topologies are usually loaded from a file, diffed against the live
+/// namespace with `plan`, and then the resulting `Plan` is printed (a dry run)
+/// or applied. Only the settings written in the file are managed, anything
+/// left out keeps whatever value the namespace already has.
+///
+/// ```yaml
+/// queues:
+///   - name: orders
+///     lock_duration: PT1M
+///     max_delivery_count: 5
+/// topics:
+///   - name: events
+///     subscriptions:
+///       - name: audit
+///         forward_to: orders
+///         rules:
+///           - name: errors-only
+///             filter: { type: sql, expression: "level = 'error'" }
+/// ```
+///
+/// JSON is always supported. YAML and TOML need the `yaml` and `toml` features.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topology {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queues: Vec<QueueDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<TopicTopology>,
+}
+
+/// A topic and the subscriptions that should exist on it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicTopology {
+    #[serde(flatten)]
+    pub description: TopicDescription,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscriptions: Vec<SubscriptionTopology>,
+}
+
+/// A subscription and its rules. When `rules` is `None` the rules of the
+/// subscription are left alone. When it is set, the list is authoritative: a
+/// new subscription will not keep the `$Default` rule Service Bus creates
+/// unless it is listed, and with pruning, other rules are removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionTopology {
+    #[serde(flatten)]
+    pub description: SubscriptionDescription,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<RuleDescription>>,
+}
+
+/// Name of the rule Service Bus adds to every subscription created without one.
+pub const DEFAULT_RULE_NAME: &str = "$Default";
+
+impl Topology {
+    pub fn from_json(s: &str) -> Result<Topology, Report> {
+        let mut topology: Topology = serde_json::from_str(s)?;
+        topology.link_parents();
+        Ok(topology)
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Topology, Report> {
+        let mut topology: Topology = serde_yaml::from_str(s)?;
+        topology.link_parents();
+        Ok(topology)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(s: &str) -> Result<Topology, Report> {
+        let mut topology: Topology = toml::from_str(s)?;
+        topology.link_parents();
+        Ok(topology)
+    }
+
+    /// Loads a topology file, picking the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Topology, Report> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&contents),
+            #[cfg(feature = "yaml")]
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents),
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml(&contents),
+            _ => Err(eyre!(
+                "Unsupported topology file {}. Supported formats depend on the enabled features.",
+                path.display()
+            )),
+        }
+    }
+
+    /// Reads everything that currently exists in a namespace, including the
+    /// rules of every subscription.
+    pub fn from_namespace<E: Executor + ?Sized>(
+        client: &NamespaceClient,
+        exec: &E,
+    ) -> Result<Topology, Report> {
+        let queues = client.queues(exec).collect::<Result<Vec<_>, _>>()?;
+        let mut topics = Vec::new();
+        for topic in client.topics(exec) {
+            let topic = topic?;
+            let mut subscriptions = Vec::new();
+            for sub in client.subscriptions(&topic.name, exec) {
+                let sub = sub?;
+                let rules = client
+                    .rules(&sub.topic, &sub.name, exec)
+                    .collect::<Result<Vec<_>, _>>()?;
+                subscriptions.push(SubscriptionTopology {
+                    description: sub,
+                    rules: Some(rules),
+                });
+            }
+            topics.push(TopicTopology {
+                description: topic,
+                subscriptions,
+            });
+        }
+
+        Ok(Topology { queues, topics })
+    }
+
+    /// Fills in the parent names of subscriptions and rules, which config files
+    /// express through nesting instead.
+    pub fn link_parents(&mut self) {
+        for topic in &mut self.topics {
+            for sub in &mut topic.subscriptions {
+                sub.description.topic = topic.description.name.clone();
+                for rule in sub.rules.iter_mut().flatten() {
+                    rule.topic = topic.description.name.clone();
+                    rule.subscription = sub.description.name.clone();
+                }
+            }
+        }
+    }
+
+    /// Works out the changes needed to make `live` look like `self`. Entities
+    /// that exist but are not described are only deleted when `prune` is set.
+    /// Fails when new queues forward to each other in a loop, since none of
+    /// them could be created first.
+    pub fn plan(&self, live: &Topology, prune: bool) -> Result<Plan, Report> {
+        let mut creates = Vec::new();
+        let mut forwarding = Vec::new();
+        let mut updates = Vec::new();
+        let mut deletes = Vec::new();
+
+        for queue in &self.queues {
+            match find(&live.queues, &queue.name, |q| &q.name) {
+                None if queue.forward_to.is_some()
+                    || queue.forward_dead_lettered_messages_to.is_some() =>
+                {
+                    forwarding.push(queue)
+                }
+                None => creates.push(Change::Create(Entity::Queue(queue.clone()))),
+                Some(current) => {
+                    let (merged, fields) = diff_queue(queue, current);
+                    if !fields.is_empty() {
+                        updates.push(Change::Update {
+                            entity: Entity::Queue(merged),
+                            fields,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut sub_changes = Vec::new();
+        let mut rule_changes = Vec::new();
+        for topic in &self.topics {
+            let current = find(&live.topics, &topic.description.name, |t| {
+                &t.description.name
+            });
+            match current {
+                None => creates.push(Change::Create(Entity::Topic(topic.description.clone()))),
+                Some(current) => {
+                    let (merged, fields) = diff_topic(&topic.description, &current.description);
+                    if !fields.is_empty() {
+                        updates.push(Change::Update {
+                            entity: Entity::Topic(merged),
+                            fields,
+                        });
+                    }
+                }
+            }
+
+            let live_subs = current.map(|t| &t.subscriptions[..]).unwrap_or(&[]);
+            for sub in &topic.subscriptions {
+                let current = find(live_subs, &sub.description.name, |s| &s.description.name);
+                match current {
+                    None => sub_changes.push(Change::Create(Entity::Subscription(
+                        sub.description.clone(),
+                    ))),
+                    Some(current) => {
+                        let (merged, fields) =
+                            diff_subscription(&sub.description, &current.description);
+                        if !fields.is_empty() {
+                            sub_changes.push(Change::Update {
+                                entity: Entity::Subscription(merged),
+                                fields,
+                            });
+                        }
+                    }
+                }
+
+                let rules = match &sub.rules {
+                    Some(rules) => rules,
+                    None => continue,
+                };
+                // Service Bus gives every new subscription a catch-all rule, so
+                // a subscription about to be created is planned as if it had one.
+                let implied;
+                let live_rules = match current {
+                    Some(current) => current.rules.as_deref().unwrap_or(&[]),
+                    None => {
+                        implied = [RuleDescription::new(
+                            &sub.description.topic,
+                            &sub.description.name,
+                            DEFAULT_RULE_NAME,
+                            RuleFilter::True,
+                        )];
+                        &implied[..]
+                    }
+                };
+                for rule in rules {
+                    match find(live_rules, &rule.name, |r| &r.name) {
+                        None => rule_changes.push(Change::Create(Entity::Rule(rule.clone()))),
+                        Some(current) => {
+                            let fields = diff_rule(rule, current);
+                            if !fields.is_empty() {
+                                rule_changes.push(Change::Update {
+                                    entity: Entity::Rule(rule.clone()),
+                                    fields,
+                                });
+                            }
+                        }
+                    }
+                }
+                for rule in live_rules {
+                    if find(rules, &rule.name, |r| &r.name).is_none() {
+                        // The catch-all rule goes once the listed rules are in place.
+                        if current.is_none() {
+                            rule_changes.push(Change::Delete(Entity::Rule(rule.clone())));
+                        } else if prune {
+                            deletes.push(Change::Delete(Entity::Rule(rule.clone())));
+                        }
+                    }
+                }
+            }
+
+            if prune {
+                for sub in live_subs {
+                    if find(&topic.subscriptions, &sub.description.name, |s| {
+                        &s.description.name
+                    })
+                    .is_none()
+                    {
+                        deletes.push(Change::Delete(Entity::Subscription(
+                            sub.description.clone(),
+                        )));
+                    }
+                }
+            }
+        }
+
+        if prune {
+            // Deleting a topic takes its subscriptions with it.
+            for topic in &live.topics {
+                if find(&self.topics, &topic.description.name, |t| {
+                    &t.description.name
+                })
+                .is_none()
+                {
+                    deletes.push(Change::Delete(Entity::Topic(topic.description.clone())));
+                }
+            }
+            for queue in &live.queues {
+                if find(&self.queues, &queue.name, |q| &q.name).is_none() {
+                    deletes.push(Change::Delete(Entity::Queue(queue.clone())));
+                }
+            }
+        }
+
+        // Forwarding targets have to exist before anything can forward to them,
+        // so queues that forward are created after the plain queues and topics,
+        // and after any other new queue they forward to.
+        let mut changes = creates;
+        for queue in forwarding_order(&forwarding)? {
+            changes.push(Change::Create(Entity::Queue(queue.clone())));
+        }
+        changes.extend(updates);
+        changes.extend(sub_changes);
+        changes.extend(rule_changes);
+        changes.extend(deletes);
+        Ok(Plan { changes })
+    }
+}
+
+// Orders new forwarding queues so every one comes after the new queues it
+// forwards to, keeping file order otherwise.
+fn forwarding_order<'a>(
+    queues: &[&'a QueueDescription],
+) -> Result<Vec<&'a QueueDescription>, Report> {
+    fn visit<'a>(
+        idx: usize,
+        queues: &[&'a QueueDescription],
+        visiting: &mut Vec<usize>,
+        done: &mut Vec<bool>,
+        order: &mut Vec<&'a QueueDescription>,
+    ) -> Result<(), Report> {
+        if done[idx] {
+            return Ok(());
+        }
+        if let Some(start) = visiting.iter().position(|&v| v == idx) {
+            let names: Vec<&str> = visiting[start..]
+                .iter()
+                .chain(Some(&idx))
+                .map(|&v| queues[v].name.as_str())
+                .collect();
+            return Err(eyre!(
+                "Queues forward to each other in a loop: {}.",
+                names.join(" -> ")
+            ));
+        }
+        visiting.push(idx);
+        let queue = queues[idx];
+        let targets = [&queue.forward_to, &queue.forward_dead_lettered_messages_to];
+        for target in targets.iter().filter_map(|t| t.as_deref()) {
+            let target = forward_target(target);
+            if let Some(next) = queues
+                .iter()
+                .position(|q| q.name.eq_ignore_ascii_case(&target))
+            {
+                visit(next, queues, visiting, done, order)?;
+            }
+        }
+        visiting.pop();
+        done[idx] = true;
+        order.push(queue);
+        Ok(())
+    }
+
+    let mut done = vec![false; queues.len()];
+    let mut order = Vec::with_capacity(queues.len());
+    for idx in 0..queues.len() {
+        visit(idx, queues, &mut Vec::new(), &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+
+// Service Bus entity names are case insensitive.
+fn find<'a, T>(items: &'a [T], name: &str, key: impl Fn(&T) -> &String) -> Option<&'a T> {
+    items.iter().find(|i| key(i).eq_ignore_ascii_case(name))
+}
+
+// Forwarding targets come back from the server as full urls, but are usually
+// written as plain entity names.
+pub(crate) fn forward_target(target: &str) -> String {
+    match target.find("://") {
+        Some(idx) => {
+            let rest = &target[idx + 3..];
+            rest.find('/')
+                .map(|slash| rest[slash + 1..].trim_end_matches('/'))
+                .unwrap_or(rest)
+                .to_lowercase()
+        }
+        None => target.to_lowercase(),
+    }
+}
+
+trait Setting {
+    fn show(&self) -> String;
+}
+
+impl Setting for Duration {
+    fn show(&self) -> String {
+        format_duration(*self)
+    }
+}
+
+macro_rules! impl_setting {
+    ($($t:ty),*) => {
+        $(impl Setting for $t {
+            fn show(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+impl_setting!(bool, u32, u64, String);
+
+// Compares the settings `desired` cares about with `live`. Returns a copy of
+// `live` with the desired settings applied, suitable for an update, and the
+// list of settings that changed.
+macro_rules! diff_settings {
+    ($desired:expr, $live:expr, [$($field:ident),* $(,)?]) => {{
+        let mut merged = $live.clone();
+        let mut fields = Vec::new();
+        $(
+            if let Some(want) = &$desired.$field {
+                if $live.$field.as_ref() != Some(want) {
+                    fields.push(FieldChange {
+                        field: stringify!($field),
+                        from: $live.$field.as_ref().map(Setting::show),
+                        to: want.show(),
+                    });
+                    merged.$field = Some(want.clone());
+                }
+            }
+        )*
+        (merged, fields)
+    }};
+}
+
+fn normalize_forwarding(to: &mut Option<String>, dlq: &mut Option<String>) {
+    *to = to.as_deref().map(forward_target);
+    *dlq = dlq.as_deref().map(forward_target);
+}
+
+fn diff_queue(
+    desired: &QueueDescription,
+    live: &QueueDescription,
+) -> (QueueDescription, Vec<FieldChange>) {
+    let (mut desired, mut live) = (desired.clone(), live.clone());
+    normalize_forwarding(
+        &mut desired.forward_to,
+        &mut desired.forward_dead_lettered_messages_to,
+    );
+    normalize_forwarding(
+        &mut live.forward_to,
+        &mut live.forward_dead_lettered_messages_to,
+    );
+    diff_settings!(
+        desired,
+        live,
+        [
+            lock_duration,
+            max_size_in_megabytes,
+            requires_duplicate_detection,
+            requires_session,
+            default_message_time_to_live,
+            dead_lettering_on_message_expiration,
+            duplicate_detection_history_time_window,
+            max_delivery_count,
+            enable_batched_operations,
+            status,
+            forward_to,
+            user_metadata,
+            auto_delete_on_idle,
+            enable_partitioning,
+            forward_dead_lettered_messages_to,
+            enable_express,
+        ]
+    )
+}
+
+fn diff_topic(
+    desired: &TopicDescription,
+    live: &TopicDescription,
+) -> (TopicDescription, Vec<FieldChange>) {
+    diff_settings!(
+        desired,
+        live,
+        [
+            default_message_time_to_live,
+            max_size_in_megabytes,
+            requires_duplicate_detection,
+            duplicate_detection_history_time_window,
+            enable_batched_operations,
+            status,
+            user_metadata,
+            support_ordering,
+            auto_delete_on_idle,
+            enable_partitioning,
+            enable_express,
+        ]
+    )
+}
+
+fn diff_subscription(
+    desired: &SubscriptionDescription,
+    live: &SubscriptionDescription,
+) -> (SubscriptionDescription, Vec<FieldChange>) {
+    let (mut desired, mut live) = (desired.clone(), live.clone());
+    normalize_forwarding(
+        &mut desired.forward_to,
+        &mut desired.forward_dead_lettered_messages_to,
+    );
+    normalize_forwarding(
+        &mut live.forward_to,
+        &mut live.forward_dead_lettered_messages_to,
+    );
+    diff_settings!(
+        desired,
+        live,
+        [
+            lock_duration,
+            requires_session,
+            default_message_time_to_live,
+            dead_lettering_on_message_expiration,
+            dead_lettering_on_filter_evaluation_exceptions,
+            max_delivery_count,
+            enable_batched_operations,
+            status,
+            forward_to,
+            user_metadata,
+            forward_dead_lettered_messages_to,
+            auto_delete_on_idle,
+        ]
+    )
+}
+
+fn diff_rule(desired: &RuleDescription, live: &RuleDescription) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    if desired.filter != live.filter {
+        fields.push(FieldChange {
+            field: "filter",
+            from: Some(format!("{:?}", live.filter)),
+            to: format!("{:?}", desired.filter),
+        });
+    }
+    if desired.action != live.action {
+        fields.push(FieldChange {
+            field: "action",
+            from: live.action.as_ref().map(|a| format!("{:?}", a)),
+            to: desired
+                .action
+                .as_ref()
+                .map(|a| format!("{:?}", a))
+                .unwrap_or_else(|| "none".to_string()),
+        });
+    }
+    fields
+}
+
+/// A single setting that an update changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    /// The current value, if the namespace reported one.
+    pub from: Option<String>,
+    pub to: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Create(Entity),
+    /// `entity` is the full description that will be sent, the live settings
+    /// with the desired ones applied on top.
+    Update {
+        entity: Entity,
+        fields: Vec<FieldChange>,
+    },
+    Delete(Entity),
+}
+
+impl Change {
+    pub fn entity(&self) -> &Entity {
+        match self {
+            Change::Create(e) | Change::Update { entity: e, .. } | Change::Delete(e) => e,
+        }
+    }
+
+    /// Builds the management request that carries out this change.
+    pub fn request(&self, client: &NamespaceClient) -> Result<Request<String>, Report> {
+        match self {
+            Change::Create(e) => client.put_entity(&e.path(), &e.to_xml(), false),
+            Change::Update { entity, .. } => {
+                client.put_entity(&entity.path(), &entity.to_xml(), true)
+            }
+            Change::Delete(e) => client.delete_path(&e.path()),
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (symbol, entity) = match self {
+            Change::Create(e) => ('+', e),
+            Change::Update { entity, .. } => ('~', entity),
+            Change::Delete(e) => ('-', e),
+        };
+        write!(f, "{} {} {}", symbol, entity.kind(), entity.path())?;
+        if let Change::Update { fields, .. } = self {
+            for field in fields {
+                write!(
+                    f,
+                    "\n    {}: {} -> {}",
+                    field.field,
+                    field.from.as_deref().unwrap_or("(unset)"),
+                    field.to
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The ordered list of changes needed to reconcile a namespace. Printing a
+/// plan gives a human readable dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Carries out every change in order, stopping at the first failure.
+    pub fn apply<E: Executor + ?Sized>(
+        &self,
+        client: &NamespaceClient,
+        exec: &E,
+    ) -> Result<(), Report> {
+        for change in &self.changes {
+            let resp = exec.execute(change.request(client)?)?;
+            interpret_results(resp.status()).wrap_err_with(|| {
+                format!(
+                    "Could not apply change to {} {}",
+                    change.entity().kind(),
+                    change.entity().path()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes. The namespace matches the topology.");
+        }
+
+        let (mut create, mut update, mut delete) = (0, 0, 0);
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+            match change {
+                Change::Create(_) => create += 1,
+                Change::Update { .. } => update += 1,
+                Change::Delete(_) => delete += 1,
+            }
+        }
+        writeln!(
+            f,
+            "\nPlan: {} to create, {} to update, {} to delete.",
+            create, update, delete
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESIRED: &str = r#"{
+        "queues": [
+            { "name": "orders", "lock_duration": "PT1M", "max_delivery_count": 5 },
+            { "name": "retries", "forward_to": "orders" }
+        ],
+        "topics": [{
+            "name": "events",
+            "subscriptions": [{
+                "name": "audit",
+                "rules": [{ "name": "errors", "filter": { "type": "sql", "expression": "level = 'error'" } }]
+            }]
+        }]
+    }"#;
+
+    fn live() -> Topology {
+        let mut orders = QueueDescription::new("Orders");
+        orders.lock_duration = Some(Duration::from_secs(30));
+        orders.max_delivery_count = Some(5);
+        orders.message_count = Some(12);
+        Topology {
+            queues: vec![orders, QueueDescription::new("stale")],
+            topics: vec![TopicTopology {
+                description: TopicDescription::new("events"),
+                subscriptions: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn config_links_parents() -> Result<(), Report> {
+        let desired = Topology::from_json(DESIRED)?;
+        let sub = &desired.topics[0].subscriptions[0];
+        assert_eq!("events", sub.description.topic);
+        assert_eq!("audit", sub.rules.as_ref().unwrap()[0].subscription);
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            desired.queues[0].lock_duration
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_config() -> Result<(), Report> {
+        let desired = Topology::from_yaml(
+            "queues:\n  - name: orders\n    lock_duration: PT1M\n\
+             topics:\n  - name: events\n    subscriptions:\n      - name: audit\n",
+        )?;
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            desired.queues[0].lock_duration
+        );
+        assert_eq!(
+            "events",
+            desired.topics[0].subscriptions[0].description.topic
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_creates_updates_and_orders_changes() -> Result<(), Report> {
+        let plan = Topology::from_json(DESIRED)?.plan(&live(), false)?;
+        let summary: Vec<String> = plan
+            .changes
+            .iter()
+            .map(|c| c.to_string().lines().next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "+ queue retries",
+                "~ queue Orders",
+                "+ subscription events/Subscriptions/audit",
+                "+ rule events/Subscriptions/audit/Rules/errors",
+                "- rule events/Subscriptions/audit/Rules/$Default",
+            ],
+            summary
+        );
+
+        match &plan.changes[1] {
+            Change::Update { entity, fields } => {
+                assert_eq!(1, fields.len());
+                assert_eq!("lock_duration", fields[0].field);
+                assert_eq!(Some("PT30S".to_string()), fields[0].from);
+                assert_eq!("PT1M", fields[0].to);
+                // The update carries the settings that were not managed.
+                match entity {
+                    Entity::Queue(q) => assert_eq!(Some(5), q.max_delivery_count),
+                    e => panic!("unexpected entity {:?}", e),
+                }
+            }
+            c => panic!("unexpected change {:?}", c),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plan_prunes_only_when_asked() -> Result<(), Report> {
+        let desired = Topology::from_json(DESIRED)?;
+        let pruned = desired.plan(&live(), true)?;
+        assert_eq!(
+            Some(&Change::Delete(Entity::Queue(QueueDescription::new(
+                "stale"
+            )))),
+            pruned.changes.last()
+        );
+        assert!(pruned
+            .to_string()
+            .ends_with("Plan: 3 to create, 1 to update, 2 to delete.\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn listed_default_rule_is_not_created_twice() -> Result<(), Report> {
+        let desired = Topology::from_json(
+            r#"{ "topics": [{ "name": "events", "subscriptions": [
+                { "name": "all", "rules": [{ "name": "$Default", "filter": { "type": "true" } }] },
+                { "name": "audit", "rules": [
+                    { "name": "$Default", "filter": { "type": "sql", "expression": "level = 'error'" } }
+                ] }
+            ] }] }"#,
+        )?;
+        let plan = desired.plan(&live(), false)?;
+        let summary: Vec<String> = plan
+            .changes
+            .iter()
+            .map(|c| c.to_string().lines().next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "+ subscription events/Subscriptions/all",
+                "+ subscription events/Subscriptions/audit",
+                "~ rule events/Subscriptions/audit/Rules/$Default",
+            ],
+            summary
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn forwarding_queues_follow_their_targets() -> Result<(), Report> {
+        let desired = Topology::from_json(
+            r#"{ "queues": [
+                { "name": "orders", "forward_dead_lettered_messages_to": "orders-dlq" },
+                { "name": "intake", "forward_to": "retries" },
+                { "name": "retries", "forward_to": "orders" },
+                { "name": "orders-dlq" }
+            ] }"#,
+        )?;
+        let plan = desired.plan(&Topology::default(), false)?;
+        let summary: Vec<String> = plan.changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            vec![
+                "+ queue orders-dlq",
+                "+ queue orders",
+                "+ queue retries",
+                "+ queue intake",
+            ],
+            summary
+        );
+
+        let looping = Topology::from_json(
+            r#"{ "queues": [
+                { "name": "a", "forward_to": "b" },
+                { "name": "b", "forward_dead_lettered_messages_to": "a" }
+            ] }"#,
+        )?;
+        let error = looping.plan(&Topology::default(), false).unwrap_err();
+        assert_eq!(
+            "Queues forward to each other in a loop: a -> b -> a.",
+            error.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn applied_plans_match_the_namespace() -> Result<(), Report> {
+        use crate::emulator::Emulator;
+
+        let emulator = Emulator::builder().start()?;
+        let exec = emulator.executor();
+        let client = NamespaceClient::with_conn(&emulator.connection_string())?;
+        let desired = Topology::from_json(
+            r#"{ "topics": [{ "name": "events", "subscriptions": [
+                { "name": "all", "rules": [{ "name": "$Default", "filter": { "type": "true" } }] },
+                { "name": "audit", "rules": [
+                    { "name": "$Default", "filter": { "type": "sql", "expression": "level = 'error'" } },
+                    { "name": "warnings", "filter": { "type": "sql", "expression": "level = 'warn'" } }
+                ] },
+                { "name": "billing", "rules": [
+                    { "name": "invoices", "filter": { "type": "sql", "expression": "kind = 'invoice'" } }
+                ] }
+            ] }] }"#,
+        )?;
+
+        let live = Topology::from_namespace(&client, &exec)?;
+        desired.plan(&live, false)?.apply(&client, &exec)?;
+        let live = Topology::from_namespace(&client, &exec)?;
+        assert!(desired.plan(&live, true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn forward_targets_compare_by_name() -> Result<(), Report> {
+        let desired = Topology::from_json(r#"{ "queues": [{ "name": "a", "forward_to": "b" }] }"#)?;
+        let mut a = QueueDescription::new("a");
+        a.forward_to = Some("https://ns.servicebus.windows.net/B".to_string());
+        let live = Topology {
+            queues: vec![a],
+            ..Default::default()
+        };
+        assert!(desired.plan(&live, false)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+//! A stand-in for Service Bus in application unit tests. `MockBus` is an
+//! `Executor` that answers the requests `QueueClient` and
+//! `SubscriptionClient` build straight from memory: it records every message
+//! sent so tests can assert on it, and hands out the messages a test has
+//! queued up to whatever receives them.
+//!
+//! `render_request` and `assert_golden` pin the exact requests the clients
+//! build to checked-in text files, see `tests/wire.rs`.
+//!
+//! ```
+//! # use azure_service_bus::core::exec::Executor;
+//! # use azure_service_bus::servicebus::brokeredmessage::BrokeredMessage;
+//! # use azure_service_bus::testing::{MockBus, Outcome};
+//! # use azure_service_bus::QueueClient;
+//! # use eyre::Report;
+//! // The code under test: forwards every order to the shipping queue.
+//! fn ship_next(orders: &QueueClient, shipping: &QueueClient, exec: &impl Executor) -> Result<(), Report> {
+//!     let order = BrokeredMessage::with_response(exec.execute(orders.receive()?.map(|()| String::new()))?);
+//!     let parcel = BrokeredMessage::with_body(&format!("parcel for {}", order.get_body()?))
+//!         .with_property("priority", 1);
+//!     exec.execute(shipping.send(parcel)?)?;
+//!     exec.execute(orders.complete_message(order)?.map(|()| String::new()))?;
+//!     Ok(())
+//! }
+//!
+//! # fn main() -> Result<(), Report> {
+//! let conn = "Endpoint=sb://test.servicebus.windows.net/;SharedAccessKeyName=test;SharedAccessKey=dGVzdA==";
+//! let orders = QueueClient::with_conn_and_queue(conn, "orders")?;
+//! let shipping = QueueClient::with_conn_and_queue(conn, "shipping")?;
+//!
+//! let bus = MockBus::new();
+//! bus.enqueue("orders", BrokeredMessage::with_body("order 1"));
+//! ship_next(&orders, &shipping, &bus)?;
+//!
+//! assert_eq!(vec!["parcel for order 1"], bus.sent_bodies("shipping"));
+//! assert_eq!(Some(&1.into()), bus.sent("shipping")[0].properties.get("priority"));
+//! assert_eq!(Outcome::Completed, bus.settled("orders")[0].outcome);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::core::exec::Executor;
+use crate::core::lock;
+use crate::servicebus::brokeredmessage::{
+    read_properties, write_properties, BrokeredMessage, BROKER_PROPERTIES_HEADER,
+};
+use eyre::{eyre, Report};
+use hyper::header::HeaderValue;
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What a receiver did with a message it had locked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    Abandoned,
+    LockRenewed,
+}
+
+/// A message that was completed, abandoned or had its lock renewed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settlement {
+    pub message: BrokeredMessage,
+    pub outcome: Outcome,
+}
+
+/// An in-memory executor that records sends and serves queued up messages.
+/// Entities are named by their path, e.g. `orders`, `orders/$DeadLetterQueue`
+/// or `events/Subscriptions/audit`, case insensitively. Cloning it gives
+/// another handle to the same messages.
+#[derive(Clone, Default)]
+pub struct MockBus {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    entities: BTreeMap<String, Entity>,
+    next_lock: u64,
+}
+
+#[derive(Default)]
+struct Entity {
+    sent: Vec<BrokeredMessage>,
+    inbox: VecDeque<BrokeredMessage>,
+    locked: Vec<BrokeredMessage>,
+    settled: Vec<Settlement>,
+    failures: VecDeque<StatusCode>,
+    next_sequence: usize,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        MockBus::default()
+    }
+
+    /// Queues a message for the next receive from `entity`.
+    pub fn enqueue(&self, entity: &str, message: BrokeredMessage) {
+        self.state().entity(entity).inbox.push_back(message);
+    }
+
+    /// Makes the next request to `entity` fail with `status` instead of
+    /// being answered. Calling it again queues up further failures.
+    pub fn fail_next(&self, entity: &str, status: StatusCode) {
+        self.state().entity(entity).failures.push_back(status);
+    }
+
+    /// Every message sent to `entity`, in order.
+    pub fn sent(&self, entity: &str) -> Vec<BrokeredMessage> {
+        self.state().entity(entity).sent.clone()
+    }
+
+    /// The bodies of the messages sent to `entity`, unwrapped from the
+    /// `<string>` element `BrokeredMessage::with_body` puts them in.
+    pub fn sent_bodies(&self, entity: &str) -> Vec<String> {
+        self.sent(entity)
+            .iter()
+            .map(|m| {
+                m.get_body()
+                    .unwrap_or_else(|_| m.get_body_raw().to_string())
+            })
+            .collect()
+    }
+
+    /// Every message received from `entity` and then settled, in order.
+    pub fn settled(&self, entity: &str) -> Vec<Settlement> {
+        self.state().entity(entity).settled.clone()
+    }
+
+    /// How many messages are still waiting to be received from `entity`,
+    /// counting the ones that are locked by a receiver.
+    pub fn pending(&self, entity: &str) -> usize {
+        let mut state = self.state();
+        let entity = state.entity(entity);
+        entity.inbox.len() + entity.locked.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+impl State {
+    fn entity(&mut self, path: &str) -> &mut Entity {
+        self.entities
+            .entry(path.trim_matches('/').to_ascii_lowercase())
+            .or_insert_with(|| Entity {
+                next_sequence: 1,
+                ..Default::default()
+            })
+    }
+
+    // Lock tokens are counted up rather than random, so tests are repeatable.
+    fn lock_token(&mut self) -> String {
+        self.next_lock += 1;
+        format!("00000000-0000-0000-0000-{:012x}", self.next_lock)
+    }
+}
+
+impl Executor for MockBus {
+    fn execute(&self, request: Request<String>) -> Result<Response<String>, Report> {
+        let path = request.uri().path().trim_matches('/').to_string();
+        let segments: Vec<&str> = path.split('/').collect();
+        let at = segments
+            .iter()
+            .position(|s| s.eq_ignore_ascii_case("messages"))
+            .ok_or_else(|| unsupported(&request))?;
+        let (name, rest) = (segments[..at].join("/"), &segments[at + 1..]);
+
+        let mut state = self.state();
+        if let Some(status) = state.entity(&name).failures.pop_front() {
+            return respond(status, None);
+        }
+        match (request.method(), rest) {
+            (&Method::POST, []) => {
+                let (parts, body) = request.into_parts();
+                let props = parts
+                    .headers
+                    .get(BROKER_PROPERTIES_HEADER)
+                    .and_then(|h| serde_json::from_str(h.to_str().ok()?).ok())
+                    .unwrap_or_default();
+                let mut message = BrokeredMessage::with_body_and_props(&body, props);
+                message.properties = read_properties(&parts.headers);
+                state.entity(&name).sent.push(message);
+                respond(StatusCode::CREATED, None)
+            }
+            (&Method::POST, [head]) | (&Method::DELETE, [head])
+                if head.eq_ignore_ascii_case("head") =>
+            {
+                let peek_lock = request.method() == Method::POST;
+                let token = state.lock_token();
+                let entity = state.entity(&name);
+                let mut message = match entity.inbox.pop_front() {
+                    Some(message) => message,
+                    None => return respond(StatusCode::NO_CONTENT, None),
+                };
+                let props = &mut message.props;
+                if props.SequenceNumber.is_none() {
+                    props.SequenceNumber = Some(entity.next_sequence);
+                    entity.next_sequence += 1;
+                }
+                props.DeliveryCount = Some(props.DeliveryCount.unwrap_or(0) + 1);
+                if !peek_lock {
+                    return respond(StatusCode::OK, Some(&message));
+                }
+                message.props.LockToken = Some(token);
+                entity.locked.push(message.clone());
+                respond(StatusCode::CREATED, Some(&message))
+            }
+            (method, [id, token]) => {
+                let entity = state.entity(&name);
+                let idx = entity.locked.iter().position(|m| {
+                    let props = &m.props;
+                    props.LockToken.as_deref() == Some(token)
+                        && (props.SequenceNumber.map(|s| s.to_string()).as_deref() == Some(id)
+                            || props.MessageId.as_deref() == Some(id))
+                });
+                let idx = match idx {
+                    Some(idx) => idx,
+                    // What Service Bus answers once a lock has been lost.
+                    None => return respond(StatusCode::NOT_FOUND, None),
+                };
+                let outcome = match *method {
+                    Method::DELETE => Outcome::Completed,
+                    Method::PUT => Outcome::Abandoned,
+                    Method::POST => Outcome::LockRenewed,
+                    _ => return Err(unsupported(&request)),
+                };
+                let message = match outcome {
+                    Outcome::LockRenewed => entity.locked[idx].clone(),
+                    _ => entity.locked.remove(idx),
+                };
+                entity.settled.push(Settlement {
+                    message: message.clone(),
+                    outcome,
+                });
+                if outcome == Outcome::Abandoned {
+                    let mut message = message;
+                    message.props.LockToken = None;
+                    entity.inbox.push_front(message);
+                }
+                respond(StatusCode::OK, None)
+            }
+            _ => Err(unsupported(&request)),
+        }
+    }
+}
+
+/// Renders a request the way it goes over the wire: the request line with
+/// the path and query, a `host` header, then the remaining headers sorted by
+/// name, a blank line and the body. Headers with several values keep them
+/// in the order they were added.
+pub fn render_request(request: &Request<String>) -> String {
+    let uri = request.uri();
+    let target = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut text = format!("{} {} HTTP/1.1\n", request.method(), target);
+    if let Some(host) = uri.authority() {
+        text.push_str(&format!("host: {}\n", host));
+    }
+    let mut names: Vec<_> = request.headers().keys().collect();
+    names.sort_by_key(|name| name.as_str());
+    for name in names {
+        for value in request.headers().get_all(name) {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    text.push('\n');
+    text.push_str(request.body());
+    text
+}
+
+/// Compares `actual` against the golden file at `path` and fails with both
+/// versions when they differ. With `UPDATE_GOLDEN=1` in the environment the
+/// file is (re)written instead, which is also how new golden files are
+/// created.
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    if std::env::var_os(UPDATE_GOLDEN).is_some_and(|v| v == "1") {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("creating the golden file directory");
+        }
+        fs::write(path, actual).expect("writing the golden file");
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "no golden file at {} ({}), run with {}=1 to create it",
+            path.display(),
+            e,
+            UPDATE_GOLDEN
+        )
+    });
+    if expected != actual {
+        panic!(
+            "{} is out of date, run with {}=1 to accept the change\n\
+             --- expected\n{}\n--- actual\n{}",
+            path.display(),
+            UPDATE_GOLDEN,
+            expected,
+            actual
+        );
+    }
+}
+
+/// The environment variable that makes `assert_golden` rewrite golden files.
+pub const UPDATE_GOLDEN: &str = "UPDATE_GOLDEN";
+
+fn unsupported(request: &Request<String>) -> Report {
+    eyre!(
+        "MockBus only answers sends, receives and settles, not {} {}",
+        request.method(),
+        request.uri().path()
+    )
+}
+
+fn respond(
+    status: StatusCode,
+    message: Option<&BrokeredMessage>,
+) -> Result<Response<String>, Report> {
+    let mut response = Response::builder().status(status).body(String::new())?;
+    if let Some(message) = message {
+        response.headers_mut().insert(
+            BROKER_PROPERTIES_HEADER,
+            HeaderValue::from_str(&message.props_as_json())?,
+        );
+        write_properties(response.headers_mut(), &message.properties)?;
+        *response.body_mut() = message.get_body_raw().to_string();
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueueClient, SubscriptionClient};
+
+    const CONN: &str =
+        "Endpoint=sb://test.servicebus.windows.net/;SharedAccessKeyName=test;SharedAccessKey=dGVzdA==";
+
+    fn receive(bus: &MockBus, request: Request<()>) -> Result<Response<String>, Report> {
+        bus.execute(request.map(|()| String::new()))
+    }
+
+    #[test]
+    fn records_sends_with_their_properties() -> Result<(), Report> {
+        let bus = MockBus::new();
+        let queue = QueueClient::with_conn_and_queue(CONN, "Orders")?;
+        let mut message = BrokeredMessage::with_body("hello").with_property("tenant", "acme");
+        message.props.MessageId = Some("1".to_string());
+        bus.execute(queue.send(message.clone())?)?;
+
+        assert_eq!(vec![message], bus.sent("orders"));
+        assert_eq!(vec!["hello"], bus.sent_bodies("ORDERS"));
+        assert!(bus.sent("other").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn scripted_receives_and_settles() -> Result<(), Report> {
+        let bus = MockBus::new();
+        let sub = SubscriptionClient::with_conn_topic_and_subscr(CONN, "events", "audit")?;
+        bus.enqueue(
+            "events/Subscriptions/audit",
+            BrokeredMessage::with_body("a"),
+        );
+        bus.enqueue(
+            "events/subscriptions/audit",
+            BrokeredMessage::with_body("b"),
+        );
+
+        let first = BrokeredMessage::with_response(receive(&bus, sub.receive()?)?);
+        assert_eq!("a", first.get_body()?);
+        assert_eq!(Some(1), first.props.DeliveryCount);
+        receive(&bus, sub.renew_message(&first)?)?;
+        receive(&bus, sub.abandon_message(first.clone())?)?;
+        let stale = receive(&bus, sub.complete_message(first)?)?;
+        assert_eq!(StatusCode::NOT_FOUND, stale.status());
+
+        let again = BrokeredMessage::with_response(receive(&bus, sub.receive()?)?);
+        assert_eq!("a", again.get_body()?);
+        assert_eq!(Some(2), again.props.DeliveryCount);
+        receive(&bus, sub.complete_message(again)?)?;
+
+        let outcomes: Vec<Outcome> = bus
+            .settled("events/subscriptions/audit")
+            .iter()
+            .map(|s| s.outcome)
+            .collect();
+        assert_eq!(
+            vec![Outcome::LockRenewed, Outcome::Abandoned, Outcome::Completed],
+            outcomes
+        );
+
+        bus.fail_next(
+            "events/subscriptions/audit",
+            StatusCode::SERVICE_UNAVAILABLE,
+        );
+        let busy = receive(&bus, sub.receive_and_delete()?)?;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, busy.status());
+        let b = receive(&bus, sub.receive_and_delete()?)?;
+        assert_eq!(StatusCode::OK, b.status());
+        assert_eq!(0, bus.pending("events/subscriptions/audit"));
+        let empty = receive(&bus, sub.receive_and_delete()?)?;
+        assert_eq!(StatusCode::NO_CONTENT, empty.status());
+        Ok(())
+    }
+}
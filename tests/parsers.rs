@@ -0,0 +1,234 @@
+//! Property tests for everything that parses input from the network or the
+//! user: message bodies and headers, connection strings, SAS tokens and the
+//! ATOM documents of the management api. The `fuzz` directory has cargo-fuzz
+//! targets for the same parsers.
+
+use azure_service_bus::core::connection_string::ConnectionString;
+use azure_service_bus::core::sas::{self, SasToken};
+use azure_service_bus::servicebus::atom::{
+    format_duration, parse_duration, parse_feed, wrap_entry, Entry, XmlElement,
+};
+use azure_service_bus::servicebus::brokeredmessage::{BrokerProperties, BrokeredMessage};
+use azure_service_bus::servicebus::description::{EntityDescription, QueueDescription};
+use azure_service_bus::QueueClient;
+use hyper::header::HeaderValue;
+use hyper::Response;
+use proptest::prelude::*;
+use serde_json::Value;
+use std::time::Duration;
+
+const CONN: &str =
+    "Endpoint=sb://test.servicebus.windows.net/;SharedAccessKeyName=test;SharedAccessKey=dGVzdA==";
+
+// A received message made out of whatever a sent request carried.
+fn deliver(message: BrokeredMessage) -> BrokeredMessage {
+    let queue = QueueClient::with_conn_and_queue(CONN, "orders").unwrap();
+    let (parts, body) = queue.send(message).unwrap().into_parts();
+    let mut response = Response::new(body);
+    *response.headers_mut() = parts.headers;
+    BrokeredMessage::with_response(response)
+}
+
+fn broker_properties() -> impl Strategy<Value = BrokerProperties> {
+    (
+        proptest::option::of(any::<String>()),
+        proptest::option::of(any::<String>()),
+        proptest::option::of(any::<String>()),
+        proptest::option::of(any::<String>()),
+        proptest::option::of(0..usize::MAX / 2),
+    )
+        .prop_map(
+            |(message_id, label, correlation_id, session_id, ttl)| BrokerProperties {
+                MessageId: message_id,
+                Label: label,
+                CorrelationId: correlation_id,
+                SessionId: session_id,
+                TimeToLive: ttl,
+                ..Default::default()
+            },
+        )
+}
+
+fn property_value() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<String>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<bool>().prop_map(Value::from),
+    ]
+}
+
+fn connection_string() -> impl Strategy<Value = String> {
+    (
+        "[a-z][a-z0-9-]{2,30}",
+        "[A-Za-z0-9_.-]{1,30}",
+        proptest::collection::vec(any::<u8>(), 1..64),
+        proptest::option::of("[a-z0-9][a-z0-9/._-]{0,30}"),
+    )
+        .prop_map(|(namespace, key_name, key, entity_path)| {
+            let mut conn = format!(
+                "Endpoint=sb://{}.servicebus.windows.net/;SharedAccessKeyName={};SharedAccessKey={}",
+                namespace,
+                key_name,
+                base64::encode(key)
+            );
+            if let Some(path) = entity_path {
+                conn.push_str(&format!(";EntityPath={}", path));
+            }
+            conn
+        })
+}
+
+proptest! {
+    #[test]
+    fn bodies_round_trip(body in any::<String>()) {
+        let message = BrokeredMessage::with_body(&body);
+        prop_assert_eq!(body, message.get_body().unwrap());
+    }
+
+    #[test]
+    fn raw_bodies_never_panic(body in any::<String>()) {
+        let message = BrokeredMessage::with_body_and_props(&body, BrokerProperties::default());
+        if let Ok(inner) = message.get_body() {
+            prop_assert!(body.contains(&inner));
+        }
+    }
+
+    #[test]
+    fn broker_properties_round_trip(props in broker_properties(), body in any::<String>()) {
+        let message = BrokeredMessage::with_body_and_props(&body, props.clone());
+        let received = deliver(message);
+        prop_assert_eq!(&props, &*received.props);
+        prop_assert_eq!(body.as_str(), received.get_body_raw());
+    }
+
+    #[test]
+    fn custom_properties_round_trip(
+        properties in proptest::collection::btree_map("x-[a-z0-9-]{1,20}", property_value(), 0..8)
+    ) {
+        let mut message = BrokeredMessage::with_body("");
+        message.properties = properties.clone();
+        prop_assert_eq!(properties, deliver(message).properties);
+    }
+
+    #[test]
+    fn broker_properties_header_never_panics(header in any::<String>()) {
+        let mut response = Response::new(String::new());
+        if let Ok(value) = HeaderValue::from_str(&header) {
+            response.headers_mut().insert("brokerproperties", value);
+        }
+        BrokeredMessage::with_response(response);
+    }
+
+    #[test]
+    fn connection_strings_round_trip(conn in connection_string()) {
+        let parsed: ConnectionString = conn.parse().unwrap();
+        prop_assert_eq!(&conn, &parsed.to_string());
+        prop_assert_eq!(parsed.clone(), parsed.to_string().parse::<ConnectionString>().unwrap());
+    }
+
+    #[test]
+    fn connection_strings_never_panic(conn in any::<String>()) {
+        let _ = conn.parse::<ConnectionString>();
+    }
+
+    #[test]
+    fn parsed_connection_strings_reparse(
+        segments in proptest::collection::vec(
+            (
+                "(?i)(endpoint|sharedaccesskeyname|sharedaccesskey|sharedaccesssignature|entitypath|transporttype|usedevelopmentemulator)",
+                prop_oneof![
+                    any::<String>(),
+                    "(sb|https|http)://[a-z]{1,10}(\\.servicebus\\.windows\\.net)?(:[0-9]{1,5})?/?",
+                    "(?i)(true|false|amqp|amqpwebsockets)",
+                    " ?[A-Za-z0-9=+/]{0,20} ?",
+                ],
+            ),
+            0..8,
+        )
+    ) {
+        let conn = segments
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(";");
+        if let Ok(parsed) = conn.parse::<ConnectionString>() {
+            prop_assert_eq!(parsed.clone(), parsed.to_string().parse::<ConnectionString>().unwrap());
+        }
+    }
+
+    #[test]
+    fn sas_tokens_verify(
+        // Entity paths are letters, digits, `.`, `-`, `_`, `$` and `/`.
+        resource in "(sb|https)://[a-z0-9.-]{1,30}(/[A-Za-z0-9$._-]{1,20}){0,3}/?",
+        key_name in "[A-Za-z0-9_.-]{1,30}",
+        key in any::<String>(),
+        expiry in 1..u64::MAX / 2,
+    ) {
+        let token = SasToken::builder(&resource).expires_at(expiry).sign(&key_name, &key);
+        let verified = sas::verify(&token.to_string(), &key, expiry - 1).unwrap();
+        prop_assert_eq!(&token, &verified);
+        prop_assert_eq!(resource, verified.resource());
+    }
+
+    #[test]
+    fn sas_tokens_never_panic(token in any::<String>(), key in any::<String>()) {
+        let _ = token.parse::<SasToken>();
+        let _ = sas::verify(&token, &key, 0);
+    }
+
+    #[test]
+    fn xml_never_panics(document in any::<String>()) {
+        let _ = XmlElement::parse(&document);
+        let _ = parse_feed(&document);
+        if let Ok(entry) = Entry::parse(&document) {
+            let _ = QueueDescription::from_entry(&entry);
+        }
+    }
+
+    #[test]
+    fn xml_like_documents_never_panic(
+        document in r#"(<[a-z:]{1,5}( [a-z:]{1,3}="[^"<]{0,5}")?/?>|</[a-z:]{1,5}>|[a-zA-Z0-9 &;]{0,5}|<!\[CDATA\[.{0,5}\]\]>){0,20}"#
+    ) {
+        let _ = XmlElement::parse(&document);
+        let _ = parse_feed(&document);
+    }
+
+    #[test]
+    fn durations_round_trip(secs in 0..u64::MAX / 1_000_000, millis in 0..1000u32) {
+        let d = Duration::new(secs, millis * 1_000_000);
+        prop_assert_eq!(d, parse_duration(&format_duration(d)).unwrap());
+    }
+
+    #[test]
+    fn durations_never_panic(s in any::<String>(), iso in "P[0-9.WDTHMS]{0,20}") {
+        let _ = parse_duration(&s);
+        let _ = parse_duration(&iso);
+    }
+
+    #[test]
+    fn huge_durations_are_errors(digits in "[1-9][0-9]{20,400}", unit in "W|D|TH|TM|TS") {
+        let (whole, fractional) = (format!("P{}{}", digits, unit), format!("P{}.5{}", digits, unit));
+        prop_assert!(parse_duration(&whole).is_err());
+        prop_assert!(parse_duration(&fractional).is_err());
+        prop_assert!(parse_duration("P99999999999999999D").is_err());
+    }
+
+    #[test]
+    fn queue_descriptions_round_trip(
+        lock_duration in proptest::option::of((1..300u64).prop_map(Duration::from_secs)),
+        max_delivery_count in proptest::option::of(any::<u32>()),
+        requires_session in proptest::option::of(any::<bool>()),
+        forward_to in proptest::option::of("[a-z0-9][a-z0-9/._-]{0,30}"),
+        // Text is trimmed and empty elements read as `None`.
+        user_metadata in proptest::option::of("\\PC+".prop_filter("trimmed", |s| s.trim() == s)),
+    ) {
+        let mut queue = QueueDescription::new("");
+        queue.lock_duration = lock_duration;
+        queue.max_delivery_count = max_delivery_count;
+        queue.requires_session = requires_session;
+        queue.forward_to = forward_to;
+        queue.user_metadata = user_metadata;
+        let entry = Entry::parse(&wrap_entry(&queue.to_xml())).unwrap();
+        prop_assert_eq!(queue, QueueDescription::from_entry(&entry).unwrap());
+    }
+}
@@ -0,0 +1,236 @@
+//! The exact requests the clients build, pinned to the text files in
+//! `tests/golden`. A change to a path, header or the `BrokerProperties` JSON
+//! shows up here as a failing test; when the change is intended, run
+//! `UPDATE_GOLDEN=1 cargo test --test wire` and commit the rewritten files.
+
+use azure_service_bus::core::clock::ManualClock;
+use azure_service_bus::core::token::{SasKeyProvider, TokenProvider};
+use azure_service_bus::servicebus::brokeredmessage::{BrokerProperties, BrokeredMessage};
+use azure_service_bus::servicebus::description::{
+    CorrelationFilter, QueueDescription, RuleAction, RuleDescription, RuleFilter,
+    SubscriptionDescription, TopicDescription,
+};
+use azure_service_bus::testing::{assert_golden, render_request};
+use azure_service_bus::{NamespaceClient, QueueClient, SubscriptionClient};
+use hyper::{Request, Uri};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const ENDPOINT: &str = "https://test.servicebus.windows.net/";
+const RESOURCE: &str = "sb://test.servicebus.windows.net/";
+
+// Every token is signed as if it were this moment, so the signatures in the
+// golden files never change.
+const NOW: u64 = 1_700_000_000;
+
+fn credential() -> Arc<dyn TokenProvider> {
+    Arc::new(
+        SasKeyProvider::new(RESOURCE, "RootManageSharedAccessKey", "dGVzdA==")
+            .with_clock(Arc::new(ManualClock::at(NOW))),
+    )
+}
+
+fn queue() -> QueueClient {
+    QueueClient::with_token_provider(ENDPOINT.parse().unwrap(), "orders", credential())
+}
+
+fn subscription() -> SubscriptionClient {
+    SubscriptionClient::with_token_provider(
+        ENDPOINT.parse().unwrap(),
+        "events",
+        "audit",
+        credential(),
+    )
+}
+
+fn namespace() -> NamespaceClient {
+    NamespaceClient::with_token_provider(ENDPOINT.parse::<Uri>().unwrap(), credential())
+}
+
+// A message as it comes back from a peek-lock receive.
+fn locked(sequence: Option<usize>, message_id: Option<&str>) -> BrokeredMessage {
+    let props = BrokerProperties {
+        LockToken: Some("4c5a3e3c-6f1c-4a4b-9d2e-0e4b1c8d7f21".to_string()),
+        SequenceNumber: sequence,
+        MessageId: message_id.map(str::to_string),
+        ..Default::default()
+    };
+    BrokeredMessage::with_body_and_props("<string>order 1</string>", props)
+}
+
+fn check(name: &str, request: Request<String>) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+        .iter()
+        .collect();
+    assert_golden(path.with_extension("http"), &render_request(&request));
+}
+
+fn empty(request: Request<()>) -> Request<String> {
+    request.map(|()| String::new())
+}
+
+#[test]
+fn queue_send() {
+    let plain = BrokeredMessage::with_body("order 1");
+    check("queue_send", queue().send(plain).unwrap());
+
+    let props = BrokerProperties {
+        MessageId: Some("order-1".to_string()),
+        CorrelationId: Some("checkout-7".to_string()),
+        SessionId: Some("customer-42".to_string()),
+        Label: Some("order".to_string()),
+        ReplyTo: Some("confirmations".to_string()),
+        TimeToLive: Some(600),
+        ScheduledEnqueueTimeUtc: Some("Tue, 14 Nov 2023 22:13:20 GMT".to_string()),
+        ..Default::default()
+    };
+    let full = BrokeredMessage::with_body_and_props("{\"id\":1}", props)
+        .with_property("priority", 2)
+        .with_property("region", "west europe")
+        .with_property("express", true);
+    let request = queue()
+        .send_with_timeout(full, Duration::from_secs(5))
+        .unwrap();
+    check("queue_send_with_properties", request);
+}
+
+#[test]
+fn queue_receive() {
+    let q = queue();
+    check("queue_receive", empty(q.receive().unwrap()));
+    check(
+        "queue_receive_with_timeout",
+        empty(q.receive_with_timeout(Duration::from_secs(60)).unwrap()),
+    );
+    check(
+        "queue_receive_and_delete",
+        empty(q.receive_and_delete().unwrap()),
+    );
+    check(
+        "queue_dead_letter_receive",
+        empty(q.dead_letter_queue().receive().unwrap()),
+    );
+}
+
+#[test]
+fn queue_settle() {
+    let q = queue();
+    let by_sequence = || locked(Some(17), Some("order-1"));
+    check(
+        "queue_complete",
+        empty(q.complete_message(by_sequence()).unwrap()),
+    );
+    check(
+        "queue_abandon",
+        empty(q.abandon_message(by_sequence()).unwrap()),
+    );
+    check(
+        "queue_renew",
+        empty(q.renew_message(&by_sequence()).unwrap()),
+    );
+
+    // Without a sequence number the message id identifies the message.
+    let by_id = locked(None, Some("order-1"));
+    check(
+        "queue_complete_by_message_id",
+        empty(q.complete_message(by_id).unwrap()),
+    );
+}
+
+#[test]
+fn subscription_requests() {
+    let s = subscription();
+    check("subscription_receive", empty(s.receive().unwrap()));
+    check(
+        "subscription_receive_and_delete_with_timeout",
+        empty(
+            s.receive_and_delete_with_timeout(Duration::from_secs(10))
+                .unwrap(),
+        ),
+    );
+    check(
+        "subscription_dead_letter_receive",
+        empty(s.dead_letter_queue().receive().unwrap()),
+    );
+    let message = || locked(Some(17), None);
+    check(
+        "subscription_complete",
+        empty(s.complete_message(message()).unwrap()),
+    );
+    check(
+        "subscription_abandon",
+        empty(s.abandon_message(message()).unwrap()),
+    );
+    check(
+        "subscription_renew",
+        empty(s.renew_message(&message()).unwrap()),
+    );
+}
+
+#[test]
+fn namespace_requests() {
+    let ns = namespace();
+    check(
+        "namespace_list_queues",
+        ns.list_queues_page(0, 100).unwrap(),
+    );
+    check(
+        "namespace_list_rules",
+        ns.list_rules_page("events", "audit", 20, 10).unwrap(),
+    );
+    check("namespace_get_queue", ns.get_queue("orders").unwrap());
+    check(
+        "namespace_get_subscription",
+        ns.get_subscription("events", "audit").unwrap(),
+    );
+
+    let mut orders = QueueDescription::new("orders");
+    orders.lock_duration = Some(Duration::from_secs(45));
+    orders.max_delivery_count = Some(5);
+    orders.requires_duplicate_detection = Some(true);
+    orders.forward_dead_lettered_messages_to = Some("poison".to_string());
+    check("namespace_create_queue", ns.create(&orders).unwrap());
+    check("namespace_update_queue", ns.update(&orders).unwrap());
+    check("namespace_delete_queue", ns.delete(&orders).unwrap());
+
+    let mut events = TopicDescription::new("events");
+    events.max_size_in_megabytes = Some(1024);
+    check("namespace_create_topic", ns.create(&events).unwrap());
+
+    let mut audit = SubscriptionDescription::new("events", "audit");
+    audit.dead_lettering_on_filter_evaluation_exceptions = Some(true);
+    audit.forward_to = Some("archive".to_string());
+    check("namespace_create_subscription", ns.create(&audit).unwrap());
+
+    let mut sql = RuleDescription::new(
+        "events",
+        "audit",
+        "high-priority",
+        RuleFilter::Sql {
+            expression: "priority > 1 AND sys.Label = 'order'".to_string(),
+        },
+    );
+    sql.action = Some(RuleAction::Sql {
+        expression: "SET audited = TRUE".to_string(),
+    });
+    check("namespace_create_sql_rule", ns.create(&sql).unwrap());
+
+    let correlation = RuleDescription::new(
+        "events",
+        "audit",
+        "checkout",
+        RuleFilter::Correlation(CorrelationFilter {
+            correlation_id: Some("checkout-7".to_string()),
+            properties: vec![("region".to_string(), "west europe".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }),
+    );
+    check(
+        "namespace_create_correlation_rule",
+        ns.create(&correlation).unwrap(),
+    );
+    check("namespace_delete_rule", ns.delete(&correlation).unwrap());
+}